@@ -0,0 +1,139 @@
+//! Throughput of the hot path in data points per second: the generators, the formatters, the devices formatting their
+//! payloads, and the simulation iterating over all devices of a run. Run with `cargo bench`, or `cargo bench -- device`
+//! for a part.
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use rumsim::device::Device;
+use rumsim::generator::{create_generator, GeneratorConfig, GeneratorType, UnitConfig, Value};
+use rumsim::payload::{Measurement, PayloadConfig, PayloadFormat};
+use rumsim::simulation::{DeliveryConfig, Simulation, SimulationParameters, TimeConfig};
+
+fn generators(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generator");
+    let config = GeneratorConfig::default();
+    // Replay has no values without the data of SIM_REPLAY_FILE.
+    for generator_type in GeneratorType::ALL
+        .into_iter()
+        .filter(|generator_type| *generator_type != GeneratorType::Replay)
+    {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let mut generator = create_generator(generator_type, 1, &config);
+        // Some generators produce several correlated values at once.
+        let values = generator.generate(&mut rng).len() as u64;
+        group.throughput(Throughput::Elements(values));
+        group.bench_function(generator_type.name(), |b| {
+            b.iter(|| black_box(generator.generate(&mut rng).len()))
+        });
+    }
+    group.finish();
+}
+
+/// Formatting into a new payload each time compared to formatting into a buffer that is reused, like a device does.
+fn payloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("payload");
+    let time = Utc::now();
+    let unit = UnitConfig {
+        unit: "°C".to_string(),
+        scale: 1.0,
+        offset: 0.0,
+    };
+    let names: Vec<_> = (0..100).map(|n| format!("sensor_{}", n)).collect();
+    let measurements: Vec<Measurement> = names
+        .iter()
+        .enumerate()
+        .map(|(n, name)| (name.as_str(), Value::Float(n as f64 * 1.5), Some(&unit)))
+        .collect();
+    group.throughput(Throughput::Elements(measurements.len() as u64));
+    for format in [
+        PayloadFormat::SmartRest,
+        PayloadFormat::Json,
+        PayloadFormat::Csv,
+        PayloadFormat::LineProtocol,
+        PayloadFormat::Cbor,
+    ] {
+        let payload = PayloadConfig {
+            format,
+            ..PayloadConfig::default()
+        };
+        let formatter = payload.formatter("rumsim-0_1");
+        let id = BenchmarkId::new("new", format!("{:?}", format));
+        group.bench_function(id, |b| {
+            b.iter(|| black_box(formatter.measurements(time, &measurements)))
+        });
+        let mut buffer = Vec::new();
+        let id = BenchmarkId::new("reused", format!("{:?}", format));
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                buffer.clear();
+                formatter
+                    .write_measurements(&mut buffer, time, &measurements)
+                    .unwrap();
+                black_box(buffer.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn devices(c: &mut Criterion) {
+    let mut group = c.benchmark_group("device");
+    let config = GeneratorConfig::default();
+    let time = Utc::now();
+    for format in [
+        PayloadFormat::SmartRest,
+        PayloadFormat::Json,
+        PayloadFormat::Csv,
+        PayloadFormat::LineProtocol,
+        PayloadFormat::Cbor,
+    ] {
+        let payload = PayloadConfig {
+            format,
+            ..PayloadConfig::default()
+        };
+        for data_points in [10, 100, 1000] {
+            let mut device = Device::new("rumsim", 1, data_points, 1, &config, &payload, None);
+            group.throughput(Throughput::Elements(device.data_points() as u64));
+            let id = BenchmarkId::new(format!("{:?}", format), data_points);
+            group.bench_function(id, |b| b.iter(|| black_box(device.generate(time))));
+        }
+    }
+    group.finish();
+}
+
+fn simulation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("simulation");
+    for (threads, devices) in [(1, 10), (1, 100), (1, 1000), (1, 10000), (4, 10000)] {
+        let mut simulation = Simulation::new(&SimulationParameters {
+            client_id: "rumsim".to_string(),
+            devices,
+            manifest: None,
+            children: 0,
+            data_points: 10,
+            seed: 1,
+            frequency_secs: 60.0,
+            generators: GeneratorConfig::default(),
+            payload: PayloadConfig::default(),
+            time: TimeConfig::default(),
+            delivery: DeliveryConfig::default(),
+            threads,
+            shard: None,
+        });
+        group.throughput(Throughput::Elements(simulation.data_points() as u64));
+        let mut run = 0;
+        let id = BenchmarkId::new(format!("{}_threads", threads), devices);
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                run += 1;
+                simulation.iter(run).for_each(|message| {
+                    black_box(message);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, generators, payloads, devices, simulation);
+criterion_main!(benches);
@@ -0,0 +1,146 @@
+//! Publishing with AMQP 1.0, for example directly to Azure Event Hubs or Service Bus.
+use fe2o3_amqp::connection::ConnectionHandle;
+use fe2o3_amqp::sasl_profile::SaslProfile;
+use fe2o3_amqp::session::SessionHandle;
+use fe2o3_amqp::types::messaging::{ApplicationProperties, Data, Message, Properties};
+use fe2o3_amqp::types::primitives::Binary;
+use fe2o3_amqp::{Connection, Sender, Session};
+use std::str::FromStr;
+use url::Url;
+
+use crate::publisher::Publisher;
+use crate::settings::Settings;
+
+/// The SASL mechanism to authenticate with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmqpSasl {
+    Anonymous,
+    /// User and password, which are the name and the key of a shared access policy with Event Hubs and Service Bus.
+    Plain,
+}
+
+impl FromStr for AmqpSasl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anonymous" => Ok(AmqpSasl::Anonymous),
+            "plain" => Ok(AmqpSasl::Plain),
+            _ => Err(format!("Unknown SASL mechanism {}", s)),
+        }
+    }
+}
+
+pub struct AmqpPublisher {
+    connection: ConnectionHandle<()>,
+    session: SessionHandle<()>,
+    sender: Option<Sender>,
+}
+
+impl AmqpPublisher {
+    /// Connect to the broker and attach a sender to the address in the path of the URL, like the name of the event hub.
+    pub async fn connect(settings: &Settings) -> Result<Self, String> {
+        let url = Url::parse(&settings.broker.url).map_err(|e| e.to_string())?;
+        let address = address(&url)?;
+        let profile = match settings.broker.amqp_sasl {
+            AmqpSasl::Anonymous => SaslProfile::Anonymous,
+            AmqpSasl::Plain => SaslProfile::Plain {
+                username: settings.broker.user.clone(),
+                password: settings.broker.pass.clone(),
+            },
+        };
+        // amqps:// is TLS from the start on port 5671 instead of upgrading an AMQP connection to TLS.
+        let mut connection = Connection::builder()
+            .container_id(&settings.broker.client_id)
+            .alt_tls_establishment(url.scheme() == "amqps")
+            .sasl_profile(profile)
+            .open(url)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut session = Session::begin(&mut connection)
+            .await
+            .map_err(|e| e.to_string())?;
+        let sender = Sender::attach(&mut session, &settings.broker.client_id, address)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(AmqpPublisher {
+            connection,
+            session,
+            sender: Some(sender),
+        })
+    }
+}
+
+impl Publisher for AmqpPublisher {
+    /// Send the payload and wait until the broker accepts it.
+    async fn publish(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        device: Option<&str>,
+        run: usize,
+    ) -> Result<(), String> {
+        let sender = self.sender.as_mut().ok_or("Sender is closed")?;
+        let outcome = sender
+            .send(message(topic, payload, device, run))
+            .await
+            .map_err(|e| e.to_string())?;
+        outcome
+            .accepted_or_else(|outcome| format!("Message not accepted: {:?}", outcome))
+            .map(|_| ())
+    }
+
+    async fn close(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.close().await;
+        }
+        let _ = self.session.end().await;
+        let _ = self.connection.close().await;
+    }
+}
+
+/// The address is the path of the URL without the leading slash.
+fn address(url: &Url) -> Result<String, String> {
+    match url.path().trim_start_matches('/') {
+        "" => Err(format!("No address in {}", url)),
+        address => Ok(address.to_string()),
+    }
+}
+
+/// The topic is the subject of the message. The device and the run are application properties like the user
+/// properties of MQTT 5.
+fn message(topic: String, payload: Vec<u8>, device: Option<&str>, run: usize) -> Message<Data> {
+    let mut properties = ApplicationProperties::builder().insert("run", run as u64);
+    if let Some(device) = device {
+        properties = properties.insert("device", device);
+    }
+    Message::builder()
+        .properties(Properties::builder().subject(topic).build())
+        .application_properties(properties.build())
+        .data(Binary::from(payload))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe2o3_amqp::types::primitives::SimpleValue;
+
+    #[test]
+    fn test_address() {
+        let url = Url::parse("amqps://example.servicebus.windows.net/telemetry").unwrap();
+        assert_eq!(address(&url), Ok("telemetry".to_string()));
+        let url = Url::parse("amqp://localhost:5672").unwrap();
+        assert!(address(&url).is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let message = message("a/b".to_string(), vec![1, 2], Some("dev"), 3);
+        assert_eq!(message.properties.unwrap().subject, Some("a/b".to_string()));
+        let properties = message.application_properties.unwrap();
+        assert_eq!(properties.get("device"), Some(&SimpleValue::from("dev")));
+        assert_eq!(properties.get("run"), Some(&SimpleValue::from(3u64)));
+        assert_eq!(message.body.0.to_vec(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,151 @@
+//! The HTTP API for Kubernetes probes and for driving the simulator from test harnesses.
+use axum::extract::State as AxumState;
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use std::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::control::{Command, Control, Progress, State, Status};
+use crate::observability::prometheus_metrics;
+
+#[derive(Clone)]
+struct Api {
+    control: watch::Sender<Control>,
+    progress: watch::Receiver<Progress>,
+}
+
+/// Serve the API on the given port until the process ends.
+pub async fn serve(
+    port: u16,
+    control: watch::Sender<Control>,
+    progress: watch::Receiver<Progress>,
+) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!(error = ?e, port, "Cannot serve the HTTP API");
+            return;
+        }
+    };
+    info!(port, "Serving the HTTP API.");
+    if let Err(e) = serve_on(listener, control, progress).await {
+        warn!(error = e, "HTTP API failed");
+    }
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    control: watch::Sender<Control>,
+    progress: watch::Receiver<Progress>,
+) -> Result<(), String> {
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    let router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .route("/control", post(control_command))
+        .route("/metrics", get(metrics))
+        .with_state(Api { control, progress });
+    axum::Server::from_tcp(listener)
+        .map_err(|e| e.to_string())?
+        .serve(router.into_make_service())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The process is alive.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The simulation has started and is not stopped.
+async fn readyz(AxumState(api): AxumState<Api>) -> (StatusCode, &'static str) {
+    let started = api.progress.borrow().started;
+    if started && api.control.borrow().state != State::Stopped {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+async fn status(AxumState(api): AxumState<Api>) -> Json<Status> {
+    Json(Status::new(&api.control, &api.progress))
+}
+
+/// The metrics for Prometheus, for example to scale the simulators with a HorizontalPodAutoscaler on the capacity.
+async fn metrics() -> (StatusCode, String) {
+    match prometheus_metrics() {
+        Some(metrics) => (StatusCode::OK, metrics),
+        None => (StatusCode::NOT_FOUND, "No metrics".to_string()),
+    }
+}
+
+/// Apply a command in the same format as on the MQTT control topic and return the resulting control.
+async fn control_command(
+    AxumState(api): AxumState<Api>,
+    body: String,
+) -> Result<Json<Control>, (StatusCode, String)> {
+    let command = body
+        .parse::<Command>()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!(?command, "Received command.");
+    api.control.send_modify(|control| control.apply(command));
+    let control = api.control.borrow().clone();
+    Ok(Json(control))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_api() {
+        let (control, _controlled) = watch::channel(Control::new(true, 60.0, 10));
+        let (progress, progressed) = watch::channel(Progress::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_on(listener, control, progressed));
+
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{}{}", url, path)).send();
+        assert_eq!(get("/healthz").await.unwrap().status(), 200);
+        assert_eq!(get("/readyz").await.unwrap().status(), 503);
+        // Without CONTROL_PORT, there is no Prometheus exporter.
+        assert_eq!(get("/metrics").await.unwrap().status(), 404);
+
+        progress.send_modify(|progress| {
+            progress.started = true;
+            progress.runs = 3;
+        });
+        assert_eq!(get("/readyz").await.unwrap().status(), 200);
+
+        let response = client
+            .post(format!("{}/control", url))
+            .body("resume")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("\"state\":\"running\""));
+
+        let response = client
+            .post(format!("{}/control", url))
+            .body("jump")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        let status = get("/status").await.unwrap().text().await.unwrap();
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(status["state"], "running");
+        assert_eq!(status["frequency_secs"], 60);
+        assert_eq!(status["progress"]["runs"], 3);
+    }
+}
@@ -0,0 +1,65 @@
+//! Authentication of the simulator as Azure IoT Hub device.
+//!
+//! IoT Hub devices authenticate with the user name <hub host>/<device ID>/?api-version=<version>
+//! and a shared access signature (SAS) token derived from the key of the device as password.
+//! See https://learn.microsoft.com/en-us/azure/iot/iot-mqtt-connect-to-iot-hub.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const API_VERSION: &str = "2021-04-12";
+
+/// The user name of a device.
+pub fn user_name(host: &str, device_id: &str) -> String {
+    format!("{}/{}/?api-version={}", host, device_id, API_VERSION)
+}
+
+/// A SAS token for a device that is valid until the expiry time.
+/// The key is the base64-encoded symmetric key of the device.
+pub fn sas_token(
+    host: &str,
+    device_id: &str,
+    key: &str,
+    expiry: DateTime<Utc>,
+) -> Result<String, String> {
+    let resource = urlencoding::encode(&format!("{}/devices/{}", host, device_id)).into_owned();
+    let expiry = expiry.timestamp();
+    let key = STANDARD
+        .decode(key)
+        .map_err(|e| format!("Invalid device key: {}", e))?;
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+    mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+    let signature = STANDARD.encode(mac.finalize().into_bytes());
+    Ok(format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource,
+        urlencoding::encode(&signature),
+        expiry
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_name() {
+        assert_eq!(
+            user_name("hub.azure-devices.net", "rumsim-0"),
+            "hub.azure-devices.net/rumsim-0/?api-version=2021-04-12"
+        );
+    }
+
+    #[test]
+    fn test_sas_token() {
+        let expiry = DateTime::from_timestamp(1704067200, 0).unwrap();
+        let key = STANDARD.encode("secret");
+        assert_eq!(
+            sas_token("hub.azure-devices.net", "rumsim-0", &key, expiry).unwrap(),
+            "SharedAccessSignature sr=hub.azure-devices.net%2Fdevices%2Frumsim-0&sig=9nYQB8dToT31kSO%2BQFZVaqa6WPJWHZ9ujQOS5qVPr24%3D&se=1704067200"
+        );
+        assert!(sas_token("hub.azure-devices.net", "rumsim-0", "not base64!", expiry).is_err());
+    }
+}
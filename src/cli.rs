@@ -0,0 +1,581 @@
+//! Command line arguments for trying out the simulator locally. Each setting can also be given as a flag, which
+//! overrides the environment variable, like `rumsim --devices 100 --data-points 50 --broker mqtt://localhost:1883`.
+//! The settings can also be kept in a TOML or YAML file with `--config rumsim.toml`, for example to version the
+//! configuration of a benchmark. The environment variables override the file. The file is reloaded on SIGHUP or when
+//! it changes.
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+use crate::control::{self, Control};
+use crate::settings::parse_frequency;
+
+/// The help heading, the environment variable, the default and the description of each setting, as in the README.
+const SETTINGS: &[(&str, &str, &str, &str)] = &[
+    ("Broker", "BROKER_URL", "mqtt://localhost:1883", "Where to send data to: an MQTT broker with the scheme mqtt, mqtts, ws or wss, an HTTP endpoint, an AMQP 1.0 broker, stdout:// or file://<path>."),
+    ("Broker", "BROKER_USER", "mqtt", "The username for connecting to the broker."),
+    ("Broker", "BROKER_PASS", "pass", "The password for connecting to the broker."),
+    ("Broker", "BROKER_PASS_FILE", "", "File with the password, like a mounted Kubernetes or Docker secret, instead of BROKER_PASS."),
+    ("Broker", "BROKER_CLIENT_ID", "<POD_NAME> or rumsim-0", "The client ID for connecting to the broker. {hostname} and {random} are replaced at startup."),
+    ("Broker", "BROKER_QOS", "1", "The quality of service (0..2) used for MQTT messages."),
+    ("Broker", "BROKER_CA_FILE", "", "PEM file with the CA certificates of the broker for TLS."),
+    ("Broker", "BROKER_CERT_FILE", "", "PEM file with the client certificate for TLS."),
+    ("Broker", "BROKER_KEY_FILE", "", "PEM file with the private key of the client certificate."),
+    ("Broker", "BROKER_ALPN", "", "Comma-separated ALPN protocols for TLS."),
+    ("Broker", "BROKER_AZURE_KEY", "", "Symmetric key of the Azure IoT Hub device instead of user and password."),
+    ("Broker", "BROKER_AZURE_TOKEN_SECS", "86400", "Validity of the Azure IoT Hub SAS token in seconds."),
+    ("Broker", "BROKER_LWT_TOPIC", "", "Topic of the last will, if any."),
+    ("Broker", "BROKER_LWT_PAYLOAD", "offline", "Payload of the last will."),
+    ("Broker", "BROKER_LWT_QOS", "1", "Quality of service of the last will."),
+    ("Broker", "BROKER_LWT_RETAIN", "false", "Whether the last will is retained."),
+    ("Broker", "BROKER_LWT_ON_CLOSE", "true", "Whether the simulator publishes the last will itself when it stops."),
+    ("Broker", "BROKER_CONNECTIONS", "1", "Number of parallel connections to the broker."),
+    ("Broker", "BROKER_CLIENT_ID_SUFFIX", "-{n}", "Suffix of the client id of each connection with BROKER_CONNECTIONS > 1, where {n} is the number of the connection."),
+    ("Broker", "BROKER_CONNECTION_PER_DEVICE", "false", "Connect each simulated device with its own MQTT session."),
+    ("Broker", "BROKER_DEVICE_CLIENT_ID", "{device}", "Client id of each device with BROKER_CONNECTION_PER_DEVICE, where {device} is the name of the device."),
+    ("Broker", "BROKER_KEEP_ALIVE_SECS", "5", "Keep-alive interval of the MQTT connections."),
+    ("Broker", "BROKER_CLEAN_SESSION", "true", "Start a clean session on each connect (clean start with MQTT 5). Set to false to keep the sessions across reconnects."),
+    ("Broker", "BROKER_MAX_INFLIGHT", "100", "The maximum number of QoS 1 and 2 messages that are sent but not yet acknowledged, per connection."),
+    ("Broker", "BROKER_MAX_PACKET_SIZE", "10240", "The maximum size of MQTT packets in bytes. Larger messages fail to publish."),
+    ("Broker", "BROKER_PUBLISH_CONCURRENCY", "1", "The maximum number of messages that are published at the same time."),
+    ("Broker", "BROKER_LOOPBACK_TOPIC", "<unset>", "Topic filter on which the simulator receives its own messages back to measure their latency."),
+    ("Broker", "BROKER_ACK_BUDGET_MS", "<unset>", "Warn when the 99th percentile of the acknowledgements with QoS 1 or 2 in a run exceeds this time."),
+    ("Broker", "BROKER_BACKPRESSURE", "block", "When the MQTT connections have CAPACITY messages queued, wait (block), drop new (drop) or old messages (drop-oldest), or skip runs (skip-run)."),
+    ("Broker", "BROKER_CHURN_SECS", "", "Mean time between random drops of each connection. No churn if not set."),
+    ("Broker", "BROKER_CHURN_GLOBAL", "false", "Drop all connections at the same time instead of each one independently."),
+    ("Broker", "BROKER_RECONNECT", "true", "Reconnect when the connection to the broker fails or the broker disconnects."),
+    ("Broker", "BROKER_CONTROL_TOPIC", "", "Topic to receive commands on, where {client_id} is replaced with BROKER_CLIENT_ID, for example rumsim/{client_id}/cmd."),
+    ("Broker", "BROKER_CONFIG_TOPIC", "", "Topic to receive changed parameters on, where {client_id} is replaced with BROKER_CLIENT_ID."),
+    ("Broker", "BROKER_STATUS_TOPIC", "", "Topic to publish the status to, where {client_id} is replaced with BROKER_CLIENT_ID, for example rumsim/{client_id}/status."),
+    ("Broker", "BROKER_STATUS_SECS", "10", "Number of seconds between status messages on BROKER_STATUS_TOPIC."),
+    ("Broker", "BROKER_CLUSTER_TOPIC", "rumsim/cluster", "Topic below which the instances of a cluster with SIM_CLUSTER_ROLE coordinate."),
+    ("Broker", "BROKER_RECONNECT_MIN_MS", "100", "Delay before reconnecting a dropped connection, doubling with each failed attempt."),
+    ("Broker", "BROKER_RECONNECT_MAX_MS", "30000", "Maximum delay between the attempts to reconnect."),
+    ("Broker", "BROKER_MQTT_VERSION", "3", "MQTT protocol version, 3 (for 3.1.1) or 5."),
+    ("Broker", "BROKER_USER_PROPERTIES", "", "MQTT 5 user properties added to each message, e.g., \"site:berlin,line:1\"."),
+    ("Broker", "BROKER_TOPIC_ALIASES", "0", "Number of MQTT 5 topic aliases to use."),
+    ("Broker", "BROKER_MESSAGE_EXPIRY_SECS", "", "MQTT 5 message expiry interval."),
+    ("Broker", "BROKER_HTTP_AUTH", "none", "Authentication of HTTP requests: none, basic with BROKER_USER and BROKER_PASS, or bearer with BROKER_PASS as token."),
+    ("Broker", "BROKER_HTTP_RETRIES", "3", "Number of retries of failed HTTP requests."),
+    ("Broker", "BROKER_AMQP_SASL", "plain", "SASL mechanism for AMQP: plain with BROKER_USER and BROKER_PASS, or anonymous."),
+    ("Simulation", "SIM_DEVICES", "100", "The number of devices to simulate."),
+    ("Simulation", "SIM_GATEWAY_CHILDREN", "0", "The number of child devices per device, 0 for no gateways."),
+    ("Simulation", "SIM_DEVICE_MANIFEST", "", "YAML or JSON file listing the devices to simulate instead of SIM_DEVICES uniform devices."),
+    ("Simulation", "SIM_DATA_POINTS", "100", "The number of data points per devices to simulate."),
+    ("Simulation", "SIM_SEED", "0", "The random number seed for generating data."),
+    ("Simulation", "SIM_FREQUENCY_SECS", "1", "How often the data should be generated, in seconds, for example 0.5."),
+    ("Simulation", "SIM_FREQUENCY", "", "How often the data should be generated as a duration like 250ms or 2.5s, instead of SIM_FREQUENCY_SECS."),
+    ("Simulation", "SIM_TARGET_MSGS_PER_SEC", "", "Publish at this aggregate rate of messages per second instead of each run at once."),
+    ("Simulation", "SIM_PACING", "burst", "Publish the messages of a run at once (burst) or evenly spread until the next run (spread)."),
+    ("Simulation", "SIM_JITTER", "0.0", "Fraction of the time between the runs within which each device publishes at a random offset."),
+    ("Simulation", "SIM_THREADS", "1", "Number of threads that generate the messages of the devices in parallel."),
+    ("Simulation", "SIM_ADAPTIVE", "false", "Reduce the load when the receiving end does not keep up, to find its ceiling."),
+    ("Simulation", "SIM_START_TIME", "<immediate>", "ISO datetime when the simulator starts generating, or the time stamp of the first run in simulated time."),
+    ("Simulation", "SIM_END_TIME", "", "ISO datetime when the simulator stops."),
+    ("Simulation", "SIM_TIME_MODE", "real", "Time stamps from the system clock (real) or advancing by SIM_FREQUENCY_SECS in each run (simulated)."),
+    ("Simulation", "SIM_BACKDATE_SECS", "0", "Number of seconds by which all time stamps are moved into the past."),
+    ("Simulation", "SIM_BACKFILL_END", "", "ISO datetime until which the history from SIM_START_TIME is backfilled."),
+    ("Simulation", "SIM_DROP_RATE", "0.0", "Probability that a message is dropped."),
+    ("Simulation", "SIM_DELAY_RATE", "0.0", "Probability that a message is published later with its original time stamp."),
+    ("Simulation", "SIM_DELAY_RUNS", "1", "Number of runs by which delayed messages are published later."),
+    ("Simulation", "SIM_DUPLICATE_RATE", "0.0", "Probability that a message is published twice."),
+    ("Simulation", "SIM_RUNS", "usize::MAX", "Number of simulator runs."),
+    ("Simulation", "SIM_DURATION_SECS", "", "Number of seconds after which the simulator stops."),
+    ("Simulation", "SIM_SNAPSHOT_FILE", "", "File to save the simulation state to and to restore it from."),
+    ("Simulation", "SIM_SNAPSHOT_RUNS", "60", "Number of runs between snapshots."),
+    ("Simulation", "SIM_MISSED_RUNS", "delay", "What happens with runs that are due while a run takes too long (delay or skip)."),
+    ("Simulation", "SIM_START_PAUSED", "false", "Wait for a start command before the first run."),
+    ("Simulation", "SIM_LOAD_PROFILE", "", "YAML or JSON file with a load profile that varies the message rate over time."),
+    ("Simulation", "SIM_PROFILE", "", "Preset of settings to start from: smoke, soak or stress."),
+    ("Simulation", "SIM_SCENARIO", "", "YAML or JSON file with phases that are simulated one after the other."),
+    ("Simulation", "SIM_SHARDS", "", "Number of instances that share the devices, for example the replicas of a StatefulSet."),
+    ("Simulation", "SIM_SHARD_INDEX", "", "Index of this instance with SIM_SHARDS. By default, the number at the end of BROKER_CLIENT_ID."),
+    ("Simulation", "SIM_SHARD_CLUSTER", "BROKER_CLIENT_ID without the index", "Name of the simulation for the device names with SIM_SHARDS."),
+    ("Simulation", "SIM_CLUSTER_ROLE", "", "leader or worker to coordinate several instances over the broker."),
+    ("Simulation", "SIM_SUMMARY_FILE", "", "JSON file to write the summary of the simulation to at the end."),
+    ("Simulation", "SIM_PAYLOAD_FORMAT", "smartrest", "Format of the messages (smartrest, json, cbor, msgpack, protobuf, c8yjson, azure, aws, thinedge, csv, lineprotocol or sparkplug)."),
+    ("Simulation", "SIM_TOPIC_TEMPLATE", "<depends on format>", "Topic of the measurements with placeholders, see below."),
+    ("Simulation", "SIM_MESSAGE_PER_DATAPOINT", "false", "Send each data point in a separate message on its own subtopic."),
+    ("Simulation", "SIM_SPARKPLUG_GROUP", "rumsim", "Sparkplug B group ID of the simulator."),
+    ("Simulation", "SIM_BATCH_RECORDS", "1", "Maximum number of records per message."),
+    ("Simulation", "SIM_BATCH_BYTES", "0", "Maximum size of a batched message in bytes, 0 for no limit."),
+    ("Simulation", "SIM_PROTOBUF_DESCRIPTOR", "", "File descriptor set with the protobuf message type."),
+    ("Simulation", "SIM_PROTOBUF_MESSAGE", "Telemetry", "Full name of the protobuf message type."),
+    ("Simulation", "SIM_AWS_SHADOW_RUNS", "0", "Number of runs between AWS IoT device shadow updates, 0 for none."),
+    ("Simulation", "SIM_COMPRESSION", "none", "Compression of the payloads (none, gzip or zstd)."),
+    ("Simulation", "SIM_METADATA_TOPIC", "<depends on format>", "Topic of the inventory properties with the same placeholders as SIM_TOPIC_TEMPLATE."),
+    ("Simulation", "SIM_RETAIN_TOPICS", "<depends on format>", "Comma-separated MQTT topic filters of the messages that are published with the retain flag."),
+    ("Generator", "SIM_GENERATOR_MIX", "status:1,noise:1,sensor:1", "Types of data points per device with relative weights."),
+    ("Generator", "SIM_PUBLISH_INTERVALS", "1:1", "Publish intervals (in runs) of data points with relative weights."),
+    ("Generator", "SIM_UNITS", "", "Units and scaling of data points per type."),
+    ("Generator", "SIM_PHASE", "0", "Maximum number of runs by which data points are shifted."),
+    ("Generator", "SIM_EVENT_RATE", "0.0", "Probability per run that a device sends an event."),
+    ("Generator", "SIM_EVENT_TOPIC", "s/us", "Topic prefix for events, followed by the device name (not used by thinedge)."),
+    ("Generator", "SIM_EVENT_TYPES", "<door and error events>", "Types and texts of events."),
+    ("Generator", "SIM_METADATA_RUNS", "0", "Number of runs between publications of the inventory properties, 0 for none."),
+    ("Generator", "SIM_METADATA_MODELS", "rumsim-100,rumsim-200", "Device models."),
+    ("Generator", "SIM_METADATA_FIRMWARE", "1.0.0,1.1.0,2.0.0", "Firmware versions."),
+    ("Generator", "SIM_METADATA_SPREAD", "0.1", "Maximum distance of the device locations from SIM_LOCATION_LATITUDE/LONGITUDE in degrees."),
+    ("Generator", "SIM_CLOCK_SKEW_MS", "0", "Maximum constant deviation of the device clocks in milliseconds, in both directions."),
+    ("Generator", "SIM_CLOCK_JITTER_MS", "0", "Maximum random deviation of each time stamp in milliseconds, in both directions."),
+    ("Generator", "SIM_GOLDEN", "false", "Only use deterministic generators, without jitter, noise, anomalies, outages and events."),
+    ("Generator", "SIM_SENSOR_OFFSET", "100.0", "Offset of the sine curve of sensor data points."),
+    ("Generator", "SIM_SENSOR_AMPLITUDE", "20.0", "Amplitude of the sine curve of sensor data points."),
+    ("Generator", "SIM_SENSOR_JITTER", "2.0", "Random jitter added to sensor data points."),
+    ("Generator", "SIM_SENSOR_PERIOD", "100", "Number of data points until the sine curve repeats."),
+    ("Generator", "SIM_NOISE_DISTRIBUTION", "uniform", "Distribution of noise data points."),
+    ("Generator", "SIM_NOISE_MEAN", "0.0", "Mean of normally distributed noise data points."),
+    ("Generator", "SIM_NOISE_STDDEV", "1.0", "Standard deviation of normally distributed noise data points."),
+    ("Generator", "SIM_NOISE_LAMBDA", "1.0", "Rate of exponentially or Poisson distributed noise data points."),
+    ("Generator", "SIM_RAMP_MAX", "1000.0", "Maximum of ramp data points before they reset."),
+    ("Generator", "SIM_RAMP_STEP", "1.0", "Increment of ramp data points per run."),
+    ("Generator", "SIM_COUNTER_MIN_INCREMENT", "0", "Minimum increment of counter data points per run."),
+    ("Generator", "SIM_COUNTER_MAX_INCREMENT", "10", "Maximum increment of counter data points per run."),
+    ("Generator", "SIM_COUNTER_BITS", "16", "Width of counter data points in bits before they wrap."),
+    ("Generator", "SIM_COUNTER_RESET_PROBABILITY", "0.0001", "Probability that a counter data point resets per run."),
+    ("Generator", "SIM_SETPOINT_MIN", "0.0", "Lower bound of setpoint data points."),
+    ("Generator", "SIM_SETPOINT_MAX", "100.0", "Upper bound of setpoint data points."),
+    ("Generator", "SIM_SETPOINT_STEP", "5.0", "Maximum change of a setpoint in a single jump."),
+    ("Generator", "SIM_SETPOINT_HOLD", "50", "Maximum number of runs a setpoint is held."),
+    ("Generator", "SIM_LOCATION_LATITUDE", "52.52", "Latitude where location data points start."),
+    ("Generator", "SIM_LOCATION_LONGITUDE", "13.405", "Longitude where location data points start."),
+    ("Generator", "SIM_LOCATION_SPEED", "10.0", "Distance in meters a location moves per run."),
+    ("Generator", "SIM_ALARM_PROBABILITY", "0.01", "Probability that an alarm data point is raised per run."),
+    ("Generator", "SIM_ALARM_HOLD", "10", "Minimum number of runs an alarm is held."),
+    ("Generator", "SIM_WALK_MIN", "0.0", "Lower bound of random walk data points."),
+    ("Generator", "SIM_WALK_MAX", "100.0", "Upper bound of random walk data points."),
+    ("Generator", "SIM_WALK_STEP", "0.5", "Maximum change of random walk data points per run."),
+    ("Generator", "SIM_ANOMALY_RATE", "0.0", "Probability per run that a data point shows an anomaly."),
+    ("Generator", "SIM_ANOMALY_SPIKE", "1000.0", "Value added to a data point during a spike anomaly."),
+    ("Generator", "SIM_ANOMALY_STUCK", "10", "Number of runs a data point is stuck during an anomaly."),
+    ("Generator", "SIM_OFFLINE_RATE", "0.0", "Probability per run that a data point goes offline."),
+    ("Generator", "SIM_OFFLINE_DURATION", "60", "Number of runs a data point stays offline."),
+    ("Generator", "SIM_OFFLINE_RECOVERY_CODE", "", "Status code published when a data point is back online."),
+    ("Generator", "SIM_GROUP_STEP", "0.05", "Maximum change of the latent signal of a group per run."),
+    ("Generator", "SIM_GROUP_MEMBERS", "20:10:0.5;25:8:0.5", "Offset, gain and noise of each member of a group."),
+    ("Generator", "SIM_MACHINE_START_PROBABILITY", "0.05", "Probability per run that an idle machine starts running."),
+    ("Generator", "SIM_MACHINE_STOP_PROBABILITY", "0.02", "Probability per run that a running machine stops."),
+    ("Generator", "SIM_MACHINE_FAULT_PROBABILITY", "0.005", "Probability per run that a running machine fails."),
+    ("Generator", "SIM_MACHINE_MIN_DWELL", "10", "Minimum number of runs a machine stays idle or running."),
+    ("Generator", "SIM_MACHINE_REPAIR_TIME", "30", "Number of runs until a failed machine is idle again."),
+    ("Generator", "SIM_MACHINE_IDLE_TEMPERATURE", "20.0", "Temperature of a machine that is not running."),
+    ("Generator", "SIM_MACHINE_RUNNING_TEMPERATURE", "80.0", "Temperature of a machine that is running."),
+    ("Generator", "SIM_MACHINE_HEATING_RATE", "0.05", "Share of the temperature difference closed per run."),
+    ("Generator", "SIM_DIURNAL_BASE", "10.0", "Value of diurnal data points at night."),
+    ("Generator", "SIM_DIURNAL_PEAK", "100.0", "Value of diurnal data points at the peak of the day."),
+    ("Generator", "SIM_DIURNAL_PEAK_HOUR", "13.0", "Hour of the day (UTC) at which diurnal data points peak."),
+    ("Generator", "SIM_DIURNAL_SEASONAL", "0.1", "Relative seasonal variation of diurnal data points."),
+    ("Generator", "SIM_DIURNAL_JITTER", "2.0", "Random jitter added to diurnal data points."),
+    ("Generator", "SIM_HISTOGRAM_STATES", "0:70,1:20,2:5,4:5", "Values of histogram data points with relative weights."),
+    ("Generator", "SIM_EXPRESSION", "50 + 10*sin(t/60) + noise(2)", "Expression computing expression data points."),
+    ("Generator", "SIM_REPLAY_FILE", "", "CSV file with recorded values for replay data points."),
+    ("Observability", "OTLP_ENDPOINT", "<console>", "URL of OTLP collector for traces and metrics. Only console logging if not set."),
+    ("Observability", "OTLP_AUTH", "", "Authentication string for OTLP collector."),
+    ("Observability", "OTLP_AUTH_FILE", "", "File with the authentication string, instead of OTLP_AUTH."),
+    ("Observability", "OTLP_TRACES", "true", "Export traces to the OTLP collector, otherwise only metrics."),
+    ("Observability", "OTLP_SAMPLE_RATIO", "1", "Share of the runs that are traced, between 0 and 1."),
+    ("Observability", "OTLP_SAMPLE_PER_SEC", "<unset>", "Maximum number of runs that are traced per second."),
+    ("Observability", "OTLP_TRACEPARENT", "false", "Pass the trace context of the runs on with the messages."),
+    ("Observability", "LOG_FORMAT", "text", "Format of the console log, text or json."),
+    ("Other", "CAPACITY", "1000", "Capacity of the message buffer, the request channel of each MQTT connection."),
+    ("Other", "CONTROL_PORT", "", "Port of the HTTP API for probes, status, control and Prometheus metrics."),
+    ("Other", "GRPC_PORT", "", "Port of the gRPC control plane."),
+    ("Other", "POD_NAME", "", "Pod name from the Kubernetes downward API, the default for BROKER_CLIENT_ID."),
+    ("Other", "POD_INDEX", "", "Pod index from the Kubernetes downward API, the default for SIM_SHARD_INDEX."),
+    ("Other", "RUST_LOG", "info", "Log and trace level."),
+];
+
+/// The flag of a setting, like `--devices` for SIM_DEVICES and `--broker-url` for BROKER_URL.
+fn flag(env: &str) -> String {
+    env.strip_prefix("SIM_")
+        .unwrap_or(env)
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+fn command() -> Command {
+    let command = Command::new("rumsim")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A data generator for simulating and benchmarking IoT workloads.")
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Print the messages to stdout instead of sending them to the broker."),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("A TOML or YAML file with the settings. Environment variables and arguments override it."),
+        );
+    SETTINGS
+        .iter()
+        .fold(command, |command, (heading, env, default, description)| {
+            let help = match *default {
+                "" => description.to_string(),
+                default => format!("{} [default: {}]", description, default),
+            };
+            let arg = Arg::new(*env)
+                .long(flag(env))
+                .value_name("VALUE")
+                .help(help)
+                .help_heading(*heading)
+                .env(*env)
+                .hide_env_values(true);
+            // The broker is given most often, so it also has a shorter flag.
+            command.arg(match *env {
+                "BROKER_URL" => arg.alias("broker"),
+                _ => arg,
+            })
+        })
+}
+
+/// What is given on the command line.
+#[derive(Debug)]
+struct Given {
+    /// The configuration file, if any.
+    config: Option<String>,
+    dry_run: bool,
+    /// The settings as environment variables and values.
+    settings: Vec<(&'static str, String)>,
+}
+
+fn given(args: impl IntoIterator<Item = String>) -> Result<Given, clap::Error> {
+    let matches = command().try_get_matches_from(args)?;
+    let settings = SETTINGS
+        .iter()
+        .filter(|(_, env, _, _)| matches.value_source(env) == Some(ValueSource::CommandLine))
+        .filter_map(|(_, env, _, _)| Some((*env, matches.get_one::<String>(env)?.clone())))
+        .collect();
+    Ok(Given {
+        config: matches.get_one::<String>("config").cloned(),
+        dry_run: matches.get_flag("dry-run"),
+        settings,
+    })
+}
+
+/// A value in a configuration file, which becomes the value of an environment variable.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    pub fn into_string(self) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::String(s) => s,
+        }
+    }
+}
+
+/// Read the settings from TOML, or from YAML if it is not TOML. The keys are the environment variables in upper or
+/// lower case, like `SIM_DEVICES = 100` or `sim_devices: 100`.
+fn parse_config(content: &str, toml: bool) -> Result<Vec<(&'static str, String)>, String> {
+    let values: BTreeMap<String, Value> = if toml {
+        toml::from_str(content).map_err(|e| e.to_string())?
+    } else {
+        serde_yaml::from_str(content).map_err(|e| e.to_string())?
+    };
+    values
+        .into_iter()
+        .map(|(key, value)| Ok((setting(&key)?, value.into_string())))
+        .collect()
+}
+
+/// The environment variable of a setting in upper or lower case.
+pub fn setting(key: &str) -> Result<&'static str, String> {
+    let key = key.to_uppercase();
+    SETTINGS
+        .iter()
+        .find(|(_, env, _, _)| *env == key)
+        .map(|(_, env, _, _)| *env)
+        .ok_or_else(|| format!("Unknown setting {}", key))
+}
+
+/// The configuration file, to reload it when it changes.
+struct ConfigFile {
+    path: String,
+    /// The settings in the environment or on the command line, which override the file.
+    overridden: Vec<&'static str>,
+    /// The settings that were read from the file.
+    settings: Vec<(&'static str, String)>,
+}
+
+static CONFIG_FILE: OnceLock<ConfigFile> = OnceLock::new();
+
+/// The settings that can be changed while the simulation runs.
+const RELOADABLE: [&str; 4] = [
+    "SIM_FREQUENCY",
+    "SIM_FREQUENCY_SECS",
+    "SIM_RUNS",
+    "SIM_DATA_POINTS",
+];
+
+/// How often the modification time of the configuration file is checked.
+const RELOAD_SECS: u64 = 5;
+
+/// Set the environment variables of the settings on the command line, so that they override the environment, and
+/// of the settings in the configuration file that are not in the environment. This has to happen before the
+/// settings are read and before any threads are started. Prints the help and exits on `--help` or on invalid
+/// arguments. Returns whether the messages are only printed (`--dry-run`).
+pub fn apply_args() -> bool {
+    let given = given(std::env::args()).unwrap_or_else(|e| e.exit());
+    for (env, value) in given.settings {
+        std::env::set_var(env, value);
+    }
+    if let Some(path) = given.config {
+        // It's OK to panic, since the simulation would not run as configured.
+        let content = std::fs::read_to_string(&path).expect("Cannot read configuration file");
+        let settings = parse_config(&content, path.ends_with(".toml"))
+            .unwrap_or_else(|e| panic!("Invalid configuration file {}: {}", path, e));
+        let (overridden, settings): (Vec<_>, Vec<_>) = settings
+            .into_iter()
+            .partition(|(env, _)| std::env::var_os(env).is_some());
+        for (env, value) in &settings {
+            std::env::set_var(env, value);
+        }
+        let overridden = overridden.into_iter().map(|(env, _)| env).collect();
+        let _ = CONFIG_FILE.set(ConfigFile {
+            path,
+            overridden,
+            settings,
+        });
+    }
+    given.dry_run
+}
+
+/// Reload the configuration file on SIGHUP or when it changes, if there is one. The frequency, the runs and the data
+/// points are changed at the next run, without reconnecting. Other settings are only used after a restart.
+pub fn reload_config(control: watch::Sender<Control>) {
+    let Some(file) = CONFIG_FILE.get() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut settings = file.settings.clone();
+        let mut modified = modification_time(&file.path);
+        let mut interval = tokio::time::interval(Duration::from_secs(RELOAD_SECS));
+        #[cfg(unix)]
+        // It's OK to panic, since the signal handler can only fail to install at startup.
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        loop {
+            #[cfg(unix)]
+            let hangup = hangup.recv();
+            #[cfg(not(unix))]
+            let hangup = futures::future::pending::<Option<()>>();
+            tokio::select! {
+                _ = hangup => info!(file = file.path, "Reloading the configuration on SIGHUP."),
+                _ = interval.tick() => {
+                    let current = modification_time(&file.path);
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!(file = file.path, "Reloading the changed configuration.");
+                }
+            }
+            let reloaded = std::fs::read_to_string(&file.path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| parse_config(&content, file.path.ends_with(".toml")));
+            let reloaded: Vec<_> = match reloaded {
+                Ok(reloaded) => reloaded
+                    .into_iter()
+                    .filter(|(env, _)| !file.overridden.contains(env))
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        error = e,
+                        file = file.path,
+                        "Cannot reload the configuration"
+                    );
+                    continue;
+                }
+            };
+            if let Some(command) = reload(&settings, &reloaded) {
+                control.send_modify(|control| control.apply(command));
+            }
+            settings = reloaded;
+        }
+    });
+}
+
+fn modification_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Log the settings that changed in the file and return the command to change the settings that can be changed while
+/// the simulation runs, if any of them changed.
+fn reload(
+    settings: &[(&'static str, String)],
+    reloaded: &[(&'static str, String)],
+) -> Option<control::Command> {
+    let value = |settings: &[(&'static str, String)], env: &str| {
+        settings
+            .iter()
+            .find(|(setting, _)| *setting == env)
+            .map(|(_, value)| value.clone())
+    };
+    let mut changed = Vec::new();
+    for (_, env, _, _) in SETTINGS {
+        let (old, new) = (value(settings, env), value(reloaded, env));
+        if old == new {
+            continue;
+        }
+        match new.as_ref().map(|new| parse_reloadable(env, new)) {
+            Some(Ok(number)) if RELOADABLE.contains(env) => {
+                info!(setting = env, ?old, ?new, "Changed setting.");
+                changed.push((*env, number));
+            }
+            Some(Err(e)) if RELOADABLE.contains(env) => {
+                warn!(setting = env, ?new, error = ?e, "Invalid setting, keeping it")
+            }
+            _ => warn!(
+                setting = env,
+                ?old,
+                ?new,
+                "Changed setting requires a restart"
+            ),
+        }
+    }
+    let number = |env: &str| {
+        changed
+            .iter()
+            .find(|(setting, _)| *setting == env)
+            .map(|(_, number)| *number)
+    };
+    (!changed.is_empty()).then(|| control::Command::Set {
+        // SIM_FREQUENCY takes precedence like when the settings are read.
+        frequency_secs: number("SIM_FREQUENCY").or(number("SIM_FREQUENCY_SECS")),
+        runs: number("SIM_RUNS").map(|runs| runs as usize),
+        data_points: number("SIM_DATA_POINTS").map(|data_points| data_points as usize),
+    })
+}
+
+/// Parse the value of a setting that can be changed while the simulation runs.
+fn parse_reloadable(env: &str, value: &str) -> Result<f64, String> {
+    match env {
+        "SIM_FREQUENCY" | "SIM_FREQUENCY_SECS" => parse_frequency(env, value),
+        _ => value
+            .parse::<usize>()
+            .map(|number| number as f64)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flag() {
+        assert_eq!(flag("SIM_DEVICES"), "devices");
+        assert_eq!(flag("SIM_DATA_POINTS"), "data-points");
+        assert_eq!(flag("BROKER_URL"), "broker-url");
+    }
+
+    #[test]
+    fn test_given() {
+        command().debug_assert();
+        let given_args = given(args(&[
+            "rumsim",
+            "--devices",
+            "100",
+            "--data-points=50",
+            "--broker",
+            "mqtt://broker:1883",
+            "--dry-run",
+        ]))
+        .unwrap();
+        assert_eq!(given_args.config, None);
+        assert!(given_args.dry_run);
+        assert_eq!(
+            given_args.settings,
+            vec![
+                ("BROKER_URL", "mqtt://broker:1883".to_string()),
+                ("SIM_DEVICES", "100".to_string()),
+                ("SIM_DATA_POINTS", "50".to_string()),
+            ]
+        );
+        assert!(given(args(&["rumsim", "--no-such-setting", "1"])).is_err());
+        let given_args = given(args(&["rumsim", "--config", "rumsim.toml"])).unwrap();
+        assert_eq!(given_args.config.as_deref(), Some("rumsim.toml"));
+        assert!(!given_args.dry_run);
+        // Only the flag itself, not a value that looks like it.
+        let given_args = given(args(&["rumsim", "--broker-client-id=--dry-run"])).unwrap();
+        assert!(!given_args.dry_run);
+    }
+
+    #[test]
+    fn test_reload() {
+        let settings = vec![
+            ("SIM_DEVICES", "100".to_string()),
+            ("SIM_FREQUENCY_SECS", "10".to_string()),
+            ("SIM_RUNS", "5".to_string()),
+        ];
+        assert_eq!(reload(&settings, &settings), None);
+
+        let reloaded = vec![
+            ("SIM_DATA_POINTS", "20".to_string()),
+            ("SIM_DEVICES", "200".to_string()),
+            ("SIM_FREQUENCY", "250ms".to_string()),
+            ("SIM_FREQUENCY_SECS", "5".to_string()),
+            ("SIM_RUNS", "many".to_string()),
+        ];
+        assert_eq!(
+            reload(&settings, &reloaded),
+            Some(control::Command::Set {
+                frequency_secs: Some(0.25),
+                runs: None,
+                data_points: Some(20),
+            })
+        );
+        // Only settings that need a restart changed.
+        assert_eq!(reload(&settings, &settings[..2]), None);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let toml = r#"
+            BROKER_URL = "mqtt://broker:1883"
+            SIM_DEVICES = 100
+            sim_drop_rate = 0.5
+            sim_start_paused = true
+        "#;
+        let expected = vec![
+            ("BROKER_URL", "mqtt://broker:1883".to_string()),
+            ("SIM_DEVICES", "100".to_string()),
+            ("SIM_DROP_RATE", "0.5".to_string()),
+            ("SIM_START_PAUSED", "true".to_string()),
+        ];
+        assert_eq!(parse_config(toml, true), Ok(expected.clone()));
+        let yaml = "
+            BROKER_URL: mqtt://broker:1883
+            SIM_DEVICES: 100
+            sim_drop_rate: 0.5
+            sim_start_paused: true
+        ";
+        assert_eq!(parse_config(yaml, false), Ok(expected));
+        assert!(parse_config("SIM_DEVICESS = 100", true).is_err());
+        assert!(parse_config("SIM_DEVICES = [1, 2]", true).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Coordination of a fleet of simulators over the broker, so that large distributed load tests can be run without
+//! external tooling. The leader publishes the state and the parameters of its simulation on a shared topic, the
+//! workers follow them with their shard of the devices and report their progress back to the leader.
+use std::str::FromStr;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::control::{Command, Control, Progress, State};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Leader,
+    Worker,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leader" => Ok(Role::Leader),
+            "worker" => Ok(Role::Worker),
+            _ => Err(format!("Unknown cluster role {}", s)),
+        }
+    }
+}
+
+/// The topics of a cluster below a shared topic.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub role: Role,
+    topic: String,
+    client_id: String,
+}
+
+impl Cluster {
+    pub fn new(role: Role, topic: &str, client_id: &str) -> Self {
+        Cluster {
+            role,
+            topic: topic.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+        }
+    }
+
+    /// The leader publishes its control state retained, so that workers that start later get it as well.
+    pub fn control_topic(&self) -> String {
+        format!("{}/control", self.topic)
+    }
+
+    /// The topic that a worker reports its progress to.
+    pub fn progress_topic(&self) -> String {
+        format!("{}/progress/{}", self.topic, self.client_id)
+    }
+
+    /// The topic filter to subscribe to with the role.
+    pub fn subscription(&self) -> String {
+        match self.role {
+            Role::Leader => format!("{}/progress/+", self.topic),
+            Role::Worker => self.control_topic(),
+        }
+    }
+
+    /// Follow the control state of the leader as a worker, or log the progress of a worker as the leader. An empty
+    /// message clears the retained state of a finished cluster run and is ignored.
+    pub fn receive(&self, topic: &str, payload: &[u8], control: &watch::Sender<Control>) {
+        if payload.is_empty() {
+            return;
+        }
+        match self.role {
+            Role::Worker if topic == self.control_topic() => {
+                match serde_json::from_slice::<Control>(payload) {
+                    Ok(leader) => {
+                        info!(?leader, "Received control state from the leader.");
+                        // A stopped simulation stays stopped, and an invalid frequency is kept, like with commands.
+                        control.send_if_modified(|control| {
+                            let mut followed = control.clone();
+                            followed.state = leader.state;
+                            followed.apply(Command::Set {
+                                frequency_secs: Some(leader.frequency_secs),
+                                runs: Some(leader.runs),
+                                data_points: leader.data_points,
+                            });
+                            let modified = control.state != State::Stopped && *control != followed;
+                            if modified {
+                                *control = followed;
+                            }
+                            modified
+                        });
+                    }
+                    Err(e) => warn!(error = ?e, "Ignoring control state"),
+                }
+            }
+            Role::Leader => {
+                let Some(worker) = topic.strip_prefix(&format!("{}/progress/", self.topic)) else {
+                    return;
+                };
+                match serde_json::from_slice::<Progress>(payload) {
+                    Ok(progress) => info!(
+                        worker,
+                        runs = progress.runs,
+                        messages = progress.messages,
+                        datapoints_per_sec = progress.datapoints_per_sec,
+                        "Worker progress."
+                    ),
+                    Err(e) => warn!(error = ?e, worker, "Ignoring progress"),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster() {
+        let worker = Cluster::new(Role::Worker, "rumsim/cluster/", "rumsim-1");
+        assert_eq!(worker.subscription(), "rumsim/cluster/control");
+        assert_eq!(worker.progress_topic(), "rumsim/cluster/progress/rumsim-1");
+        assert_eq!(
+            Cluster::new(Role::Leader, "rumsim/cluster", "rumsim-0").subscription(),
+            "rumsim/cluster/progress/+"
+        );
+
+        let (control, controlled) = watch::channel(Control::new(true, 60.0, 10));
+        let leader = serde_json::to_vec(&Control::new(false, 5.0, 100)).unwrap();
+        worker.receive("rumsim/cluster/other", &leader, &control);
+        worker.receive("rumsim/cluster/control", b"", &control);
+        worker.receive("rumsim/cluster/control", b"{}", &control);
+        assert_eq!(*controlled.borrow(), Control::new(true, 60.0, 10));
+        worker.receive("rumsim/cluster/control", &leader, &control);
+        assert_eq!(*controlled.borrow(), Control::new(false, 5.0, 100));
+
+        for frequency in ["-1", "1e300"] {
+            let leader = format!(
+                r#"{{"state": "running", "frequency_secs": {}, "runs": 50}}"#,
+                frequency
+            );
+            worker.receive("rumsim/cluster/control", leader.as_bytes(), &control);
+            assert_eq!(*controlled.borrow(), Control::new(false, 5.0, 50));
+        }
+
+        control.send_modify(|control| control.state = State::Stopped);
+        worker.receive("rumsim/cluster/control", &leader, &control);
+        assert_eq!(controlled.borrow().state, State::Stopped);
+    }
+}
@@ -0,0 +1,1211 @@
+//! Controlling a running simulation from outside, so that a fleet of simulators can be reconfigured centrally
+//! without restarting them. All ways to control the simulation, from signals to commands over MQTT, HTTP and gRPC,
+//! change the same watch channel, which the simulation follows. The Orchestrator runs the simulation under this
+//! control.
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize, Serializer};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{debug, info, span, warn, Instrument};
+
+use crate::latency::{self, Loopback};
+use crate::observability::{self, Metering};
+use crate::pacing::{jitter, Adaptive, Pacer, Pacing};
+use crate::payload;
+use crate::publisher::{Backlog, Backpressure, Concurrent, Message, Outcome, Publisher, Queue};
+use crate::scenario::Phase;
+use crate::settings::Settings;
+use crate::simulation::{MissedRuns, Simulation, SimulationParameters};
+use crate::summary::{self, Recorder};
+
+/// What the simulation does.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    /// No runs are published until the simulation is started or resumed.
+    Paused,
+    /// The simulation ends after the current run and cannot be started again.
+    Stopped,
+}
+
+/// The state of the simulation and the parameters that can be changed while it runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Control {
+    pub state: State,
+    #[serde(serialize_with = "seconds")]
+    pub frequency_secs: f64,
+    pub runs: usize,
+    /// The data points of each device, if they were changed while the simulation runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_points: Option<usize>,
+}
+
+impl Control {
+    pub fn new(paused: bool, frequency_secs: f64, runs: usize) -> Self {
+        Control {
+            state: if paused {
+                State::Paused
+            } else {
+                State::Running
+            },
+            frequency_secs,
+            runs,
+            data_points: None,
+        }
+    }
+
+    /// Apply a command. A stopped simulation stays stopped.
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::Start | Command::Resume if self.state == State::Paused => {
+                self.state = State::Running
+            }
+            Command::Pause if self.state == State::Running => self.state = State::Paused,
+            Command::Stop => self.state = State::Stopped,
+            Command::Set {
+                frequency_secs,
+                runs,
+                data_points,
+            } => {
+                // Zero runs the simulation as fast as possible.
+                if let Some(secs) = frequency_secs {
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(_) => self.frequency_secs = secs,
+                        Err(e) => warn!(secs, error = %e, "Invalid frequency, keeping it"),
+                    }
+                }
+                self.runs = runs.unwrap_or(self.runs);
+                self.data_points = data_points.or(self.data_points);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// What the simulation has done so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Whether the simulation has started, after waiting for the start time.
+    pub started: bool,
+    /// The number of completed runs, including the runs from a snapshot.
+    pub runs: usize,
+    pub messages: u64,
+    /// The data points per second in the last run.
+    pub datapoints_per_sec: f64,
+}
+
+/// The connections to the broker as the simulator sees them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BrokerHealth {
+    pub connections: usize,
+    /// The connections that are currently connected.
+    pub connected: usize,
+    pub reconnects: u64,
+    /// The failed connection attempts and the disconnects by the broker.
+    pub errors: u64,
+}
+
+/// The state, the parameters and the progress of the simulation, the connections to the broker if it is reported over
+/// MQTT, and the command that is acknowledged, if any.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub control: Control,
+    pub progress: Progress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broker: Option<BrokerHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acknowledged: Option<Command>,
+}
+
+impl Status {
+    pub fn new(control: &watch::Sender<Control>, progress: &watch::Receiver<Progress>) -> Self {
+        Status {
+            control: control.borrow().clone(),
+            progress: progress.borrow().clone(),
+            broker: None,
+            acknowledged: None,
+        }
+    }
+}
+
+/// A command like `pause`, or as JSON like `{"command": "set", "frequency_secs": 10}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Command {
+    /// Start a simulation that waits with SIM_START_PAUSED.
+    Start,
+    Stop,
+    Pause,
+    Resume,
+    /// Change the parameters. Parameters that are not given stay the same.
+    Set {
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "optional_seconds"
+        )]
+        frequency_secs: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        runs: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data_points: Option<usize>,
+    },
+}
+
+/// The parameters of the simulation without the command, like on the config topic.
+#[derive(Deserialize)]
+struct Parameters {
+    frequency_secs: Option<f64>,
+    runs: Option<usize>,
+    data_points: Option<usize>,
+}
+
+impl Command {
+    /// Read parameters like `{"frequency_secs": 10, "data_points": 50}` as a command to set them.
+    pub fn from_parameters(payload: &[u8]) -> Result<Self, String> {
+        let parameters: Parameters =
+            serde_json::from_slice(payload).map_err(|e| format!("Invalid parameters: {}", e))?;
+        Ok(Command::Set {
+            frequency_secs: parameters.frequency_secs,
+            runs: parameters.runs,
+            data_points: parameters.data_points,
+        })
+    }
+}
+
+/// Whole seconds are serialized as integers like before fractions of seconds were possible, so that clients that
+/// expect integers keep working.
+fn seconds<S: Serializer>(secs: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    if secs.fract() == 0.0 && (0.0..=u64::MAX as f64).contains(secs) {
+        serializer.serialize_u64(*secs as u64)
+    } else {
+        serializer.serialize_f64(*secs)
+    }
+}
+
+fn optional_seconds<S: Serializer>(secs: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secs {
+        Some(secs) => seconds(secs, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "start" => Ok(Command::Start),
+            "stop" => Ok(Command::Stop),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            s => serde_json::from_str(s).map_err(|e| format!("Unknown command {}: {}", s, e)),
+        }
+    }
+}
+
+/// Stop the simulation on Ctrl-C or on SIGTERM, which Kubernetes sends to terminate a pod. The current run is still
+/// published and the publisher is closed, so that the broker sees a clean disconnect. A second signal exits right away.
+pub fn stop_on_signal(control: watch::Sender<Control>) {
+    tokio::spawn(async move {
+        signal().await;
+        info!("Stopping the simulation.");
+        control.send_modify(|control| control.apply(Command::Stop));
+        signal().await;
+        warn!("Exiting without closing the connection.");
+        std::process::exit(130);
+    });
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        // It's OK to panic, since the signal handler can only fail to install at startup.
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Wait until the simulation is stopped.
+pub async fn stopped(control: &mut watch::Receiver<Control>) {
+    // If the sender is gone, there will be no stop.
+    if control
+        .wait_for(|control| control.state == State::Stopped)
+        .await
+        .is_err()
+    {
+        futures::future::pending().await
+    }
+}
+
+/// Wait while the simulation is paused.
+pub async fn unpaused(control: &mut watch::Receiver<Control>) -> Control {
+    let _ = control
+        .wait_for(|control| control.state != State::Paused)
+        .await;
+    let current = control.borrow().clone();
+    current
+}
+
+/// Runs the simulation with a publisher under the control of the watch channel, so that the runs follow the changed
+/// parameters, and pausing and stopping take effect right away. Reports the progress while the simulation runs and the
+/// summary when it is done.
+pub struct Orchestrator<'a, P: Publisher> {
+    publisher: &'a mut P,
+    control: &'a watch::Sender<Control>,
+    progress: &'a watch::Sender<Progress>,
+    settings: &'a Settings,
+    recorder: Recorder,
+}
+
+impl<'a, P: Publisher> Orchestrator<'a, P> {
+    pub fn new(
+        publisher: &'a mut P,
+        control: &'a watch::Sender<Control>,
+        progress: &'a watch::Sender<Progress>,
+        settings: &'a Settings,
+    ) -> Self {
+        Orchestrator {
+            publisher,
+            control,
+            progress,
+            settings,
+            recorder: Recorder::new(),
+        }
+    }
+
+    /// Simulate the phases of the scenario, if there is one, and otherwise the parameters from the settings.
+    pub async fn simulate_configured(self, parms: SimulationParameters, simulation: Simulation) {
+        match &self.settings.sim.scenario {
+            Some(phases) => self.simulate_scenario(parms, phases).await,
+            None => self.simulate(parms, simulation).await,
+        }
+    }
+
+    /// Simulate the phases one after the other with the same publisher. A phase sets the frequency and the number of
+    /// runs, which can still be changed while the phase is running. Stopping the simulation skips the remaining
+    /// phases. In simulated time, a phase continues from the time stamp after the last run of the previous phase.
+    pub async fn simulate_scenario(mut self, base: SimulationParameters, phases: &[Phase]) {
+        let mut start = base.time.start;
+        for phase in phases {
+            let mut parms = phase.parameters(&base);
+            parms.time.start = start;
+            let runs = phase.runs(parms.frequency_secs);
+            info!(
+                phase = phase.name,
+                sim_devices = parms.devices,
+                sim_data_points = parms.data_points,
+                sim_frequency_secs = parms.frequency_secs,
+                runs,
+                "Starting phase."
+            );
+            self.control.send_modify(|control| {
+                control.frequency_secs = parms.frequency_secs;
+                control.runs = runs;
+                control.data_points = None;
+            });
+            let mut simulation = Simulation::new(&parms);
+            let runs = self.run(parms, &mut simulation).await;
+            start = Some(simulation.start_after(runs));
+            if self.control.borrow().state == State::Stopped {
+                break;
+            }
+        }
+        self.finish().await;
+    }
+
+    pub async fn simulate(mut self, parms: SimulationParameters, mut simulation: Simulation) {
+        self.run(parms, &mut simulation).await;
+        self.finish().await;
+    }
+
+    /// Close the publisher and report the summary.
+    async fn finish(self) {
+        self.publisher.close().await;
+        self.recorder
+            .summary()
+            .report(self.settings.sim.summary_file.as_deref());
+    }
+
+    /// Run the simulation until all runs are done or it is stopped, without closing the publisher. Returns the number
+    /// of completed runs.
+    async fn run(&mut self, parms: SimulationParameters, simulation: &mut Simulation) -> usize {
+        let publisher = &mut *self.publisher;
+        let progress = self.progress;
+        let settings = self.settings;
+        let recorder = &mut self.recorder;
+        let mut control = self.control.subscribe();
+        let metering = Metering::new(settings);
+
+        let completed_runs = restore_snapshot(simulation, settings);
+        let mut datapoints = simulation.data_points();
+        let mut data_points = parms.data_points;
+
+        if let Some(node) = simulation.sparkplug() {
+            let (topic, data) = node.birth(simulation.time(completed_runs));
+            if let Err(e) = publisher.publish(topic, data, None, 0).await {
+                warn!(error = ?e, "Failed to publish");
+                return completed_runs;
+            }
+        }
+
+        // A backfill publishes the history as fast as possible instead of waiting between the runs.
+        let backfill_runs = simulation.backfill_runs();
+        if let Some(backfill_runs) = backfill_runs {
+            info!(backfill_runs, "Backfilling.");
+        }
+
+        // With a target rate, the messages are spread over the time instead of waiting between the runs.
+        let target = settings
+            .sim
+            .target_msgs_per_sec
+            .filter(|_| backfill_runs.is_none());
+        // Spreading the messages of a run over the time until the next run expects as many messages as in the previous
+        // run, or one per device in the first run.
+        let spread =
+            settings.sim.pacing == Pacing::Spread && target.is_none() && backfill_runs.is_none();
+        let mut expected = simulation.names().len();
+        let mut pacer = match target {
+            Some(target) => Some(Pacer::new(target)),
+            None => spread.then(|| Pacer::new(1.0)),
+        };
+        // Jitter delays the devices within the start of a run, as long as the messages are not paced otherwise.
+        let jittered = settings.sim.jitter > 0.0 && pacer.is_none() && backfill_runs.is_none();
+        // The adaptive mode reduces the rate of the runs, or the target rate, when the receiving end does not keep up.
+        let mut adaptive = (settings.sim.adaptive && backfill_runs.is_none()).then(Adaptive::new);
+        let mut target_rate = 0.0;
+        let mut jitter_rng = ChaCha12Rng::seed_from_u64(parms.seed);
+        // The messages that are queued in the publisher, for the backpressure.
+        let queue = publisher.queue();
+        let backpressure = |policy| {
+            settings.broker.backpressure == policy && queue.as_ref().is_some_and(Queue::is_full)
+        };
+        // With drop-oldest, the messages wait in the backlog across the runs until the queue has room.
+        let mut backlog = queue
+            .clone()
+            .filter(|_| settings.broker.backpressure == Backpressure::DropOldest)
+            .map(|queue| Backlog::new(queue, settings.capacity));
+        // The forks publish concurrently with the publisher, as far as the publisher supports it.
+        let mut forks: Vec<_> = (1..settings.broker.publish_concurrency)
+            .map_while(|_| publisher.fork())
+            .collect();
+
+        let mut run = completed_runs;
+        // The time in the load profile advances by the time between the runs, also in simulated time.
+        let mut profile_time = Duration::ZERO;
+        progress.send_modify(|progress| {
+            progress.started = true;
+            progress.runs = run;
+        });
+        loop {
+            // The number of runs and the frequency may be changed while the simulation is running.
+            let current = unpaused(&mut control).await;
+            let runs = current.runs.min(backfill_runs.unwrap_or(usize::MAX));
+            if current.state == State::Stopped || run >= runs {
+                break;
+            }
+            // Changed data points take effect between the runs, without recreating the devices.
+            if let Some(changed) = current
+                .data_points
+                .filter(|changed| *changed != data_points)
+            {
+                match simulation.set_data_points(changed) {
+                    Ok(()) => info!(data_points = changed, "Changed the data points."),
+                    Err(e) => warn!(error = e, "Cannot change the data points"),
+                }
+                data_points = changed;
+                datapoints = simulation.data_points();
+            }
+            let frequency = match &settings.sim.load_profile {
+                Some(profile) => profile.frequency(
+                    Duration::from_secs_f64(current.frequency_secs),
+                    profile_time,
+                ),
+                None => Duration::from_secs_f64(current.frequency_secs),
+            };
+            let load = adaptive.as_ref().map_or(1.0, Adaptive::load);
+            let frequency = frequency.div_f64(load);
+            // The load profile varies the target rate like the rate of the runs.
+            if let (Some(pacer), Some(target)) = (&mut pacer, target) {
+                let rate = settings
+                    .sim
+                    .load_profile
+                    .as_ref()
+                    .map_or(1.0, |profile| profile.rate(profile_time));
+                target_rate = target * rate * load;
+                pacer.set_rate(target_rate);
+            }
+            if let Some(pacer) = pacer.as_mut().filter(|_| spread) {
+                pacer.reset();
+                pacer.set_rate(expected.max(1) as f64 / frequency.as_secs_f64());
+            }
+            // Only runs at the frequency are skipped, since the other runs follow each other right away.
+            if target.is_none() && backfill_runs.is_none() && backpressure(Backpressure::SkipRun) {
+                warn!(run, "The queue is full, skipping the run.");
+                metering.record_skipped_runs(1);
+                recorder.record_skipped_runs(1);
+                run += 1;
+                profile_time += frequency;
+                save_snapshot(simulation, run, settings);
+                tokio::select! {
+                    _ = sleep(frequency) => (),
+                    _ = stopped(&mut control) => (),
+                }
+                continue;
+            }
+
+            let simulation_span = span!(
+                tracing::Level::INFO,
+                "simulation_run",
+                run,
+                devices = parms.devices,
+                data_points,
+                client_id = &settings.broker.client_id,
+                broker = &settings.broker.url
+            );
+            // The span is only entered while the run is polled, so that the other tasks do not inherit it.
+            async {
+                debug!(parent: &simulation_span, sim_devices = parms.devices, sim_data_points = parms.data_points, sim_frequency = ?frequency, sim_seed = parms.seed, "Running simulation");
+
+                let start = Instant::now();
+                let (mut bytes, mut compressed_bytes, mut messages, mut dropped) = (0, 0, 0, 0);
+                let mut paused = Duration::ZERO;
+                let mut behind = false;
+                let window = frequency.mul_f64(settings.sim.jitter);
+                let mut offsets = if jittered {
+                    jitter(&mut jitter_rng, simulation.names().len(), window)
+                } else {
+                    Vec::new()
+                }
+                .into_iter();
+                let mut previous_device = None;
+                // Without MQTT 5 user properties, the trace context of the run goes into the payload.
+                let traceparent = (settings.otlp.traceparent && !publisher.has_trace_context())
+                    .then(observability::traceparent)
+                    .flatten();
+                let mut concurrent =
+                    Concurrent::new(std::iter::once(&mut *publisher).chain(&mut forks).collect());
+                // The messages of the backlog may be from earlier runs, and keep their run.
+                let mut publish = |concurrent: &mut Concurrent<_>, message: Message| {
+                    let (topic, data, device, run) = message;
+                    let data = match &traceparent {
+                        Some(traceparent) => parms.payload.add_traceparent(data, traceparent),
+                        None => data,
+                    };
+                    bytes += data.len();
+                    let data = parms.payload.compression.compress(data);
+                    compressed_bytes += data.len();
+                    messages += 1;
+                    concurrent.publish(topic, data, device, run);
+                };
+                let mut iter = simulation.iter(run);
+                loop {
+                    // A failed run is not repeated, since the time stamps of a real device would not be repeated either.
+                    concurrent.ready().await;
+                    if !record_publishes(concurrent.take_completed(), run, &metering, recorder) {
+                        break;
+                    }
+                    let Some((topic, data)) = iter.next() else {
+                        break;
+                    };
+                    // Pause right away, but keep the state of the run, so that the output continues as without the pause.
+                    if control.borrow().state == State::Paused {
+                        info!(run, "Paused.");
+                        let paused_at = Instant::now();
+                        concurrent.wait(unpaused(&mut control)).await;
+                        paused += paused_at.elapsed();
+                        info!(run, "Resumed.");
+                        if let Some(pacer) = &mut pacer {
+                            pacer.reset();
+                        }
+                    }
+                    if let Some(pacer) = &mut pacer {
+                        let on_time = concurrent
+                            .wait(async {
+                                tokio::select! {
+                                    on_time = pacer.wait() => Some(on_time),
+                                    _ = stopped(&mut control) => None,
+                                }
+                            })
+                            .await;
+                        match on_time {
+                            Some(on_time) => behind |= !on_time,
+                            None => break,
+                        }
+                    }
+                    let device = iter.device().map(str::to_string);
+                    // The offset of a device applies to all of its messages, which follow each other.
+                    if device.is_some() && device != previous_device {
+                        if let Some(offset) = offsets.next() {
+                            let jittered = concurrent
+                                .wait(async {
+                                    tokio::select! {
+                                        _ = sleep_until(start + paused + offset) => true,
+                                        _ = stopped(&mut control) => false,
+                                    }
+                                })
+                                .await;
+                            if !jittered {
+                                break;
+                            }
+                        }
+                        previous_device = device.clone();
+                    }
+                    if backpressure(Backpressure::Drop) {
+                        dropped += 1;
+                        continue;
+                    }
+                    let Some(backlog) = &mut backlog else {
+                        publish(&mut concurrent, (topic, data, device, run));
+                        continue;
+                    };
+                    dropped += backlog.push((topic, data, device, run)) as u64;
+                    while let Some(message) = backlog.pop() {
+                        concurrent.ready().await;
+                        publish(&mut concurrent, message);
+                    }
+                }
+                drop(iter);
+                // The backlog goes out as far as the queue has room, the rest waits for the next run.
+                if let Some(backlog) = &mut backlog {
+                    while let Some(message) = backlog.pop() {
+                        concurrent.ready().await;
+                        publish(&mut concurrent, message);
+                    }
+                }
+                concurrent.finish().await;
+                record_publishes(concurrent.take_completed(), run, &metering, recorder);
+                if let Some((failed, error)) = payload::take_format_errors() {
+                    warn!(parent: &simulation_span, failed, error, "Failed to format messages, they are left out.");
+                    metering.record_publish_errors(failed);
+                    recorder.record_publish_errors(failed);
+                }
+                if dropped > 0 {
+                    warn!(parent: &simulation_span, dropped, "The queue is full, dropped messages.");
+                    metering.record_dropped_messages(dropped);
+                    recorder.record_dropped_messages(dropped);
+                }
+                if let Some(queue) = &queue {
+                    metering.record_queue_depth(queue.depth());
+                }
+                if let Some(loopback) = latency::loopback() {
+                    record_loopback(loopback, run, recorder);
+                }
+                record_acks(run, recorder, settings);
+
+                let elapsed = start.elapsed().saturating_sub(paused);
+                metering.record_payload(bytes, compressed_bytes, datapoints);
+                metering.record_run(run);
+                recorder.record_run(datapoints);
+                profile_time += if target.is_some() { elapsed } else { frequency };
+                expected = messages as usize;
+                // With a target rate, the pacer already waited between the messages, and a backfill does not wait at all,
+                // so the next run continues right away.
+                let paced = target.is_some() || backfill_runs.is_some();
+                let period = if paced {
+                    elapsed.max(Duration::from_millis(1))
+                } else {
+                    frequency
+                };
+                let overloaded = match target {
+                    Some(_) => behind,
+                    None => backfill_runs.is_none() && elapsed >= frequency,
+                };
+                if overloaded {
+                    metering.is_overloaded();
+                    recorder.record_overload();
+                    if target.is_some() {
+                        warn!(parent: &simulation_span, "Messages cannot be sent at the target rate. Increase capacity on receiving end or reduce SIM_TARGET_MSGS_PER_SEC.");
+                    } else {
+                        warn!(parent: &simulation_span, "Messages cannot be sent fast enough. Increase capacity on receiving end, increase wait time or reduce the number of data points.");
+                    }
+                }
+                if backfill_runs.is_none() {
+                    let intended = match target {
+                        Some(_) => Duration::from_secs_f64(messages as f64 / target_rate),
+                        None => frequency,
+                    };
+                    adapt(
+                        adaptive.as_mut(),
+                        recorder,
+                        overloaded,
+                        messages,
+                        intended,
+                        elapsed,
+                    );
+                }
+                metering.record_datapoints(datapoints, period);
+                if !paced {
+                    metering.record_capacity(elapsed, frequency);
+                }
+                record_progress(progress, run, messages, datapoints, period);
+                let (missed, remainder) = if paced {
+                    (0, Duration::ZERO)
+                } else {
+                    missed_runs(elapsed, frequency, settings.sim.missed_runs)
+                };
+                if missed > 0 {
+                    warn!(parent: &simulation_span, missed, "Skipping missed runs");
+                    metering.record_skipped_runs(missed);
+                    recorder.record_skipped_runs(missed);
+                }
+                run += 1 + missed;
+                save_snapshot(simulation, run, settings);
+                if !paced {
+                    debug!(parent: &simulation_span, remainder=?remainder, "Sleeping");
+                    tokio::select! {
+                        _ = sleep(remainder) => (),
+                        _ = stopped(&mut control) => (),
+                    }
+                }
+            }
+            .instrument(simulation_span.clone())
+            .await;
+        }
+        // The messages that are left in the backlog are not published any more.
+        if let Some(dropped) = backlog
+            .filter(|backlog| !backlog.is_empty())
+            .map(|backlog| backlog.len() as u64)
+        {
+            warn!(dropped, "The queue is full, dropped the backlog.");
+            metering.record_dropped_messages(dropped);
+            recorder.record_dropped_messages(dropped);
+        }
+        run
+    }
+}
+
+/// Adapt the load to a run that was intended to take the given time, and record the rate of the runs that kept up,
+/// which is the ceiling of the receiving end once the load is adapted.
+fn adapt(
+    adaptive: Option<&mut Adaptive>,
+    recorder: &mut Recorder,
+    overloaded: bool,
+    messages: u64,
+    intended: Duration,
+    elapsed: Duration,
+) {
+    let rate = messages as f64 / elapsed.max(intended).as_secs_f64();
+    if !overloaded {
+        recorder.record_sustained_rate(rate);
+    }
+    let Some(adaptive) = adaptive else {
+        return;
+    };
+    adaptive.update(overloaded, intended.as_secs_f64() / elapsed.as_secs_f64());
+    if overloaded {
+        info!(
+            load = adaptive.load(),
+            msgs_per_sec = rate,
+            "Reduced the load to what the receiving end sustains."
+        );
+    }
+}
+
+/// Log the latencies of the messages that were received back during the run, which may include messages of the
+/// previous run.
+fn record_loopback(loopback: &Loopback, run: usize, recorder: &mut Recorder) {
+    let (latencies, lost) = loopback.take();
+    if latencies.count() > 0 {
+        info!(
+            run,
+            messages = latencies.count(),
+            p50_ms = summary::millis(latencies.percentile(0.5)),
+            p95_ms = summary::millis(latencies.percentile(0.95)),
+            p99_ms = summary::millis(latencies.percentile(0.99)),
+            "Loopback latency."
+        );
+    }
+    if lost > 0 {
+        warn!(
+            run,
+            lost, "Messages were not received back on BROKER_LOOPBACK_TOPIC."
+        );
+    }
+    recorder.record_loopback(&latencies);
+}
+
+/// Record the acknowledgements of the messages with QoS 1 or 2 during the run, and warn if their 99th percentile exceeds
+/// BROKER_ACK_BUDGET_MS.
+fn record_acks(run: usize, recorder: &mut Recorder, settings: &Settings) {
+    let latencies = latency::take_acks();
+    if latencies.count() == 0 {
+        return;
+    }
+    let p99 = summary::millis(latencies.percentile(0.99));
+    debug!(
+        run,
+        messages = latencies.count(),
+        p50_ms = summary::millis(latencies.percentile(0.5)),
+        p99_ms = p99,
+        "Acknowledgement latency."
+    );
+    let slow = settings
+        .broker
+        .ack_budget_ms
+        .is_some_and(|budget| p99 > budget);
+    if slow {
+        warn!(
+            run,
+            p99_ms = p99,
+            budget_ms = settings.broker.ack_budget_ms,
+            "The broker acknowledges the messages slower than BROKER_ACK_BUDGET_MS."
+        );
+    }
+    recorder.record_acks(&latencies, slow);
+}
+
+/// Record the outcomes of the publishes. Returns false if any of them failed.
+fn record_publishes(
+    outcomes: Vec<Outcome>,
+    run: usize,
+    metering: &Metering,
+    recorder: &mut Recorder,
+) -> bool {
+    let mut published = true;
+    let (mut messages, mut bytes) = (0, 0);
+    for (result, elapsed, size) in outcomes {
+        match result {
+            Ok(()) => {
+                recorder.record_publish(elapsed);
+                messages += 1;
+                bytes += size;
+            }
+            Err(e) => {
+                warn!(error = ?e, run, "Failed to publish, continuing with the next run");
+                metering.record_publish_errors(1);
+                recorder.record_publish_errors(1);
+                published = false;
+            }
+        }
+    }
+    metering.record_published(messages, bytes);
+    published
+}
+
+fn record_progress(
+    progress: &watch::Sender<Progress>,
+    run: usize,
+    messages: u64,
+    datapoints: usize,
+    elapsed: Duration,
+) {
+    progress.send_modify(|progress| {
+        progress.runs = run + 1;
+        progress.messages += messages;
+        progress.datapoints_per_sec = datapoints as f64 / elapsed.as_secs_f64();
+    });
+}
+
+/// The number of runs to skip after a run that took the given time, and the time to wait until the next run.
+/// Without skipping, the next run starts right away when a run takes longer than the frequency.
+fn missed_runs(elapsed: Duration, frequency: Duration, missed: MissedRuns) -> (usize, Duration) {
+    if missed == MissedRuns::Delay || frequency.is_zero() {
+        return (0, frequency.saturating_sub(elapsed));
+    }
+    let (elapsed, frequency) = (elapsed.as_nanos(), frequency.as_nanos());
+    let runs = usize::try_from(elapsed / frequency).unwrap_or(usize::MAX);
+    let remainder = frequency - elapsed % frequency;
+    // The remainder is at most the frequency, so its seconds fit like those of the frequency.
+    let remainder = Duration::new(
+        (remainder / 1_000_000_000) as u64,
+        (remainder % 1_000_000_000) as u32,
+    );
+    (runs, remainder)
+}
+
+/// Continue from the snapshot file, if there is one. Returns the number of runs already completed.
+fn restore_snapshot(simulation: &mut Simulation, settings: &Settings) -> usize {
+    match &settings.sim.snapshot_file {
+        Some(file) if Path::new(file).exists() => {
+            // It's OK to panic, since continuing with a fresh state would break reproducibility.
+            let runs = simulation
+                .restore(Path::new(file))
+                .unwrap_or_else(|e| panic!("Cannot restore snapshot {}: {}", file, e));
+            info!(sim_snapshot_file = file, runs, "Restored snapshot.");
+            runs
+        }
+        _ => 0,
+    }
+}
+
+/// Write a snapshot every SIM_SNAPSHOT_RUNS runs, if a snapshot file is configured.
+fn save_snapshot(simulation: &Simulation, runs: usize, settings: &Settings) {
+    if let Some(file) = &settings.sim.snapshot_file {
+        if runs.is_multiple_of(settings.sim.snapshot_runs.max(1)) {
+            if let Err(e) = simulation.save(Path::new(file), runs) {
+                warn!(
+                    error = e,
+                    sim_snapshot_file = file,
+                    "Failed to save snapshot"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::GeneratorConfig;
+    use crate::payload::PayloadConfig;
+    use crate::scenario;
+    use crate::simulation::{DeliveryConfig, TimeConfig, TimeMode};
+    use chrono::{DateTime, Utc};
+
+    #[test]
+    fn test_command() {
+        assert_eq!("pause".parse(), Ok(Command::Pause));
+        assert_eq!(" stop\n".parse(), Ok(Command::Stop));
+        assert_eq!(
+            r#"{"command": "set", "frequency_secs": 10}"#.parse(),
+            Ok(Command::Set {
+                frequency_secs: Some(10.0),
+                runs: None,
+                data_points: None,
+            })
+        );
+        assert_eq!(
+            Command::from_parameters(br#"{"data_points": 50}"#),
+            Ok(Command::Set {
+                frequency_secs: None,
+                runs: None,
+                data_points: Some(50),
+            })
+        );
+        assert!(Command::from_parameters(b"stop").is_err());
+        assert_eq!(r#"{"command": "start"}"#.parse(), Ok(Command::Start));
+        assert!("jump".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut control = Control::new(true, 60.0, 100);
+        control.apply(Command::Pause);
+        assert_eq!(control.state, State::Paused);
+        control.apply(Command::Start);
+        assert_eq!(control.state, State::Running);
+        control.apply(Command::Set {
+            frequency_secs: Some(5.0),
+            runs: None,
+            data_points: None,
+        });
+        assert_eq!(control, Control::new(false, 5.0, 100));
+        control.apply(Command::Set {
+            frequency_secs: None,
+            runs: None,
+            data_points: Some(10),
+        });
+        assert_eq!(control.data_points, Some(10));
+        control.apply(Command::Stop);
+        control.apply(Command::Resume);
+        assert_eq!(control.state, State::Stopped);
+    }
+
+    #[test]
+    fn test_status() {
+        let (control, _controlled) = watch::channel(Control::new(false, 60.0, 100));
+        let (_progress, progressed) = watch::channel(Progress::default());
+        let status = Status {
+            acknowledged: Some(Command::Set {
+                frequency_secs: Some(10.0),
+                runs: None,
+                data_points: None,
+            }),
+            ..Status::new(&control, &progressed)
+        };
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(
+            json.starts_with(r#"{"state":"running","frequency_secs":60,"runs":100,"progress":"#)
+        );
+        assert!(json.ends_with(r#""acknowledged":{"command":"set","frequency_secs":10}}"#));
+    }
+
+    #[tokio::test]
+    async fn test_unpaused() {
+        let (control, mut controlled) = watch::channel(Control::new(true, 60.0, 100));
+        tokio::spawn(async move {
+            control.send_modify(|control| control.apply(Command::Resume));
+            control.send_modify(|control| control.apply(Command::Stop));
+        });
+        stopped(&mut controlled).await;
+        assert_eq!(unpaused(&mut controlled).await.state, State::Stopped);
+    }
+
+    /// Remembers the published messages.
+    #[derive(Default)]
+    struct RecordingPublisher {
+        messages: Vec<(String, Option<String>, usize)>,
+        payloads: Vec<Vec<u8>>,
+        closed: bool,
+        /// Pause the simulation after the first message and resume it shortly after.
+        pause: Option<watch::Sender<Control>>,
+    }
+
+    impl Publisher for RecordingPublisher {
+        async fn publish(
+            &mut self,
+            topic: String,
+            payload: Vec<u8>,
+            device: Option<&str>,
+            run: usize,
+        ) -> Result<(), String> {
+            self.messages.push((topic, device.map(str::to_string), run));
+            self.payloads.push(payload);
+            if let Some(control) = self.pause.take() {
+                control.send_modify(|control| control.apply(Command::Pause));
+                tokio::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    control.send_modify(|control| control.apply(Command::Resume));
+                });
+            }
+            Ok(())
+        }
+
+        async fn close(&mut self) {
+            self.closed = true;
+        }
+    }
+
+    #[test]
+    fn test_missed_runs() {
+        let second = Duration::from_secs(1);
+        let missed = |elapsed, missed| missed_runs(elapsed, second, missed);
+        assert_eq!(
+            missed(Duration::from_millis(300), MissedRuns::Skip),
+            (0, Duration::from_millis(700))
+        );
+        assert_eq!(
+            missed(Duration::from_millis(2300), MissedRuns::Skip),
+            (2, Duration::from_millis(700))
+        );
+        assert_eq!(
+            missed(Duration::from_millis(2300), MissedRuns::Delay),
+            (0, Duration::ZERO)
+        );
+        assert_eq!(
+            missed_runs(second, Duration::ZERO, MissedRuns::Skip),
+            (0, Duration::ZERO)
+        );
+        // More missed runs than fit into 32 bits.
+        assert_eq!(
+            missed_runs(
+                Duration::from_secs(5),
+                Duration::from_nanos(1),
+                MissedRuns::Skip
+            ),
+            (5_000_000_000, Duration::from_nanos(1))
+        );
+    }
+
+    fn parameters(frequency_secs: f64) -> SimulationParameters {
+        SimulationParameters {
+            client_id: "test".to_string(),
+            devices: 2,
+            manifest: None,
+            children: 0,
+            data_points: 1,
+            seed: 12345,
+            frequency_secs,
+            generators: GeneratorConfig::default(),
+            payload: PayloadConfig::default(),
+            time: TimeConfig::default(),
+            delivery: DeliveryConfig::default(),
+            threads: 1,
+            shard: None,
+        }
+    }
+
+    /// The default settings, independent of the environment.
+    fn settings() -> Settings {
+        Settings::from_variables(&[]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_simulate() {
+        let parms = parameters(0.0);
+        let mut publisher = RecordingPublisher::default();
+        let simulation = Simulation::new(&parms);
+        let (control, _controlled) = watch::channel(Control::new(false, 0.0, 2));
+        let (progress, _progressed) = watch::channel(Progress::default());
+        Orchestrator::new(&mut publisher, &control, &progress, &settings())
+            .simulate(parms, simulation)
+            .await;
+
+        assert!(publisher.closed);
+        assert_eq!(progress.borrow().runs, 2);
+        assert_eq!(progress.borrow().messages, 4);
+        let messages: Vec<_> = publisher
+            .messages
+            .iter()
+            .map(|(topic, device, run)| (topic.as_str(), device.as_deref(), *run))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("s/us/test_0", Some("test_0"), 0),
+                ("s/us/test_1", Some("test_1"), 0),
+                ("s/us/test_0", Some("test_0"), 1),
+                ("s/us/test_1", Some("test_1"), 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_simulate_scenario() {
+        let yaml = b"
+- {name: few, devices: 1, frequency_secs: 0, runs: 2}
+- {name: more, devices: 3, frequency_secs: 0, runs: 1}
+";
+        let phases = scenario::parse_scenario(yaml, |_| PayloadConfig::default()).unwrap();
+        let mut publisher = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control::new(false, 60.0, usize::MAX));
+        let (progress, _progressed) = watch::channel(Progress::default());
+        Orchestrator::new(&mut publisher, &control, &progress, &settings())
+            .simulate_scenario(parameters(60.0), &phases)
+            .await;
+
+        assert!(publisher.closed);
+        assert_eq!(progress.borrow().messages, 5);
+        let runs: Vec<_> = publisher.messages.iter().map(|(_, _, run)| *run).collect();
+        assert_eq!(runs, vec![0, 1, 0, 0, 0]);
+        assert_eq!(control.borrow().runs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_scenario_time() {
+        let yaml = b"
+- {name: first, devices: 1, frequency_secs: 60, runs: 2}
+- {name: second, devices: 1, frequency_secs: 60, runs: 2}
+";
+        let phases = scenario::parse_scenario(yaml, |_| PayloadConfig::default()).unwrap();
+        let start = Utc::now();
+        let parms = SimulationParameters {
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                backfill_end: Some(start + chrono::Duration::days(1)),
+                ..TimeConfig::default()
+            },
+            ..parameters(60.0)
+        };
+        let mut publisher = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control::new(false, 60.0, usize::MAX));
+        let (progress, _progressed) = watch::channel(Progress::default());
+        Orchestrator::new(&mut publisher, &control, &progress, &settings())
+            .simulate_scenario(parms, &phases)
+            .await;
+
+        // The second phase continues after the first one instead of starting over.
+        let times: Vec<_> = publisher
+            .payloads
+            .iter()
+            .map(|payload| {
+                let payload = std::str::from_utf8(payload).unwrap();
+                let time = payload.split(',').nth(2).unwrap();
+                DateTime::parse_from_rfc3339(time).unwrap()
+            })
+            .collect();
+        assert_eq!(times.len(), 4);
+        assert_eq!(times[0], start);
+        assert!(times[2] > times[1]);
+        assert_eq!(times[2] - times[1], chrono::Duration::seconds(60));
+    }
+
+    #[tokio::test]
+    async fn test_simulate_data_points() {
+        let start = Utc::now();
+        let parms = |data_points| SimulationParameters {
+            data_points,
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                ..TimeConfig::default()
+            },
+            ..parameters(0.0)
+        };
+        let (progress, _progressed) = watch::channel(Progress::default());
+        let mut expected = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control::new(false, 0.0, 1));
+        let simulation = Simulation::new(&parms(3));
+        Orchestrator::new(&mut expected, &control, &progress, &settings())
+            .simulate(parms(3), simulation)
+            .await;
+
+        // The data points are changed before the first run.
+        let mut changed = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control {
+            data_points: Some(3),
+            ..Control::new(false, 0.0, 1)
+        });
+        let simulation = Simulation::new(&parms(1));
+        Orchestrator::new(&mut changed, &control, &progress, &settings())
+            .simulate(parms(1), simulation)
+            .await;
+        assert_eq!(changed.payloads, expected.payloads);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_stop() {
+        let parms = parameters(3600.0);
+        let mut publisher = RecordingPublisher::default();
+        let simulation = Simulation::new(&parms);
+        let (control, _controlled) = watch::channel(Control::new(false, 3600.0, usize::MAX));
+        let (progress, _progressed) = watch::channel(Progress::default());
+        let stop = control.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            stop.send_modify(|control| control.apply(Command::Stop));
+        });
+        Orchestrator::new(&mut publisher, &control, &progress, &settings())
+            .simulate(parms, simulation)
+            .await;
+
+        // The first run is complete, the second one is not started.
+        assert!(publisher.closed);
+        assert_eq!(publisher.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_pause() {
+        let start = Utc::now();
+        let parms = || SimulationParameters {
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                ..TimeConfig::default()
+            },
+            ..parameters(0.0)
+        };
+        let mut expected = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control::new(false, 0.0, 3));
+        let (progress, _progressed) = watch::channel(Progress::default());
+        let simulation = Simulation::new(&parms());
+        Orchestrator::new(&mut expected, &control, &progress, &settings())
+            .simulate(parms(), simulation)
+            .await;
+
+        let mut paused = RecordingPublisher::default();
+        let (control, _controlled) = watch::channel(Control::new(false, 0.0, 3));
+        paused.pause = Some(control.clone());
+        let simulation = Simulation::new(&parms());
+        let started = Instant::now();
+        Orchestrator::new(&mut paused, &control, &progress, &settings())
+            .simulate(parms(), simulation)
+            .await;
+
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(paused.messages, expected.messages);
+        assert_eq!(paused.payloads, expected.payloads);
+    }
+}
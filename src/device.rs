@@ -1,124 +1,1067 @@
-use chrono::Utc;
-use rand::rngs::StdRng;
-use rand::SeedableRng;
+use chrono::{DateTime, Duration, Utc};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
 
-use crate::generator::{create_generator, Generator, GeneratorType};
+use crate::generator::{
+    create_generator, DeviceMetadata, EventGenerator, Generator, GeneratorConfig, GeneratorState,
+    GeneratorType, UnitConfig, Value,
+};
+use crate::payload::{
+    format_aws_shadow, record_format_error, Measurement, Message, PayloadConfig, PayloadFormatter,
+};
+use crate::sparkplug::{SparkplugDevice, SparkplugNode};
 
 pub struct Device {
     name: String,
-    generators: Vec<Box<dyn Generator>>,
-    rng: StdRng,
+    formatter: Box<dyn PayloadFormatter>,
+    /// The topic of the measurements with the placeholders of the device already replaced.
+    topic: String,
+    /// Whether the topic has placeholders for the data point or its type.
+    topic_per_data_point: bool,
+    generators: Vec<DataPoint>,
+    events: Option<EventGenerator>,
+    event_topic: String,
+    /// The topic of the inventory properties, the number of runs between them and the properties, if publishing them.
+    metadata: Option<(String, u32, DeviceMetadata)>,
+    /// The topic of device shadow updates and the number of runs between them, if sending shadow updates.
+    aws_shadow: Option<(String, u32)>,
+    /// The edge node and the metric aliases of the device, if publishing Sparkplug B.
+    sparkplug: Option<(SparkplugNode, SparkplugDevice)>,
+    /// The registrations of the child devices, if the device is a gateway.
+    children: Vec<Message>,
+    /// The constant deviation of the device clock.
+    skew: Duration,
+    /// Maximum random deviation of each time stamp in milliseconds.
+    jitter_millis: u64,
+    rng: ChaCha12Rng,
+    tick: u32,
+    /// The measurements are formatted here and copied into the messages, so that the buffer grows to the size of the
+    /// messages once instead of in each run.
+    buffer: Vec<u8>,
+}
+
+/// A data point generator with the interval in which it is published and its unit.
+struct DataPoint {
+    generator_type: GeneratorType,
+    generator: Box<dyn Generator>,
+    interval: u32,
+    unit: Option<UnitConfig>,
+}
+
+/// The state of a device that is needed to continue a simulation where it left off.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    tick: u32,
+    rng: ChaCha12Rng,
+    generators: Vec<GeneratorState>,
+}
+
+/// The name and the data points of a device, either listed in a device manifest or generated uniformly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// The serial number in the inventory properties instead of a random one.
+    pub serial: Option<String>,
+    pub data_points: usize,
+    /// The generator mix of the device instead of the one of the simulation.
+    pub mix: Option<Vec<(GeneratorType, u32)>>,
+    /// The publish intervals of the device instead of the ones of the simulation.
+    pub intervals: Option<Vec<(u32, u32)>>,
+}
+
+impl DeviceProfile {
+    /// The profile of a device without manifest, named after the cluster and device IDs.
+    pub fn new(cluster_id: &str, device_id: usize, data_points: usize) -> Self {
+        DeviceProfile {
+            name: format!("{}_{}", cluster_id, device_id),
+            serial: None,
+            data_points,
+            mix: None,
+            intervals: None,
+        }
+    }
+
+    /// The same profile with only the deterministic generators in its mix, like GeneratorConfig::golden.
+    pub fn golden(mut self, simulated_time: bool) -> Self {
+        if let Some(mix) = &mut self.mix {
+            mix.retain(|(generator_type, _)| generator_type.is_deterministic(simulated_time));
+        }
+        self
+    }
+
+    /// Random inventory properties with the serial number of the device, if it has one.
+    fn metadata(&self, config: &GeneratorConfig, seed: u64) -> DeviceMetadata {
+        let mut metadata = DeviceMetadata::new(config, seed);
+        if let Some(serial) = &self.serial {
+            metadata.serial = serial.clone();
+        }
+        metadata
+    }
+
+    /// The generator configuration of the simulation with the settings of the device applied.
+    fn apply(&self, config: &GeneratorConfig) -> GeneratorConfig {
+        GeneratorConfig {
+            mix: self.mix.clone().unwrap_or_else(|| config.mix.clone()),
+            intervals: self
+                .intervals
+                .clone()
+                .unwrap_or_else(|| config.intervals.clone()),
+            ..config.clone()
+        }
+    }
 }
 
 impl Device {
     /// Create a new device with the given cluster and device IDs and the number of data points.
     /// Cluster ID serves as a prefix for the device name to distinguish several simulators from each other.
-    pub fn new(cluster_id: &str, device_id: usize, data_points: usize, seed: u64) -> Self {
-        let name = format!("{}_{}", cluster_id, device_id);
-        let generators = Self::create_data_point_generators(data_points);
-        let rng = StdRng::seed_from_u64(seed);
+    /// Devices publishing Sparkplug B share the edge node of the simulation.
+    pub fn new(
+        cluster_id: &str,
+        device_id: usize,
+        data_points: usize,
+        seed: u64,
+        config: &GeneratorConfig,
+        payload: &PayloadConfig,
+        sparkplug: Option<&SparkplugNode>,
+    ) -> Self {
+        let profile = DeviceProfile::new(cluster_id, device_id, data_points);
+        Self::from_profile(
+            cluster_id, device_id, &profile, seed, config, payload, sparkplug,
+        )
+    }
+
+    /// Create a new device from a profile. The device ID is still used for the topic placeholders.
+    pub fn from_profile(
+        cluster_id: &str,
+        device_id: usize,
+        profile: &DeviceProfile,
+        seed: u64,
+        config: &GeneratorConfig,
+        payload: &PayloadConfig,
+        sparkplug: Option<&SparkplugNode>,
+    ) -> Self {
+        let config = &profile.apply(config);
+        let name = profile.name.clone();
+        let topic = payload.topic(cluster_id, device_id, &name);
+        // Sparkplug B has its own topic namespace, so the data points are never split by topic.
+        let topic_per_data_point = sparkplug.is_none() && PayloadConfig::is_per_data_point(&topic);
+        let formatter = payload.formatter(&name);
+        let aws_shadow = payload.aws_shadow(&name);
+        let metadata = (config.metadata.runs > 0).then(|| {
+            (
+                payload.metadata_topic(cluster_id, device_id, &name),
+                config.metadata.runs,
+                profile.metadata(config, seed),
+            )
+        });
+        let mut generators = Self::create_data_point_generators(profile.data_points, config);
+        // Events are only drawn from the random number generator if they are enabled, so that they don't change the measurements.
+        let events = (config.events.rate > 0.0).then(|| EventGenerator::new(&config.events));
+        let event_topic = payload.event_topic(&config.events.topic, &name);
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        if config.phase > 0 {
+            Self::shift_phases(&mut generators, config.phase, &mut rng);
+        }
+        let skew = random_millis(config.clock.skew_millis, &mut rng);
         Device {
             name,
+            formatter,
+            topic,
+            topic_per_data_point,
             generators,
+            events,
+            event_topic,
+            metadata,
+            aws_shadow,
+            sparkplug: sparkplug.map(|node| (node.clone(), SparkplugDevice::default())),
+            children: Vec::new(),
+            skew,
+            jitter_millis: config.clock.jitter_millis,
             rng,
+            tick: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data_points(&self) -> usize {
+        self.generators.len()
+    }
+
+    /// Change the number of data points at the next run. The data points that stay keep the state of their generators,
+    /// so that their curves continue. They are matched by their type and their number within the type, like sensor_3.
+    pub fn set_data_points(&mut self, data_points: usize, config: &GeneratorConfig) {
+        let mut old: Vec<Option<DataPoint>> = std::mem::take(&mut self.generators)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let old_keys = numbered(
+            old.iter()
+                .flatten()
+                .map(|data_point| data_point.generator_type),
+        );
+        let mut generators = Self::create_data_point_generators(data_points, config);
+        let keys = numbered(
+            generators
+                .iter()
+                .map(|data_point| data_point.generator_type),
+        );
+        for (data_point, key) in generators.iter_mut().zip(keys) {
+            if let Some(index) = old_keys.iter().position(|old_key| *old_key == key) {
+                if let Some(old) = old[index].take() {
+                    data_point.generator = old.generator;
+                }
+            }
+        }
+        self.generators = generators;
+    }
+
+    /// Make the device a gateway of the given child device. The gateway registers the child in its first run,
+    /// if the payload format requires registering child devices.
+    pub fn add_child(&mut self, payload: &PayloadConfig, child: &str) {
+        self.children
+            .extend(payload.child_registration(&self.name, child));
+    }
+
+    /// Advance each data point by a random number of runs below phase, so that
+    /// the curves of the data points are out of phase but still reproducible.
+    fn shift_phases(generators: &mut [DataPoint], phase: u32, rng: &mut ChaCha12Rng) {
+        for data_point in generators {
+            for _ in 0..rng.gen_range(0..phase) {
+                data_point.generator.generate(rng);
+            }
         }
     }
 
-    /// Iterate over the data point generators and collect them into a string of the form
-    /// 201,S,<time>,SF,<data point 1>,<value 1>,,SF,<data point 2>,<value 2>,,...
-    /// What are the limitations here in terms of number of data points for C8Y?
-    pub fn generate(&mut self) -> (String, String) {
-        let topic = format!("s/us/{}", self.name);
+    /// Generate the messages of the device for this run: The registrations of child devices in the first run,
+    /// the inventory properties and the measurements that are due and an occasional event.
+    /// The messages are time stamped with the time of the run as seen by the possibly skewed clock of the device.
+    pub fn generate(&mut self, time: DateTime<Utc>) -> Vec<Message> {
+        for data_point in self.generators.iter_mut() {
+            data_point.generator.set_time(time);
+        }
+        let current_time = time + self.skew + random_millis(self.jitter_millis, &mut self.rng);
+        let tick = self.tick;
+        self.tick = self.tick.wrapping_add(1);
 
-        let mut message = String::with_capacity(40 + 20 * self.generators.len());
-        message.push_str("201,S,");
+        let mut messages = Vec::with_capacity(2);
+        if tick == 0 {
+            messages.extend(self.children.iter().cloned());
+        }
+        messages.extend(self.generate_metadata(tick));
+        messages.append(&mut self.generate_measurements(current_time, tick));
+        messages.extend(self.generate_event(current_time));
+        messages
+    }
 
-        let current_time = Utc::now();
-        // The comma at the end of the format string is intentional.
-        let time_str = current_time.format("%+,").to_string();
-        message.push_str(time_str.as_str());
+    /// The inventory properties, if they are due in this run. With Sparkplug B, they are sent as metrics.
+    fn generate_metadata(&self, tick: u32) -> Option<Message> {
+        match &self.metadata {
+            Some((topic, runs, metadata))
+                if self.sparkplug.is_none() && tick.is_multiple_of(*runs) =>
+            {
+                Some((topic.clone(), self.formatter.metadata(metadata)))
+            }
+            _ => None,
+        }
+    }
 
-        let data = self
-            .generators
-            .iter_mut()
-            .fold(message, |mut acc, generator| {
-                let (datapoint, value) = generator.generate(&mut self.rng);
-                acc.push_str(&format!("SF,{},{},", datapoint, value));
-                acc
-            });
+    /// Iterate over the data point generators that are due in this run and collect them into messages.
+    /// Usually, all data points go into one message. If the topic has placeholders for the data point
+    /// or its type, consecutive data points with the same topic go into one message.
+    /// Values of data points with a unit are scaled to engineering units.
+    /// Returns no message if no data point is due.
+    fn generate_measurements(&mut self, current_time: DateTime<Utc>, tick: u32) -> Vec<Message> {
+        let mut groups: Vec<(Option<String>, Vec<Measurement>)> = Vec::new();
+        // Without a topic per data point, all data points go into one group.
+        let group_capacity = if self.topic_per_data_point {
+            1
+        } else {
+            self.generators.len()
+        };
+        for data_point in self.generators.iter_mut() {
+            let DataPoint {
+                generator_type,
+                generator,
+                interval,
+                unit,
+            } = data_point;
+            if !tick.is_multiple_of((*interval).max(1)) {
+                continue;
+            }
+            let unit = unit.as_ref();
+            for (datapoint, value) in generator.generate(&mut self.rng) {
+                let value = match unit {
+                    Some(unit) => value.scale(unit.scale, unit.offset),
+                    None => value,
+                };
+                let topic = self.topic_per_data_point.then(|| {
+                    self.topic
+                        .replace("{type}", generator_type.name())
+                        .replace("{datapoint}", datapoint)
+                });
+                match groups.last_mut() {
+                    Some((last, measurements)) if *last == topic => {
+                        measurements.push((datapoint, value, unit))
+                    }
+                    _ => {
+                        let mut measurements = Vec::with_capacity(group_capacity);
+                        measurements.push((datapoint, value, unit));
+                        groups.push((topic, measurements));
+                    }
+                }
+            }
+        }
 
-        (topic, data)
+        if let Some((node, device)) = &mut self.sparkplug {
+            if let Some((_, runs, metadata)) = &self.metadata {
+                if tick.is_multiple_of(*runs) {
+                    let properties = [
+                        (
+                            "Properties/Serial Number",
+                            Value::Text(metadata.serial.clone()),
+                        ),
+                        ("Properties/Model", Value::Text(metadata.model.clone())),
+                        (
+                            "Properties/Firmware",
+                            Value::Text(metadata.firmware.clone()),
+                        ),
+                        ("Properties/Latitude", Value::Float(metadata.latitude)),
+                        ("Properties/Longitude", Value::Float(metadata.longitude)),
+                    ];
+                    let properties = properties.map(|(name, value)| (name, value, None));
+                    match groups.first_mut() {
+                        Some((_, measurements)) => measurements.extend(properties),
+                        None => groups.push((None, properties.to_vec())),
+                    }
+                }
+            }
+            return groups
+                .iter()
+                .map(|(_, measurements)| device.data(node, &self.name, current_time, measurements))
+                .collect();
+        }
+        let shadow = match &self.aws_shadow {
+            Some((topic, runs)) if !groups.is_empty() && tick.is_multiple_of(*runs) => {
+                let measurements = groups.iter().flat_map(|(_, measurements)| measurements);
+                Some((topic.clone(), format_aws_shadow(measurements)))
+            }
+            _ => None,
+        };
+        groups
+            .into_iter()
+            .filter_map(|(topic, measurements)| {
+                self.buffer.clear();
+                let formatted = self.formatter.write_measurements(
+                    &mut self.buffer,
+                    current_time,
+                    &measurements,
+                );
+                if let Err(e) = formatted {
+                    record_format_error(e);
+                    return None;
+                }
+                let topic = topic.unwrap_or_else(|| self.topic.clone());
+                Some((topic, self.buffer.clone()))
+            })
+            .chain(shadow)
+            .collect()
     }
 
-    /// Each device produces roughly 1/3 of each type of data point, status, noise, and sensor data.
-    fn create_data_point_generators(data_points: usize) -> Vec<Box<dyn Generator>> {
-        let mut generators = Vec::with_capacity(data_points.into());
+    /// Occasionally create an event. Sparkplug B has no events, so they are sent as text metrics.
+    fn generate_event(&mut self, current_time: DateTime<Utc>) -> Option<Message> {
+        let (event_type, text) = self.events.as_mut()?.generate(&mut self.rng)?;
+        if let Some((node, device)) = &mut self.sparkplug {
+            let name = format!("event/{}", event_type);
+            let measurement = (name.as_str(), Value::Text(text.to_string()), None);
+            return Some(device.data(node, &self.name, current_time, &[measurement]));
+        }
+        let message = match self.formatter.event(current_time, event_type, text) {
+            Ok(message) => message,
+            Err(e) => {
+                record_format_error(e);
+                return None;
+            }
+        };
+        let topic = self.event_topic.replace("{event_type}", event_type);
+        Some((topic, message))
+    }
 
-        for i in 0..data_points / 3 {
-            let generator = create_generator(GeneratorType::Status, i);
-            generators.push(generator);
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot {
+            tick: self.tick,
+            rng: self.rng.clone(),
+            generators: self
+                .generators
+                .iter()
+                .map(|data_point| data_point.generator.save())
+                .collect(),
         }
+    }
 
-        for i in data_points / 3..2 * data_points / 3 {
-            let generator = create_generator(GeneratorType::Noise, i - data_points / 3);
-            generators.push(generator);
+    /// Restore a snapshot taken from a device with the same configuration.
+    pub fn restore(&mut self, snapshot: DeviceSnapshot) -> Result<(), String> {
+        if snapshot.generators.len() != self.generators.len() {
+            return Err(format!(
+                "Snapshot of {} has {} data points instead of {}.",
+                self.name,
+                snapshot.generators.len(),
+                self.generators.len()
+            ));
+        }
+        for (data_point, state) in self.generators.iter_mut().zip(snapshot.generators) {
+            data_point.generator.restore(state)?;
         }
+        self.tick = snapshot.tick;
+        self.rng = snapshot.rng;
+        Ok(())
+    }
 
-        for i in 2 * data_points / 3..data_points {
-            let generator = create_generator(GeneratorType::Sensor, i - 2 * data_points / 3);
-            generators.push(generator);
+    /// Each device produces a share of each type of data point according to the weights in the mix.
+    /// The data points of each type are in turn split across the publish intervals.
+    fn create_data_point_generators(
+        data_points: usize,
+        config: &GeneratorConfig,
+    ) -> Vec<DataPoint> {
+        let mut generators = Vec::with_capacity(data_points);
+        for (generator_type, range) in split(&config.mix, data_points) {
+            let unit = config
+                .units
+                .iter()
+                .find(|(unit_type, _)| *unit_type == generator_type)
+                .map(|(_, unit)| unit);
+            let intervals = split(&config.intervals, range.len());
+            for (interval, interval_range) in intervals {
+                for i in interval_range {
+                    generators.push(DataPoint {
+                        generator_type,
+                        generator: create_generator(generator_type, i, config),
+                        interval,
+                        unit: unit.cloned(),
+                    });
+                }
+            }
         }
         generators
     }
 }
 
+/// The types with the number of each data point within its type.
+fn numbered(types: impl Iterator<Item = GeneratorType>) -> Vec<(GeneratorType, usize)> {
+    let mut numbered: Vec<(GeneratorType, usize)> = Vec::new();
+    for generator_type in types {
+        let number = numbered
+            .iter()
+            .filter(|(other, _)| *other == generator_type)
+            .count();
+        numbered.push((generator_type, number));
+    }
+    numbered
+}
+
+/// A random duration of at most max milliseconds in both directions. Nothing is drawn from the
+/// random number generator if max is 0, so that the clock errors don't change the measurements unless enabled.
+fn random_millis(max: u64, rng: &mut ChaCha12Rng) -> Duration {
+    match max {
+        0 => Duration::zero(),
+        max => Duration::milliseconds(rng.gen_range(-(max as i64)..=max as i64)),
+    }
+}
+
+/// Split count items into consecutive ranges according to the relative weights.
+fn split<T: Copy>(weighted: &[(T, u32)], count: usize) -> Vec<(T, Range<usize>)> {
+    let total: usize = weighted.iter().map(|(_, weight)| *weight as usize).sum();
+    let mut cumulated = 0;
+    weighted
+        .iter()
+        .map(|&(value, weight)| {
+            let start = cumulated * count / total.max(1);
+            cumulated += weight as usize;
+            let end = cumulated * count / total.max(1);
+            (value, start..end)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::generator::{ClockConfig, EventConfig, MetadataConfig};
+    use crate::payload::PayloadFormat;
     use rand::SeedableRng;
 
+    /// The messages of a device with text payloads.
+    fn generate_text(device: &mut Device) -> Vec<(String, String)> {
+        device
+            .generate(Utc::now())
+            .into_iter()
+            .map(|(topic, data)| (topic, String::from_utf8(data).unwrap()))
+            .collect()
+    }
+
     #[test]
     fn test_create_generators() {
-        let mut rng = StdRng::seed_from_u64(1);
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GeneratorConfig::default();
 
-        let generators = Device::create_data_point_generators(0);
+        let generators = Device::create_data_point_generators(0, &config);
         assert_eq!(generators.len(), 0);
 
-        let mut generators = Device::create_data_point_generators(1);
+        let mut generators = Device::create_data_point_generators(1, &config);
         assert_eq!(generators.len(), 1);
-        let (name, _value) = generators[0].generate(&mut rng);
+        let name = generators[0].generator.generate(&mut rng)[0].0;
         assert!(name.contains("sensor"));
 
-        let mut generators = Device::create_data_point_generators(2);
+        let mut generators = Device::create_data_point_generators(2, &config);
         assert_eq!(generators.len(), 2);
-        let (name, _value) = generators[0].generate(&mut rng);
+        let name = generators[0].generator.generate(&mut rng)[0].0;
         assert!(name.contains("noise"));
-        let (name, _value) = generators[1].generate(&mut rng);
+        let name = generators[1].generator.generate(&mut rng)[0].0;
         assert!(name.contains("sensor"));
 
-        let mut generators = Device::create_data_point_generators(3);
+        let mut generators = Device::create_data_point_generators(3, &config);
         assert_eq!(generators.len(), 3);
-        let (name, _value) = generators[0].generate(&mut rng);
+        let name = generators[0].generator.generate(&mut rng)[0].0;
         assert!(name.contains("status"));
-        let (name, _value) = generators[1].generate(&mut rng);
+        let name = generators[1].generator.generate(&mut rng)[0].0;
         assert!(name.contains("noise"));
-        let (name, _value) = generators[2].generate(&mut rng);
+        let name = generators[2].generator.generate(&mut rng)[0].0;
         assert!(name.contains("sensor"));
 
-        let mut generators = Device::create_data_point_generators(4);
+        let mut generators = Device::create_data_point_generators(4, &config);
         assert_eq!(generators.len(), 4);
-        let (name, _value) = generators[2].generate(&mut rng);
+        let name = generators[2].generator.generate(&mut rng)[0].0;
         assert!(name.contains("sensor"));
-        let (name, _value) = generators[3].generate(&mut rng);
+        let name = generators[3].generator.generate(&mut rng)[0].0;
         assert!(name.contains("sensor"));
+
+        let generators = Device::create_data_point_generators(100, &config);
+        let count = |generator_type| {
+            generators
+                .iter()
+                .filter(|data_point| data_point.generator_type == generator_type)
+                .count()
+        };
+        assert_eq!(count(GeneratorType::Status), 33);
+        assert_eq!(count(GeneratorType::Noise), 33);
+        assert_eq!(count(GeneratorType::Sensor), 34);
+    }
+
+    #[test]
+    fn test_create_generators_with_mix() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Status, 1), (GeneratorType::Sensor, 3)],
+            ..GeneratorConfig::default()
+        };
+
+        let mut generators = Device::create_data_point_generators(8, &config);
+        assert_eq!(generators.len(), 8);
+        for (i, data_point) in generators.iter_mut().enumerate() {
+            let name = data_point.generator.generate(&mut rng)[0].0;
+            let expected = if i < 2 { "status" } else { "sensor" };
+            assert!(name.contains(expected));
+        }
+
+        let config = GeneratorConfig {
+            mix: vec![],
+            ..GeneratorConfig::default()
+        };
+        assert!(Device::create_data_point_generators(8, &config).is_empty());
+    }
+
+    #[test]
+    fn test_create_generators_with_intervals() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Status, 1), (GeneratorType::Sensor, 1)],
+            intervals: vec![(1, 3), (60, 1)],
+            ..GeneratorConfig::default()
+        };
+
+        let mut generators = Device::create_data_point_generators(8, &config);
+        let intervals: Vec<u32> = generators
+            .iter()
+            .map(|data_point| data_point.interval)
+            .collect();
+        assert_eq!(intervals, vec![1, 1, 1, 60, 1, 1, 1, 60]);
+        let name = generators[3].generator.generate(&mut rng)[0].0;
+        assert_eq!(name, "status_3");
+    }
+
+    #[test]
+    fn test_generate_due_data_points() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            intervals: vec![(1, 1), (2, 1)],
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            2,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let (_topic, data) = generate_text(&mut device).remove(0);
+        assert!(data.contains("ramp_0") && data.contains("ramp_1"));
+        let (_topic, data) = generate_text(&mut device).remove(0);
+        assert!(data.contains("ramp_0") && !data.contains("ramp_1"));
+
+        let config = GeneratorConfig {
+            intervals: vec![(2, 1)],
+            ..config
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            2,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        assert_eq!(generate_text(&mut device).len(), 1);
+        assert!(generate_text(&mut device).is_empty());
+    }
+
+    #[test]
+    fn test_set_data_points() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            ..GeneratorConfig::default()
+        };
+        let new_device = || {
+            Device::new(
+                "rumsim-2",
+                3,
+                1,
+                1,
+                &config,
+                &PayloadConfig::default(),
+                None,
+            )
+        };
+        let mut expected = new_device();
+        let mut device = new_device();
+        for _ in 0..2 {
+            generate_text(&mut expected);
+            generate_text(&mut device);
+        }
+        let (_topic, expected) = generate_text(&mut expected).remove(0);
+        assert!(expected.ends_with("ramp_0,2,,"));
+
+        device.set_data_points(2, &config);
+        assert_eq!(device.data_points(), 2);
+        let (_topic, data) = generate_text(&mut device).remove(0);
+        // The first ramp continues, the second one is new.
+        assert!(data.ends_with("ramp_0,2,,SF,ramp_1,0,,"));
+
+        device.set_data_points(0, &config);
+        assert!(generate_text(&mut device).is_empty());
+    }
+
+    #[test]
+    fn test_phase() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            3,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let (_topic, data) = generate_text(&mut device).remove(0);
+        assert!(data.ends_with(",SF,ramp_0,0,,SF,ramp_1,0,,SF,ramp_2,0,,"));
+
+        let config = GeneratorConfig {
+            phase: 100,
+            ..config
+        };
+        let first = generate_text(&mut Device::new(
+            "rumsim-2",
+            3,
+            3,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        ))
+        .remove(0)
+        .1;
+        let second = generate_text(&mut Device::new(
+            "rumsim-2",
+            3,
+            3,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        ))
+        .remove(0)
+        .1;
+        let values: Vec<&str> = first.split(",SF,").skip(1).collect();
+        assert_ne!(values[0], values[1]);
+        assert_ne!(values[1], values[2]);
+        assert_eq!(
+            first.split_once(",SF,").unwrap().1,
+            second.split_once(",SF,").unwrap().1
+        );
+    }
+
+    #[test]
+    fn test_generate_units() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1), (GeneratorType::Status, 1)],
+            units: vec![(
+                GeneratorType::Ramp,
+                UnitConfig {
+                    unit: "bar".to_string(),
+                    scale: 0.5,
+                    offset: 10.0,
+                },
+            )],
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            2,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        generate_text(&mut device);
+        let (_topic, data) = generate_text(&mut device).remove(0);
+        assert!(data.ends_with(",SF,ramp_0,10.5,bar,SF,status_0,0,,"));
+    }
+
+    #[test]
+    fn test_generate_events() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            events: EventConfig {
+                rate: 1.0,
+                ..EventConfig::default()
+            },
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let messages = generate_text(&mut device);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, "s/us/rumsim-2_3");
+        let (topic, data) = &messages[1];
+        assert_eq!(topic, "s/us/rumsim-2_3");
+        assert!(data.starts_with("400,rumsim_"));
+        assert_eq!(data.split(',').count(), 4);
+    }
+
+    #[test]
+    fn test_generate_json() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            ..GeneratorConfig::default()
+        };
+        let payload = PayloadConfig {
+            format: PayloadFormat::Json,
+            topic: "factory/{device}/telemetry".to_string(),
+            ..PayloadConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 2, 1, &config, &payload, None);
+        let (topic, data) = generate_text(&mut device).remove(0);
+        assert_eq!(topic, "factory/rumsim-2_3/telemetry");
+        let message: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            message["values"],
+            serde_json::json!({"ramp_0": 0.0, "ramp_1": 0.0})
+        );
+    }
+
+    #[test]
+    fn test_generate_topic_per_type() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1), (GeneratorType::Status, 1)],
+            ..GeneratorConfig::default()
+        };
+        let payload = PayloadConfig {
+            topic: "factory/{cluster}/{device_id}/{type}".to_string(),
+            ..PayloadConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 4, 1, &config, &payload, None);
+        let messages = generate_text(&mut device);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, "factory/rumsim-2/3/ramp");
+        assert!(messages[0].1.ends_with(",SF,ramp_0,0,,SF,ramp_1,0,,"));
+        assert_eq!(messages[1].0, "factory/rumsim-2/3/status");
+
+        let payload = PayloadConfig {
+            topic: "factory/{device}/{datapoint}".to_string(),
+            ..payload
+        };
+        let mut device = Device::new("rumsim-2", 3, 4, 1, &config, &payload, None);
+        let messages = generate_text(&mut device);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[3].0, "factory/rumsim-2_3/status_1");
+        // The messages are formatted in the same buffer, but each has only its own data point, also in the next run.
+        for _ in 0..2 {
+            assert!(generate_text(&mut device)
+                .iter()
+                .all(|(_, data)| data.starts_with("201,S,") && data.matches("SF,").count() == 1));
+        }
+    }
+
+    #[test]
+    fn test_generate_aws_shadow() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            ..GeneratorConfig::default()
+        };
+        let payload = PayloadConfig {
+            format: PayloadFormat::AwsIot,
+            topic: PayloadFormat::AwsIot.default_topic().to_string(),
+            aws_shadow_runs: 2,
+            ..PayloadConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 2, 1, &config, &payload, None);
+        let messages = generate_text(&mut device);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, "dt/rumsim/rumsim-2/rumsim-2_3");
+        assert_eq!(messages[1].0, "$aws/things/rumsim-2_3/shadow/update");
+        let shadow: serde_json::Value = serde_json::from_str(&messages[1].1).unwrap();
+        assert_eq!(shadow["state"]["reported"]["ramp_1"], 0.0);
+        assert_eq!(generate_text(&mut device).len(), 1);
+        assert_eq!(generate_text(&mut device).len(), 2);
+    }
+
+    #[test]
+    fn test_generate_thin_edge() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            events: EventConfig {
+                rate: 1.0,
+                types: vec![("door".to_string(), "Door opened".to_string())],
+                ..EventConfig::default()
+            },
+            ..GeneratorConfig::default()
+        };
+        let payload = PayloadConfig {
+            format: PayloadFormat::ThinEdge,
+            topic: PayloadFormat::ThinEdge.default_topic().to_string(),
+            ..PayloadConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 2, 1, &config, &payload, None);
+        let messages = generate_text(&mut device);
+        assert_eq!(messages[0].0, "te/device/rumsim-2_3///m/");
+        let measurements: serde_json::Value = serde_json::from_str(&messages[0].1).unwrap();
+        assert_eq!(measurements["ramp_1"], 0.0);
+        assert_eq!(messages[1].0, "te/device/rumsim-2_3///e/door");
+        let event: serde_json::Value = serde_json::from_str(&messages[1].1).unwrap();
+        assert_eq!(event["text"], "Door opened");
+    }
+
+    #[test]
+    fn test_generate_clock() {
+        let payload = PayloadConfig {
+            format: PayloadFormat::Json,
+            ..PayloadConfig::default()
+        };
+        let time_of = |device: &mut Device, time: DateTime<Utc>| {
+            let (_topic, data) = device.generate(time).remove(0);
+            let message: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            DateTime::parse_from_rfc3339(message["time"].as_str().unwrap())
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let time = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let config = GeneratorConfig::default();
+        let mut device = Device::new("rumsim-2", 3, 1, 1, &config, &payload, None);
+        assert_eq!(time_of(&mut device, time), time);
+
+        let config = GeneratorConfig {
+            clock: ClockConfig {
+                skew_millis: 60000,
+                jitter_millis: 0,
+            },
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 1, 1, &config, &payload, None);
+        let skew = time_of(&mut device, time) - time;
+        assert!(skew != Duration::zero() && skew.num_seconds().abs() <= 60);
+        assert_eq!(time_of(&mut device, time) - time, skew);
+
+        let config = GeneratorConfig {
+            clock: ClockConfig {
+                skew_millis: 0,
+                jitter_millis: 1000,
+            },
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new("rumsim-2", 3, 1, 1, &config, &payload, None);
+        let jitters: Vec<Duration> = (0..10).map(|_| time_of(&mut device, time) - time).collect();
+        assert!(jitters
+            .iter()
+            .all(|jitter| jitter.num_milliseconds().abs() <= 1000));
+        assert!(jitters.iter().any(|jitter| *jitter != jitters[0]));
+    }
+
+    #[test]
+    fn test_generate_diurnal() {
+        let value_at = |device: &mut Device, time: &str| {
+            let time = DateTime::parse_from_rfc3339(time)
+                .unwrap()
+                .with_timezone(&Utc);
+            let (_topic, data) = device.generate(time).remove(0);
+            let data = String::from_utf8(data).unwrap();
+            let (_, value) = data.split_once(",SF,diurnal_0,").unwrap();
+            value.split(',').next().unwrap().to_string()
+        };
+        let mut config = GeneratorConfig {
+            mix: vec![(GeneratorType::Diurnal, 1)],
+            ..GeneratorConfig::default()
+        };
+        config.diurnal.jitter = 0.0;
+
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let day = value_at(&mut device, "2024-06-21T13:00:00Z");
+        let night = value_at(&mut device, "2024-06-21T01:00:00Z");
+        assert_ne!(day, night);
+
+        // The time also reaches diurnal data points wrapped for anomalies and outages.
+        config.anomaly.rate = 1e-12;
+        config.offline.rate = 1e-12;
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        assert_eq!(value_at(&mut device, "2024-06-21T13:00:00Z"), day);
+        assert_eq!(value_at(&mut device, "2024-06-21T01:00:00Z"), night);
+    }
+
+    #[test]
+    fn test_generate_metadata() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            metadata: MetadataConfig {
+                runs: 2,
+                ..MetadataConfig::default()
+            },
+            ..GeneratorConfig::default()
+        };
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let messages = generate_text(&mut device);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, "s/us/rumsim-2_3");
+        assert!(messages[0].1.starts_with("110,RS-"));
+        assert!(messages[1].1.starts_with("201,S,"));
+        assert_eq!(generate_text(&mut device).len(), 1);
+        assert_eq!(generate_text(&mut device).len(), 2);
+
+        // The properties don't change the measurements.
+        let mut plain = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &GeneratorConfig {
+                metadata: MetadataConfig::default(),
+                ..config.clone()
+            },
+            &PayloadConfig::default(),
+            None,
+        );
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            1,
+            1,
+            &config,
+            &PayloadConfig::default(),
+            None,
+        );
+        let without_time = |data: &str| data.split_once(",SF,").unwrap().1.to_string();
+        assert_eq!(
+            without_time(&generate_text(&mut plain)[0].1),
+            without_time(&generate_text(&mut device)[1].1)
+        );
+    }
+
+    #[test]
+    fn test_generate_sparkplug() {
+        let config = GeneratorConfig {
+            mix: vec![(GeneratorType::Ramp, 1)],
+            ..GeneratorConfig::default()
+        };
+        let payload = PayloadConfig {
+            format: PayloadFormat::SparkplugB,
+            ..PayloadConfig::default()
+        };
+        let node = payload.sparkplug_node("rumsim-2").unwrap();
+        let mut device = Device::new("rumsim-2", 3, 2, 1, &config, &payload, Some(&node));
+        let (topic, _data) = device.generate(Utc::now()).remove(0);
+        assert_eq!(topic, "spBv1.0/rumsim/DBIRTH/rumsim-2/rumsim-2_3");
+        let (topic, _data) = device.generate(Utc::now()).remove(0);
+        assert_eq!(topic, "spBv1.0/rumsim/DDATA/rumsim-2/rumsim-2_3");
     }
 
     #[tokio::test]
     async fn test_iter() {
         let data_points = 1;
-        let mut device = Device::new("rumsim-2", 3, data_points, 1);
-        let (topic, data) = device.generate();
+        let mut device = Device::new(
+            "rumsim-2",
+            3,
+            data_points,
+            1,
+            &GeneratorConfig::default(),
+            &PayloadConfig::default(),
+            None,
+        );
+        let (topic, data) = generate_text(&mut device).remove(0);
         assert_eq!(topic, String::from("s/us/rumsim-2_3"));
-        assert_eq!(data.split(',').count(), 7);
+        assert_eq!(data.split(',').count(), 8);
     }
 }
@@ -0,0 +1,383 @@
+//! Evaluate arithmetic expressions describing synthetic signals, such as `50 + 10*sin(t/60) + noise(2)`.
+//!
+//! Expressions support numbers, the variable `t` (the index of the data point), the constants `pi` and `e`,
+//! the operators `+ - * / % ^`, parentheses and the functions listed in `Function`.
+use rand::Rng;
+use rand_chacha::ChaCha12Rng;
+use std::str::FromStr;
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Time,
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(Function, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+}
+
+/// The functions available in expressions. `noise(a)` produces uniform random noise in the range +/- a.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Floor,
+    Ceil,
+    Round,
+    Min,
+    Max,
+    Noise,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<(Function, usize)> {
+        let function = match name {
+            "sin" => (Function::Sin, 1),
+            "cos" => (Function::Cos, 1),
+            "tan" => (Function::Tan, 1),
+            "abs" => (Function::Abs, 1),
+            "sqrt" => (Function::Sqrt, 1),
+            "exp" => (Function::Exp, 1),
+            "ln" => (Function::Ln, 1),
+            "floor" => (Function::Floor, 1),
+            "ceil" => (Function::Ceil, 1),
+            "round" => (Function::Round, 1),
+            "min" => (Function::Min, 2),
+            "max" => (Function::Max, 2),
+            "noise" => (Function::Noise, 1),
+            _ => return None,
+        };
+        Some(function)
+    }
+}
+
+impl Expression {
+    /// Evaluate the expression for the data point with index t.
+    pub fn evaluate(&self, t: f64, rng: &mut ChaCha12Rng) -> f64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Time => t,
+            Expression::Negate(operand) => -operand.evaluate(t, rng),
+            Expression::Binary(operator, left, right) => {
+                let left = left.evaluate(t, rng);
+                let right = right.evaluate(t, rng);
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                    Operator::Remainder => left % right,
+                    Operator::Power => left.powf(right),
+                }
+            }
+            Expression::Call(function, arguments) => {
+                let arguments: Vec<f64> = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(t, rng))
+                    .collect();
+                let x = arguments[0];
+                match function {
+                    Function::Sin => x.sin(),
+                    Function::Cos => x.cos(),
+                    Function::Tan => x.tan(),
+                    Function::Abs => x.abs(),
+                    Function::Sqrt => x.sqrt(),
+                    Function::Exp => x.exp(),
+                    Function::Ln => x.ln(),
+                    Function::Floor => x.floor(),
+                    Function::Ceil => x.ceil(),
+                    Function::Round => x.round(),
+                    Function::Min => x.min(arguments[1]),
+                    Function::Max => x.max(arguments[1]),
+                    Function::Noise => x * (2.0 * rng.gen::<f64>() - 1.0),
+                }
+            }
+        }
+    }
+}
+
+impl Expression {
+    /// The same expression with all noise replaced by 0, so that it evaluates deterministically.
+    pub fn without_noise(&self) -> Expression {
+        match self {
+            Expression::Number(_) | Expression::Time => self.clone(),
+            Expression::Negate(operand) => Expression::Negate(Box::new(operand.without_noise())),
+            Expression::Binary(operator, left, right) => Expression::Binary(
+                *operator,
+                Box::new(left.without_noise()),
+                Box::new(right.without_noise()),
+            ),
+            Expression::Call(Function::Noise, _) => Expression::Number(0.0),
+            Expression::Call(function, arguments) => Expression::Call(
+                *function,
+                arguments
+                    .iter()
+                    .map(|argument| argument.without_noise())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expression = parser.expression()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(format!("Unexpected {:?} in expression {}.", token, s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Symbol(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let value = number
+                .parse()
+                .map_err(|_| format!("Invalid number {} in expression {}.", number, s))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() {
+            let mut identifier = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                identifier.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Identifier(identifier));
+        } else if "+-*/%^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected character {} in expression {}.", c, s));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser for the grammar
+///   expression := term (('+' | '-') term)*
+///   term := unary (('*' | '/' | '%') unary)*
+///   unary := '-' unary | power
+///   power := primary ('^' unary)?
+///   primary := number | 't' | 'pi' | 'e' | function '(' arguments ')' | '(' expression ')'
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(format!("Expected {} but found {:?}.", symbol, self.peek()))
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        let mut expression = self.term()?;
+        loop {
+            let operator = if self.accept('+') {
+                Operator::Add
+            } else if self.accept('-') {
+                Operator::Subtract
+            } else {
+                return Ok(expression);
+            };
+            let right = self.term()?;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+        loop {
+            let operator = if self.accept('*') {
+                Operator::Multiply
+            } else if self.accept('/') {
+                Operator::Divide
+            } else if self.accept('%') {
+                Operator::Remainder
+            } else {
+                return Ok(expression);
+            };
+            let right = self.unary()?;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.accept('-') {
+            Ok(Expression::Negate(Box::new(self.unary()?)))
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Expression, String> {
+        let base = self.primary()?;
+        if self.accept('^') {
+            let exponent = self.unary()?;
+            Ok(Expression::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Identifier(name)) => match name.as_str() {
+                "t" => Ok(Expression::Time),
+                "pi" => Ok(Expression::Number(std::f64::consts::PI)),
+                "e" => Ok(Expression::Number(std::f64::consts::E)),
+                _ => self.call(&name),
+            },
+            Some(Token::Symbol('(')) => {
+                let expression = self.expression()?;
+                self.expect(')')?;
+                Ok(expression)
+            }
+            token => Err(format!("Unexpected {:?} in expression.", token)),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expression, String> {
+        let (function, arity) =
+            Function::parse(name).ok_or_else(|| format!("Unknown function {}.", name))?;
+        self.expect('(')?;
+        let mut arguments = vec![self.expression()?];
+        while self.accept(',') {
+            arguments.push(self.expression()?);
+        }
+        self.expect(')')?;
+        if arguments.len() != arity {
+            return Err(format!(
+                "Function {} expects {} arguments but got {}.",
+                name,
+                arity,
+                arguments.len()
+            ));
+        }
+        Ok(Expression::Call(function, arguments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn evaluate(expression: &str, t: f64) -> f64 {
+        let expression: Expression = expression.parse().unwrap();
+        expression.evaluate(t, &mut ChaCha12Rng::seed_from_u64(1))
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(evaluate("1 + 2 * 3", 0.0), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3", 0.0), 9.0);
+        assert_eq!(evaluate("10 - 4 - 3", 0.0), 3.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2", 0.0), 512.0);
+        assert_eq!(evaluate("-2 ^ 2", 0.0), -4.0);
+        assert_eq!(evaluate("7 % 4", 0.0), 3.0);
+        assert_eq!(evaluate("t / 2", 5.0), 2.5);
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(evaluate("sin(0) + cos(0)", 0.0), 1.0);
+        assert_eq!(evaluate("max(t, 3) + min(t, 3)", 5.0), 8.0);
+        assert!((evaluate("sin(pi / 2)", 0.0) - 1.0).abs() < 1e-12);
+        assert!((evaluate("ln(e)", 0.0) - 1.0).abs() < 1e-12);
+
+        let value = evaluate("50 + 10*sin(t/60) + noise(2)", 0.0);
+        assert!((48.0..=52.0).contains(&value));
+    }
+
+    #[test]
+    fn test_without_noise() {
+        let expression: Expression = "50 + 10*sin(t/60) + noise(2) * max(noise(1), 1)"
+            .parse()
+            .unwrap();
+        let expected: Expression = "50 + 10*sin(t/60) + 0 * max(0, 1)".parse().unwrap();
+        assert_eq!(expression.without_noise(), expected);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!("1 +".parse::<Expression>().is_err());
+        assert!("(1 + 2".parse::<Expression>().is_err());
+        assert!("1 2".parse::<Expression>().is_err());
+        assert!("foo(1)".parse::<Expression>().is_err());
+        assert!("max(1)".parse::<Expression>().is_err());
+        assert!("x".parse::<Expression>().is_err());
+        assert!("1 $ 2".parse::<Expression>().is_err());
+    }
+}
@@ -1,46 +1,1710 @@
 //! Generate numerical data to simulate IoT device data points.
-use rand::{rngs::StdRng, Rng};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use rand_distr::{Exp, Normal, Poisson};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::expression::Expression;
 
 /// The currently available types of generators for data points.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GeneratorType {
+    Alarm,
+    Counter,
+    Diurnal,
+    Expression,
+    Group,
+    Histogram,
+    Location,
+    Machine,
     Noise,
+    Ramp,
+    RandomWalk,
+    Replay,
     Sensor,
+    Setpoint,
     Status,
+    /// A generator registered by name in the GeneratorRegistry.
+    Custom(&'static str),
+}
+
+impl GeneratorType {
+    /// All built-in generator types.
+    pub const ALL: [GeneratorType; 15] = [
+        GeneratorType::Status,
+        GeneratorType::Histogram,
+        GeneratorType::Alarm,
+        GeneratorType::Noise,
+        GeneratorType::Ramp,
+        GeneratorType::Counter,
+        GeneratorType::Setpoint,
+        GeneratorType::RandomWalk,
+        GeneratorType::Group,
+        GeneratorType::Machine,
+        GeneratorType::Diurnal,
+        GeneratorType::Expression,
+        GeneratorType::Location,
+        GeneratorType::Replay,
+        GeneratorType::Sensor,
+    ];
+
+    /// The name of the generator type as used in the configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneratorType::Alarm => "alarm",
+            GeneratorType::Counter => "counter",
+            GeneratorType::Diurnal => "diurnal",
+            GeneratorType::Expression => "expression",
+            GeneratorType::Group => "group",
+            GeneratorType::Histogram => "histogram",
+            GeneratorType::Location => "location",
+            GeneratorType::Machine => "machine",
+            GeneratorType::Noise => "noise",
+            GeneratorType::Ramp => "ramp",
+            GeneratorType::RandomWalk => "walk",
+            GeneratorType::Replay => "replay",
+            GeneratorType::Sensor => "sensor",
+            GeneratorType::Setpoint => "setpoint",
+            GeneratorType::Status => "status",
+            GeneratorType::Custom(name) => name,
+        }
+    }
+
+    /// Whether the generator produces a smooth curve without random numbers in golden mode. Replay generators only
+    /// pick their starting point at random, and custom generators are up to their registration. Diurnal generators
+    /// follow the time stamps of the runs, which only repeat in simulated time.
+    pub fn is_deterministic(&self, simulated_time: bool) -> bool {
+        match self {
+            GeneratorType::Diurnal => simulated_time,
+            GeneratorType::Custom(name) => GeneratorRegistry::global()
+                .read()
+                .unwrap()
+                .is_deterministic(name),
+            _ => matches!(
+                self,
+                GeneratorType::Counter
+                    | GeneratorType::Expression
+                    | GeneratorType::Ramp
+                    | GeneratorType::Replay
+                    | GeneratorType::Sensor
+            ),
+        }
+    }
+}
+
+impl FromStr for GeneratorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GeneratorType::ALL
+            .into_iter()
+            .find(|generator_type| generator_type.name() == s)
+            .or_else(|| GeneratorRegistry::global().read().unwrap().custom_type(s))
+            .ok_or_else(|| format!("Unknown generator type {}.", s))
+    }
+}
+
+/// Configuration of the generators, shared by all devices of a simulation.
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    /// The types of data points on a device with their relative weights.
+    pub mix: Vec<(GeneratorType, u32)>,
+    /// Units and scaling of the data points of a generator type.
+    pub units: Vec<(GeneratorType, UnitConfig)>,
+    /// Events published by the devices besides the measurements.
+    pub events: EventConfig,
+    /// Inventory properties published regularly by the devices.
+    pub metadata: MetadataConfig,
+    /// Errors of the clocks of the devices.
+    pub clock: ClockConfig,
+    /// Publish intervals in multiples of the simulation frequency with their relative weights.
+    /// The data points of each type are split across the intervals.
+    pub intervals: Vec<(u32, u32)>,
+    /// Maximum number of runs by which each data point is advanced before the first run,
+    /// so that the curves of the data points are out of phase.
+    pub phase: u32,
+    pub sensor: SensorConfig,
+    pub noise: NoiseConfig,
+    pub ramp: RampConfig,
+    pub counter: CounterConfig,
+    pub setpoint: SetpointConfig,
+    pub location: LocationConfig,
+    pub alarm: AlarmConfig,
+    pub random_walk: RandomWalkConfig,
+    pub anomaly: AnomalyConfig,
+    pub offline: OfflineConfig,
+    pub group: GroupConfig,
+    pub machine: MachineConfig,
+    pub diurnal: DiurnalConfig,
+    pub histogram: HistogramConfig,
+    pub expression: Expression,
+    /// Recorded values for replay generators, if any have been loaded.
+    pub replay: Option<Arc<ReplayData>>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            // The other types are only used if explicitly requested.
+            mix: vec![
+                (GeneratorType::Status, 1),
+                (GeneratorType::Noise, 1),
+                (GeneratorType::Sensor, 1),
+            ],
+            intervals: vec![(1, 1)],
+            phase: 0,
+            units: Vec::new(),
+            events: EventConfig::default(),
+            metadata: MetadataConfig::default(),
+            clock: ClockConfig::default(),
+            sensor: SensorConfig::default(),
+            noise: NoiseConfig::default(),
+            ramp: RampConfig::default(),
+            counter: CounterConfig::default(),
+            setpoint: SetpointConfig::default(),
+            location: LocationConfig::default(),
+            alarm: AlarmConfig::default(),
+            random_walk: RandomWalkConfig::default(),
+            anomaly: AnomalyConfig::default(),
+            offline: OfflineConfig::default(),
+            group: GroupConfig::default(),
+            machine: MachineConfig::default(),
+            diurnal: DiurnalConfig::default(),
+            histogram: HistogramConfig::default(),
+            expression: DEFAULT_EXPRESSION.parse().unwrap(),
+            replay: None,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// The same configuration with only the deterministic generators, and without jitter, noise,
+    /// anomalies, outages, events and counter resets, so that a simulation produces the same
+    /// smooth curves in each run.
+    pub fn golden(mut self, simulated_time: bool) -> Self {
+        self.mix
+            .retain(|(generator_type, _)| generator_type.is_deterministic(simulated_time));
+        let increment = (self.counter.min_increment + self.counter.max_increment) / 2;
+        self.counter.min_increment = increment;
+        self.counter.max_increment = increment;
+        self.sensor.jitter = 0.0;
+        self.diurnal.jitter = 0.0;
+        self.group
+            .members
+            .iter_mut()
+            .for_each(|member| member.noise = 0.0);
+        self.expression = self.expression.without_noise();
+        self.counter.reset_probability = 0.0;
+        self.anomaly.rate = 0.0;
+        self.offline.rate = 0.0;
+        self.events.rate = 0.0;
+        self.clock.jitter_millis = 0;
+        self
+    }
+}
+
+/// The signal produced by the expression generator unless configured otherwise.
+pub const DEFAULT_EXPRESSION: &str = "50 + 10*sin(t/60) + noise(2)";
+
+/// Sporadic text events of a device, such as a door that is opened or an error code.
+#[derive(Debug, Clone)]
+pub struct EventConfig {
+    /// Probability per run that a device emits an event.
+    pub rate: f64,
+    /// Topic prefix for events. The device name is appended.
+    pub topic: String,
+    /// The event types with their texts, picked with equal probability.
+    pub types: Vec<(String, String)>,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        EventConfig {
+            rate: 0.0,
+            topic: "s/us".to_string(),
+            types: [
+                ("rumsim_DoorOpened", "Door opened"),
+                ("rumsim_DoorClosed", "Door closed"),
+                ("rumsim_Error", "Error code 42"),
+            ]
+            .iter()
+            .map(|(event_type, text)| (event_type.to_string(), text.to_string()))
+            .collect(),
+        }
+    }
+}
+
+/// Engineering unit of a data point. Numerical values are published as value * scale + offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitConfig {
+    pub unit: String,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+/// Shape of the sine curve produced by the sensor generator.
+#[derive(Debug, Clone, Copy)]
+pub struct SensorConfig {
+    /// Offset of the sine curve.
+    pub offset: f64,
+    /// Generated values are in the range offset +/- amplitude.
+    pub amplitude: f64,
+    /// The jitter added on top of the sine curve.
+    pub jitter: f64,
+    /// The sine repeats every period data points.
+    pub period: u32,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            offset: 100.0,
+            amplitude: 20.0,
+            jitter: 2.0,
+            period: 100,
+        }
+    }
+}
+
+/// Distribution of the values produced by the noise generator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoiseConfig {
+    pub distribution: NoiseDistribution,
+}
+
+/// The distributions available for noise data points.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NoiseDistribution {
+    /// Uniformly distributed integers in the 16 bit range.
+    #[default]
+    Uniform,
+    Normal {
+        mean: f64,
+        std_dev: f64,
+    },
+    Exponential {
+        lambda: f64,
+    },
+    /// Poisson distributed integers, such as the number of events per interval.
+    Poisson {
+        lambda: f64,
+    },
+}
+
+/// Shape of the sawtooth produced by the ramp generator.
+#[derive(Debug, Clone, Copy)]
+pub struct RampConfig {
+    /// Maximum of the ramp before it resets.
+    pub max: f64,
+    /// Increment of the ramp per data point.
+    pub step: f64,
+}
+
+impl Default for RampConfig {
+    fn default() -> Self {
+        RampConfig {
+            max: 1000.0,
+            step: 1.0,
+        }
+    }
+}
+
+/// Behaviour of the PLC-style counters produced by the counter generator.
+#[derive(Debug, Clone, Copy)]
+pub struct CounterConfig {
+    /// Minimum increment of the counter per data point.
+    pub min_increment: u32,
+    /// Maximum increment of the counter per data point.
+    pub max_increment: u32,
+    /// Width of the counter in bits, e.g. 16 or 32. The counter wraps to zero when it overflows.
+    pub bits: u32,
+    /// Probability per data point that the counter is reset to zero, e.g. by a power cycle.
+    pub reset_probability: f64,
+}
+
+impl Default for CounterConfig {
+    fn default() -> Self {
+        CounterConfig {
+            min_increment: 0,
+            max_increment: 10,
+            bits: 16,
+            reset_probability: 0.0001,
+        }
+    }
+}
+
+/// Range and dynamics of the setpoint generator.
+#[derive(Debug, Clone, Copy)]
+pub struct SetpointConfig {
+    /// Lower bound of the setpoint.
+    pub min: f64,
+    /// Upper bound of the setpoint.
+    pub max: f64,
+    /// Maximum change of the setpoint in a single jump.
+    pub step: f64,
+    /// Maximum number of data points the setpoint is held before it jumps.
+    pub hold: u32,
+}
+
+impl Default for SetpointConfig {
+    fn default() -> Self {
+        SetpointConfig {
+            min: 0.0,
+            max: 100.0,
+            step: 5.0,
+            hold: 50,
+        }
+    }
+}
+
+/// Starting point and speed of the location generator.
+#[derive(Debug, Clone, Copy)]
+pub struct LocationConfig {
+    /// Latitude of the starting point in degrees.
+    pub latitude: f64,
+    /// Longitude of the starting point in degrees.
+    pub longitude: f64,
+    /// Distance travelled per data point in meters.
+    pub speed: f64,
+}
+
+impl Default for LocationConfig {
+    fn default() -> Self {
+        LocationConfig {
+            latitude: 52.52,
+            longitude: 13.405,
+            speed: 10.0,
+        }
+    }
+}
+
+/// Frequency and duration of alarms raised by the alarm generator.
+#[derive(Debug, Clone, Copy)]
+pub struct AlarmConfig {
+    /// Probability that an alarm is raised per data point.
+    pub probability: f64,
+    /// Minimum number of data points an alarm is held once raised.
+    pub hold: u32,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        AlarmConfig {
+            probability: 0.01,
+            hold: 10,
+        }
+    }
+}
+
+/// Bounds and step size of the random walk generator.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomWalkConfig {
+    /// Lower bound of the random walk.
+    pub min: f64,
+    /// Upper bound of the random walk.
+    pub max: f64,
+    /// Maximum change of the value per data point.
+    pub step: f64,
+}
+
+impl Default for RandomWalkConfig {
+    fn default() -> Self {
+        RandomWalkConfig {
+            min: 0.0,
+            max: 100.0,
+            step: 0.5,
+        }
+    }
+}
+
+/// Injection of anomalies into the values of all generators.
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyConfig {
+    /// Probability that an anomaly starts per data point. Zero disables anomalies.
+    pub rate: f64,
+    /// Value added to a data point to produce a spike.
+    pub spike: f64,
+    /// Number of data points a stuck value is held.
+    pub stuck: u32,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            rate: 0.0,
+            spike: 1000.0,
+            stuck: 10,
+        }
+    }
+}
+
+/// Sensors that go offline for a while and stop being published.
+#[derive(Debug, Clone, Copy)]
+pub struct OfflineConfig {
+    /// Probability that a data point goes offline per data point. Zero disables failures.
+    pub rate: f64,
+    /// Number of data points a data point stays offline.
+    pub duration: u32,
+    /// Status code published alongside a data point when it comes back online, if any.
+    pub recovery_code: Option<i64>,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        OfflineConfig {
+            rate: 0.0,
+            duration: 60,
+            recovery_code: None,
+        }
+    }
+}
+
+/// A latent signal and the outputs of a group of correlated sensors driven by it.
+#[derive(Debug, Clone)]
+pub struct GroupConfig {
+    /// Maximum change of the latent signal per data point. The latent signal stays within +/- 1.
+    pub step: f64,
+    /// The outputs derived from the latent signal.
+    pub members: Vec<GroupMember>,
+}
+
+/// An output of a group computed as offset + gain * latent signal +/- noise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupMember {
+    pub offset: f64,
+    pub gain: f64,
+    pub noise: f64,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            step: 0.05,
+            members: vec![
+                GroupMember {
+                    offset: 20.0,
+                    gain: 10.0,
+                    noise: 0.5,
+                },
+                GroupMember {
+                    offset: 25.0,
+                    gain: 8.0,
+                    noise: 0.5,
+                },
+            ],
+        }
+    }
+}
+
+/// Transitions of the machine generator between idle, running and fault
+/// and the temperature of the machine in these states.
+#[derive(Debug, Clone, Copy)]
+pub struct MachineConfig {
+    /// Probability per run that an idle machine starts running.
+    pub start_probability: f64,
+    /// Probability per run that a running machine stops.
+    pub stop_probability: f64,
+    /// Probability per run that a running machine fails.
+    pub fault_probability: f64,
+    /// Minimum number of runs a machine stays idle or running.
+    pub min_dwell: u32,
+    /// Number of runs until a failed machine is repaired and idle again.
+    pub repair_time: u32,
+    /// Temperature that an idle or failed machine cools down to.
+    pub idle_temperature: f64,
+    /// Temperature that a running machine heats up to.
+    pub running_temperature: f64,
+    /// Share of the difference to the target temperature that is closed per run.
+    pub heating_rate: f64,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            start_probability: 0.05,
+            stop_probability: 0.02,
+            fault_probability: 0.005,
+            min_dwell: 10,
+            repair_time: 30,
+            idle_temperature: 20.0,
+            running_temperature: 80.0,
+            heating_rate: 0.05,
+        }
+    }
+}
+
+/// Daily and seasonal pattern of the diurnal generator.
+#[derive(Debug, Clone, Copy)]
+pub struct DiurnalConfig {
+    /// Value at night.
+    pub base: f64,
+    /// Value at the peak of the day.
+    pub peak: f64,
+    /// Hour of the day (UTC) at which the peak is reached.
+    pub peak_hour: f64,
+    /// Relative seasonal variation over the year, highest in summer.
+    pub seasonal: f64,
+    /// The jitter added on top of the pattern.
+    pub jitter: f64,
+}
+
+impl Default for DiurnalConfig {
+    fn default() -> Self {
+        DiurnalConfig {
+            base: 10.0,
+            peak: 100.0,
+            peak_hour: 13.0,
+            seasonal: 0.1,
+            jitter: 2.0,
+        }
+    }
+}
+
+/// Discrete values drawn by the histogram generator with their relative weights.
+#[derive(Debug, Clone)]
+pub struct HistogramConfig {
+    pub states: Vec<(i64, u32)>,
+}
+
+impl Default for HistogramConfig {
+    fn default() -> Self {
+        HistogramConfig {
+            states: vec![(0, 70), (1, 20), (2, 5), (4, 5)],
+        }
+    }
+}
+
+/// Columns of recorded values loaded from a CSV file with a header row.
+pub struct ReplayData {
+    columns: Vec<(String, Vec<f64>)>,
+}
+
+impl ReplayData {
+    /// Parse CSV content with a header row of column names followed by rows of numerical values.
+    pub fn from_csv(content: &str) -> Result<ReplayData, String> {
+        let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+        let header = lines.next().ok_or("The CSV file is empty.")?;
+        let mut columns: Vec<(String, Vec<f64>)> = header
+            .split(',')
+            .map(|name| (name.trim().to_string(), Vec::new()))
+            .collect();
+
+        for (row, line) in lines.enumerate() {
+            let cells: Vec<&str> = line.split(',').collect();
+            if cells.len() != columns.len() {
+                return Err(format!(
+                    "Row {} has {} cells, but the header has {} columns.",
+                    row + 1,
+                    cells.len(),
+                    columns.len()
+                ));
+            }
+            for ((name, values), cell) in columns.iter_mut().zip(cells) {
+                let value = cell.trim().parse().map_err(|_| {
+                    format!(
+                        "Invalid value {} in row {} of column {}.",
+                        cell,
+                        row + 1,
+                        name
+                    )
+                })?;
+                values.push(value);
+            }
+        }
+
+        if columns[0].1.is_empty() {
+            return Err("The CSV file contains no values.".to_string());
+        }
+        Ok(ReplayData { columns })
+    }
+}
+
+/// The recorded values are omitted since they can be large.
+impl fmt::Debug for ReplayData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.columns.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("ReplayData")
+            .field("columns", &names)
+            .field("rows", &self.columns[0].1.len())
+            .finish()
+    }
+}
+
+/// A typed value of a data point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+    Text(String),
+}
+
+impl Value {
+    /// Shift a value by delta. Booleans are flipped and texts stay unchanged.
+    fn shift(&self, delta: f64) -> Value {
+        match self {
+            Value::Float(value) => Value::Float(value + delta),
+            Value::Int(value) => Value::Int(value.saturating_add(delta as i64)),
+            Value::Bool(value) => Value::Bool(!value),
+            Value::Text(value) => Value::Text(value.clone()),
+        }
+    }
+
+    /// The value as JSON. Unlike the text representation, booleans stay booleans.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Float(value) => (*value).into(),
+            Value::Int(value) => (*value).into(),
+            Value::Bool(value) => (*value).into(),
+            Value::Text(value) => value.clone().into(),
+        }
+    }
+
+    /// Scale a numerical value to engineering units. Booleans and texts stay unchanged.
+    pub fn scale(&self, scale: f64, offset: f64) -> Value {
+        let scaled = |value: f64| ((value * scale + offset) * 1e6).round() / 1e6;
+        match self {
+            _ if scale == 1.0 && offset == 0.0 => self.clone(),
+            Value::Float(value) => Value::Float(scaled(*value)),
+            Value::Int(value) => Value::Float(scaled(*value as f64)),
+            _ => self.clone(),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::Int(value.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+/// Text-based payloads expect numerical flags, so booleans are written as 0 and 1.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", u8::from(*value)),
+            Value::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Generate the next values for a data point. Most generators produce a single
+/// named value, but some produce several correlated values that belong into the same message.
+pub trait Generator: Send {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)>;
+
+    /// Generators that depend on the time of day get the time stamp of the run before generating.
+    fn set_time(&mut self, _time: DateTime<Utc>) {}
+
+    /// Save the internal state of the generator for a snapshot. Stateless generators keep the default.
+    fn save(&self) -> GeneratorState {
+        GeneratorState::Null
+    }
+
+    /// Restore the internal state from a snapshot of a generator with the same configuration.
+    fn restore(&mut self, _state: GeneratorState) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Generator for Box<dyn Generator> {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        self.as_mut().generate(rng)
+    }
+
+    fn set_time(&mut self, time: DateTime<Utc>) {
+        self.as_mut().set_time(time)
+    }
+
+    fn save(&self) -> GeneratorState {
+        self.as_ref().save()
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.as_mut().restore(state)
+    }
+}
+
+/// The internal state of a generator as stored in snapshots.
+pub type GeneratorState = serde_json::Value;
+
+fn save_state<T: Serialize>(state: T) -> GeneratorState {
+    // Generator states are plain numbers and values, which always serialize.
+    serde_json::to_value(state).unwrap()
+}
+
+fn restore_state<T: DeserializeOwned>(state: GeneratorState) -> Result<T, String> {
+    serde_json::from_value(state).map_err(|e| format!("Invalid generator state: {}", e))
+}
+
+/// Create a generator from its ID and the generator configuration.
+pub type GeneratorFactory =
+    Box<dyn Fn(usize, &GeneratorConfig) -> Box<dyn Generator> + Send + Sync>;
+
+/// Named generator factories. The registry contains the built-in generators
+/// and can be extended at runtime with custom generators, which can then be
+/// referenced by name in the generator mix.
+pub struct GeneratorRegistry {
+    factories: HashMap<&'static str, GeneratorFactory>,
+    /// The custom generators that stay in the mix in golden mode.
+    deterministic: HashSet<&'static str>,
+}
+
+static REGISTRY: LazyLock<RwLock<GeneratorRegistry>> =
+    LazyLock::new(|| RwLock::new(GeneratorRegistry::default()));
+
+impl GeneratorRegistry {
+    /// The registry that is consulted by create_generator.
+    pub fn global() -> &'static RwLock<GeneratorRegistry> {
+        &REGISTRY
+    }
+
+    /// Register a generator under the given name, replacing any previous registration. The generator is left out in
+    /// golden mode, since it may draw random numbers.
+    pub fn register<F>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(usize, &GeneratorConfig) -> Box<dyn Generator> + Send + Sync + 'static,
+    {
+        self.factories.insert(name, Box::new(factory));
+        self.deterministic.remove(name);
+    }
+
+    /// Register a generator that produces the same values in each run of the simulator, so that it stays in the mix
+    /// in golden mode.
+    pub fn register_deterministic<F>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(usize, &GeneratorConfig) -> Box<dyn Generator> + Send + Sync + 'static,
+    {
+        self.register(name, factory);
+        self.deterministic.insert(name);
+    }
+
+    fn is_deterministic(&self, name: &str) -> bool {
+        self.deterministic.contains(name)
+    }
+
+    /// Create a generator by name, if a generator of that name is registered.
+    pub fn create(
+        &self,
+        name: &str,
+        id: usize,
+        config: &GeneratorConfig,
+    ) -> Option<Box<dyn Generator>> {
+        self.factories.get(name).map(|factory| factory(id, config))
+    }
+
+    fn custom_type(&self, name: &str) -> Option<GeneratorType> {
+        self.factories
+            .get_key_value(name)
+            .map(|(name, _)| GeneratorType::Custom(name))
+    }
+}
+
+impl Default for GeneratorRegistry {
+    fn default() -> Self {
+        let mut registry = GeneratorRegistry {
+            factories: HashMap::new(),
+            deterministic: HashSet::new(),
+        };
+        registry.register("alarm", |id, config| {
+            Box::new(AlarmGenerator::new(id, config.alarm))
+        });
+        registry.register("counter", |id, config| {
+            Box::new(CounterGenerator::new(id, config.counter))
+        });
+        registry.register("diurnal", |id, config| {
+            Box::new(DiurnalGenerator::new(id, config.diurnal))
+        });
+        registry.register("expression", |id, config| {
+            Box::new(ExpressionGenerator::new(id, config.expression.clone()))
+        });
+        registry.register("group", |id, config| {
+            Box::new(GroupGenerator::new(id, config.group.clone()))
+        });
+        registry.register("histogram", |id, config| {
+            Box::new(HistogramGenerator::new(id, &config.histogram))
+        });
+        registry.register("location", |id, config| {
+            Box::new(LocationGenerator::new(id, config.location))
+        });
+        registry.register("machine", |id, config| {
+            Box::new(MachineGenerator::new(id, config.machine))
+        });
+        registry.register("noise", |id, config| {
+            Box::new(NoiseGenerator::new(id, config.noise))
+        });
+        registry.register("ramp", |id, config| {
+            Box::new(RampGenerator::new(id, config.ramp))
+        });
+        registry.register("walk", |id, config| {
+            Box::new(RandomWalkGenerator::new(id, config.random_walk))
+        });
+        registry.register("replay", |id, config| {
+            Box::new(ReplayGenerator::new(id, config.replay.clone()))
+        });
+        registry.register("sensor", |id, config| {
+            Box::new(SensorGenerator::new(id, config.sensor))
+        });
+        registry.register("setpoint", |id, config| {
+            Box::new(SetpointGenerator::new(id, config.setpoint))
+        });
+        registry.register("status", |id, _config| Box::new(StatusGenerator::new(id)));
+        registry
+    }
+}
+
+/// Factory method for creating a new generator.
+pub fn create_generator(
+    generator_type: GeneratorType,
+    id: usize,
+    config: &GeneratorConfig,
+) -> Box<dyn Generator> {
+    let name = generator_type.name();
+    let mut generator = GeneratorRegistry::global()
+        .read()
+        .unwrap()
+        .create(name, id, config)
+        .unwrap_or_else(|| panic!("Generator type {} is not registered.", name));
+    if config.anomaly.rate > 0.0 {
+        generator = Box::new(AnomalousGenerator::new(generator, config.anomaly));
+    }
+    if config.offline.rate > 0.0 {
+        generator = Box::new(OfflineGenerator::new(generator, config.offline));
+    }
+    generator
+}
+
+/// Generate sporadic text events of a device. Unlike the other
+/// generators, events are not data points of a measurement but are
+/// published as separate messages.
+pub struct EventGenerator {
+    config: EventConfig,
+}
+
+impl EventGenerator {
+    pub fn new(config: &EventConfig) -> Self {
+        EventGenerator {
+            config: config.clone(),
+        }
+    }
+
+    /// Return the type and text of an event, if one occurs in this run.
+    pub fn generate(&mut self, rng: &mut ChaCha12Rng) -> Option<(&str, &str)> {
+        if self.config.types.is_empty() || !rng.gen_bool(self.config.rate.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let (event_type, text) = &self.config.types[rng.gen_range(0..self.config.types.len())];
+        Some((event_type, text))
+    }
+}
+
+/// Inventory properties of the devices that are published regularly besides the measurements.
+#[derive(Debug, Clone)]
+pub struct MetadataConfig {
+    /// Number of runs between publications of the properties, 0 to not publish them.
+    pub runs: u32,
+    /// The device models, picked with equal probability.
+    pub models: Vec<String>,
+    /// The firmware versions, picked with equal probability.
+    pub firmware: Vec<String>,
+    /// Maximum distance of a device from the starting point of the location generator in degrees.
+    pub spread: f64,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            runs: 0,
+            models: vec!["rumsim-100".to_string(), "rumsim-200".to_string()],
+            firmware: vec![
+                "1.0.0".to_string(),
+                "1.1.0".to_string(),
+                "2.0.0".to_string(),
+            ],
+            spread: 0.1,
+        }
+    }
+}
+
+/// Errors of the device clocks, so that the time stamps of the devices are out of sync.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockConfig {
+    /// Maximum constant deviation of a device clock in milliseconds, in both directions.
+    pub skew_millis: u64,
+    /// Maximum random deviation of each time stamp in milliseconds, in both directions.
+    pub jitter_millis: u64,
+}
+
+/// The inventory properties of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMetadata {
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl DeviceMetadata {
+    /// The properties are drawn from a separate stream of the device seed, so that they don't change the measurements.
+    pub fn new(config: &GeneratorConfig, seed: u64) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        rng.set_stream(1);
+        let mut pick = |values: &[String]| match values.len() {
+            0 => String::new(),
+            len => values[rng.gen_range(0..len)].clone(),
+        };
+        let model = pick(&config.metadata.models);
+        let firmware = pick(&config.metadata.firmware);
+        let spread = config.metadata.spread.abs();
+        let mut offset = || match spread {
+            0.0 => 0.0,
+            spread => rng.gen_range(-spread..spread),
+        };
+        let latitude = config.location.latitude + offset();
+        let longitude = config.location.longitude + offset();
+        DeviceMetadata {
+            serial: format!("RS-{:08X}", rng.gen::<u32>()),
+            model,
+            firmware,
+            latitude,
+            longitude,
+        }
+    }
+}
+
+/// Wrap a generator and occasionally take it offline, so that its data
+/// points are missing from the messages for a while. The wrapped
+/// generator keeps running while offline, like the process behind a
+/// failed sensor. On recovery, an optional status code is published as
+/// an additional data point named <data point>_recovered.
+struct OfflineGenerator<G> {
+    inner: G,
+    config: OfflineConfig,
+    offline_remaining: u32,
+    recovery_name: String,
+}
+
+impl<G: Generator> OfflineGenerator<G> {
+    fn new(inner: G, config: OfflineConfig) -> Self {
+        OfflineGenerator {
+            inner,
+            config,
+            offline_remaining: 0,
+            recovery_name: String::new(),
+        }
+    }
+}
+
+impl<G: Generator> Generator for OfflineGenerator<G> {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let mut values = self.inner.generate(rng);
+
+        if self.offline_remaining > 0 {
+            self.offline_remaining -= 1;
+            if self.offline_remaining > 0 {
+                values.clear();
+            } else if let (Some(code), Some((name, _))) =
+                (self.config.recovery_code, values.first())
+            {
+                self.recovery_name = format!("{}_recovered", name);
+                values.push((&self.recovery_name, Value::Int(code)));
+            }
+        } else if rng.gen_bool(self.config.rate.clamp(0.0, 1.0)) && self.config.duration > 0 {
+            // This run is the first one of the duration.
+            self.offline_remaining = self.config.duration;
+            values.clear();
+        }
+        values
+    }
+
+    fn set_time(&mut self, time: DateTime<Utc>) {
+        self.inner.set_time(time)
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.offline_remaining, self.inner.save()))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        let inner;
+        (self.offline_remaining, inner) = restore_state(state)?;
+        self.inner.restore(inner)
+    }
+}
+
+/// Wrap a generator and occasionally distort its values to test anomaly
+/// detection. An anomaly is either a spike, a dropout where no values are
+/// emitted, or a stuck sensor that repeats its last values for a while.
+struct AnomalousGenerator<G> {
+    inner: G,
+    config: AnomalyConfig,
+    stuck_remaining: u32,
+    stuck_values: Vec<Value>,
+}
+
+impl<G: Generator> AnomalousGenerator<G> {
+    fn new(inner: G, config: AnomalyConfig) -> Self {
+        AnomalousGenerator {
+            inner,
+            config,
+            stuck_remaining: 0,
+            stuck_values: Vec::new(),
+        }
+    }
+}
+
+impl<G: Generator> Generator for AnomalousGenerator<G> {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let mut values = self.inner.generate(rng);
+
+        if self.stuck_remaining > 0 {
+            self.stuck_remaining -= 1;
+            for ((_name, value), stuck) in values.iter_mut().zip(&self.stuck_values) {
+                *value = stuck.clone();
+            }
+        } else if rng.gen_bool(self.config.rate.clamp(0.0, 1.0)) {
+            match rng.gen_range(0..3) {
+                0 => values
+                    .iter_mut()
+                    .for_each(|(_name, value)| *value = value.shift(self.config.spike)),
+                1 => values.clear(),
+                _ => {
+                    self.stuck_values = values.iter().map(|(_name, value)| value.clone()).collect();
+                    self.stuck_remaining = self.config.stuck;
+                }
+            }
+        }
+        values
+    }
+
+    fn set_time(&mut self, time: DateTime<Utc>) {
+        self.inner.set_time(time)
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.stuck_remaining, &self.stuck_values, self.inner.save()))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        let inner;
+        (self.stuck_remaining, self.stuck_values, inner) = restore_state(state)?;
+        self.inner.restore(inner)
+    }
+}
+
+/// Generate a binary alarm flag that is 0 most of the time and
+/// occasionally switches to 1 for at least a minimum duration.
+struct AlarmGenerator {
+    name: String,
+    config: AlarmConfig,
+    remaining: u32,
+}
+
+impl AlarmGenerator {
+    fn new(id: usize, config: AlarmConfig) -> Self {
+        let name = format!("alarm_{}", id);
+        AlarmGenerator {
+            name,
+            config,
+            remaining: 0,
+        }
+    }
+}
+
+impl Generator for AlarmGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let active = if self.remaining > 0 {
+            self.remaining -= 1;
+            true
+        } else if rng.gen_bool(self.config.probability.clamp(0.0, 1.0)) {
+            self.remaining = self.config.hold.saturating_sub(1);
+            true
+        } else {
+            false
+        };
+        vec![(&self.name, active.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.remaining)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.remaining = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate a counter in the style of PLC registers that increments by a
+/// random amount, wraps around at the width of the register and
+/// occasionally resets to zero. Backends computing deltas between
+/// counter values need to cope with both.
+struct CounterGenerator {
+    name: String,
+    config: CounterConfig,
+    current_value: u64,
+}
+
+impl CounterGenerator {
+    fn new(id: usize, config: CounterConfig) -> Self {
+        let name = format!("counter_{}", id);
+        CounterGenerator {
+            name,
+            config,
+            current_value: 0,
+        }
+    }
+
+    /// The largest value of the counter before it wraps. Counters are limited to 63 bits.
+    fn max_value(&self) -> u64 {
+        u64::MAX >> (64 - self.config.bits.clamp(1, 63))
+    }
+}
+
+impl Generator for CounterGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let value = self.current_value;
+        if rng.gen_bool(self.config.reset_probability.clamp(0.0, 1.0)) {
+            self.current_value = 0;
+        } else {
+            let min = self.config.min_increment;
+            let increment = rng.gen_range(min..=self.config.max_increment.max(min));
+            self.current_value = (self.current_value + u64::from(increment)) & self.max_value();
+        }
+        vec![(&self.name, Value::Int(value as i64))]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.current_value)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.current_value = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate values that follow the wall-clock time of day, such as
+/// the load of a factory that is busy during working hours and idle
+/// at night. Since the value depends on the actual time rather than
+/// an index, restarting the simulation does not reset the phase.
+struct DiurnalGenerator {
+    name: String,
+    config: DiurnalConfig,
+    /// The time stamp of the current run.
+    time: DateTime<Utc>,
+}
+
+impl DiurnalGenerator {
+    fn new(id: usize, config: DiurnalConfig) -> Self {
+        let name = format!("diurnal_{}", id);
+        DiurnalGenerator {
+            name,
+            config,
+            time: Utc::now(),
+        }
+    }
+
+    /// The value of the pattern at the given time without jitter.
+    fn value_at(&self, time: DateTime<Utc>) -> f64 {
+        let DiurnalConfig {
+            base,
+            peak,
+            peak_hour,
+            seasonal,
+            ..
+        } = self.config;
+        let hour = f64::from(time.num_seconds_from_midnight()) / 3600.0;
+        let daily = (1.0 + (2.0 * PI * (hour - peak_hour) / 24.0).cos()) / 2.0;
+        // Day 172 is around the summer solstice on the northern hemisphere.
+        let day = f64::from(time.ordinal0());
+        let season = 1.0 + seasonal * (2.0 * PI * (day - 172.0) / 365.0).cos();
+        (base + (peak - base) * daily) * season
+    }
+}
+
+impl Generator for DiurnalGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let jitter = self.config.jitter;
+        let value = self.value_at(self.time) + jitter * (2.0 * rng.gen::<f64>() - 1.0);
+        let rounded_value = (value * 100.0).trunc() / 100.0;
+        vec![(&self.name, rounded_value.into())]
+    }
+
+    fn set_time(&mut self, time: DateTime<Utc>) {
+        self.time = time;
+    }
+}
+
+/// Generate values from a user-supplied expression of the data point
+/// index t, such as 50 + 10*sin(t/60) + noise(2).
+struct ExpressionGenerator {
+    name: String,
+    expression: Expression,
+    index: u64,
+}
+
+impl ExpressionGenerator {
+    fn new(id: usize, expression: Expression) -> Self {
+        let name = format!("expression_{}", id);
+        ExpressionGenerator {
+            name,
+            expression,
+            index: 0,
+        }
+    }
+}
+
+impl Generator for ExpressionGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let value = self.expression.evaluate(self.index as f64, rng);
+        self.index += 1;
+        let rounded_value = (value * 100.0).trunc() / 100.0;
+        vec![(&self.name, rounded_value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.index)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.index = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate a group of correlated sensor values such as inlet and
+/// outlet temperature. A latent random walk drives all members of the
+/// group, each with its individual offset, gain and noise.
+struct GroupGenerator {
+    names: Vec<String>,
+    config: GroupConfig,
+    latent: f64,
+}
+
+impl GroupGenerator {
+    fn new(id: usize, config: GroupConfig) -> Self {
+        let names = (0..config.members.len())
+            .map(|member| format!("group_{}_{}", id, member))
+            .collect();
+        GroupGenerator {
+            names,
+            config,
+            latent: 0.0,
+        }
+    }
+}
+
+impl Generator for GroupGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let step = self.config.step;
+        self.latent = (self.latent + rng.gen_range(-step..=step)).clamp(-1.0, 1.0);
+
+        let latent = self.latent;
+        self.names
+            .iter()
+            .zip(&self.config.members)
+            .map(|(name, member)| {
+                let noise = member.noise * (2.0 * rng.gen::<f64>() - 1.0);
+                let value = member.offset + member.gain * latent + noise;
+                let rounded_value = (value * 100.0).trunc() / 100.0;
+                (name.as_str(), rounded_value.into())
+            })
+            .collect()
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.latent)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.latent = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate discrete values such as machine states drawn from a
+/// weighted distribution, so that some states are much more common
+/// than others.
+struct HistogramGenerator {
+    name: String,
+    states: Vec<i64>,
+    distribution: Option<WeightedIndex<u32>>,
+}
+
+impl HistogramGenerator {
+    fn new(id: usize, config: &HistogramConfig) -> Self {
+        let name = format!("histogram_{}", id);
+        let states = config.states.iter().map(|(state, _)| *state).collect();
+        // Without any positive weight, the generator sticks to the first state (or 0).
+        let distribution = WeightedIndex::new(config.states.iter().map(|(_, weight)| *weight)).ok();
+        HistogramGenerator {
+            name,
+            states,
+            distribution,
+        }
+    }
 }
 
-/// Generate the next numerical value for a data point.
-pub trait Generator: Send {
-    fn generate(&mut self, rng: &mut StdRng) -> (&str, f64);
+impl Generator for HistogramGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let index = match &self.distribution {
+            Some(distribution) => distribution.sample(rng),
+            None => 0,
+        };
+        let state = self.states.get(index).copied().unwrap_or(0);
+        vec![(&self.name, Value::Int(state))]
+    }
 }
 
-/// Factory method for creating a new generator.
-pub fn create_generator(generator_type: GeneratorType, id: usize) -> Box<dyn Generator> {
-    match generator_type {
-        GeneratorType::Noise => Box::new(NoiseGenerator::new(id)),
-        GeneratorType::Sensor => Box::new(SensorGenerator::new(id)),
-        GeneratorType::Status => Box::new(StatusGenerator::new(id)),
+/// Generate a position that moves along a random path, such as a
+/// vehicle or a mobile asset. Latitude and longitude are emitted as
+/// a pair of data points so that they stay correlated in a message.
+struct LocationGenerator {
+    latitude_name: String,
+    longitude_name: String,
+    config: LocationConfig,
+    latitude: f64,
+    longitude: f64,
+    heading: f64,
+}
+
+impl LocationGenerator {
+    fn new(id: usize, config: LocationConfig) -> Self {
+        LocationGenerator {
+            latitude_name: format!("latitude_{}", id),
+            longitude_name: format!("longitude_{}", id),
+            config,
+            latitude: config.latitude,
+            longitude: config.longitude,
+            heading: 0.0,
+        }
+    }
+}
+
+/// Approximate length of one degree of latitude in meters.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Maximum change of the heading per data point in radians.
+const MAX_TURN: f64 = PI / 8.0;
+
+impl Generator for LocationGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let latitude = (self.latitude * 1e6).round() / 1e6;
+        let longitude = (self.longitude * 1e6).round() / 1e6;
+
+        self.heading = (self.heading + rng.gen_range(-MAX_TURN..=MAX_TURN)).rem_euclid(2.0 * PI);
+        let distance = self.config.speed / METERS_PER_DEGREE;
+        self.latitude = (self.latitude + distance * self.heading.cos()).clamp(-90.0, 90.0);
+        let scale = self.latitude.to_radians().cos().max(f64::EPSILON);
+        self.longitude = (self.longitude + distance * self.heading.sin() / scale + 180.0)
+            .rem_euclid(360.0)
+            - 180.0;
+
+        vec![
+            (&self.latitude_name, latitude.into()),
+            (&self.longitude_name, longitude.into()),
+        ]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.latitude, self.longitude, self.heading))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        (self.latitude, self.longitude, self.heading) = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// The states of the machine generator, published as 0, 1 and 2.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum MachineState {
+    Idle = 0,
+    Running = 1,
+    Fault = 2,
+}
+
+/// Generate the state of a machine that cycles between idle, running and
+/// fault together with its temperature, which rises while the machine is
+/// running and falls while it is not. The state only changes after a
+/// minimum dwell time, and a fault lasts until the machine is repaired.
+struct MachineGenerator {
+    state_name: String,
+    temperature_name: String,
+    config: MachineConfig,
+    state: MachineState,
+    dwell: u32,
+    temperature: f64,
+}
+
+impl MachineGenerator {
+    fn new(id: usize, config: MachineConfig) -> Self {
+        MachineGenerator {
+            state_name: format!("machine_{}_state", id),
+            temperature_name: format!("machine_{}_temperature", id),
+            config,
+            state: MachineState::Idle,
+            dwell: 0,
+            temperature: config.idle_temperature,
+        }
+    }
+
+    fn next_state(&self, rng: &mut ChaCha12Rng) -> MachineState {
+        let config = &self.config;
+        let chance = |probability: f64| probability.clamp(0.0, 1.0);
+        match self.state {
+            MachineState::Fault if self.dwell >= config.repair_time => MachineState::Idle,
+            MachineState::Fault => MachineState::Fault,
+            _ if self.dwell < config.min_dwell => self.state,
+            MachineState::Idle if rng.gen_bool(chance(config.start_probability)) => {
+                MachineState::Running
+            }
+            MachineState::Running if rng.gen_bool(chance(config.fault_probability)) => {
+                MachineState::Fault
+            }
+            MachineState::Running if rng.gen_bool(chance(config.stop_probability)) => {
+                MachineState::Idle
+            }
+            state => state,
+        }
     }
 }
 
-/// Generate random numerical data in a 16 bit range.
+impl Generator for MachineGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let state = self.next_state(rng);
+        if state == self.state {
+            self.dwell += 1;
+        } else {
+            self.state = state;
+            self.dwell = 1;
+        }
+
+        let target = match self.state {
+            MachineState::Running => self.config.running_temperature,
+            MachineState::Idle | MachineState::Fault => self.config.idle_temperature,
+        };
+        self.temperature += (target - self.temperature) * self.config.heating_rate;
+        let rounded_temperature = (self.temperature * 100.0).trunc() / 100.0;
+        vec![
+            (&self.state_name, Value::Int(self.state as i64)),
+            (&self.temperature_name, rounded_temperature.into()),
+        ]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.state, self.dwell, self.temperature))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        (self.state, self.dwell, self.temperature) = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate random numerical data, by default in a 16 bit range.
 /// This generator represents PLC process registers that contain
-/// rapidly changing values reflecting a production process.
+/// rapidly changing values reflecting a production process, or
+/// sensor errors following a configurable distribution.
 struct NoiseGenerator {
     name: String,
+    sampler: NoiseSampler,
+}
+
+enum NoiseSampler {
+    Uniform,
+    Normal(Normal<f64>),
+    Exponential(Exp<f64>),
+    Poisson(Poisson<f64>),
 }
 
 impl NoiseGenerator {
-    fn new(id: usize) -> Self {
+    fn new(id: usize, config: NoiseConfig) -> Self {
         let name = format!("noise_{}", id);
-        NoiseGenerator { name }
+        // It's OK to panic, since the settings only accept parameters that describe a distribution.
+        let sampler = match config.distribution {
+            NoiseDistribution::Uniform => NoiseSampler::Uniform,
+            NoiseDistribution::Normal { mean, std_dev } => {
+                NoiseSampler::Normal(Normal::new(mean, std_dev).unwrap())
+            }
+            NoiseDistribution::Exponential { lambda } => {
+                NoiseSampler::Exponential(Exp::new(lambda).unwrap())
+            }
+            NoiseDistribution::Poisson { lambda } => {
+                NoiseSampler::Poisson(Poisson::new(lambda).unwrap())
+            }
+        };
+        NoiseGenerator { name, sampler }
     }
 }
 
 impl Generator for NoiseGenerator {
-    fn generate(&mut self, rng: &mut StdRng) -> (&str, f64) {
-        let value: u16 = rng.gen();
-        (&self.name, value.into())
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let value = match &self.sampler {
+            NoiseSampler::Uniform => rng.gen::<u16>().into(),
+            NoiseSampler::Normal(distribution) => {
+                ((distribution.sample(rng) * 100.0).trunc() / 100.0).into()
+            }
+            NoiseSampler::Exponential(distribution) => {
+                ((distribution.sample(rng) * 100.0).trunc() / 100.0).into()
+            }
+            NoiseSampler::Poisson(distribution) => Value::Int(distribution.sample(rng) as i64),
+        };
+        vec![(&self.name, value)]
+    }
+}
+
+/// Generate a sawtooth that rises linearly up to a maximum and then
+/// resets to zero. This generator represents counters and totalizers
+/// such as production piece counts.
+struct RampGenerator {
+    name: String,
+    config: RampConfig,
+    current_value: f64,
+}
+
+impl RampGenerator {
+    fn new(id: usize, config: RampConfig) -> Self {
+        let name = format!("ramp_{}", id);
+        RampGenerator {
+            name,
+            config,
+            current_value: 0.0,
+        }
+    }
+}
+
+impl Generator for RampGenerator {
+    fn generate(&mut self, _rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let value = self.current_value;
+        self.current_value += self.config.step;
+        if self.current_value > self.config.max {
+            self.current_value = 0.0;
+        }
+        vec![(&self.name, value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.current_value)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.current_value = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate a slowly drifting value such as a tank level or a
+/// pressure. The value moves by a random step per data point and
+/// is clamped to the configured bounds.
+struct RandomWalkGenerator {
+    name: String,
+    config: RandomWalkConfig,
+    current_value: f64,
+}
+
+impl RandomWalkGenerator {
+    fn new(id: usize, config: RandomWalkConfig) -> Self {
+        let name = format!("walk_{}", id);
+        RandomWalkGenerator {
+            name,
+            config,
+            current_value: (config.min + config.max) / 2.0,
+        }
+    }
+}
+
+impl Generator for RandomWalkGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let RandomWalkConfig { min, max, step } = self.config;
+        let value = self.current_value;
+        self.current_value = (value + rng.gen_range(-step..=step)).clamp(min, max);
+        let rounded_value = (value * 100.0).trunc() / 100.0;
+        vec![(&self.name, rounded_value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.current_value)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.current_value = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Replay recorded values from a column of a CSV file, cycling back
+/// to the start at the end of the recording. Column and starting row
+/// are drawn from the random number generator of the device, so
+/// devices replay different sources but remain reproducible.
+struct ReplayGenerator {
+    name: String,
+    data: Option<Arc<ReplayData>>,
+    position: Option<(usize, usize)>,
+}
+
+impl ReplayGenerator {
+    fn new(id: usize, data: Option<Arc<ReplayData>>) -> Self {
+        let name = format!("replay_{}", id);
+        ReplayGenerator {
+            name,
+            data,
+            position: None,
+        }
+    }
+}
+
+impl Generator for ReplayGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let Some(data) = &self.data else {
+            return vec![];
+        };
+        let (column, row) = *self.position.get_or_insert_with(|| {
+            let column = rng.gen_range(0..data.columns.len());
+            (column, rng.gen_range(0..data.columns[column].1.len()))
+        });
+        let values = &data.columns[column].1;
+        self.position = Some((column, (row + 1) % values.len()));
+        vec![(&self.name, values[row].into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.position)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.position = restore_state(state)?;
+        Ok(())
     }
 }
 
@@ -50,39 +1714,97 @@ impl Generator for NoiseGenerator {
 struct SensorGenerator {
     name: String,
     index: u32,
+    config: SensorConfig,
 }
 
 impl SensorGenerator {
-    fn new(id: usize) -> Self {
+    fn new(id: usize, config: SensorConfig) -> Self {
         let name = format!("sensor_{}", id);
-        SensorGenerator { name, index: 0 }
+        SensorGenerator {
+            name,
+            index: 0,
+            config,
+        }
     }
 }
 
-/// Offset of the sine curve.
-const AVG_TEMPERATURE: f64 = 100.0;
-
-/// Generated temperature is in the range AVG_TEMPERATURE +/- DELTA_TEMPERATURE.
-const DELTA_TEMPERATURE: f64 = 20.0;
-
-/// The jitter added
-const JITTER: f64 = 2.0;
-
-/// The sine repeats every SPREAD data points.
-const SPREAD: u32 = 100;
-
 impl Generator for SensorGenerator {
-    fn generate(&mut self, rng: &mut StdRng) -> (&str, f64) {
-        let x: f64 = 2.0 * PI * f64::from(self.index) / f64::from(SPREAD);
-        let plain_value = x.sin() * DELTA_TEMPERATURE + AVG_TEMPERATURE;
-        let jitter_value: f64 = JITTER * 2.0 * rng.gen::<f64>() - JITTER + plain_value;
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        let SensorConfig {
+            offset,
+            amplitude,
+            jitter,
+            period,
+        } = self.config;
+        let x: f64 = 2.0 * PI * f64::from(self.index) / f64::from(period);
+        let plain_value = x.sin() * amplitude + offset;
+        let jitter_value: f64 = jitter * 2.0 * rng.gen::<f64>() - jitter + plain_value;
         let rounded_value = (jitter_value * 100.0).trunc() / 100.0;
-        if self.index == SPREAD {
+        if self.index == period {
             self.index = 0;
         } else {
             self.index += 1;
         }
-        (&self.name, rounded_value)
+        vec![(&self.name, rounded_value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state(self.index)
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        self.index = restore_state(state)?;
+        Ok(())
+    }
+}
+
+/// Generate data in the style of operator setpoints. The value stays
+/// constant for a random number of data points and then jumps by a
+/// bounded delta within the configured range.
+struct SetpointGenerator {
+    name: String,
+    config: SetpointConfig,
+    remaining: u32,
+    current_value: f64,
+}
+
+impl SetpointGenerator {
+    fn new(id: usize, config: SetpointConfig) -> Self {
+        let name = format!("setpoint_{}", id);
+        SetpointGenerator {
+            name,
+            config,
+            remaining: config.hold,
+            current_value: (config.min + config.max) / 2.0,
+        }
+    }
+}
+
+impl Generator for SetpointGenerator {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
+        if self.remaining == 0 {
+            let SetpointConfig {
+                min,
+                max,
+                step,
+                hold,
+            } = self.config;
+            let delta = rng.gen_range(-step..=step);
+            self.current_value = (self.current_value + delta).clamp(min, max);
+            self.remaining = rng.gen_range(1..=hold.max(1));
+        } else {
+            self.remaining -= 1;
+        }
+        vec![(&self.name, self.current_value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.remaining, self.current_value))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        (self.remaining, self.current_value) = restore_state(state)?;
+        Ok(())
     }
 }
 
@@ -110,14 +1832,23 @@ impl StatusGenerator {
 const SUSTAIN: u16 = 100;
 
 impl Generator for StatusGenerator {
-    fn generate(&mut self, rng: &mut StdRng) -> (&str, f64) {
+    fn generate(&mut self, rng: &mut ChaCha12Rng) -> Vec<(&str, Value)> {
         if self.index == SUSTAIN {
             self.index = 0;
             self.current_value = rng.gen()
         } else {
             self.index += 1;
         }
-        (&self.name, self.current_value.into())
+        vec![(&self.name, self.current_value.into())]
+    }
+
+    fn save(&self) -> GeneratorState {
+        save_state((self.index, self.current_value))
+    }
+
+    fn restore(&mut self, state: GeneratorState) -> Result<(), String> {
+        (self.index, self.current_value) = restore_state(state)?;
+        Ok(())
     }
 }
 
@@ -127,52 +1858,689 @@ mod tests {
 
     use super::*;
 
+    /// The numerical representation of a value, if it has one.
+    fn as_f64(value: &Value) -> Option<f64> {
+        match value {
+            Value::Float(value) => Some(*value),
+            Value::Int(value) => Some(*value as f64),
+            Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Value::Text(_) => None,
+        }
+    }
+
+    /// Generate the next value of a single-valued generator as a number.
+    fn next_value(gen: &mut impl Generator, rng: &mut ChaCha12Rng) -> f64 {
+        as_f64(&gen.generate(rng)[0].1).unwrap()
+    }
+
+    #[test]
+    fn test_value() {
+        assert_eq!(Value::from(1.5).to_string(), "1.5");
+        assert_eq!(Value::from(42u16).to_string(), "42");
+        assert_eq!(Value::from(true).to_string(), "1");
+        assert_eq!(Value::from(12.3).scale(0.1, 1.0), Value::Float(2.23));
+        assert_eq!(Value::from(42u16).scale(1.0, 0.0), Value::Int(42));
+        assert_eq!(Value::from(42u16).scale(0.5, 0.0), Value::Float(21.0));
+        assert_eq!(Value::from(true).scale(2.0, 0.0), Value::Bool(true));
+        assert_eq!(Value::from("open").to_string(), "open");
+
+        assert_eq!(Value::Int(1).shift(10.0), Value::Int(11));
+        assert_eq!(Value::Bool(false).shift(10.0), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_anomalous_generator_disabled() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = RampConfig::default();
+        let mut gen =
+            AnomalousGenerator::new(RampGenerator::new(1, config), AnomalyConfig::default());
+        for i in 0..100 {
+            assert_eq!(
+                gen.generate(&mut rng),
+                vec![("ramp_1", Value::Float(i as f64))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_anomalous_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = AnomalyConfig {
+            rate: 1.0,
+            spike: 1000.0,
+            stuck: 3,
+        };
+        let ramp = RampGenerator::new(1, RampConfig::default());
+        let mut gen = AnomalousGenerator::new(ramp, config);
+
+        let (mut spikes, mut dropouts, mut stuck) = (0, 0, 0);
+        for i in 0..100 {
+            let values = gen.generate(&mut rng);
+            match values.first().and_then(|(_name, value)| as_f64(value)) {
+                None => dropouts += 1,
+                Some(value) if value >= config.spike => spikes += 1,
+                Some(value) if value < i as f64 => stuck += 1,
+                Some(value) => assert_eq!(value, i as f64),
+            }
+        }
+        assert!(spikes > 0);
+        assert!(dropouts > 0);
+        assert!(stuck > 0);
+    }
+
+    #[test]
+    fn test_offline_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = OfflineConfig {
+            rate: 1.0,
+            duration: 3,
+            recovery_code: Some(42),
+        };
+        let ramp = RampGenerator::new(1, RampConfig::default());
+        let mut gen = OfflineGenerator::new(ramp, config);
+
+        for _i in 0..3 {
+            assert!(gen.generate(&mut rng).is_empty());
+        }
+        assert_eq!(
+            gen.generate(&mut rng),
+            vec![
+                ("ramp_1", Value::Float(3.0)),
+                ("ramp_1_recovered", Value::Int(42))
+            ]
+        );
+        assert!(gen.generate(&mut rng).is_empty());
+
+        let ramp = RampGenerator::new(1, RampConfig::default());
+        let mut gen = OfflineGenerator::new(
+            ramp,
+            OfflineConfig {
+                duration: 0,
+                ..config
+            },
+        );
+        for i in 0..10 {
+            assert_eq!(
+                gen.generate(&mut rng),
+                vec![("ramp_1", Value::Float(i as f64))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_offline_generator_disabled() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let ramp = RampGenerator::new(1, RampConfig::default());
+        let mut gen = OfflineGenerator::new(ramp, OfflineConfig::default());
+        for i in 0..100 {
+            assert_eq!(
+                gen.generate(&mut rng),
+                vec![("ramp_1", Value::Float(i as f64))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = EventConfig {
+            rate: 0.5,
+            ..EventConfig::default()
+        };
+        let mut gen = EventGenerator::new(&config);
+        let events: Vec<_> = (0..1000)
+            .filter_map(|_| {
+                gen.generate(&mut rng)
+                    .map(|(event_type, _)| event_type.to_string())
+            })
+            .collect();
+        assert!((400..600).contains(&events.len()));
+        assert!(events
+            .iter()
+            .all(|event_type| event_type.starts_with("rumsim_")));
+
+        let mut gen = EventGenerator::new(&EventConfig {
+            rate: 1.0,
+            types: vec![],
+            ..config
+        });
+        assert!(gen.generate(&mut rng).is_none());
+    }
+
+    #[test]
+    fn test_device_metadata() {
+        let config = GeneratorConfig::default();
+        let metadata = DeviceMetadata::new(&config, 1);
+        assert_eq!(metadata, DeviceMetadata::new(&config, 1));
+        assert_ne!(metadata.serial, DeviceMetadata::new(&config, 2).serial);
+        assert!(metadata.serial.starts_with("RS-"));
+        assert!(config.metadata.models.contains(&metadata.model));
+        assert!(config.metadata.firmware.contains(&metadata.firmware));
+        assert!((metadata.latitude - config.location.latitude).abs() < 0.1);
+        assert!((metadata.longitude - config.location.longitude).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_alarm_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = AlarmConfig {
+            probability: 0.0,
+            hold: 3,
+        };
+        let mut gen = AlarmGenerator::new(1, config);
+        for _i in 0..100 {
+            assert_eq!(gen.generate(&mut rng)[0], ("alarm_1", Value::Bool(false)));
+        }
+
+        let config = AlarmConfig {
+            probability: 1.0,
+            hold: 3,
+        };
+        let mut gen = AlarmGenerator::new(1, config);
+        for _i in 0..100 {
+            assert_eq!(gen.generate(&mut rng)[0], ("alarm_1", Value::Bool(true)));
+        }
+    }
+
+    #[test]
+    fn test_alarm_generator_hold() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = AlarmConfig {
+            probability: 0.1,
+            hold: 5,
+        };
+        let mut gen = AlarmGenerator::new(1, config);
+        let values: Vec<f64> = (0..1000).map(|_| next_value(&mut gen, &mut rng)).collect();
+        let alarm_runs: Vec<&[f64]> = values
+            .split(|value| *value == 0.0)
+            .filter(|run| !run.is_empty())
+            .collect();
+        assert!(!alarm_runs.is_empty());
+        // The last run may be cut off by the end of the sample.
+        for run in &alarm_runs[..alarm_runs.len() - 1] {
+            assert!(run.len() >= config.hold as usize);
+        }
+    }
+
+    #[test]
+    fn test_counter_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = CounterConfig {
+            min_increment: 1,
+            max_increment: 3,
+            bits: 4,
+            reset_probability: 0.0,
+        };
+        let mut gen = CounterGenerator::new(1, config);
+        let mut previous = next_value(&mut gen, &mut rng);
+        let mut wrapped = false;
+        for _i in 0..100 {
+            let value = next_value(&mut gen, &mut rng);
+            assert!((0.0..16.0).contains(&value));
+            if value < previous {
+                wrapped = true;
+                assert!(value + 16.0 - previous <= 3.0);
+            } else {
+                assert!((1.0..=3.0).contains(&(value - previous)));
+            }
+            previous = value;
+        }
+        assert!(wrapped);
+
+        let config = CounterConfig {
+            reset_probability: 1.0,
+            ..config
+        };
+        let mut gen = CounterGenerator::new(1, config);
+        for _i in 0..10 {
+            assert_eq!(next_value(&mut gen, &mut rng), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_diurnal_generator() {
+        let config = DiurnalConfig {
+            seasonal: 0.0,
+            ..DiurnalConfig::default()
+        };
+        let gen = DiurnalGenerator::new(1, config);
+        let at = |time: &str| {
+            let time = DateTime::parse_from_rfc3339(time).unwrap();
+            gen.value_at(time.with_timezone(&Utc))
+        };
+        assert!((at("2024-03-01T13:00:00Z") - config.peak).abs() < 1e-9);
+        assert!((at("2024-03-01T01:00:00Z") - config.base).abs() < 1e-9);
+        assert!(at("2024-03-01T09:00:00Z") > at("2024-03-01T05:00:00Z"));
+
+        // The generator follows the time stamp of the run, not the system time.
+        let mut gen = DiurnalGenerator::new(
+            1,
+            DiurnalConfig {
+                jitter: 0.0,
+                ..config
+            },
+        );
+        let night = DateTime::parse_from_rfc3339("2024-03-01T01:00:00Z").unwrap();
+        gen.set_time(night.with_timezone(&Utc));
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        assert_eq!(next_value(&mut gen, &mut rng), config.base);
+
+        let config = DiurnalConfig::default();
+        let gen = DiurnalGenerator::new(1, config);
+        let summer = DateTime::parse_from_rfc3339("2024-06-21T13:00:00Z").unwrap();
+        let winter = DateTime::parse_from_rfc3339("2024-12-21T13:00:00Z").unwrap();
+        assert!(
+            gen.value_at(summer.with_timezone(&Utc)) > gen.value_at(winter.with_timezone(&Utc))
+        );
+    }
+
+    #[test]
+    fn test_expression_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let mut gen = ExpressionGenerator::new(1, "2 * t + 1".parse().unwrap());
+        let values: Vec<f64> = (0..4).map(|_| next_value(&mut gen, &mut rng)).collect();
+        assert_eq!(values, vec![1.0, 3.0, 5.0, 7.0]);
+    }
+
+    #[test]
+    fn test_group_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GroupConfig {
+            step: 0.1,
+            members: vec![
+                GroupMember {
+                    offset: 0.0,
+                    gain: 10.0,
+                    noise: 0.0,
+                },
+                GroupMember {
+                    offset: 5.0,
+                    gain: -10.0,
+                    noise: 0.0,
+                },
+            ],
+        };
+        let mut gen = GroupGenerator::new(1, config);
+
+        for _i in 0..100 {
+            let values = gen.generate(&mut rng);
+            assert_eq!(values.len(), 2);
+            assert_eq!(values[0].0, "group_1_0");
+            assert_eq!(values[1].0, "group_1_1");
+            let first = as_f64(&values[0].1).unwrap();
+            let second = as_f64(&values[1].1).unwrap();
+            // Both members are driven by the same latent signal with opposite gains.
+            assert!((first + second - 5.0).abs() < 0.02);
+            assert!((-10.0..=10.0).contains(&first));
+        }
+    }
+
+    #[test]
+    fn test_histogram_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = HistogramConfig {
+            states: vec![(0, 70), (1, 20), (2, 5), (4, 5), (3, 0)],
+        };
+        let mut gen = HistogramGenerator::new(1, &config);
+        let mut counts = HashMap::new();
+        for _i in 0..1000 {
+            *counts
+                .entry(next_value(&mut gen, &mut rng) as i64)
+                .or_insert(0) += 1;
+        }
+        assert!(!counts.contains_key(&3));
+        assert!((600..800).contains(&counts[&0]));
+        assert!(counts[&0] > counts[&1]);
+        assert!(counts[&1] > counts[&2]);
+        assert!(counts[&1] > counts[&4]);
+
+        let mut gen = HistogramGenerator::new(1, &HistogramConfig { states: vec![] });
+        assert_eq!(next_value(&mut gen, &mut rng), 0.0);
+    }
+
+    #[test]
+    fn test_location_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = LocationConfig::default();
+        let mut gen = LocationGenerator::new(1, config);
+
+        let values = gen.generate(&mut rng);
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0], ("latitude_1", config.latitude.into()));
+        assert_eq!(values[1], ("longitude_1", config.longitude.into()));
+
+        // After 100 steps of 10 m, the position is at most 1 km away from the start.
+        let mut values = gen.generate(&mut rng);
+        for _i in 0..100 {
+            values = gen.generate(&mut rng);
+        }
+        let max_delta = 1000.0 / METERS_PER_DEGREE;
+        let latitude = as_f64(&values[0].1).unwrap();
+        assert!((latitude - config.latitude).abs() <= max_delta);
+        assert_ne!(values[1].1, config.longitude.into());
+    }
+
+    #[test]
+    fn test_machine_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = MachineConfig {
+            start_probability: 0.5,
+            stop_probability: 0.1,
+            fault_probability: 0.05,
+            min_dwell: 3,
+            repair_time: 5,
+            ..MachineConfig::default()
+        };
+        let mut gen = MachineGenerator::new(1, config);
+
+        let mut states = Vec::new();
+        let mut previous_temperature = config.idle_temperature;
+        for _i in 0..1000 {
+            let values = gen.generate(&mut rng);
+            assert_eq!(values[0].0, "machine_1_state");
+            assert_eq!(values[1].0, "machine_1_temperature");
+            let state = as_f64(&values[0].1).unwrap() as i64;
+            let temperature = as_f64(&values[1].1).unwrap();
+            // The temperature rises while running and falls otherwise.
+            if state == 1 {
+                assert!(temperature >= previous_temperature);
+            } else {
+                assert!(temperature <= previous_temperature);
+            }
+            assert!((config.idle_temperature..=config.running_temperature).contains(&temperature));
+            previous_temperature = temperature;
+            states.push(state);
+        }
+
+        // Every state lasts at least the minimum dwell time, faults last until they are repaired.
+        let mut runs: Vec<(i64, usize)> = Vec::new();
+        for state in states {
+            match runs.last_mut() {
+                Some((last, count)) if *last == state => *count += 1,
+                _ => runs.push((state, 1)),
+            }
+        }
+        for (state, count) in &runs[..runs.len() - 1] {
+            let min = if *state == 2 { 5 } else { 3 };
+            assert!(*count >= min);
+        }
+        for state in 0..3 {
+            assert!(runs.iter().any(|(run_state, _)| *run_state == state));
+        }
+    }
+
     #[test]
     fn test_noise_generator() {
-        let mut gen = NoiseGenerator::new(1);
-        let (_name, value) = gen.generate(&mut StdRng::seed_from_u64(1));
-        assert!((0.0..u16::MAX as f64).contains(&value));
+        let mut gen = NoiseGenerator::new(1, NoiseConfig::default());
+        let (_name, value) = gen.generate(&mut ChaCha12Rng::seed_from_u64(1)).remove(0);
+        assert!(matches!(value, Value::Int(0..=65535)));
+    }
+
+    #[test]
+    fn test_noise_generator_distributions() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let mean_of = |distribution, rng: &mut ChaCha12Rng| {
+            let mut gen = NoiseGenerator::new(1, NoiseConfig { distribution });
+            (0..1000).map(|_| next_value(&mut gen, rng)).sum::<f64>() / 1000.0
+        };
+
+        let normal = NoiseDistribution::Normal {
+            mean: 50.0,
+            std_dev: 2.0,
+        };
+        assert!((mean_of(normal, &mut rng) - 50.0).abs() < 0.5);
+
+        let exponential = NoiseDistribution::Exponential { lambda: 0.5 };
+        assert!((mean_of(exponential, &mut rng) - 2.0).abs() < 0.3);
+
+        let poisson = NoiseDistribution::Poisson { lambda: 4.0 };
+        assert!((mean_of(poisson, &mut rng) - 4.0).abs() < 0.3);
+        let mut gen = NoiseGenerator::new(
+            1,
+            NoiseConfig {
+                distribution: poisson,
+            },
+        );
+        assert!(matches!(gen.generate(&mut rng)[0].1, Value::Int(_)));
+    }
+
+    #[test]
+    fn test_ramp_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = RampConfig {
+            max: 3.0,
+            step: 1.0,
+        };
+        let mut gen = RampGenerator::new(1, config);
+        let values: Vec<f64> = (0..6).map(|_| next_value(&mut gen, &mut rng)).collect();
+        assert_eq!(values, vec![0.0, 1.0, 2.0, 3.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_random_walk_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = RandomWalkConfig {
+            min: 0.0,
+            max: 10.0,
+            step: 1.0,
+        };
+        let mut gen = RandomWalkGenerator::new(1, config);
+        let mut previous = next_value(&mut gen, &mut rng);
+        assert_eq!(previous, 5.0);
+
+        for _i in 0..1000 {
+            let value = next_value(&mut gen, &mut rng);
+            assert!((config.min..=config.max).contains(&value));
+            // Allow for the truncation to two decimals.
+            assert!((value - previous).abs() <= config.step + 0.01);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn test_replay_data() {
+        let data = ReplayData::from_csv("a, b\n1,2\n3, 4.5\n\n").unwrap();
+        assert_eq!(data.columns[0], ("a".to_string(), vec![1.0, 3.0]));
+        assert_eq!(data.columns[1], ("b".to_string(), vec![2.0, 4.5]));
+
+        assert!(ReplayData::from_csv("").is_err());
+        assert!(ReplayData::from_csv("a,b").is_err());
+        assert!(ReplayData::from_csv("a,b\n1").is_err());
+        assert!(ReplayData::from_csv("a,b\n1,x").is_err());
+    }
+
+    #[test]
+    fn test_replay_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let data = ReplayData::from_csv("a\n1\n2\n3").unwrap();
+        let mut gen = ReplayGenerator::new(1, Some(Arc::new(data)));
+        let first = next_value(&mut gen, &mut rng);
+        let second = next_value(&mut gen, &mut rng);
+        let third = next_value(&mut gen, &mut rng);
+        assert_eq!(first % 3.0 + 1.0, second);
+        assert_eq!(second % 3.0 + 1.0, third);
+        assert_eq!(next_value(&mut gen, &mut rng), first);
+
+        let mut gen = ReplayGenerator::new(1, None);
+        assert!(gen.generate(&mut rng).is_empty());
     }
 
     #[test]
     fn test_sensor_generator() {
-        let mut rng = StdRng::seed_from_u64(1);
-        let mut gen = SensorGenerator::new(1);
-        let (mut _name, mut value) = gen.generate(&mut rng);
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = SensorConfig::default();
+        let mut gen = SensorGenerator::new(1, config);
+        let mut value = next_value(&mut gen, &mut rng);
+
+        let range = config.offset - config.jitter..config.offset + config.jitter;
+        assert!(range.contains(&value));
 
-        assert!((AVG_TEMPERATURE - JITTER..AVG_TEMPERATURE + JITTER).contains(&value));
+        for _i in 0..config.period - 1 {
+            value = next_value(&mut gen, &mut rng);
+        }
+
+        assert!(range.contains(&value));
+    }
+
+    #[test]
+    fn test_setpoint_generator() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = SetpointConfig::default();
+        let mut gen = SetpointGenerator::new(1, config);
+        let start_value = next_value(&mut gen, &mut rng);
+        assert_eq!(start_value, 50.0);
 
-        for _i in 0..SPREAD - 1 {
-            (_name, value) = gen.generate(&mut rng);
+        for _i in 0..config.hold - 1 {
+            let value = next_value(&mut gen, &mut rng);
+            assert_eq!(start_value, value);
         }
 
-        assert!((AVG_TEMPERATURE - JITTER..AVG_TEMPERATURE + JITTER).contains(&value));
+        let mut previous = start_value;
+        for _i in 0..10 * config.hold {
+            let value = next_value(&mut gen, &mut rng);
+            assert!((config.min..=config.max).contains(&value));
+            assert!((value - previous).abs() <= config.step);
+            previous = value;
+        }
     }
 
     #[test]
     fn test_status_generator() {
-        let mut rng = StdRng::seed_from_u64(1);
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
         let mut gen = StatusGenerator::new(1);
-        let (_name, start_value) = gen.generate(&mut rng);
+        let start_value = next_value(&mut gen, &mut rng);
 
         for _i in 0..SUSTAIN - 1 {
-            let (_name, value) = gen.generate(&mut rng);
+            let value = next_value(&mut gen, &mut rng);
             assert_eq!(start_value, value);
         }
 
-        let (_name, next_value) = gen.generate(&mut rng);
+        let next_value = next_value(&mut gen, &mut rng);
         assert_ne!(start_value, next_value);
     }
 
+    #[test]
+    fn test_golden_config() {
+        let config = GeneratorConfig {
+            mix: GeneratorType::ALL
+                .into_iter()
+                .map(|generator_type| (generator_type, 1))
+                .collect(),
+            anomaly: AnomalyConfig {
+                rate: 0.5,
+                ..AnomalyConfig::default()
+            },
+            clock: ClockConfig {
+                skew_millis: 0,
+                jitter_millis: 500,
+            },
+            ..GeneratorConfig::default()
+        };
+        let simulated = config.clone().golden(true);
+        assert!(simulated.mix.contains(&(GeneratorType::Diurnal, 1)));
+
+        let config = config.golden(false);
+        assert!(config
+            .mix
+            .iter()
+            .all(|(generator_type, _)| generator_type.is_deterministic(false)));
+        assert!(config.mix.contains(&(GeneratorType::Sensor, 1)));
+        assert!(!config.mix.contains(&(GeneratorType::Diurnal, 1)));
+        assert_eq!(config.counter.min_increment, config.counter.max_increment);
+        assert_eq!(config.sensor.jitter, 0.0);
+        assert_eq!(config.anomaly.rate, 0.0);
+        assert_eq!(config.clock.jitter_millis, 0);
+        assert!(config
+            .group
+            .members
+            .iter()
+            .all(|member| member.noise == 0.0));
+
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let mut sensor = SensorGenerator::new(1, config.sensor);
+        let values: Vec<f64> = (0..4).map(|_| next_value(&mut sensor, &mut rng)).collect();
+        assert_eq!(values, vec![100.0, 101.25, 102.5, 103.74]);
+        let mut expression = ExpressionGenerator::new(1, config.expression);
+        assert_eq!(next_value(&mut expression, &mut rng), 50.0);
+    }
+
+    #[test]
+    fn test_generator_type_from_str() {
+        for generator_type in GeneratorType::ALL {
+            assert_eq!(generator_type.name().parse(), Ok(generator_type));
+        }
+        assert!("hans".parse::<GeneratorType>().is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GeneratorConfig::default();
+        assert!("test_custom".parse::<GeneratorType>().is_err());
+
+        GeneratorRegistry::global()
+            .write()
+            .unwrap()
+            .register("test_custom", |id, _config| {
+                Box::new(RampGenerator::new(id, RampConfig::default()))
+            });
+
+        let generator_type: GeneratorType = "test_custom".parse().unwrap();
+        assert_eq!(generator_type, GeneratorType::Custom("test_custom"));
+        let mut custom = create_generator(generator_type, 7, &config);
+        assert_eq!(custom.generate(&mut rng)[0].0, "ramp_7");
+        assert!(!generator_type.is_deterministic(true));
+
+        GeneratorRegistry::global()
+            .write()
+            .unwrap()
+            .register_deterministic("test_deterministic", |id, _config| {
+                Box::new(RampGenerator::new(id, RampConfig::default()))
+            });
+        let generator_type: GeneratorType = "test_deterministic".parse().unwrap();
+        assert!(generator_type.is_deterministic(false));
+
+        let registry = GeneratorRegistry::default();
+        assert!(registry.create("test_custom", 1, &config).is_none());
+        for generator_type in GeneratorType::ALL {
+            assert!(registry.create(generator_type.name(), 1, &config).is_some());
+        }
+    }
+
     #[test]
     fn test_factory() {
-        let mut rng = StdRng::seed_from_u64(1);
+        let mut rng = ChaCha12Rng::seed_from_u64(1);
+        let config = GeneratorConfig::default();
         // TODO: Can I test the type that is returned by the factory?
-        let mut noise = create_generator(GeneratorType::Noise, 1);
+        let mut alarm = create_generator(GeneratorType::Alarm, 1, &config);
+        alarm.generate(&mut rng);
+        let mut counter = create_generator(GeneratorType::Counter, 1, &config);
+        counter.generate(&mut rng);
+        let mut diurnal = create_generator(GeneratorType::Diurnal, 1, &config);
+        diurnal.generate(&mut rng);
+        let mut expression = create_generator(GeneratorType::Expression, 1, &config);
+        expression.generate(&mut rng);
+        let mut group = create_generator(GeneratorType::Group, 1, &config);
+        assert_eq!(group.generate(&mut rng).len(), 2);
+        let mut histogram = create_generator(GeneratorType::Histogram, 1, &config);
+        histogram.generate(&mut rng);
+        let mut location = create_generator(GeneratorType::Location, 1, &config);
+        assert_eq!(location.generate(&mut rng).len(), 2);
+        let mut machine = create_generator(GeneratorType::Machine, 1, &config);
+        assert_eq!(machine.generate(&mut rng).len(), 2);
+        let mut noise = create_generator(GeneratorType::Noise, 1, &config);
         noise.generate(&mut rng);
-        let mut sensor = create_generator(GeneratorType::Sensor, 1);
+        let mut ramp = create_generator(GeneratorType::Ramp, 1, &config);
+        ramp.generate(&mut rng);
+        let mut random_walk = create_generator(GeneratorType::RandomWalk, 1, &config);
+        random_walk.generate(&mut rng);
+        let mut replay = create_generator(GeneratorType::Replay, 1, &config);
+        replay.generate(&mut rng);
+        let mut sensor = create_generator(GeneratorType::Sensor, 1, &config);
         sensor.generate(&mut rng);
-        let mut status = create_generator(GeneratorType::Status, 1);
+        let mut setpoint = create_generator(GeneratorType::Setpoint, 1, &config);
+        setpoint.generate(&mut rng);
+        let mut status = create_generator(GeneratorType::Status, 1, &config);
         status.generate(&mut rng);
     }
 }
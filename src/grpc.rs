@@ -0,0 +1,179 @@
+//! The gRPC control plane, so that an external orchestrator can coordinate many simulator instances, for example to
+//! ramp up load in a scripted way. The service is defined in proto/control.proto.
+use tokio::sync::watch;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::control::{Command, Control, Progress, State};
+
+mod proto {
+    tonic::include_proto!("rumsim.control");
+}
+
+use proto::control_server::{Control as ControlService, ControlServer};
+use proto::{StartRequest, Stats, StatsRequest, StopRequest, UpdateRequest};
+
+struct GrpcControl {
+    control: watch::Sender<Control>,
+    progress: watch::Receiver<Progress>,
+}
+
+impl GrpcControl {
+    fn apply(&self, command: Command) -> Response<Stats> {
+        info!(?command, "Received command.");
+        self.control.send_modify(|control| control.apply(command));
+        self.stats()
+    }
+
+    fn stats(&self) -> Response<Stats> {
+        let control = self.control.borrow().clone();
+        let progress = self.progress.borrow().clone();
+        let state = match control.state {
+            State::Running => "running",
+            State::Paused => "paused",
+            State::Stopped => "stopped",
+        };
+        Response::new(Stats {
+            state: state.to_string(),
+            frequency_secs: control.frequency_secs as u64,
+            frequency_ms: (control.frequency_secs * 1000.0).round() as u64,
+            runs: control.runs as u64,
+            started: progress.started,
+            completed_runs: progress.runs as u64,
+            messages: progress.messages,
+            datapoints_per_sec: progress.datapoints_per_sec,
+            data_points: control.data_points.map(|data_points| data_points as u64),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl ControlService for GrpcControl {
+    async fn start_simulation(
+        &self,
+        _request: Request<StartRequest>,
+    ) -> Result<Response<Stats>, Status> {
+        Ok(self.apply(Command::Start))
+    }
+
+    async fn stop_simulation(
+        &self,
+        _request: Request<StopRequest>,
+    ) -> Result<Response<Stats>, Status> {
+        Ok(self.apply(Command::Stop))
+    }
+
+    async fn get_stats(&self, _request: Request<StatsRequest>) -> Result<Response<Stats>, Status> {
+        Ok(self.stats())
+    }
+
+    async fn update_parameters(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<Stats>, Status> {
+        let request = request.into_inner();
+        let runs = match request.runs.map(usize::try_from).transpose() {
+            Ok(runs) => runs,
+            Err(_) => return Err(Status::invalid_argument("Too many runs")),
+        };
+        let data_points = match request.data_points.map(usize::try_from).transpose() {
+            Ok(data_points) => data_points,
+            Err(_) => return Err(Status::invalid_argument("Too many data points")),
+        };
+        Ok(self.apply(Command::Set {
+            frequency_secs: request
+                .frequency_ms
+                .map(|ms| ms as f64 / 1000.0)
+                .or(request.frequency_secs.map(|secs| secs as f64)),
+            runs,
+            data_points,
+        }))
+    }
+}
+
+/// Serve the control plane on the given port until the process ends.
+pub async fn serve(
+    port: u16,
+    control: watch::Sender<Control>,
+    progress: watch::Receiver<Progress>,
+) {
+    info!(port, "Serving the gRPC control plane.");
+    let service = ControlServer::new(GrpcControl { control, progress });
+    let result = Server::builder()
+        .add_service(service)
+        .serve(([0, 0, 0, 0], port).into())
+        .await;
+    if let Err(e) = result {
+        warn!(error = ?e, port, "gRPC control plane failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grpc_control() {
+        let (control, _controlled) = watch::channel(Control::new(true, 60.0, 10));
+        let (_progress, progressed) = watch::channel(Progress {
+            started: true,
+            runs: 3,
+            messages: 30,
+            datapoints_per_sec: 1.5,
+        });
+        let service = GrpcControl {
+            control,
+            progress: progressed,
+        };
+
+        let stats = service
+            .get_stats(Request::new(StatsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.state, "paused");
+        assert_eq!(stats.completed_runs, 3);
+        assert_eq!(stats.messages, 30);
+
+        let stats = service
+            .start_simulation(Request::new(StartRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.state, "running");
+
+        let stats = service
+            .update_parameters(Request::new(UpdateRequest {
+                frequency_secs: Some(5),
+                runs: None,
+                data_points: Some(20),
+                frequency_ms: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((stats.frequency_secs, stats.runs), (5, 10));
+        assert_eq!(stats.frequency_ms, 5000);
+        assert_eq!(stats.data_points, Some(20));
+
+        let stats = service
+            .update_parameters(Request::new(UpdateRequest {
+                frequency_secs: Some(5),
+                runs: None,
+                data_points: None,
+                frequency_ms: Some(250),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((stats.frequency_secs, stats.frequency_ms), (0, 250));
+
+        let stats = service
+            .stop_simulation(Request::new(StopRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.state, "stopped");
+    }
+}
@@ -0,0 +1,267 @@
+//! Publishing with HTTP POST requests to a REST endpoint or a webhook, for where MQTT is not available.
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use std::str::FromStr;
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+
+use crate::publisher::Publisher;
+use crate::settings::{read_secret, Settings};
+
+/// The authentication of the HTTP requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpAuth {
+    None,
+    /// Basic authentication with BROKER_USER and BROKER_PASS.
+    Basic,
+    /// A bearer token in BROKER_PASS.
+    Bearer,
+}
+
+impl FromStr for HttpAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(HttpAuth::None),
+            "basic" => Ok(HttpAuth::Basic),
+            "bearer" => Ok(HttpAuth::Bearer),
+            _ => Err(format!("Unknown HTTP authentication {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpPublisher {
+    client: Client,
+    /// The endpoint, where {topic} is replaced with the topic of the message.
+    url: String,
+    auth: HttpAuth,
+    user: String,
+    pass: String,
+    /// BROKER_PASS_FILE, from which a rotated password is read.
+    pass_file: Option<String>,
+    retries: usize,
+    content_type: &'static str,
+    content_encoding: Option<&'static str>,
+}
+
+impl HttpPublisher {
+    pub fn new(settings: &Settings) -> Self {
+        HttpPublisher {
+            // It's OK to panic, since the simulator cannot send anything without a client.
+            client: Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            url: settings.broker.url.clone(),
+            auth: settings.broker.http_auth,
+            user: settings.broker.user.clone(),
+            pass: settings.broker.pass.clone(),
+            pass_file: settings.broker.pass_file.clone(),
+            retries: settings.broker.http_retries,
+            content_type: settings.sim.payload.format.content_type(),
+            content_encoding: settings.sim.payload.compression.content_encoding(),
+        }
+    }
+}
+
+impl Publisher for HttpPublisher {
+    /// POST the payload. Failed requests are retried with exponential backoff if the endpoint is unreachable,
+    /// overloaded or has a server error.
+    async fn publish(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        _device: Option<&str>,
+        _run: usize,
+    ) -> Result<(), String> {
+        let url = self.url.replace("{topic}", &topic);
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(&url)
+                .header(CONTENT_TYPE, self.content_type)
+                .body(payload.clone());
+            if let Some(encoding) = self.content_encoding {
+                request = request.header(CONTENT_ENCODING, encoding);
+            }
+            request = match self.auth {
+                HttpAuth::None => request,
+                HttpAuth::Basic => request.basic_auth(&self.user, Some(&self.pass)),
+                HttpAuth::Bearer => request.bearer_auth(&self.pass),
+            };
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response)
+                    if response.status() == StatusCode::UNAUTHORIZED && self.rotate_password() =>
+                {
+                    format!("HTTP status {}", response.status())
+                }
+                Ok(response) if !is_retryable(response.status()) => {
+                    return Err(format!("HTTP status {}", response.status()))
+                }
+                Ok(response) => format!("HTTP status {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            warn!(error, attempt, "Retrying HTTP request");
+            sleep(backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn close(&mut self) {}
+
+    /// The requests of the forks share the connection pool of the client.
+    fn fork(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+}
+
+impl HttpPublisher {
+    /// Read the password from BROKER_PASS_FILE again after the endpoint rejected it. Returns true if the password was
+    /// rotated, so that the request is retried with the new password.
+    fn rotate_password(&mut self) -> bool {
+        let Some(file) = &self.pass_file else {
+            return false;
+        };
+        match read_secret(file) {
+            Ok(pass) if pass != self.pass => {
+                info!("Using the rotated password.");
+                self.pass = pass;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!(error = e, "Cannot read the password");
+                false
+            }
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// 100 ms for the first retry, doubling up to 10 s.
+fn backoff(attempt: usize) -> Duration {
+    Duration::from_millis(100 * 2u64.pow(attempt.min(7) as u32)).min(Duration::from_secs(10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answer the requests with the given status codes and pass the request heads and bodies on.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/{{topic}}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender
+                    .send((head, String::from_utf8(body).unwrap()))
+                    .unwrap();
+                let response = format!("HTTP/1.1 {} X\r\ncontent-length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn publisher(url: String, auth: HttpAuth) -> HttpPublisher {
+        HttpPublisher {
+            client: Client::new(),
+            url,
+            auth,
+            user: "user".to_string(),
+            pass: "secret".to_string(),
+            pass_file: None,
+            retries: 1,
+            content_type: "application/json",
+            content_encoding: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_publisher() {
+        let (url, requests) = serve(vec![503, 200]);
+        let mut publisher = publisher(url, HttpAuth::Bearer);
+        let result = publisher
+            .publish("a/b".to_string(), b"{\"x\":1}".to_vec(), None, 0)
+            .await;
+        assert_eq!(result, Ok(()));
+
+        for _ in 0..2 {
+            let (head, body) = requests.recv().unwrap();
+            assert!(head.starts_with("POST /a/b HTTP/1.1"));
+            assert!(head.contains("authorization: Bearer secret"));
+            assert!(head.contains("content-type: application/json"));
+            assert_eq!(body, "{\"x\":1}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_publisher_failure() {
+        let (url, _requests) = serve(vec![500, 500, 400]);
+        let mut publisher = publisher(url, HttpAuth::Basic);
+        let result = publisher.publish("a".to_string(), vec![], None, 0).await;
+        assert_eq!(
+            result,
+            Err("HTTP status 500 Internal Server Error".to_string())
+        );
+
+        // Client errors are not retried.
+        let result = publisher.publish("a".to_string(), vec![], None, 0).await;
+        assert_eq!(result, Err("HTTP status 400 Bad Request".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_http_publisher_rotated_password() {
+        let (url, requests) = serve(vec![401, 200]);
+        let file = std::env::temp_dir().join("rumsim_test_http_pass");
+        std::fs::write(&file, "rotated\n").unwrap();
+        let mut publisher = HttpPublisher {
+            pass_file: Some(file.to_str().unwrap().to_string()),
+            ..publisher(url, HttpAuth::Bearer)
+        };
+        let result = publisher.publish("a".to_string(), vec![], None, 0).await;
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(result, Ok(()));
+        assert!(requests.recv().unwrap().0.contains("Bearer secret"));
+        assert!(requests.recv().unwrap().0.contains("Bearer rotated"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(20), Duration::from_secs(10));
+    }
+}
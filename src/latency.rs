@@ -0,0 +1,183 @@
+//! The latency of the messages from publishing until they are received back from the broker, with a subscription to
+//! the own messages on BROKER_LOOPBACK_TOPIC. The messages are recognized by their payload, so that they can also
+//! come back on another topic, for example from a reflector. With QoS 1 or 2, also the latency from sending each
+//! message until the broker acknowledges it.
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use tokio::time::{Duration, Instant};
+
+use crate::summary::Latencies;
+
+/// Messages that are not received back within this time are counted as lost, so that the pending messages don't
+/// grow.
+const LOST_AFTER: Duration = Duration::from_secs(60);
+
+/// The loopback is shared by the publishers and the listeners of the connections, which run separately.
+static LOOPBACK: OnceLock<Loopback> = OnceLock::new();
+
+/// Measure the latency of the published messages from now on.
+pub fn enable() -> &'static Loopback {
+    LOOPBACK.get_or_init(Loopback::new)
+}
+
+/// The loopback, if the latency is measured.
+pub fn loopback() -> Option<&'static Loopback> {
+    LOOPBACK.get()
+}
+
+pub struct Loopback {
+    /// The time at which each message was published, by the hash of its payload.
+    pending: Mutex<HashMap<u64, Instant>>,
+    /// The latencies of the messages that were received since they were last taken.
+    received: Mutex<Latencies>,
+}
+
+impl Loopback {
+    fn new() -> Self {
+        Loopback {
+            pending: Mutex::new(HashMap::new()),
+            received: Mutex::new(Latencies::new()),
+        }
+    }
+
+    /// Remember when a message is published.
+    pub fn publish(&self, payload: &[u8]) {
+        self.publish_at(payload, Instant::now());
+    }
+
+    /// The latency of a received message, if it is one of the published messages. Messages that were published
+    /// several times with the same payload count once.
+    pub fn receive(&self, payload: &[u8]) -> Option<Duration> {
+        self.receive_at(payload, Instant::now())
+    }
+
+    /// The latencies of the messages that were received since the last call, and the number of messages that were
+    /// not received in time.
+    pub fn take(&self) -> (Latencies, usize) {
+        self.take_at(Instant::now())
+    }
+
+    fn publish_at(&self, payload: &[u8], now: Instant) {
+        self.pending.lock().unwrap().insert(hash(payload), now);
+    }
+
+    fn receive_at(&self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let published = self.pending.lock().unwrap().remove(&hash(payload))?;
+        let latency = now.duration_since(published);
+        self.received.lock().unwrap().record(latency);
+        Some(latency)
+    }
+
+    fn take_at(&self, now: Instant) -> (Latencies, usize) {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|_, published| now.duration_since(*published) < LOST_AFTER);
+        let lost = before - pending.len();
+        let latencies = std::mem::replace(&mut *self.received.lock().unwrap(), Latencies::new());
+        (latencies, lost)
+    }
+}
+
+/// The latencies of the acknowledgements on all connections since they were last taken.
+static ACKNOWLEDGED: LazyLock<Mutex<Latencies>> = LazyLock::new(|| Mutex::new(Latencies::new()));
+
+/// The latencies of the acknowledgements since the last call.
+pub fn take_acks() -> Latencies {
+    std::mem::replace(&mut *ACKNOWLEDGED.lock().unwrap(), Latencies::new())
+}
+
+/// The messages of a connection that were sent with QoS 1 or 2 and are not acknowledged yet, by their packet id. The
+/// time starts when the event loop sends a message, since the packet id is only assigned then, so the time in the
+/// queue of the client is not included.
+pub struct Acks {
+    sent: HashMap<u16, Instant>,
+}
+
+impl Acks {
+    pub fn new() -> Self {
+        Acks {
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Remember when a message is sent. A message that is sent again after a reconnect counts from then, and a packet
+    /// id that is reused for a message that was never acknowledged replaces it.
+    pub fn send(&mut self, pkid: u16) {
+        self.send_at(pkid, Instant::now());
+    }
+
+    /// The latency of an acknowledged message, which is also recorded for the run.
+    pub fn acknowledge(&mut self, pkid: u16) -> Option<Duration> {
+        self.acknowledge_at(pkid, Instant::now())
+    }
+
+    fn send_at(&mut self, pkid: u16, now: Instant) {
+        self.sent.insert(pkid, now);
+    }
+
+    fn acknowledge_at(&mut self, pkid: u16, now: Instant) -> Option<Duration> {
+        let sent = self.sent.remove(&pkid)?;
+        let latency = now.duration_since(sent);
+        ACKNOWLEDGED.lock().unwrap().record(latency);
+        Some(latency)
+    }
+}
+
+fn hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback() {
+        let loopback = Loopback::new();
+        let start = Instant::now();
+        loopback.publish_at(b"a", start);
+        loopback.publish_at(b"b", start + Duration::from_millis(10));
+        assert_eq!(
+            loopback.receive_at(b"b", start + Duration::from_millis(15)),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(loopback.receive_at(b"b", start), None);
+        assert_eq!(loopback.receive_at(b"status", start), None);
+        assert_eq!(
+            loopback.receive_at(b"a", start + Duration::from_millis(20)),
+            Some(Duration::from_millis(20))
+        );
+        let (latencies, lost) = loopback.take();
+        assert_eq!((latencies.count(), lost), (2, 0));
+        assert_eq!(loopback.take().0.count(), 0);
+
+        loopback.publish_at(b"c", start);
+        loopback.publish_at(b"d", start + Duration::from_secs(1));
+        assert_eq!(loopback.take_at(start + LOST_AFTER).1, 1);
+        assert!(loopback.receive_at(b"d", start + LOST_AFTER).is_some());
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut acks = Acks::new();
+        let start = Instant::now();
+        acks.send_at(1, start);
+        acks.send_at(2, start);
+        // Sent again after a reconnect.
+        acks.send_at(2, start + Duration::from_millis(10));
+        assert_eq!(
+            acks.acknowledge_at(2, start + Duration::from_millis(12)),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(acks.acknowledge_at(2, start), None);
+        assert_eq!(acks.acknowledge_at(3, start), None);
+        assert_eq!(
+            acks.acknowledge_at(1, start + Duration::from_millis(7)),
+            Some(Duration::from_millis(7))
+        );
+        assert!(acks.sent.is_empty());
+    }
+}
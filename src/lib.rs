@@ -0,0 +1,29 @@
+//! The simulator as a library, so that the benchmarks in `benches/` can drive the generators and the simulation
+//! directly. The binary in `main.rs` runs the simulation configured by the settings.
+// Constructors like `Recorder::new` read the settings or start a clock, which a `Default` would hide.
+#![allow(clippy::new_without_default)]
+pub mod amqp;
+pub mod api;
+pub mod azure;
+pub mod cli;
+pub mod cluster;
+pub mod control;
+pub mod device;
+pub mod expression;
+pub mod generator;
+pub mod grpc;
+pub mod http;
+pub mod latency;
+pub mod mqtt;
+pub mod observability;
+pub mod pacing;
+pub mod payload;
+pub mod presets;
+pub mod profile;
+pub mod publisher;
+pub mod scenario;
+pub mod settings;
+pub mod simulation;
+pub mod sparkplug;
+pub mod summary;
+pub mod tls;
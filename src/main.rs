@@ -1,163 +1,261 @@
-#[macro_use]
-extern crate lazy_static;
+use chrono::{DateTime, Utc};
+use cluster::Role;
+use control::{stop_on_signal, stopped, Command, Control, Orchestrator, Progress};
+use rumsim::{
+    amqp, api, cli, cluster, control, grpc, http, mqtt, presets, publisher, settings, simulation,
+};
+use tracing::info;
 
-use chrono::Utc;
-use observability::Metering;
-use opentelemetry::global::shutdown_tracer_provider;
-use tracing::{debug, info, span, trace, warn};
-
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use amqp::AmqpPublisher;
+use futures::future::Either;
+use http::HttpPublisher;
+use mqtt::{connect_pool, listen_all, Connection, MqttPool};
+use publisher::{Publisher, WritePublisher};
 use settings::Settings;
 use simulation::Simulation;
-use tokio::time::{sleep, Duration, Instant};
-
-use crate::{observability::init_tracing, simulation::SimulationParameters};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
 
-mod device;
-mod generator;
-mod observability;
-mod settings;
-mod simulation;
+use rumsim::{
+    observability::{init_metering, init_tracing, shutdown_telemetry},
+    simulation::{SimulationParameters, TimeMode},
+};
 
-lazy_static! {
-    static ref CONFIG: Settings = Settings::new();
+fn main() {
+    // Before the settings are read and before the runtime starts its threads.
+    let dry_run = cli::apply_args();
+    presets::Preset::apply();
+    // Report all problems with the settings before connecting to anything.
+    let settings = Settings::new().unwrap_or_else(|problems| {
+        eprintln!("Invalid settings:");
+        for problem in problems {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(2);
+    });
+    run(Arc::new(settings), dry_run);
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(settings))]
 #[tokio::main]
-async fn main() {
-    init_tracing();
+async fn run(settings: Arc<Settings>, dry_run: bool) {
+    init_tracing(&settings);
+    // Before any instruments are created, since they would not be exported otherwise.
+    init_metering(&settings);
+    // Workers wait for the leader of the cluster to start them.
+    let (control, _) = watch::channel(Control::new(
+        settings.sim.start_paused || settings.sim.cluster_role == Some(Role::Worker),
+        settings.sim.frequency_secs,
+        settings.sim.runs,
+    ));
+    stop_on_signal(control.clone());
+    cli::reload_config(control.clone());
+    let (progress, progressed) = watch::channel(Progress::default());
+    if let Some(port) = settings.control_port {
+        tokio::spawn(api::serve(port, control.clone(), progressed.clone()));
+    }
+    if let Some(port) = settings.grpc_port {
+        tokio::spawn(grpc::serve(port, control.clone(), progressed.clone()));
+    }
 
-    let (client, eventloop) = connect_broker().await;
-    wait_for_start_time().await;
+    if settings.broker.url == "stdout://" || dry_run {
+        simulate_without_broker(
+            &mut WritePublisher::stdout(),
+            &settings,
+            &control,
+            &progress,
+        )
+        .await;
+        return;
+    }
+    if let Some(path) = settings.broker.url.strip_prefix("file://") {
+        // It's OK to panic, since there is nowhere else to write to.
+        let mut publisher = WritePublisher::file(path).unwrap_or_else(|e| panic!("{}", e));
+        simulate_without_broker(&mut publisher, &settings, &control, &progress).await;
+        return;
+    }
+    if settings.broker.url.starts_with("http://") || settings.broker.url.starts_with("https://") {
+        let mut publisher = HttpPublisher::new(&settings);
+        simulate_without_broker(&mut publisher, &settings, &control, &progress).await;
+        return;
+    }
+    if settings.broker.url.starts_with("amqp://") || settings.broker.url.starts_with("amqps://") {
+        // It's OK to panic, since there is nothing to simulate for without a connection.
+        let mut publisher = AmqpPublisher::connect(&settings)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot connect to {}: {}", settings.broker.url, e));
+        simulate_without_broker(&mut publisher, &settings, &control, &progress).await;
+        return;
+    }
+
+    // The simulation comes first, since there may be a connection for each of its devices.
+    let params = get_parameters(&settings);
+    let simulation = Simulation::new(&params);
+    let (mut pool, eventloops) = connect_broker(settings.clone(), simulation.names()).await;
+    let mut commands = pool.commands(control.clone());
+    if let Some(commands) = &mut commands {
+        tokio::spawn(commands.coordinate(progressed.clone()));
+        tokio::spawn(commands.report(progressed));
+    }
+    wait_for_start_time(&settings, &mut control.subscribe()).await;
+    stop_at_end(&settings, &control);
 
-    let params = get_parameters();
-    let simulation_handle = tokio::spawn(async move { simulate(client, params).await });
-    let listen_handle = tokio::spawn(async move { listen(eventloop).await });
-    futures::future::select(simulation_handle, listen_handle).await;
+    let simulated = settings.clone();
+    let simulation_handle = tokio::spawn(async move {
+        Orchestrator::new(&mut pool, &control, &progress, &simulated)
+            .simulate_configured(params, simulation)
+            .await
+    });
+    let listen_handle =
+        tokio::spawn(async move { listen_all(&settings, eventloops, commands).await });
+    if let Either::Left((_, listen_handle)) =
+        futures::future::select(simulation_handle, listen_handle).await
+    {
+        // Give the event loop the chance to send the last messages before disconnecting.
+        let _ = tokio::time::timeout(Duration::from_secs(5), listen_handle).await;
+    }
 
     info!("Shutting down.");
-    shutdown_tracer_provider();
+    shutdown_telemetry();
 }
 
-async fn connect_broker() -> (AsyncClient, EventLoop) {
-    info!(broker_url = &CONFIG.broker_url,
-        broker_user = &CONFIG.broker_user, broker_pass = anonymize(&CONFIG.broker_pass),
-        broker_client_id = &CONFIG.broker_client_id, broker_qos = CONFIG.broker_qos,
-        otlp_collector = ?CONFIG.otlp_collector, otlp_auth = anonymize_opt(&CONFIG.otlp_auth),
-        capacity = CONFIG.capacity, sim_start_time = ?CONFIG.sim_start_time,
-        "Connecting to broker.");
-    create_mqtt_client().await
+/// Sinks other than MQTT have no connection to listen to.
+async fn simulate_without_broker(
+    publisher: &mut impl Publisher,
+    settings: &Settings,
+    control: &watch::Sender<Control>,
+    progress: &watch::Sender<Progress>,
+) {
+    info!(broker_url = &settings.broker.url, broker_http_auth = ?settings.broker.http_auth, broker_amqp_sasl = ?settings.broker.amqp_sasl, "Publishing without broker.");
+    let params = get_parameters(settings);
+    let simulation = Simulation::new(&params);
+    wait_for_start_time(settings, &mut control.subscribe()).await;
+    stop_at_end(settings, control);
+    Orchestrator::new(publisher, control, progress, settings)
+        .simulate_configured(params, simulation)
+        .await;
+    info!("Shutting down.");
+    shutdown_telemetry();
 }
 
-async fn wait_for_start_time() {
-    if let Some(start_time) = CONFIG.sim_start_time {
-        let now = Utc::now();
-        let wait_time = (start_time - now).num_milliseconds().max(0) as u64;
-        sleep(Duration::from_millis(wait_time)).await;
-    }
+async fn connect_broker(
+    settings: Arc<Settings>,
+    devices: &[String],
+) -> (MqttPool, Vec<Connection>) {
+    info!(broker_url = &settings.broker.url,
+        broker_user = &settings.broker.user, broker_pass = anonymize(&settings.broker.pass), broker_pass_file = ?settings.broker.pass_file,
+        broker_client_id = &settings.broker.client_id, broker_connections = settings.broker.connections, broker_connection_per_device = settings.broker.connection_per_device, broker_device_client_id = &settings.broker.device_client_id, broker_keep_alive_secs = settings.broker.keep_alive_secs, broker_clean_session = settings.broker.clean_session, broker_max_inflight = settings.broker.max_inflight, broker_max_packet_size = settings.broker.max_packet_size, broker_publish_concurrency = settings.broker.publish_concurrency, broker_backpressure = ?settings.broker.backpressure, broker_loopback_topic = ?settings.broker.loopback_topic, broker_ack_budget_ms = ?settings.broker.ack_budget_ms, broker_qos = settings.broker.qos,
+        broker_ca_file = ?settings.broker.ca_file, broker_cert_file = ?settings.broker.cert_file,
+        broker_alpn = ?settings.broker.alpn, broker_mqtt_version = ?settings.broker.mqtt_version, broker_lwt_topic = ?settings.broker.lwt_topic, broker_azure_key = anonymize_opt(&settings.broker.azure_key),
+        otlp_collector = ?settings.otlp.collector, otlp_auth = anonymize_opt(&settings.otlp.auth), otlp_auth_file = ?settings.otlp.auth_file, otlp_traces = settings.otlp.traces, otlp_sample_ratio = settings.otlp.sample_ratio, otlp_sample_per_sec = ?settings.otlp.sample_per_sec, otlp_traceparent = settings.otlp.traceparent, log_format = ?settings.log_format,
+        capacity = settings.capacity, sim_start_time = ?settings.sim.start_time,
+        "Connecting to broker.");
+    connect_pool(settings, devices).await
 }
 
-fn get_parameters() -> SimulationParameters {
-    info!(
-        sim_devices = CONFIG.sim_devices,
-        sim_data_points = CONFIG.sim_data_points,
-        sim_seed = CONFIG.sim_seed,
-        sim_frequency_secs = CONFIG.sim_frequency_secs,
-        sim_runs = CONFIG.sim_runs,
-        "Running the simulation."
-    );
-    SimulationParameters {
-        client_id: CONFIG.broker_client_id.clone(),
-        devices: CONFIG.sim_devices,
-        data_points: CONFIG.sim_data_points,
-        seed: CONFIG.sim_seed,
-        frequency_secs: CONFIG.sim_frequency_secs,
-        qos: CONFIG.broker_qos,
+/// In simulated time, the start time is the time stamp of the first run, so there is nothing to wait for.
+async fn wait_for_start_time(settings: &Settings, stop: &mut watch::Receiver<Control>) {
+    if settings.sim.time.mode == TimeMode::Simulated {
+        return;
     }
-}
-
-async fn simulate(client: AsyncClient, parms: SimulationParameters) {
-    let metering = Metering::new();
-
-    let mut simulation = Simulation::new(&parms);
-    let frequency = Duration::from_secs(parms.frequency_secs);
-    let datapoints = parms.devices * parms.data_points;
-    let qos = get_qos();
-
-    for _ in 0..CONFIG.sim_runs {
-        let simulation_span = span!(tracing::Level::INFO, "simulation_run");
-        let _enter = simulation_span.enter();
-        debug!(parent: &simulation_span, sim_devices = parms.devices, sim_data_points = parms.data_points, sim_frequency = parms.frequency_secs, sim_seed = parms.seed, "Running simulation");
-
-        let start = Instant::now();
-        for (topic, data) in simulation.iter() {
-            match client.publish(topic, qos, false, data).await {
-                Ok(_) => (),
-                Err(e) => {
-                    warn!(error = ?e, "Failed to publish");
-                    return;
-                }
-            }
-        }
-
-        let elapsed = start.elapsed();
-        let remainder = frequency.saturating_sub(elapsed);
-        if remainder == Duration::ZERO {
-            metering.is_overloaded();
-            warn!(parent: &simulation_span, "Messages cannot be sent fast enough. Increase capacity on receiving end, increase wait time or reduce the number of data points.");
+    if let Some(start_time) = settings.sim.start_time {
+        let now = Utc::now();
+        let wait_time = (start_time - now).num_milliseconds().max(0) as u64;
+        tokio::select! {
+            _ = sleep(Duration::from_millis(wait_time)) => (),
+            _ = stopped(stop) => (),
         }
-        metering.record_datapoints(datapoints, frequency);
-        metering.record_capacity(elapsed, frequency);
-        debug!(parent: &simulation_span, remainder=?remainder, "Sleeping");
-        sleep(remainder).await;
     }
 }
 
-fn get_qos() -> QoS {
-    match CONFIG.broker_qos {
-        0 => QoS::AtMostOnce,
-        1 => QoS::AtLeastOnce,
-        2 => QoS::ExactlyOnce,
-        _ => panic!("Invalid QoS level."),
-    }
+/// Stop the simulation after SIM_DURATION_SECS or at SIM_END_TIME, whichever comes first, so that the instances of a
+/// distributed test end together. Like with a signal, the current run is completed.
+fn stop_at_end(settings: &Settings, control: &watch::Sender<Control>) {
+    let Some(remaining) = remaining_time(
+        Utc::now(),
+        settings.sim.duration_secs,
+        settings.sim.end_time,
+    ) else {
+        return;
+    };
+    info!(?remaining, "Stopping after the remaining time.");
+    let control = control.clone();
+    tokio::spawn(async move {
+        sleep(remaining).await;
+        info!("Stopping at the end time.");
+        control.send_modify(|control| control.apply(Command::Stop));
+    });
 }
 
-/// Listen for incoming messages and handle them. If I don't handle the incoming messages, sending messages will block.
-async fn listen(mut eventloop: EventLoop) {
-    loop {
-        match eventloop.poll().await {
-            Ok(Event::Incoming(Packet::Disconnect)) => {
-                warn!("Disconnected from the broker.");
-                return;
-            }
-            Ok(x) => {
-                trace!(message = ?x, "Received message");
-            }
-            Err(e) => {
-                warn!(error = ?e, "Failed to connect");
-                return;
-            }
-        }
+fn remaining_time(
+    now: DateTime<Utc>,
+    duration_secs: Option<u64>,
+    end_time: Option<DateTime<Utc>>,
+) -> Option<Duration> {
+    let duration = duration_secs.map(Duration::from_secs);
+    // An end time in the past stops right away.
+    let until_end = end_time.map(|end_time| (end_time - now).to_std().unwrap_or(Duration::ZERO));
+    match (duration, until_end) {
+        (Some(duration), Some(until_end)) => Some(duration.min(until_end)),
+        (duration, until_end) => duration.or(until_end),
     }
 }
 
-/// Create the MQTT connection based on the configuration.
-async fn create_mqtt_client() -> (AsyncClient, EventLoop) {
-    let url = format!(
-        "{}?client_id={}",
-        CONFIG.broker_url, CONFIG.broker_client_id
+fn get_parameters(settings: &Settings) -> SimulationParameters {
+    info!(
+        sim_devices = settings.sim.devices,
+        sim_gateway_children = settings.sim.gateway_children,
+        sim_manifest_devices = settings.sim.manifest.as_ref().map(|manifest| manifest.len()),
+        sim_data_points = settings.sim.data_points,
+        sim_seed = settings.sim.seed,
+        sim_frequency_secs = settings.sim.frequency_secs,
+        sim_runs = settings.sim.runs,
+        sim_duration_secs = settings.sim.duration_secs,
+        sim_end_time = ?settings.sim.end_time,
+        sim_generators = ?settings.sim.generators,
+        sim_payload = ?settings.sim.payload,
+        sim_time = ?settings.sim.time,
+        sim_delivery = ?settings.sim.delivery,
+        sim_snapshot_file = ?settings.sim.snapshot_file,
+        sim_snapshot_runs = settings.sim.snapshot_runs,
+        sim_missed_runs = ?settings.sim.missed_runs,
+        sim_target_msgs_per_sec = settings.sim.target_msgs_per_sec,
+        sim_pacing = ?settings.sim.pacing,
+        sim_jitter = settings.sim.jitter,
+        sim_threads = settings.sim.threads,
+        sim_adaptive = settings.sim.adaptive,
+        sim_load_profile = ?settings.sim.load_profile,
+        sim_profile = ?settings.sim.profile,
+        sim_scenario_phases = settings.sim.scenario.as_ref().map(|phases| phases.len()),
+        sim_shard = ?settings.sim.shard,
+        "Running the simulation."
     );
-    let mut opts = MqttOptions::parse_url(url).unwrap();
-
-    opts.set_credentials(&CONFIG.broker_user, &CONFIG.broker_pass);
-    opts.set_keep_alive(Duration::from_secs(5));
-
-    AsyncClient::new(opts, CONFIG.capacity)
+    SimulationParameters {
+        client_id: settings.broker.client_id.clone(),
+        devices: settings.sim.devices,
+        manifest: settings.sim.manifest.clone(),
+        children: settings.sim.gateway_children,
+        data_points: settings.sim.data_points,
+        seed: settings.sim.seed,
+        frequency_secs: settings.sim.frequency_secs,
+        generators: settings.sim.generators.clone(),
+        payload: settings.sim.payload.clone(),
+        time: settings.sim.time,
+        delivery: settings.sim.delivery,
+        threads: settings.sim.threads,
+        shard: settings.sim.shard.clone(),
+    }
 }
 
+/// The first and the last character of a secret, which are enough to tell secrets apart in the log. Secrets that are
+/// too short for that are left out completely.
 fn anonymize(s: &str) -> String {
-    format!("{}…{}", &s[..1], &s[s.len() - 1..])
+    let mut chars = s.chars();
+    match (chars.next(), chars.next_back()) {
+        (Some(first), Some(last)) => format!("{}…{}", first, last),
+        _ => "…".to_string(),
+    }
 }
 
 fn anonymize_opt(s: &Option<String>) -> String {
@@ -166,3 +264,36 @@ fn anonymize_opt(s: &Option<String>) -> String {
         None => "None".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymize() {
+        assert_eq!(anonymize("s3cret"), "s…t");
+        assert_eq!(anonymize("äöü"), "ä…ü");
+        assert_eq!(anonymize("a"), "…");
+        assert_eq!(anonymize(""), "…");
+        assert_eq!(anonymize_opt(&None), "None");
+    }
+
+    #[test]
+    fn test_remaining_time() {
+        let now = Utc::now();
+        let minutes = |minutes| now + chrono::Duration::minutes(minutes);
+        assert_eq!(remaining_time(now, None, None), None);
+        assert_eq!(
+            remaining_time(now, Some(60), None),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            remaining_time(now, Some(600), Some(minutes(2))),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            remaining_time(now, None, Some(minutes(-2))),
+            Some(Duration::ZERO)
+        );
+    }
+}
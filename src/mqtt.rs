@@ -0,0 +1,1349 @@
+//! The connection to the MQTT broker with MQTT 3.1.1 or MQTT 5.
+use chrono::Utc;
+use futures::future::pending;
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand_distr::{Distribution, Exp};
+use rumqttc::v5::mqttbytes::v5::{LastWill as LastWillV5, Packet as PacketV5, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS as QoSV5;
+use rumqttc::{
+    v5, AsyncClient, ConnectionError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet,
+    QoS, Transport,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{info, trace, warn};
+
+use crate::cluster::{Cluster, Role};
+use crate::control::{BrokerHealth, Command, Control, Progress, State, Status};
+use crate::latency::{self, Acks};
+use crate::observability::{self, Metering};
+use crate::publisher::{Publisher, Queue};
+use crate::settings::{read_secret, BrokerSettings, Settings};
+use crate::{azure, summary, tls};
+
+/// The connections are counted by their listeners, which run separately from the status reports.
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTED: AtomicUsize = AtomicUsize::new(0);
+static CONNECTION_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// The health of the connections to the broker as the simulator sees them.
+pub fn broker_health() -> BrokerHealth {
+    BrokerHealth {
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+        connected: CONNECTED.load(Ordering::Relaxed),
+        reconnects: summary::reconnects(),
+        errors: CONNECTION_ERRORS.load(Ordering::Relaxed),
+    }
+}
+
+/// The supported versions of the MQTT protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MqttVersion {
+    V3,
+    V5,
+}
+
+impl FromStr for MqttVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3" | "3.1.1" => Ok(MqttVersion::V3),
+            "5" => Ok(MqttVersion::V5),
+            _ => Err(format!("Unknown MQTT version {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum MqttClient {
+    V3(AsyncClient),
+    /// With MQTT 5, the client keeps the topic aliases that it has assigned.
+    V5 {
+        client: v5::AsyncClient,
+        aliases: TopicAliases,
+    },
+}
+
+pub enum MqttEventLoop {
+    V3(Box<EventLoop>),
+    /// With MQTT 5, the event loop announces the number of topic aliases that the broker accepts with each ConnAck.
+    V5 {
+        eventloop: Box<v5::EventLoop>,
+        alias_max: watch::Sender<u16>,
+    },
+}
+
+/// The topic aliases that the client has assigned on the current connection. Aliases are only valid for the
+/// connection on which they were assigned, so they start over with each ConnAck.
+#[derive(Clone)]
+pub struct TopicAliases {
+    topics: HashMap<String, u16>,
+    /// The topic alias maximum of the broker, which changes with each ConnAck, even if it has the same value.
+    max: watch::Receiver<u16>,
+}
+
+impl TopicAliases {
+    fn new(max: watch::Receiver<u16>) -> Self {
+        TopicAliases {
+            topics: HashMap::new(),
+            max,
+        }
+    }
+
+    /// Replace the topic by its alias, using at most as many aliases as configured and as the broker accepts.
+    fn alias(&mut self, topic: String, configured: u16) -> (String, Option<u16>) {
+        if self.max.has_changed().unwrap_or(false) {
+            self.topics.clear();
+        }
+        let max = (*self.max.borrow_and_update()).min(configured);
+        topic_alias(&mut self.topics, topic, max)
+    }
+}
+
+/// One or more connections to the broker. The messages of a device always go through the same connection.
+pub struct MqttPool {
+    clients: Vec<MqttClient>,
+    /// The connection of each device, if each device has its own connection.
+    devices: HashMap<String, usize>,
+    /// The last wills of the connections that have one.
+    wills: Vec<(usize, LastWill)>,
+    next: usize,
+    queue: Queue,
+    settings: Arc<Settings>,
+}
+
+impl Publisher for MqttPool {
+    async fn publish(
+        &mut self,
+        topic: String,
+        data: Vec<u8>,
+        device: Option<&str>,
+        run: usize,
+    ) -> Result<(), String> {
+        let index = match device.and_then(|device| self.devices.get(device)) {
+            Some(index) => *index,
+            None => connection(device, &mut self.next, self.clients.len()),
+        };
+        if let Some(loopback) = latency::loopback() {
+            loopback.publish(&data);
+        }
+        self.queue.push(index);
+        let published = self.clients[index]
+            .publish(topic, data, device, run, &self.settings)
+            .await;
+        if published.is_err() {
+            self.queue.pop(index);
+        }
+        published
+    }
+
+    /// Send the last wills on a clean shutdown as well, so that the broker sees the same presence messages, and
+    /// disconnect.
+    async fn close(&mut self) {
+        for (index, will) in self.wills.drain(..) {
+            if self.settings.broker.lwt_on_close {
+                self.clients[index].publish_will(will).await;
+            }
+        }
+        for client in &self.clients {
+            client.disconnect().await;
+        }
+    }
+
+    /// The forks publish over the same connections, but not with topic aliases, since an alias has to reach the broker
+    /// before the messages that use it.
+    fn fork(&self) -> Option<Self> {
+        if self.settings.broker.topic_aliases > 0 {
+            return None;
+        }
+        Some(MqttPool {
+            clients: self.clients.clone(),
+            devices: self.devices.clone(),
+            wills: Vec::new(),
+            next: self.next,
+            queue: self.queue.clone(),
+            settings: self.settings.clone(),
+        })
+    }
+
+    fn queue(&self) -> Option<Queue> {
+        Some(self.queue.clone())
+    }
+
+    /// With MQTT 5, the trace context is a user property.
+    fn has_trace_context(&self) -> bool {
+        self.settings.broker.mqtt_version == MqttVersion::V5
+    }
+}
+
+impl MqttPool {
+    /// Receive commands on BROKER_CONTROL_TOPIC and parameters on BROKER_CONFIG_TOPIC, report the status on
+    /// BROKER_STATUS_TOPIC, where {client_id} is replaced with the client id, and take part in the cluster with
+    /// SIM_CLUSTER_ROLE.
+    pub fn commands(&self, control: watch::Sender<Control>) -> Option<Commands> {
+        let broker = &self.settings.broker;
+        let topic = broker
+            .control_topic
+            .as_ref()
+            .map(|topic| topic.replace("{client_id}", &broker.client_id));
+        let config_topic = broker
+            .config_topic
+            .as_ref()
+            .map(|topic| topic.replace("{client_id}", &broker.client_id));
+        let cluster = self
+            .settings
+            .sim
+            .cluster_role
+            .map(|role| Cluster::new(role, &broker.cluster_topic, &broker.client_id));
+        let status_topic = broker
+            .status_topic
+            .as_ref()
+            .map(|topic| topic.replace("{client_id}", &broker.client_id));
+        if topic.is_none() && config_topic.is_none() && cluster.is_none() && status_topic.is_none()
+        {
+            return None;
+        }
+        let (acknowledgements, acknowledged) = mpsc::unbounded_channel();
+        Some(Commands {
+            client: self.clients[0].clone(),
+            topic,
+            config_topic,
+            status_topic,
+            status_secs: broker.status_secs,
+            acknowledgements,
+            acknowledged: Some(acknowledged),
+            cluster,
+            control,
+        })
+    }
+}
+
+/// The connection of a device. Batches that mix devices are spread round robin.
+fn connection(device: Option<&str>, next: &mut usize, connections: usize) -> usize {
+    match device {
+        Some(device) => {
+            let mut hasher = DefaultHasher::new();
+            device.hash(&mut hasher);
+            (hasher.finish() % connections as u64) as usize
+        }
+        None => {
+            *next = (*next + 1) % connections;
+            *next
+        }
+    }
+}
+
+/// The client ids of the connections. A single connection uses the configured client id as is.
+fn client_ids(client_id: &str, suffix: &str, connections: usize) -> Vec<String> {
+    if connections == 1 {
+        return vec![client_id.to_string()];
+    }
+    (0..connections)
+        .map(|n| format!("{}{}", client_id, suffix.replace("{n}", &n.to_string())))
+        .collect()
+}
+
+/// What differs between the connections of a pool.
+struct ConnectionConfig {
+    client_id: String,
+    /// The device of the connection with BROKER_CONNECTION_PER_DEVICE.
+    device: Option<String>,
+    user: String,
+    pass: String,
+    will: Option<LastWill>,
+}
+
+/// The connections with BROKER_CONNECTIONS. Only the first connection has the last will, so that the broker sees one
+/// presence message.
+fn pool_connections(settings: &Settings) -> Vec<ConnectionConfig> {
+    let broker = &settings.broker;
+    let connections = broker.connections.max(1);
+    let ids = client_ids(&broker.client_id, &broker.client_id_suffix, connections);
+    ids.into_iter()
+        .enumerate()
+        .map(|(n, client_id)| ConnectionConfig {
+            client_id,
+            device: None,
+            user: broker.user.clone(),
+            pass: broker.pass.clone(),
+            will: if n == 0 { last_will(settings) } else { None },
+        })
+        .collect()
+}
+
+/// One connection per device, where {device} in the client id, the credentials and the last will is replaced with
+/// the name of the device.
+fn device_connections(settings: &Settings, devices: &[String]) -> Vec<ConnectionConfig> {
+    let broker = &settings.broker;
+    devices
+        .iter()
+        .map(|device| ConnectionConfig {
+            client_id: broker.device_client_id.replace("{device}", device),
+            device: Some(device.clone()),
+            user: broker.user.replace("{device}", device),
+            pass: broker.pass.replace("{device}", device),
+            will: last_will(settings).map(|will| device_will(will, device)),
+        })
+        .collect()
+}
+
+fn device_will(will: LastWill, device: &str) -> LastWill {
+    let message = String::from_utf8_lossy(&will.message).replace("{device}", device);
+    LastWill::new(
+        will.topic.replace("{device}", device),
+        message,
+        will.qos,
+        will.retain,
+    )
+}
+
+/// Create the connections to the broker based on the settings.
+pub async fn connect_pool(
+    settings: Arc<Settings>,
+    devices: &[String],
+) -> (MqttPool, Vec<Connection>) {
+    if settings.broker.loopback_topic.is_some() {
+        latency::enable();
+    }
+    let connections = if settings.broker.connection_per_device {
+        device_connections(&settings, devices)
+    } else {
+        pool_connections(&settings)
+    };
+
+    let mut pool = MqttPool {
+        clients: Vec::new(),
+        devices: HashMap::new(),
+        wills: Vec::new(),
+        next: 0,
+        queue: Queue::new(connections.len(), settings.capacity),
+        settings: settings.clone(),
+    };
+    if settings.broker.connection_per_device {
+        pool.devices = devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| (device.clone(), index))
+            .collect();
+    }
+    let mut eventloops = Vec::new();
+    for (index, connection) in connections.into_iter().enumerate() {
+        if let Some(will) = &connection.will {
+            pool.wills.push((index, will.clone()));
+        }
+        let device = connection.device.clone();
+        let (client, eventloop) = create_mqtt_client(&settings, connection).await;
+        // The first connection receives the own messages back to measure their latency.
+        let loopback = settings
+            .broker
+            .loopback_topic
+            .as_ref()
+            .filter(|_| index == 0)
+            .map(|topic| (client.clone(), topic.clone()));
+        pool.clients.push(client);
+        eventloops.push(Connection {
+            eventloop,
+            device,
+            queue: pool.queue.clone(),
+            index,
+            loopback,
+            acks: Acks::new(),
+        });
+    }
+    (pool, eventloops)
+}
+
+impl MqttClient {
+    /// Publish a message with the configured QoS. With MQTT 5, the configured user properties, the device that sent
+    /// the message, the run and with OTLP_TRACEPARENT the trace context of the run are attached as user properties,
+    /// and topics are replaced by topic aliases once they have one.
+    async fn publish(
+        &mut self,
+        topic: String,
+        data: Vec<u8>,
+        device: Option<&str>,
+        run: usize,
+        settings: &Settings,
+    ) -> Result<(), String> {
+        let qos = get_qos(settings.broker.qos);
+        let retain = settings.sim.payload.is_retained(&topic);
+        match self {
+            MqttClient::V3(client) => client
+                .publish(topic, qos, retain, data)
+                .await
+                .map_err(|e| e.to_string()),
+            MqttClient::V5 { client, aliases } => {
+                let mut user_properties = settings.broker.user_properties.clone();
+                if let Some(device) = device {
+                    user_properties.push(("device".to_string(), device.to_string()));
+                }
+                user_properties.push(("run".to_string(), run.to_string()));
+                if let Some(traceparent) = settings
+                    .otlp
+                    .traceparent
+                    .then(observability::traceparent)
+                    .flatten()
+                {
+                    user_properties.push(("traceparent".to_string(), traceparent));
+                }
+                let (topic, topic_alias) = aliases.alias(topic, settings.broker.topic_aliases);
+                let properties = PublishProperties {
+                    message_expiry_interval: settings.broker.message_expiry_secs,
+                    topic_alias,
+                    user_properties,
+                    ..PublishProperties::default()
+                };
+                client
+                    .publish_with_properties(topic, qos_v5(qos), retain, data, properties)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    async fn publish_will(&self, will: LastWill) {
+        self.publish_plain(will.topic, will.qos, will.retain, will.message.to_vec())
+            .await;
+    }
+
+    /// Publish a message of the simulator itself, without the properties of the simulated messages.
+    async fn publish_plain(&self, topic: String, qos: QoS, retain: bool, message: Vec<u8>) {
+        let result = match self {
+            MqttClient::V3(client) => client
+                .publish(topic, qos, retain, message)
+                .await
+                .map_err(|e| e.to_string()),
+            MqttClient::V5 { client, .. } => client
+                .publish(topic, qos_v5(qos), retain, message)
+                .await
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            warn!(error = e, "Failed to publish");
+        }
+    }
+
+    fn subscribe(&self, topic: &str) {
+        let result = match self {
+            MqttClient::V3(client) => client
+                .try_subscribe(topic, QoS::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+            MqttClient::V5 { client, .. } => client
+                .try_subscribe(topic, QoSV5::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+        };
+        match result {
+            Ok(_) => info!(topic, "Subscribed."),
+            Err(e) => warn!(error = e, topic, "Failed to subscribe"),
+        }
+    }
+
+    async fn disconnect(&self) {
+        let result = match self {
+            MqttClient::V3(client) => client.disconnect().await.map_err(|e| e.to_string()),
+            MqttClient::V5 { client, .. } => client.disconnect().await.map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            warn!(error = e, "Failed to disconnect");
+        }
+    }
+}
+
+/// Replace the topic by its alias once it has one. New topics get aliases until all aliases are used.
+fn topic_alias(
+    aliases: &mut HashMap<String, u16>,
+    topic: String,
+    max: u16,
+) -> (String, Option<u16>) {
+    if let Some(alias) = aliases.get(&topic) {
+        return (String::new(), Some(*alias));
+    }
+    if aliases.len() < max as usize {
+        let alias = aliases.len() as u16 + 1;
+        aliases.insert(topic.clone(), alias);
+        return (topic, Some(alias));
+    }
+    (topic, None)
+}
+
+pub fn get_qos(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => panic!("Invalid QoS level."),
+    }
+}
+
+fn qos_v5(qos: QoS) -> QoSV5 {
+    match qos {
+        QoS::AtMostOnce => QoSV5::AtMostOnce,
+        QoS::AtLeastOnce => QoSV5::AtLeastOnce,
+        QoS::ExactlyOnce => QoSV5::ExactlyOnce,
+    }
+}
+
+/// Listen to all connections until they are disconnected or one of them fails. The commands are received through the
+/// first connection.
+pub async fn listen_all(
+    settings: &Settings,
+    eventloops: Vec<Connection>,
+    commands: Option<Commands>,
+) {
+    let metering = Metering::new(settings);
+    CONNECTIONS.store(eventloops.len(), Ordering::Relaxed);
+    let churns = churns(&settings.broker, eventloops.len());
+    let mut listeners: FuturesUnordered<_> = eventloops
+        .into_iter()
+        .zip(churns)
+        .enumerate()
+        .map(|(index, (connection, churn))| {
+            let commands = commands.as_ref().filter(|_| index == 0);
+            listen(&settings.broker, connection, churn, &metering, commands)
+        })
+        .collect();
+    while let Some(disconnected) = listeners.next().await {
+        if !disconnected {
+            return;
+        }
+    }
+}
+
+/// The event loop of a connection to the broker.
+pub struct Connection {
+    eventloop: MqttEventLoop,
+    /// The device of the connection with BROKER_CONNECTION_PER_DEVICE.
+    device: Option<String>,
+    /// The queue of the pool and the index of the connection in it.
+    queue: Queue,
+    index: usize,
+    /// The client and the topic filter of the subscription to the own messages with BROKER_LOOPBACK_TOPIC.
+    loopback: Option<(MqttClient, String)>,
+    /// The messages that wait for an acknowledgement from the broker.
+    acks: Acks,
+}
+
+impl Connection {
+    /// Read the password from BROKER_PASS_FILE again before reconnecting, so that a rotated secret is used. The old
+    /// password is kept if the file cannot be read, for example while the secret is being updated.
+    fn rotate_password(&mut self, broker: &BrokerSettings) {
+        let Some(file) = &broker.pass_file else {
+            return;
+        };
+        // With Azure IoT Hub, the password is a token from the device key.
+        if broker.azure_key.is_some() {
+            return;
+        }
+        let pass = match read_secret(file) {
+            Ok(pass) => pass,
+            Err(e) => {
+                warn!(error = e, "Cannot read the password");
+                return;
+            }
+        };
+        let pass = match &self.device {
+            Some(device) => pass.replace("{device}", device),
+            None => pass,
+        };
+        let credentials = match &self.eventloop {
+            MqttEventLoop::V3(eventloop) => eventloop.mqtt_options.credentials(),
+            MqttEventLoop::V5 { eventloop, .. } => eventloop.options.credentials(),
+        };
+        let Some((user, old)) = credentials else {
+            return;
+        };
+        if old == pass {
+            return;
+        }
+        info!("Using the rotated password.");
+        match &mut self.eventloop {
+            MqttEventLoop::V3(eventloop) => {
+                eventloop.mqtt_options.set_credentials(user, pass);
+            }
+            MqttEventLoop::V5 { eventloop, .. } => {
+                eventloop.options.set_credentials(user, pass);
+            }
+        }
+    }
+}
+
+/// What happened on a connection.
+enum Polled {
+    Connected,
+    /// The connection was closed, by the simulator if true, otherwise by the broker.
+    Disconnected(bool),
+    /// The connection failed, with the error and its kind.
+    Failed(String, &'static str),
+    /// A message from the broker with topic and payload.
+    Message(String, Vec<u8>),
+    /// A message was sent, with the packet id if it has QoS 1 or 2.
+    Sent(u16),
+    /// A message with QoS 1 or 2 was acknowledged by the broker, with the packet id.
+    Acknowledged(u16),
+    Other,
+}
+
+impl MqttEventLoop {
+    async fn poll(&mut self) -> Polled {
+        match self {
+            MqttEventLoop::V3(eventloop) => match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Polled::Connected,
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    Polled::Message(publish.topic, publish.payload.to_vec())
+                }
+                Ok(Event::Incoming(Packet::Disconnect)) => {
+                    warn!("Disconnected from the broker.");
+                    Polled::Disconnected(false)
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    info!("Disconnected.");
+                    Polled::Disconnected(true)
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Polled::Sent(pkid),
+                Ok(Event::Incoming(Packet::PubAck(ack))) => Polled::Acknowledged(ack.pkid),
+                Ok(Event::Incoming(Packet::PubComp(comp))) => Polled::Acknowledged(comp.pkid),
+                Ok(x) => {
+                    trace!(message = ?x, "Received message");
+                    Polled::Other
+                }
+                Err(e) => Polled::Failed(e.to_string(), error_kind(&e)),
+            },
+            MqttEventLoop::V5 {
+                eventloop,
+                alias_max,
+            } => match eventloop.poll().await {
+                Ok(v5::Event::Incoming(PacketV5::ConnAck(connack))) => {
+                    // Without a topic alias maximum, the broker does not accept any topic aliases.
+                    let max = connack
+                        .properties
+                        .and_then(|properties| properties.topic_alias_max);
+                    alias_max.send_replace(max.unwrap_or(0));
+                    Polled::Connected
+                }
+                Ok(v5::Event::Incoming(PacketV5::Publish(publish))) => Polled::Message(
+                    String::from_utf8_lossy(&publish.topic).to_string(),
+                    publish.payload.to_vec(),
+                ),
+                Ok(v5::Event::Incoming(PacketV5::Disconnect(disconnect))) => {
+                    warn!(reason = ?disconnect.reason_code, "Disconnected from the broker.");
+                    Polled::Disconnected(false)
+                }
+                Ok(v5::Event::Outgoing(Outgoing::Disconnect)) => {
+                    info!("Disconnected.");
+                    Polled::Disconnected(true)
+                }
+                Ok(v5::Event::Outgoing(Outgoing::Publish(pkid))) => Polled::Sent(pkid),
+                Ok(v5::Event::Incoming(PacketV5::PubAck(ack))) => Polled::Acknowledged(ack.pkid),
+                Ok(v5::Event::Incoming(PacketV5::PubComp(comp))) => Polled::Acknowledged(comp.pkid),
+                Ok(x) => {
+                    trace!(message = ?x, "Received message");
+                    Polled::Other
+                }
+                Err(e) => Polled::Failed(e.to_string(), error_kind_v5(&e)),
+            },
+        }
+    }
+
+    /// Drop the network connection without saying goodbye, like a device that loses power or network. The next poll
+    /// reconnects, and the messages that were not acknowledged are sent again.
+    fn drop_connection(&mut self) {
+        match self {
+            MqttEventLoop::V3(eventloop) => eventloop.clean(),
+            MqttEventLoop::V5 { eventloop, .. } => eventloop.clean(),
+        }
+    }
+}
+
+/// The kind of a connection error, as a label of the metrics.
+fn error_kind(error: &ConnectionError) -> &'static str {
+    match error {
+        ConnectionError::MqttState(_) => "state",
+        ConnectionError::NetworkTimeout | ConnectionError::FlushTimeout => "timeout",
+        ConnectionError::Tls(_) => "tls",
+        ConnectionError::Io(_) => "io",
+        ConnectionError::ConnectionRefused(_) => "refused",
+        ConnectionError::NotConnAck(_) => "protocol",
+        ConnectionError::RequestsDone => "closed",
+        ConnectionError::InvalidUrl(_) => "url",
+        ConnectionError::Websocket(_)
+        | ConnectionError::WsConnect(_)
+        | ConnectionError::ResponseValidation(_) => "websocket",
+    }
+}
+
+fn error_kind_v5(error: &v5::ConnectionError) -> &'static str {
+    match error {
+        v5::ConnectionError::MqttState(_) => "state",
+        v5::ConnectionError::Timeout(_) => "timeout",
+        v5::ConnectionError::Tls(_) => "tls",
+        v5::ConnectionError::Io(_) => "io",
+        v5::ConnectionError::ConnectionRefused(_) => "refused",
+        v5::ConnectionError::NotConnAck(_) => "protocol",
+        v5::ConnectionError::RequestsDone => "closed",
+        v5::ConnectionError::InvalidUrl(_) => "url",
+        v5::ConnectionError::Websocket(_)
+        | v5::ConnectionError::WsConnect(_)
+        | v5::ConnectionError::ResponseValidation(_) => "websocket",
+    }
+}
+
+/// Count a connection as connected or not, once for each change.
+fn count_connected(up: &mut bool, connected: bool, metering: &Metering) {
+    if *up == connected {
+        return;
+    }
+    *up = connected;
+    metering.record_connection(connected);
+    if connected {
+        CONNECTED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        CONNECTED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn count_error(kind: &'static str, metering: &Metering) {
+    CONNECTION_ERRORS.fetch_add(1, Ordering::Relaxed);
+    metering.record_connection_error(kind);
+}
+
+/// Listen for incoming messages and handle them. If I don't handle the incoming messages, sending messages will block.
+/// Failed connections are reconnected with BROKER_RECONNECT, otherwise only connections that were dropped on purpose.
+/// Returns true if the simulator disconnected, false if the broker disconnected or the connection failed.
+async fn listen(
+    broker: &BrokerSettings,
+    mut connection: Connection,
+    mut churn: Churn,
+    metering: &Metering,
+    commands: Option<&Commands>,
+) -> bool {
+    let mut connected = false;
+    // Whether the connection is currently up, while connected is whether it ever was.
+    let mut up = false;
+    // The number of attempts to reconnect since the connection was lost.
+    let mut reconnect = None;
+    loop {
+        let polled = tokio::select! {
+            polled = connection.eventloop.poll() => polled,
+            _ = churn.wait() => {
+                info!("Dropping the connection.");
+                count_connected(&mut up, false, metering);
+                connection.eventloop.drop_connection();
+                connection.rotate_password(broker);
+                sleep(reconnect_delay(broker, 0)).await;
+                reconnect = Some(1);
+                continue;
+            }
+        };
+        match &polled {
+            Polled::Connected => count_connected(&mut up, true, metering),
+            Polled::Disconnected(by_simulator) => {
+                count_connected(&mut up, false, metering);
+                if !by_simulator {
+                    count_error("disconnected", metering);
+                }
+            }
+            Polled::Failed(_, kind) => {
+                count_connected(&mut up, false, metering);
+                count_error(kind, metering);
+            }
+            _ => (),
+        }
+        match (polled, reconnect) {
+            (Polled::Connected, _) => {
+                if connected {
+                    info!("Reconnected.");
+                    metering.record_reconnect();
+                }
+                connected = true;
+                reconnect = None;
+                // The subscription is gone after a reconnect with a clean session.
+                if let Some(commands) = commands {
+                    commands.subscribe();
+                }
+                if let Some((client, topic)) = &connection.loopback {
+                    client.subscribe(topic);
+                }
+            }
+            (Polled::Disconnected(true), _) => return true,
+            (Polled::Disconnected(false), _) if !broker.reconnect => return false,
+            (Polled::Disconnected(false), _) => {
+                connection.rotate_password(broker);
+                sleep(reconnect_delay(broker, 0)).await;
+                reconnect = Some(1);
+            }
+            (Polled::Failed(error, _), None) if !broker.reconnect => {
+                warn!(error, "Failed to connect");
+                return false;
+            }
+            (Polled::Failed(error, _), attempt) => {
+                let attempt = attempt.unwrap_or(0);
+                warn!(error, attempt, "Failed to connect, retrying");
+                connection.rotate_password(broker);
+                sleep(reconnect_delay(broker, attempt)).await;
+                reconnect = Some(attempt + 1);
+            }
+            (Polled::Message(topic, payload), _) => {
+                if let Some(latency) =
+                    latency::loopback().and_then(|loopback| loopback.receive(&payload))
+                {
+                    metering.record_loopback_latency(latency);
+                } else if let Some(commands) = commands {
+                    commands.receive(&topic, &payload);
+                }
+            }
+            // With QoS 0, a message is delivered once it is sent.
+            (Polled::Sent(pkid), _) => {
+                if broker.qos == 0 {
+                    connection.queue.pop(connection.index);
+                }
+                if pkid > 0 {
+                    connection.acks.send(pkid);
+                }
+            }
+            (Polled::Acknowledged(pkid), _) => {
+                connection.queue.pop(connection.index);
+                if let Some(latency) = connection.acks.acknowledge(pkid) {
+                    metering.record_ack_latency(latency);
+                }
+            }
+            (Polled::Other, _) => (),
+        }
+    }
+}
+
+/// Receives commands for the simulation on the control topic, parameters on the config topic and the messages of the
+/// cluster.
+pub struct Commands {
+    client: MqttClient,
+    topic: Option<String>,
+    config_topic: Option<String>,
+    status_topic: Option<String>,
+    status_secs: u64,
+    /// The received commands, which are acknowledged on the status topic.
+    acknowledgements: mpsc::UnboundedSender<Command>,
+    acknowledged: Option<mpsc::UnboundedReceiver<Command>>,
+    cluster: Option<Cluster>,
+    control: watch::Sender<Control>,
+}
+
+impl Commands {
+    fn subscribe(&self) {
+        if let Some(topic) = &self.topic {
+            self.subscribe_to(topic);
+        }
+        if let Some(topic) = &self.config_topic {
+            self.subscribe_to(topic);
+        }
+        if let Some(cluster) = &self.cluster {
+            self.subscribe_to(&cluster.subscription());
+        }
+    }
+
+    /// Subscribe without waiting, since the listener would have to make room for the request.
+    fn subscribe_to(&self, topic: &str) {
+        self.client.subscribe(topic);
+    }
+
+    fn receive(&self, topic: &str, payload: &[u8]) {
+        if let Some(cluster) = &self.cluster {
+            cluster.receive(topic, payload, &self.control);
+        }
+        let command = if self.topic.as_deref() == Some(topic) {
+            String::from_utf8_lossy(payload).parse::<Command>()
+        } else if self.config_topic.as_deref() == Some(topic) && !payload.is_empty() {
+            Command::from_parameters(payload)
+        } else {
+            return;
+        };
+        match command {
+            Ok(command) => {
+                info!(?command, "Received command.");
+                self.control
+                    .send_modify(|control| control.apply(command.clone()));
+                // Nobody listens without a status topic.
+                let _ = self.acknowledgements.send(command);
+            }
+            Err(e) => warn!(error = e, "Ignoring command"),
+        }
+    }
+
+    /// Publish the status retained to the status topic every BROKER_STATUS_SECS seconds and right after each command,
+    /// which is acknowledged in the status, until the simulation is stopped. The status includes the health of the
+    /// connections to the broker.
+    pub fn report(&mut self, progress: watch::Receiver<Progress>) -> impl Future<Output = ()> {
+        let client = self.client.clone();
+        let topic = self.status_topic.clone();
+        let status_secs = self.status_secs;
+        let control = self.control.clone();
+        let acknowledged = self.acknowledged.take();
+        async move {
+            let (Some(topic), Some(mut acknowledged)) = (topic, acknowledged) else {
+                return;
+            };
+            let mut interval = tokio::time::interval(Duration::from_secs(status_secs.max(1)));
+            loop {
+                let command = tokio::select! {
+                    command = acknowledged.recv() => command,
+                    _ = interval.tick() => None,
+                };
+                let status = Status {
+                    broker: Some(broker_health()),
+                    acknowledged: command,
+                    ..Status::new(&control, &progress)
+                };
+                // It's OK to unwrap, since the status is always valid JSON.
+                let message = serde_json::to_vec(&status).unwrap();
+                client
+                    .publish_plain(topic.clone(), QoS::AtLeastOnce, true, message)
+                    .await;
+                if status.control.state == State::Stopped {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Publish the control state as the leader of the cluster whenever it changes, or the progress as a worker, until
+    /// the simulation is stopped. The leader clears the retained state at the end, so that the workers of the next
+    /// cluster run do not stop right away.
+    pub fn coordinate(&self, mut progress: watch::Receiver<Progress>) -> impl Future<Output = ()> {
+        let client = self.client.clone();
+        let cluster = self.cluster.clone();
+        let mut control = self.control.subscribe();
+        async move {
+            let Some(cluster) = cluster else {
+                return;
+            };
+            match cluster.role {
+                Role::Leader => loop {
+                    let current = control.borrow_and_update().clone();
+                    // It's OK to unwrap, since the control state is always valid JSON.
+                    let message = serde_json::to_vec(&current).unwrap();
+                    client
+                        .publish_plain(cluster.control_topic(), QoS::AtLeastOnce, true, message)
+                        .await;
+                    if current.state == State::Stopped || control.changed().await.is_err() {
+                        client
+                            .publish_plain(
+                                cluster.control_topic(),
+                                QoS::AtLeastOnce,
+                                true,
+                                Vec::new(),
+                            )
+                            .await;
+                        return;
+                    }
+                },
+                Role::Worker => {
+                    while progress.changed().await.is_ok() {
+                        let current = progress.borrow_and_update().clone();
+                        // It's OK to unwrap, since the progress is always valid JSON.
+                        let message = serde_json::to_vec(&current).unwrap();
+                        client
+                            .publish_plain(
+                                cluster.progress_topic(),
+                                QoS::AtMostOnce,
+                                false,
+                                message,
+                            )
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn reconnect_delay(broker: &BrokerSettings, attempt: u32) -> Duration {
+    backoff(attempt, broker.reconnect_min_ms, broker.reconnect_max_ms)
+}
+
+/// The minimum delay for the first attempt, doubling up to the maximum delay.
+fn backoff(attempt: u32, min_ms: u64, max_ms: u64) -> Duration {
+    Duration::from_millis(min_ms.saturating_mul(1 << attempt.min(20)).min(max_ms))
+}
+
+/// Drops connections at random times like flapping devices or networks, to test how the broker copes with session
+/// takeovers and persistent sessions.
+enum Churn {
+    None,
+    /// Each connection drops on its own, after exponentially distributed times.
+    Connection {
+        times: Exp<f64>,
+        next: Instant,
+    },
+    /// All connections drop together, like with a network outage.
+    Global(broadcast::Receiver<()>),
+}
+
+impl Churn {
+    /// Wait until the connection should be dropped. Cancelling the wait keeps the time of the next drop.
+    async fn wait(&mut self) {
+        match self {
+            Churn::None => pending().await,
+            Churn::Connection { times, next } => {
+                sleep_until(*next).await;
+                *next = Instant::now() + random_duration(times);
+            }
+            Churn::Global(receiver) => {
+                if let Err(RecvError::Closed) = receiver.recv().await {
+                    pending().await
+                }
+            }
+        }
+    }
+}
+
+fn random_duration(times: &Exp<f64>) -> Duration {
+    Duration::from_secs_f64(times.sample(&mut rand::thread_rng()))
+}
+
+/// The churn of each connection with BROKER_CHURN_SECS as the mean time between the drops.
+fn churns(broker: &BrokerSettings, connections: usize) -> Vec<Churn> {
+    let Some(secs) = broker.churn_secs else {
+        return (0..connections).map(|_| Churn::None).collect();
+    };
+    // It's OK to panic, since the settings only accept a positive BROKER_CHURN_SECS.
+    let times = Exp::new(1.0 / secs).unwrap();
+    if !broker.churn_global {
+        return (0..connections)
+            .map(|_| Churn::Connection {
+                times,
+                next: Instant::now() + random_duration(&times),
+            })
+            .collect();
+    }
+    let (sender, _) = broadcast::channel(1);
+    let churns = (0..connections)
+        .map(|_| Churn::Global(sender.subscribe()))
+        .collect();
+    tokio::spawn(async move {
+        loop {
+            sleep(random_duration(&times)).await;
+            if sender.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    churns
+}
+
+/// Create an MQTT connection based on the settings.
+async fn create_mqtt_client(
+    settings: &Settings,
+    connection: ConnectionConfig,
+) -> (MqttClient, MqttEventLoop) {
+    let broker = &settings.broker;
+    let ConnectionConfig {
+        client_id,
+        device: _,
+        user,
+        pass,
+        will,
+    } = connection;
+    let client_id = client_id.as_str();
+    let url = format!("{}?client_id={}", broker.url, client_id);
+    let keep_alive = Duration::from_secs(broker.keep_alive_secs);
+    // With WebSockets, rumqttc expects the complete URL including the path as broker address.
+    let websocket = is_websocket(&broker.url);
+    match broker.mqtt_version {
+        MqttVersion::V3 => {
+            let mut opts = MqttOptions::parse_url(url).unwrap();
+            let (host, port) = opts.broker_address();
+            if websocket {
+                let transport = opts.transport();
+                opts = MqttOptions::new(client_id, &broker.url, port);
+                opts.set_transport(transport);
+            }
+            if let Some(transport) = tls_transport(broker, websocket) {
+                opts.set_transport(transport);
+            }
+            let (user, pass) = credentials(broker, &host, client_id, user, pass);
+            opts.set_credentials(user, pass);
+            opts.set_keep_alive(keep_alive);
+            opts.set_clean_session(broker.clean_session);
+            opts.set_inflight(broker.max_inflight);
+            opts.set_max_packet_size(broker.max_packet_size, broker.max_packet_size);
+            if let Some(will) = will {
+                opts.set_last_will(will);
+            }
+            let (client, eventloop) = AsyncClient::new(opts, settings.capacity);
+            (
+                MqttClient::V3(client),
+                MqttEventLoop::V3(Box::new(eventloop)),
+            )
+        }
+        MqttVersion::V5 => {
+            let mut opts = v5::MqttOptions::parse_url(url).unwrap();
+            let (host, port) = opts.broker_address();
+            if websocket {
+                let transport = opts.transport();
+                opts = v5::MqttOptions::new(client_id, &broker.url, port);
+                opts.set_transport(transport);
+            }
+            if let Some(transport) = tls_transport(broker, websocket) {
+                opts.set_transport(transport);
+            }
+            let (user, pass) = credentials(broker, &host, client_id, user, pass);
+            opts.set_credentials(user, pass);
+            opts.set_keep_alive(keep_alive);
+            opts.set_clean_start(broker.clean_session);
+            opts.set_outgoing_inflight_upper_limit(broker.max_inflight);
+            opts.set_max_packet_size(Some(broker.max_packet_size as u32));
+            if let Some(will) = will {
+                opts.set_last_will(LastWillV5::new(
+                    will.topic,
+                    will.message,
+                    qos_v5(will.qos),
+                    will.retain,
+                    None,
+                ));
+            }
+            let (client, eventloop) = v5::AsyncClient::new(opts, settings.capacity);
+            let (alias_max, aliases) = watch::channel(0);
+            let client = MqttClient::V5 {
+                client,
+                aliases: TopicAliases::new(aliases),
+            };
+            let eventloop = MqttEventLoop::V5 {
+                eventloop: Box::new(eventloop),
+                alias_max,
+            };
+            (client, eventloop)
+        }
+    }
+}
+
+fn is_websocket(url: &str) -> bool {
+    url.starts_with("ws://") || url.starts_with("wss://")
+}
+
+/// A SAS token for Azure IoT Hub, if a device key is configured, otherwise user and password.
+fn credentials(
+    broker: &BrokerSettings,
+    host: &str,
+    client_id: &str,
+    user: String,
+    pass: String,
+) -> (String, String) {
+    match &broker.azure_key {
+        Some(key) => {
+            let expiry = Utc::now() + chrono::Duration::seconds(broker.azure_token_secs as i64);
+            // It's OK to panic, since the simulator cannot connect without a valid token.
+            let token = azure::sas_token(host, client_id, key, expiry)
+                .unwrap_or_else(|e| panic!("Cannot create SAS token: {}", e));
+            (azure::user_name(host, client_id), token)
+        }
+        None => (user, pass),
+    }
+}
+
+/// The NDEATH message with Sparkplug B, otherwise the configured last will, if any.
+fn last_will(settings: &Settings) -> Option<LastWill> {
+    let broker = &settings.broker;
+    // The settings check that Sparkplug B is not combined with BROKER_LWT_TOPIC.
+    if let Some(node) = settings.sim.payload.sparkplug_node(&broker.client_id) {
+        let (topic, data) = node.death();
+        return Some(LastWill::new(topic, data, QoS::AtLeastOnce, false));
+    }
+    let topic = broker.lwt_topic.as_ref()?;
+    Some(LastWill::new(
+        topic,
+        broker.lwt_payload.as_bytes().to_vec(),
+        get_qos(broker.lwt_qos),
+        broker.lwt_retain,
+    ))
+}
+
+/// TLS with a custom CA, client certificate or ALPN protocols, if configured, with the root certificates of the system
+/// unless there is a custom CA.
+/// For example, AWS IoT Core accepts client certificates on port 443 with the ALPN protocol x-amzn-mqtt-ca.
+fn tls_transport(broker: &BrokerSettings, websocket: bool) -> Option<Transport> {
+    if broker.ca_file.is_none() && broker.cert_file.is_none() && broker.alpn.is_none() {
+        return None;
+    }
+    let ca = broker.ca_file.as_deref().map(read_file);
+    // The settings check that the certificate and the key are set together.
+    let client_auth = match (&broker.cert_file, &broker.key_file) {
+        (Some(cert), Some(key)) => Some((read_file(cert), read_file(key))),
+        _ => None,
+    };
+    let alpn = broker.alpn.as_ref().map(|alpn| {
+        alpn.split(',')
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect()
+    });
+    let client_auth = client_auth
+        .as_ref()
+        .map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+    // It's OK to panic, since the simulator cannot connect with broken certificates.
+    let config = tls::client_config(ca.as_deref(), client_auth, alpn)
+        .unwrap_or_else(|e| panic!("Invalid TLS configuration: {}", e));
+    if websocket {
+        Some(Transport::wss_with_config(config.into()))
+    } else {
+        Some(Transport::tls_with_config(config.into()))
+    }
+}
+
+/// It's OK to panic, since the simulator cannot connect without the files.
+fn read_file(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mqtt_version() {
+        assert_eq!("3.1.1".parse(), Ok(MqttVersion::V3));
+        assert_eq!("5".parse(), Ok(MqttVersion::V5));
+        assert!("4".parse::<MqttVersion>().is_err());
+    }
+
+    #[test]
+    fn test_is_websocket() {
+        assert!(is_websocket("ws://localhost:8080/mqtt"));
+        assert!(is_websocket("wss://broker.example.com/mqtt"));
+        assert!(!is_websocket("mqtts://broker.example.com"));
+    }
+
+    #[test]
+    fn test_client_ids() {
+        assert_eq!(client_ids("sim", "-{n}", 1), vec!["sim"]);
+        assert_eq!(client_ids("sim", "-{n}", 2), vec!["sim-0", "sim-1"]);
+        assert_eq!(client_ids("sim", "_c{n}", 1), vec!["sim"]);
+    }
+
+    #[test]
+    fn test_device_will() {
+        let will = LastWill::new(
+            "status/{device}",
+            "{device} offline",
+            QoS::AtLeastOnce,
+            true,
+        );
+        let will = device_will(will, "dev_1");
+        assert_eq!(will.topic, "status/dev_1");
+        assert_eq!(will.message, "dev_1 offline".as_bytes());
+        assert!(will.retain);
+    }
+
+    #[test]
+    fn test_connection() {
+        let mut next = 0;
+        let first = connection(Some("dev_1"), &mut next, 4);
+        assert!(first < 4);
+        assert_eq!(connection(Some("dev_1"), &mut next, 4), first);
+        assert_eq!(connection(None, &mut next, 4), 1);
+        assert_eq!(connection(None, &mut next, 4), 2);
+        assert_eq!(connection(Some("dev_1"), &mut next, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let (control, controlled) = watch::channel(Control::new(false, 60.0, 10));
+        let (acknowledgements, mut acknowledged) = mpsc::unbounded_channel();
+        let commands = Commands {
+            client: MqttClient::V3(client),
+            topic: Some("rumsim/test/cmd".to_string()),
+            config_topic: Some("rumsim/test/config".to_string()),
+            status_topic: None,
+            status_secs: 60,
+            acknowledgements,
+            acknowledged: None,
+            cluster: None,
+            control,
+        };
+        commands.receive("other", b"stop");
+        commands.receive("rumsim/test/cmd", b"jump");
+        assert_eq!(*controlled.borrow(), Control::new(false, 60.0, 10));
+        commands.receive("rumsim/test/cmd", b"pause");
+        commands.receive("rumsim/test/cmd", br#"{"command":"set","runs":20}"#);
+        assert_eq!(*controlled.borrow(), Control::new(true, 60.0, 20));
+        assert_eq!(acknowledged.try_recv(), Ok(Command::Pause));
+        commands.receive(
+            "rumsim/test/config",
+            br#"{"frequency_secs":5,"data_points":50}"#,
+        );
+        assert_eq!(
+            *controlled.borrow(),
+            Control {
+                data_points: Some(50),
+                ..Control::new(true, 5.0, 20)
+            }
+        );
+    }
+
+    #[test]
+    fn test_broker_health() {
+        let metering = Metering::new(&Settings::from_variables(&[]).unwrap());
+        let before = broker_health();
+        let (mut first, mut second) = (false, false);
+        count_connected(&mut first, true, &metering);
+        count_connected(&mut first, true, &metering);
+        count_connected(&mut second, true, &metering);
+        count_connected(&mut second, false, &metering);
+        count_error("refused", &metering);
+        let after = broker_health();
+        assert_eq!(after.connected - before.connected, 1);
+        assert_eq!(after.errors - before.errors, 1);
+        assert_eq!(error_kind(&ConnectionError::NetworkTimeout), "timeout");
+        let io = || std::io::Error::other("reset");
+        assert_eq!(error_kind(&ConnectionError::Io(io())), "io");
+        assert_eq!(error_kind_v5(&v5::ConnectionError::Io(io())), "io");
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0, 100, 30000), Duration::from_millis(100));
+        assert_eq!(backoff(3, 100, 30000), Duration::from_millis(800));
+        assert_eq!(backoff(50, 100, 30000), Duration::from_millis(30000));
+    }
+
+    #[test]
+    fn test_topic_alias() {
+        let mut aliases = HashMap::new();
+        let alias = |aliases: &mut HashMap<String, u16>, topic: &str| {
+            topic_alias(aliases, topic.to_string(), 2)
+        };
+        assert_eq!(alias(&mut aliases, "a"), ("a".to_string(), Some(1)));
+        assert_eq!(alias(&mut aliases, "b"), ("b".to_string(), Some(2)));
+        assert_eq!(alias(&mut aliases, "c"), ("c".to_string(), None));
+        assert_eq!(alias(&mut aliases, "a"), (String::new(), Some(1)));
+        assert_eq!(alias(&mut aliases, "c"), ("c".to_string(), None));
+    }
+
+    #[test]
+    fn test_topic_aliases() {
+        let (alias_max, max) = watch::channel(0);
+        let mut aliases = TopicAliases::new(max);
+        assert_eq!(aliases.alias("a".to_string(), 2), ("a".to_string(), None));
+
+        // The aliases are limited by the broker and start over with each ConnAck.
+        alias_max.send_replace(1);
+        assert_eq!(
+            aliases.alias("a".to_string(), 2),
+            ("a".to_string(), Some(1))
+        );
+        assert_eq!(aliases.alias("b".to_string(), 2), ("b".to_string(), None));
+        assert_eq!(aliases.alias("a".to_string(), 2), (String::new(), Some(1)));
+        alias_max.send_replace(1);
+        assert_eq!(
+            aliases.alias("b".to_string(), 2),
+            ("b".to_string(), Some(1))
+        );
+        assert_eq!(aliases.alias("a".to_string(), 2), ("a".to_string(), None));
+    }
+}
@@ -1,92 +1,313 @@
 use opentelemetry::{
     global::{self},
-    metrics::{Counter, Gauge, Unit},
-    Key, KeyValue,
+    metrics::{Counter, Gauge, Histogram, Unit, UpDownCounter},
+    propagation::TextMapPropagator,
+    trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceId, TraceState},
+    Context, Key, KeyValue,
 };
-use opentelemetry_otlp::{TonicExporterBuilder, WithExportConfig};
-use opentelemetry_sdk::{trace as sdktrace, Resource};
+use opentelemetry_otlp::{MetricsExporterBuilder, TonicExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::metrics::reader::{DefaultAggregationSelector, DefaultTemporalitySelector};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
 use opentelemetry_semantic_conventions::resource::SERVICE_NAME;
-use tokio::time::Duration;
+use prometheus::{Registry, TextEncoder};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::time::{Duration, Instant};
 use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::Request;
+use tracing::{warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
-use crate::CONFIG;
+use crate::settings::{read_secret, OtlpSettings, Settings};
+use crate::summary;
 
-fn new_exporter() -> TonicExporterBuilder {
-    let mut map = MetadataMap::with_capacity(1);
-    if let Some(auth) = &CONFIG.otlp_auth {
-        map.insert("authorization", auth.parse().unwrap());
+/// The meter provider is kept to export the last metrics on shutdown.
+static METER_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
+static PROMETHEUS_REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The format of the console log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of the spans, for log aggregation.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
     }
-    opentelemetry_otlp::new_exporter()
+}
+
+fn new_exporter(otlp: &OtlpSettings) -> TonicExporterBuilder {
+    let exporter = opentelemetry_otlp::new_exporter()
         .tonic()
-        .with_endpoint(CONFIG.otlp_collector.clone().unwrap())
-        .with_timeout(Duration::from_secs(3))
-        .with_metadata(map.clone())
+        .with_endpoint(otlp.collector.clone().unwrap())
+        .with_timeout(Duration::from_secs(3));
+    match &otlp.auth_file {
+        Some(file) => exporter.with_interceptor(Authorize {
+            file: file.clone(),
+            auth: otlp.auth.clone(),
+        }),
+        None => {
+            let mut map = MetadataMap::with_capacity(1);
+            if let Some(auth) = &otlp.auth {
+                map.insert("authorization", auth.parse().unwrap());
+            }
+            exporter.with_metadata(map)
+        }
+    }
+}
+
+/// Reads OTLP_AUTH_FILE for each export, so that a rotated secret is used. The authentication from startup is kept
+/// if the file cannot be read.
+#[derive(Clone)]
+struct Authorize {
+    file: String,
+    auth: Option<String>,
+}
+
+impl Interceptor for Authorize {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, tonic::Status> {
+        let auth = read_secret(&self.file).ok().or_else(|| self.auth.clone());
+        if let Some(auth) = auth.and_then(|auth| auth.parse().ok()) {
+            request.metadata_mut().insert("authorization", auth);
+        }
+        Ok(request)
+    }
+}
+
+/// Samples OTLP_SAMPLE_RATIO of the traces, and at most OTLP_SAMPLE_PER_SEC traces per second, so that the collector
+/// keeps up with short runs at high message rates. Each run is a trace of its own, and the spans and events within a
+/// trace follow the decision for it.
+#[derive(Debug, Clone)]
+struct RunSampler {
+    ratio: Sampler,
+    limit: Option<Arc<RateLimit>>,
+}
+
+impl ShouldSample for RunSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let result =
+            self.ratio
+                .should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+        match &self.limit {
+            Some(limit)
+                if result.decision == SamplingDecision::RecordAndSample && !limit.allow() =>
+            {
+                SamplingResult {
+                    decision: SamplingDecision::Drop,
+                    attributes: Vec::new(),
+                    trace_state: TraceState::default(),
+                }
+            }
+            _ => result,
+        }
+    }
 }
 
-fn init_otlp_tracing() {
-    let tracer = opentelemetry_otlp::new_pipeline()
+/// A token bucket that allows a number of traces per second on average, and up to one second of them at once.
+#[derive(Debug)]
+struct RateLimit {
+    per_sec: f64,
+    /// The available traces and when they were last refilled.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    fn new(per_sec: f64) -> Self {
+        RateLimit {
+            per_sec,
+            tokens: Mutex::new((per_sec.max(1.0), Instant::now())),
+        }
+    }
+
+    fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (available, refilled) = *tokens;
+        let available = (available + now.duration_since(refilled).as_secs_f64() * self.per_sec)
+            .min(self.per_sec.max(1.0));
+        let allowed = available >= 1.0;
+        *tokens = (if allowed { available - 1.0 } else { available }, now);
+        allowed
+    }
+}
+
+/// The sampler of the traces. Spans with a parent follow the parent, so only the runs are sampled.
+fn sampler(otlp: &OtlpSettings) -> Sampler {
+    Sampler::ParentBased(Box::new(RunSampler {
+        ratio: Sampler::TraceIdRatioBased(otlp.sample_ratio),
+        limit: otlp
+            .sample_per_sec
+            .map(|per_sec| Arc::new(RateLimit::new(per_sec))),
+    }))
+}
+
+fn otlp_tracer(otlp: &OtlpSettings) -> sdktrace::Tracer {
+    opentelemetry_otlp::new_pipeline()
         .tracing()
-        .with_exporter(new_exporter())
+        .with_exporter(new_exporter(otlp))
         .with_trace_config(
             sdktrace::config()
+                .with_sampler(sampler(otlp))
                 .with_resource(Resource::new(vec![KeyValue::new(SERVICE_NAME, "rumsim")])),
         )
         .install_batch(opentelemetry_sdk::runtime::Tokio)
-        .expect("Failed to initialize tracer.");
+        .expect("Failed to initialize tracer.")
+}
 
-    let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+/// Log to the console, and export the traces to OTLP_ENDPOINT if it is set, unless OTLP_TRACES=false. The console also
+/// shows why the simulator stopped if the last traces do not reach the collector. RUST_LOG filters both, with info by
+/// default. With
+/// LOG_FORMAT=json, each line carries the fields of the spans around it, such as the run, the devices and the broker.
+pub fn init_tracing(settings: &Settings) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let otlp = settings
+        .otlp
+        .collector
+        .as_ref()
+        .filter(|_| settings.otlp.traces)
+        .map(|_| tracing_opentelemetry::layer().with_tracer(otlp_tracer(&settings.otlp)));
+    let (text, json) = match settings.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(true),
+            ),
+        ),
+    };
     let subscriber = tracing_subscriber::registry()
-        .with(EnvFilter::from_default_env())
-        .with(layer);
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(otlp);
     tracing::subscriber::set_global_default(subscriber).unwrap();
 }
 
-fn init_stdout_tracing() {
-    let layer = tracing_subscriber::fmt::layer();
-    let subscriber = tracing_subscriber::registry()
-        .with(EnvFilter::from_default_env())
-        .with(layer);
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+/// Export the metrics to OTLP_ENDPOINT and to Prometheus with CONTROL_PORT. Without either, no meter provider is
+/// installed and the instruments of the global no-op meter discard the measurements.
+pub fn init_metering(settings: &Settings) {
+    let prometheus = settings.control_port.is_some();
+    if settings.otlp.collector.is_none() && !prometheus {
+        return;
+    }
+    let mut builder = SdkMeterProvider::builder();
+    if settings.otlp.collector.is_some() {
+        let exporter = MetricsExporterBuilder::from(new_exporter(&settings.otlp))
+            .build_metrics_exporter(
+                Box::new(DefaultTemporalitySelector::new()),
+                Box::new(DefaultAggregationSelector::new()),
+            )
+            .unwrap();
+        builder = builder.with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build());
+    }
+    // Prometheus scrapes the metrics from the HTTP API, for example for a HorizontalPodAutoscaler.
+    if prometheus {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .unwrap();
+        builder = builder.with_reader(exporter);
+        let _ = PROMETHEUS_REGISTRY.set(registry);
+    }
+    let meter_provider = builder.build();
+    let _ = METER_PROVIDER.set(meter_provider.clone());
+    global::set_meter_provider(meter_provider);
 }
 
-pub fn init_tracing() {
-    if CONFIG.otlp_collector != None {
-        init_otlp_tracing();
-    } else {
-        init_stdout_tracing();
-    };
+/// The W3C trace context of the current span as a traceparent like `00-<trace id>-<span id>-01`, so that the consumers
+/// of a message can continue the trace of the run that published it. None without OTLP_ENDPOINT, since the spans are
+/// not traced then.
+pub fn traceparent() -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+    carrier.remove("traceparent")
 }
 
-pub fn init_metering() {
-    if CONFIG.otlp_collector == None {
-        return;
+/// The metrics in the Prometheus text format, if they are exported to Prometheus.
+pub fn prometheus_metrics() -> Option<String> {
+    let registry = PROMETHEUS_REGISTRY.get()?;
+    TextEncoder::new()
+        .encode_to_string(&registry.gather())
+        .map_err(|e| warn!(error = ?e, "Failed to encode metrics"))
+        .ok()
+}
+
+/// Export the remaining traces and metrics.
+pub fn shutdown_telemetry() {
+    global::shutdown_tracer_provider();
+    if let Some(meter_provider) = METER_PROVIDER.get() {
+        let _ = meter_provider.shutdown();
     }
-    let meter_provider = opentelemetry_otlp::new_pipeline()
-        .metrics(opentelemetry_sdk::runtime::Tokio)
-        .with_exporter(new_exporter())
-        .build()
-        .unwrap();
-    global::set_meter_provider(meter_provider);
 }
 
 pub struct Metering {
     datapoint_sec: Gauge<f64>,
     capacity_percent: Gauge<f64>,
     overload_cnt: Counter<f64>,
+    payload_bytes: Counter<u64>,
+    compressed_bytes: Counter<u64>,
+    bytes_per_datapoint: Gauge<f64>,
+    reconnect_cnt: Counter<u64>,
+    publish_error_cnt: Counter<u64>,
+    skipped_runs_cnt: Counter<u64>,
+    queue_depth: Gauge<u64>,
+    dropped_messages_cnt: Counter<u64>,
+    loopback_latency: Histogram<f64>,
+    ack_latency: Histogram<f64>,
+    messages_published: Counter<u64>,
+    bytes_published: Counter<u64>,
+    run: Gauge<u64>,
+    connections_up: UpDownCounter<i64>,
+    connection_errors: Counter<u64>,
     labels: Vec<KeyValue>,
 }
 
 impl Metering {
-    pub fn new() -> Metering {
-        init_metering();
-
+    pub fn new(settings: &Settings) -> Metering {
         let meter = global::meter("rumsim");
 
-        let labels = vec![
+        let mut labels = vec![
             Key::new(SERVICE_NAME).string("rumsim"),
-            Key::new("service.replica").string(CONFIG.broker_client_id.clone()),
+            Key::new("service.replica").string(settings.broker.client_id.clone()),
+            Key::new("payload.format").string(format!("{:?}", settings.sim.payload.format)),
         ];
+        // The instances of a sharded fleet are told apart by their shard.
+        if let Some(shard) = &settings.sim.shard {
+            labels.push(Key::new("sim.shard").i64(shard.index as i64));
+        }
 
         let dp_unit = Unit::new("1/s");
         let datapoint_sec = meter.f64_gauge("datapoints").with_unit(dp_unit).init();
@@ -96,10 +317,61 @@ impl Metering {
 
         let overload_cnt = meter.f64_counter("overload").init();
 
+        let bytes_unit = Unit::new("By");
+        let payload_bytes = meter
+            .u64_counter("payload_bytes")
+            .with_unit(bytes_unit.clone())
+            .init();
+        let compressed_bytes = meter
+            .u64_counter("compressed_bytes")
+            .with_unit(bytes_unit.clone())
+            .init();
+        let bytes_per_datapoint = meter
+            .f64_gauge("bytes_per_datapoint")
+            .with_unit(bytes_unit)
+            .init();
+
+        let reconnect_cnt = meter.u64_counter("reconnects").init();
+        let publish_error_cnt = meter.u64_counter("publish_errors").init();
+        let skipped_runs_cnt = meter.u64_counter("skipped_runs").init();
+        let queue_depth = meter.u64_gauge("queue_depth").init();
+        let dropped_messages_cnt = meter.u64_counter("dropped_messages").init();
+        let loopback_latency = meter
+            .f64_histogram("loopback_latency")
+            .with_unit(Unit::new("ms"))
+            .init();
+        let ack_latency = meter
+            .f64_histogram("ack_latency")
+            .with_unit(Unit::new("ms"))
+            .init();
+        let messages_published = meter.u64_counter("messages_published").init();
+        let bytes_published = meter
+            .u64_counter("bytes_published")
+            .with_unit(Unit::new("By"))
+            .init();
+        let run = meter.u64_gauge("run").init();
+        let connections_up = meter.i64_up_down_counter("connections_up").init();
+        let connection_errors = meter.u64_counter("connection_errors").init();
+
         Metering {
             datapoint_sec,
             capacity_percent,
             overload_cnt,
+            payload_bytes,
+            compressed_bytes,
+            bytes_per_datapoint,
+            reconnect_cnt,
+            publish_error_cnt,
+            skipped_runs_cnt,
+            queue_depth,
+            dropped_messages_cnt,
+            loopback_latency,
+            ack_latency,
+            messages_published,
+            bytes_published,
+            run,
+            connections_up,
+            connection_errors,
             labels,
         }
     }
@@ -113,8 +385,143 @@ impl Metering {
         self.datapoint_sec.record(dpsec_value, &self.labels);
     }
 
+    /// Record the size of the payloads sent in a run before and after compression,
+    /// so that the overhead of payload formats can be compared.
+    pub fn record_payload(&self, bytes: usize, compressed_bytes: usize, datapoints: usize) {
+        self.payload_bytes.add(bytes as u64, &self.labels);
+        self.compressed_bytes
+            .add(compressed_bytes as u64, &self.labels);
+        if datapoints > 0 {
+            let per_datapoint = bytes as f64 / datapoints as f64;
+            self.bytes_per_datapoint.record(per_datapoint, &self.labels);
+        }
+    }
+
     pub fn record_capacity(&self, elapsed: Duration, wait_time: Duration) {
         let cap_value = elapsed.as_secs_f64() / wait_time.as_secs_f64() * 100.0;
         self.capacity_percent.record(cap_value, &self.labels);
     }
+
+    /// Record the messages that were published successfully and the size of their payloads as sent.
+    pub fn record_published(&self, messages: u64, bytes: usize) {
+        self.messages_published.add(messages, &self.labels);
+        self.bytes_published.add(bytes as u64, &self.labels);
+    }
+
+    /// Record the number of the run that was just published, so that the volume can be related to the runs.
+    pub fn record_run(&self, run: usize) {
+        self.run.record(run as u64, &self.labels);
+    }
+
+    pub fn record_reconnect(&self) {
+        summary::record_reconnect();
+        self.reconnect_cnt.add(1, &self.labels);
+    }
+
+    /// Record that a connection to the broker came up or went down.
+    pub fn record_connection(&self, up: bool) {
+        self.connections_up
+            .add(if up { 1 } else { -1 }, &self.labels);
+    }
+
+    /// Record a failed connection attempt or a disconnect by the broker, labeled with the kind of error.
+    pub fn record_connection_error(&self, kind: &'static str) {
+        let mut labels = self.labels.clone();
+        labels.push(KeyValue::new("error", kind));
+        self.connection_errors.add(1, &labels);
+    }
+
+    pub fn record_publish_errors(&self, errors: u64) {
+        self.publish_error_cnt.add(errors, &self.labels);
+    }
+
+    pub fn record_skipped_runs(&self, runs: usize) {
+        self.skipped_runs_cnt.add(runs as u64, &self.labels);
+    }
+
+    /// Record the number of messages that were published but not delivered yet.
+    pub fn record_queue_depth(&self, depth: usize) {
+        self.queue_depth.record(depth as u64, &self.labels);
+    }
+
+    pub fn record_dropped_messages(&self, messages: u64) {
+        self.dropped_messages_cnt.add(messages, &self.labels);
+    }
+
+    /// Record the time from publishing a message until it was received back.
+    pub fn record_loopback_latency(&self, latency: Duration) {
+        self.loopback_latency
+            .record(summary::millis(latency), &self.labels);
+    }
+
+    /// Record the time from sending a message with QoS 1 or 2 until the broker acknowledged it.
+    pub fn record_ack_latency(&self, latency: Duration) {
+        self.ack_latency
+            .record(summary::millis(latency), &self.labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing::info_span;
+
+    #[test]
+    fn test_traceparent() {
+        assert_eq!(traceparent(), None);
+        let provider = sdktrace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("simulation_run");
+            let _enter = span.enter();
+            let traceparent = traceparent().unwrap();
+            let parts: Vec<_> = traceparent.split('-').collect();
+            assert_eq!(parts.len(), 4);
+            assert_eq!((parts[0], parts[1].len(), parts[2].len()), ("00", 32, 16));
+        });
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(2.0);
+        let start = Instant::now();
+        assert!(limit.allow_at(start));
+        assert!(limit.allow_at(start));
+        assert!(!limit.allow_at(start));
+        assert!(limit.allow_at(start + Duration::from_millis(500)));
+        assert!(!limit.allow_at(start + Duration::from_millis(600)));
+        // Up to one second of traces are kept for later.
+        assert!(limit.allow_at(start + Duration::from_secs(10)));
+        assert!(limit.allow_at(start + Duration::from_secs(10)));
+        assert!(!limit.allow_at(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_sampler() {
+        let decide = |sampler: &RunSampler| {
+            sampler
+                .should_sample(
+                    None,
+                    TraceId::from_bytes([1; 16]),
+                    "simulation_run",
+                    &SpanKind::Internal,
+                    &[],
+                    &[],
+                )
+                .decision
+        };
+        let limited = RunSampler {
+            ratio: Sampler::TraceIdRatioBased(1.0),
+            limit: Some(Arc::new(RateLimit::new(1.0))),
+        };
+        assert_eq!(decide(&limited), SamplingDecision::RecordAndSample);
+        assert_eq!(decide(&limited), SamplingDecision::Drop);
+        let none = RunSampler {
+            ratio: Sampler::TraceIdRatioBased(0.0),
+            limit: None,
+        };
+        assert_eq!(decide(&none), SamplingDecision::Drop);
+    }
 }
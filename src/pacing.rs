@@ -0,0 +1,204 @@
+//! Pacing the messages to a target rate, instead of publishing each run as fast as possible.
+use rand::Rng;
+use std::str::FromStr;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// How the messages of a run are published within the time between the runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// All messages at the start of the run, followed by a pause until the next run.
+    Burst,
+    /// The messages evenly spread over the time until the next run, so that the broker sees a smooth rate.
+    Spread,
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "burst" => Ok(Pacing::Burst),
+            "spread" => Ok(Pacing::Spread),
+            _ => Err(format!("Unknown pacing {}", s)),
+        }
+    }
+}
+
+/// How far the pacer may fall behind before it stops catching up, so that a slow broker does not cause a burst later.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Spreads the messages evenly over time with the interval of the rate. Intervals below the resolution of the timer
+/// are met on average by publishing the messages that are due together.
+pub struct Pacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(rate: f64) -> Self {
+        let mut pacer = Pacer {
+            interval: Duration::ZERO,
+            next: None,
+        };
+        pacer.set_rate(rate);
+        pacer
+    }
+
+    /// Change the number of messages per second, starting with the next message.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.interval = Duration::from_secs_f64(1.0 / rate);
+    }
+
+    /// Wait until the next message is due. Returns false if the pacer fell behind and the rate was not met.
+    pub async fn wait(&mut self) -> bool {
+        let (due, on_time) = self.schedule(Instant::now());
+        sleep_until(due).await;
+        on_time
+    }
+
+    /// Start over after a pause, so that the paused time is not caught up.
+    pub fn reset(&mut self) {
+        self.next = None;
+    }
+
+    /// The time at which the next message is due, and whether the pacer is keeping up.
+    fn schedule(&mut self, now: Instant) -> (Instant, bool) {
+        let next = self.next.unwrap_or(now);
+        let on_time = next + MAX_LAG >= now;
+        let due = if on_time { next } else { now };
+        self.next = Some(due + self.interval);
+        (due, on_time)
+    }
+}
+
+/// The share of the load that is taken back on an overload beyond the rate that was reached, so that the queues drain.
+const MARGIN: f64 = 0.9;
+/// The share of the configured load that is added after each run that kept up.
+const INCREASE: f64 = 0.05;
+/// The lowest share of the configured load, so that the simulation keeps publishing.
+const MIN_LOAD: f64 = 0.01;
+
+/// Adapts the load to what the receiving end sustains: after an overload, the load is reduced to the rate that was
+/// reached, and after each run that kept up, it grows again by a small step up to the configured load. The load
+/// settles just below the ceiling of the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adaptive {
+    /// The share of the configured load, 1.0 for the full load.
+    load: f64,
+}
+
+impl Adaptive {
+    pub fn new() -> Self {
+        Adaptive { load: 1.0 }
+    }
+
+    pub fn load(&self) -> f64 {
+        self.load
+    }
+
+    /// Adapt the load to a run in which the given share of the intended rate was reached.
+    pub fn update(&mut self, overloaded: bool, reached: f64) {
+        if overloaded {
+            // A run that took longer than intended at least halves the load.
+            self.load = (self.load * reached.clamp(0.5, 1.0) * MARGIN).max(MIN_LOAD);
+        } else {
+            self.load = (self.load + INCREASE).min(1.0);
+        }
+    }
+}
+
+/// Random offsets of the devices within the window at the start of a run, in the order of the devices, so that they
+/// don't all publish at the same time. The broker sees the same arrivals as if each device had its own random offset.
+pub fn jitter(rng: &mut impl Rng, devices: usize, window: Duration) -> Vec<Duration> {
+    let mut offsets: Vec<Duration> = (0..devices)
+        .map(|_| window.mul_f64(rng.gen::<f64>()))
+        .collect();
+    offsets.sort();
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_schedule() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(4.0);
+        assert_eq!(pacer.schedule(start), (start, true));
+        let next = start + Duration::from_millis(250);
+        assert_eq!(pacer.schedule(start), (next, true));
+        // Messages that are a little late are caught up.
+        let late = next + Duration::from_millis(300);
+        let next = next + Duration::from_millis(250);
+        assert_eq!(pacer.schedule(late), (next, true));
+        // Falling further behind starts over from the current time.
+        let later = late + Duration::from_secs(1);
+        assert_eq!(pacer.schedule(later), (later, false));
+        pacer.set_rate(10.0);
+        let next = later + Duration::from_millis(250);
+        assert_eq!(pacer.schedule(later), (next, true));
+        let next = next + Duration::from_millis(100);
+        assert_eq!(pacer.schedule(later), (next, true));
+        pacer.reset();
+        assert_eq!(pacer.schedule(start), (start, true));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(0);
+        let window = Duration::from_secs(1);
+        let offsets = jitter(&mut rng, 100, window);
+        assert_eq!(offsets.len(), 100);
+        assert!(offsets.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(
+            offsets[0] < Duration::from_millis(100) && offsets[99] > Duration::from_millis(900)
+        );
+        assert!(offsets[99] < window);
+        assert!(jitter(&mut rng, 3, Duration::ZERO)
+            .iter()
+            .all(|offset| offset.is_zero()));
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let mut adaptive = Adaptive::new();
+        adaptive.update(false, 1.0);
+        assert_eq!(adaptive.load(), 1.0);
+        // A run at 80% of the intended rate reduces the load to 80% with a margin.
+        adaptive.update(true, 0.8);
+        assert!((adaptive.load() - 0.72).abs() < 1e-9);
+        adaptive.update(false, 1.0);
+        assert!((adaptive.load() - 0.77).abs() < 1e-9);
+        // Far slower runs only halve the load, and the load does not go to 0.
+        adaptive.update(true, 0.1);
+        assert!((adaptive.load() - 0.3465).abs() < 1e-9);
+        for _ in 0..20 {
+            adaptive.update(true, 0.1);
+        }
+        assert_eq!(adaptive.load(), MIN_LOAD);
+        for _ in 0..30 {
+            adaptive.update(false, 1.0);
+        }
+        assert_eq!(adaptive.load(), 1.0);
+    }
+
+    #[test]
+    fn test_pacing() {
+        assert_eq!("spread".parse(), Ok(Pacing::Spread));
+        assert!("smooth".parse::<Pacing>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(2000.0);
+        for _ in 0..401 {
+            pacer.wait().await;
+        }
+        // The intervals are below the resolution of the timer, but the rate is met on average.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,1415 @@
+//! Formats of the messages sent by the simulated devices.
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use prost::Message as _;
+use prost_reflect::{DescriptorPool, DeserializeOptions, DynamicMessage, MessageDescriptor};
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::generator::{DeviceMetadata, UnitConfig, Value};
+use crate::sparkplug::SparkplugNode;
+
+/// The available payload formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    /// Cumulocity IoT SmartREST 2.0 static templates.
+    SmartRest,
+    /// A JSON object with the time stamp and a map of data point names to values.
+    Json,
+    /// Cumulocity IoT measurements in JSON via MQTT format.
+    Cumulocity,
+    /// JSON sent as Azure IoT Hub device-to-cloud messages.
+    AzureIotHub,
+    /// JSON sent to AWS IoT Core with optional device shadow updates.
+    AwsIot,
+    /// thin-edge.io measurements and events of child devices.
+    ThinEdge,
+    /// The JSON structure encoded as CBOR.
+    Cbor,
+    /// The JSON structure encoded as MessagePack.
+    MessagePack,
+    /// The JSON structure mapped to a user-supplied protobuf message.
+    Protobuf,
+    /// Comma-separated values with one line per data point.
+    Csv,
+    /// InfluxDB line protocol.
+    LineProtocol,
+    /// Sparkplug B protobuf messages in the Sparkplug topic namespace.
+    SparkplugB,
+}
+
+impl PayloadFormat {
+    /// The topic template used for a format unless configured otherwise.
+    pub fn default_topic(&self) -> &'static str {
+        match self {
+            PayloadFormat::SmartRest => "s/us/{device}",
+            PayloadFormat::Json
+            | PayloadFormat::Cbor
+            | PayloadFormat::MessagePack
+            | PayloadFormat::Protobuf
+            | PayloadFormat::Csv
+            | PayloadFormat::LineProtocol => "rumsim/{device}",
+            PayloadFormat::Cumulocity => "measurement/measurements/create/{device}",
+            // The simulated devices send through the IoT Hub device of the simulator with their name as property.
+            PayloadFormat::AzureIotHub => {
+                "devices/{cluster}/messages/events/$.ct=application%2Fjson&$.ce=utf-8&device={device}"
+            }
+            // Following the AWS recommendation dt/<application>/<context>/<thing> for telemetry topics.
+            PayloadFormat::AwsIot => "dt/rumsim/{cluster}/{device}",
+            PayloadFormat::ThinEdge => "te/device/{device}///m/",
+            // Sparkplug B has a fixed topic namespace, so the template is not used.
+            PayloadFormat::SparkplugB => "",
+        }
+    }
+
+    /// The topic template of the inventory properties unless configured otherwise.
+    pub fn default_metadata_topic(&self) -> &'static str {
+        match self {
+            PayloadFormat::SmartRest => "s/us/{device}",
+            _ => "rumsim/{device}/metadata",
+        }
+    }
+
+    /// The media type of the payloads for sinks that need one, like HTTP.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::SmartRest | PayloadFormat::Csv => "text/csv",
+            PayloadFormat::LineProtocol => "text/plain",
+            PayloadFormat::Cbor => "application/cbor",
+            PayloadFormat::MessagePack => "application/msgpack",
+            PayloadFormat::Protobuf | PayloadFormat::SparkplugB => "application/x-protobuf",
+            _ => "application/json",
+        }
+    }
+}
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smartrest" => Ok(PayloadFormat::SmartRest),
+            "json" => Ok(PayloadFormat::Json),
+            "c8yjson" => Ok(PayloadFormat::Cumulocity),
+            "azure" => Ok(PayloadFormat::AzureIotHub),
+            "aws" => Ok(PayloadFormat::AwsIot),
+            "thinedge" => Ok(PayloadFormat::ThinEdge),
+            "cbor" => Ok(PayloadFormat::Cbor),
+            "msgpack" => Ok(PayloadFormat::MessagePack),
+            "protobuf" => Ok(PayloadFormat::Protobuf),
+            "csv" => Ok(PayloadFormat::Csv),
+            "lineprotocol" => Ok(PayloadFormat::LineProtocol),
+            "sparkplug" => Ok(PayloadFormat::SparkplugB),
+            _ => Err(format!("Unknown payload format {}.", s)),
+        }
+    }
+}
+
+/// Compression of the payloads before publishing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The HTTP content encoding of the compressed payloads, if any.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    pub fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+        // Compressing into memory cannot fail.
+        match self {
+            Compression::None => data,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data.as_slice(), 0).unwrap(),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression {}.", s)),
+        }
+    }
+}
+
+/// Format and topic of the messages.
+#[derive(Debug, Clone)]
+pub struct PayloadConfig {
+    pub format: PayloadFormat,
+    /// Topic of the measurements. The placeholder {device} is replaced by the device name.
+    pub topic: String,
+    /// The Sparkplug B group that the simulator belongs to.
+    pub sparkplug_group: String,
+    /// Limits for packing several messages into one.
+    pub batch: BatchConfig,
+    /// The message type used by the protobuf format.
+    pub protobuf: Option<MessageDescriptor>,
+    /// Number of runs between device shadow updates of the AWS IoT format, 0 for no updates.
+    pub aws_shadow_runs: u32,
+    pub compression: Compression,
+    /// Topic of the inventory properties. The placeholders are the same as for the measurements.
+    pub metadata_topic: String,
+    /// Filters of the topics that are published with the retain flag.
+    pub retain: Vec<String>,
+}
+
+/// Consecutive messages with the same topic are packed into one message with one record per line,
+/// up to a maximum number of records and a maximum payload size in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub records: usize,
+    /// 0 means that the size of the payload is not limited.
+    pub bytes: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            records: 1,
+            bytes: 0,
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Whether a payload of the given size fits into a batch.
+    pub fn fits(&self, size: usize) -> bool {
+        self.bytes == 0 || size <= self.bytes
+    }
+}
+
+impl Default for PayloadConfig {
+    fn default() -> Self {
+        PayloadConfig {
+            format: PayloadFormat::SmartRest,
+            topic: PayloadFormat::SmartRest.default_topic().to_string(),
+            sparkplug_group: "rumsim".to_string(),
+            batch: BatchConfig::default(),
+            protobuf: None,
+            aws_shadow_runs: 0,
+            compression: Compression::None,
+            metadata_topic: PayloadFormat::SmartRest
+                .default_metadata_topic()
+                .to_string(),
+            retain: Vec::new(),
+        }
+    }
+}
+
+impl PayloadConfig {
+    /// The topic of the measurements of a device. The placeholders {cluster}, {device_id} and {device}
+    /// are replaced by the cluster ID, the running number of the device and the device name.
+    /// {gateway} is replaced by the device name, unless the device is a child of a gateway.
+    /// The placeholders {type} and {datapoint} are left for the device to replace.
+    pub fn topic(&self, cluster_id: &str, device_id: usize, device: &str) -> String {
+        replace_device(&self.topic, cluster_id, device_id, device)
+    }
+
+    /// Format a sample measurement and event, so that a protobuf message type that does not fit the values is found
+    /// before the simulation starts. The other formats have no schema that could fail.
+    pub fn check(&self) -> Result<(), String> {
+        if self.format != PayloadFormat::Protobuf || self.protobuf.is_none() {
+            return Ok(());
+        }
+        let formatter = self.formatter("check");
+        let time = Utc::now();
+        let unit = UnitConfig {
+            unit: "°C".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let measurements = [
+            ("sensor", Value::Float(1.5), Some(&unit)),
+            ("status", Value::Int(3), None),
+            ("alarm", Value::Bool(true), None),
+        ];
+        formatter.measurements(time, &measurements)?;
+        formatter.event(time, "door", "Door opened")?;
+        Ok(())
+    }
+
+    /// Add the trace context as the field "traceparent" to a JSON object, so that it reaches the consumers without
+    /// MQTT 5 user properties. Other payloads stay unchanged.
+    pub fn add_traceparent(&self, payload: Vec<u8>, traceparent: &str) -> Vec<u8> {
+        if self.format != PayloadFormat::Json || payload.first() != Some(&b'{') {
+            return payload;
+        }
+        let mut message = Vec::with_capacity(payload.len() + traceparent.len() + 18);
+        message.extend_from_slice(b"{\"traceparent\":\"");
+        message.extend_from_slice(traceparent.as_bytes());
+        message.push(b'"');
+        if payload[1..].first() != Some(&b'}') {
+            message.push(b',');
+        }
+        message.extend_from_slice(&payload[1..]);
+        message
+    }
+
+    /// The limits for batching messages. Only formats with one record per line can be batched.
+    pub fn batch(&self) -> BatchConfig {
+        match self.format {
+            PayloadFormat::SmartRest | PayloadFormat::Csv | PayloadFormat::LineProtocol => {
+                self.batch
+            }
+            PayloadFormat::Json
+            | PayloadFormat::Cumulocity
+            | PayloadFormat::AzureIotHub
+            | PayloadFormat::AwsIot
+            | PayloadFormat::ThinEdge
+            | PayloadFormat::Cbor
+            | PayloadFormat::MessagePack
+            | PayloadFormat::Protobuf
+            | PayloadFormat::SparkplugB => BatchConfig::default(),
+        }
+    }
+
+    /// Publish each data point as a separate message on its own subtopic.
+    /// Topics that already have a placeholder for the data point stay unchanged.
+    pub fn per_data_point(self) -> Self {
+        if self.topic.contains("{datapoint}") {
+            return self;
+        }
+        PayloadConfig {
+            topic: format!("{}/{{datapoint}}", self.topic),
+            ..self
+        }
+    }
+
+    /// The topic of the device shadow updates of a device and the number of runs between the updates,
+    /// if the device sends shadow updates.
+    pub fn aws_shadow(&self, device: &str) -> Option<(String, u32)> {
+        (self.format == PayloadFormat::AwsIot && self.aws_shadow_runs > 0).then(|| {
+            (
+                format!("$aws/things/{}/shadow/update", device),
+                self.aws_shadow_runs,
+            )
+        })
+    }
+
+    /// The topic of the inventory properties of a device.
+    pub fn metadata_topic(&self, cluster_id: &str, device_id: usize, device: &str) -> String {
+        replace_device(&self.metadata_topic, cluster_id, device_id, device)
+    }
+
+    /// The topic of the events of a device. The placeholder {event_type} is left for the device to replace.
+    /// Events are sent to the topic prefix followed by the device name, unless the format has its own event topics.
+    pub fn event_topic(&self, prefix: &str, device: &str) -> String {
+        match self.format {
+            PayloadFormat::ThinEdge => format!("te/device/{}///e/{{event_type}}", device),
+            _ => format!("{}/{}", prefix, device),
+        }
+    }
+
+    /// Whether a topic has placeholders for the data point or its type.
+    pub fn is_per_data_point(topic: &str) -> bool {
+        topic.contains("{type}") || topic.contains("{datapoint}")
+    }
+
+    /// The configuration of the child devices of a gateway: {cluster} and {gateway} in the topics are
+    /// replaced by the cluster ID and the name of the gateway.
+    pub fn for_gateway(&self, cluster_id: &str, gateway: &str) -> Self {
+        let replace = |template: &str| {
+            template
+                .replace("{cluster}", cluster_id)
+                .replace("{gateway}", gateway)
+        };
+        PayloadConfig {
+            topic: replace(&self.topic),
+            metadata_topic: replace(&self.metadata_topic),
+            ..self.clone()
+        }
+    }
+
+    /// The message that a gateway sends to register a child device, if the format has one.
+    /// Sparkplug B devices are registered by their birth message instead.
+    pub fn child_registration(&self, gateway: &str, child: &str) -> Option<Message> {
+        match self.format {
+            PayloadFormat::SmartRest => Some((
+                format!("s/us/{}", gateway),
+                format!("101,{},{},c8y_MQTTChildDevice", child, child).into_bytes(),
+            )),
+            PayloadFormat::ThinEdge => Some((
+                format!("te/device/{}//", child),
+                serde_json::json!({
+                    "@type": "child-device",
+                    "@id": child,
+                    "@parent": format!("device/{}//", gateway),
+                })
+                .to_string()
+                .into_bytes(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// The filters of the retained topics unless configured otherwise: The child device registrations of thin-edge.io
+    /// and the inventory properties of formats with a topic of their own. Cumulocity and Azure IoT Hub don't
+    /// support retained messages and Sparkplug B doesn't allow them, so nothing is retained there.
+    pub fn default_retain(&self) -> Vec<String> {
+        match self.format {
+            PayloadFormat::SmartRest
+            | PayloadFormat::Cumulocity
+            | PayloadFormat::AzureIotHub
+            | PayloadFormat::SparkplugB => Vec::new(),
+            PayloadFormat::ThinEdge => vec!["te/device/+//".to_string()],
+            PayloadFormat::Json
+            | PayloadFormat::AwsIot
+            | PayloadFormat::Cbor
+            | PayloadFormat::MessagePack
+            | PayloadFormat::Protobuf
+            | PayloadFormat::Csv
+            | PayloadFormat::LineProtocol => vec![topic_filter(&self.metadata_topic)],
+        }
+    }
+
+    /// Whether a message on the topic is published with the retain flag.
+    pub fn is_retained(&self, topic: &str) -> bool {
+        self.retain
+            .iter()
+            .any(|filter| topic_matches(filter, topic))
+    }
+
+    /// The Sparkplug B edge node of a simulator with the given cluster ID, if publishing Sparkplug B.
+    pub fn sparkplug_node(&self, cluster_id: &str) -> Option<SparkplugNode> {
+        (self.format == PayloadFormat::SparkplugB)
+            .then(|| SparkplugNode::new(&self.sparkplug_group, cluster_id))
+    }
+
+    /// The formatter of the messages of a device. Sparkplug B messages are created by the
+    /// device itself, since they depend on earlier messages, so the formatter is not used then.
+    pub fn formatter(&self, device: &str) -> Box<dyn PayloadFormatter> {
+        match self.format {
+            PayloadFormat::SmartRest => Box::new(SmartRestFormatter),
+            PayloadFormat::Json
+            | PayloadFormat::AzureIotHub
+            | PayloadFormat::AwsIot
+            | PayloadFormat::SparkplugB => Box::new(JsonFormatter),
+            PayloadFormat::Cumulocity => Box::new(CumulocityFormatter),
+            PayloadFormat::ThinEdge => Box::new(ThinEdgeFormatter),
+            PayloadFormat::Cbor => Box::new(CborFormatter),
+            PayloadFormat::MessagePack => Box::new(MessagePackFormatter),
+            // The settings make sure that there is a message type for the protobuf format.
+            PayloadFormat::Protobuf => Box::new(ProtobufFormatter {
+                message: self.protobuf.clone().unwrap(),
+            }),
+            PayloadFormat::Csv => Box::new(CsvFormatter),
+            PayloadFormat::LineProtocol => Box::new(LineProtocolFormatter {
+                device: escape_tag(device).into_owned(),
+            }),
+        }
+    }
+}
+
+/// Replace the placeholders of a device in a topic template.
+fn replace_device(template: &str, cluster_id: &str, device_id: usize, device: &str) -> String {
+    template
+        .replace("{cluster}", cluster_id)
+        .replace("{device_id}", &device_id.to_string())
+        .replace("{gateway}", device)
+        .replace("{device}", device)
+}
+
+/// The topic filter matching all topics of a template: Levels with placeholders match any value.
+fn topic_filter(template: &str) -> String {
+    template
+        .split('/')
+        .map(|level| if level.contains('{') { "+" } else { level })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether a topic matches an MQTT topic filter with the wildcards + and #.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        if filter_level == "#" {
+            return true;
+        }
+        match levels.next() {
+            Some(level) if filter_level == "+" || filter_level == level => (),
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// A message consisting of topic and payload.
+pub type Message = (String, Vec<u8>);
+
+/// A value of a data point together with its unit, if it has one.
+pub type Measurement<'a> = (&'a str, Value, Option<&'a UnitConfig>);
+
+/// Converts the measurements and events of a device into message payloads.
+/// The number of messages that could not be formatted with the last error. The devices format their messages in the
+/// simulation threads, so the errors are collected here until the simulation loop logs and counts them.
+static FORMAT_ERRORS: Mutex<(u64, Option<String>)> = Mutex::new((0, None));
+
+/// Record a message that could not be formatted and is left out.
+pub fn record_format_error(error: String) {
+    // The counter stays usable if a thread panicked while holding it.
+    let mut errors = FORMAT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    errors.0 += 1;
+    errors.1 = Some(error);
+}
+
+/// The number of messages that could not be formatted since the last call, with the last error, if there were any.
+pub fn take_format_errors() -> Option<(u64, String)> {
+    let mut errors = FORMAT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    let (count, error) = std::mem::take(&mut *errors);
+    error.map(|error| (count, error))
+}
+
+pub trait PayloadFormatter: Send {
+    /// Append the measurements of a device taken at the given time to the buffer, so that a device can format its
+    /// measurements in the same buffer in each run. Only formats with a schema fail, if the schema does not fit.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String>;
+
+    /// Format the measurements of a device taken at the given time.
+    fn measurements(
+        &self,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        self.write_measurements(&mut data, time, measurements)?;
+        Ok(data)
+    }
+
+    /// Format an event of a device.
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String>;
+
+    /// Format the inventory properties of a device in the form
+    /// {"serial":"<serial>","model":"<model>","firmware":"<version>","location":{"latitude":<lat>,"longitude":<lng>}}
+    fn metadata(&self, metadata: &DeviceMetadata) -> Vec<u8> {
+        serde_json::json!({
+            "serial": metadata.serial,
+            "model": metadata.model,
+            "firmware": metadata.firmware,
+            "location": {"latitude": metadata.latitude, "longitude": metadata.longitude},
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+/// Cumulocity IoT SmartREST 2.0 static templates.
+pub struct SmartRestFormatter;
+
+impl PayloadFormatter for SmartRestFormatter {
+    /// Format measurements in the form
+    /// 201,S,<time>,SF,<data point 1>,<value 1>,<unit 1>,SF,<data point 2>,<value 2>,<unit 2>,...
+    /// What are the limitations here in terms of number of data points for C8Y?
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        // Writing to a vector cannot fail. The comma at the end of the time is intentional.
+        let _ = write!(buffer, "201,S,{},", time.format("%+"));
+        for (datapoint, value, unit) in measurements {
+            let unit = unit.map_or("", |unit| unit.unit.as_str());
+            let _ = write!(buffer, "SF,{},{},{},", datapoint, value, unit);
+        }
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(format!("400,{},\"{}\",{}", event_type, text, time.format("%+")).into_bytes())
+    }
+
+    /// Set hardware, firmware and position with the static templates 110, 115 and 112.
+    fn metadata(&self, metadata: &DeviceMetadata) -> Vec<u8> {
+        format!(
+            "110,{},{},\n115,rumsim,{},\n112,{},{},,",
+            metadata.serial,
+            metadata.model,
+            metadata.firmware,
+            metadata.latitude,
+            metadata.longitude
+        )
+        .into_bytes()
+    }
+}
+
+/// A JSON object with the time stamp and a map of data point names to values.
+pub struct JsonFormatter;
+
+impl PayloadFormatter for JsonFormatter {
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        write_json(buffer, &structure_measurements(time, measurements));
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(structure_event(time, event_type, text)
+            .to_string()
+            .into_bytes())
+    }
+}
+
+/// The measurements in the form
+/// {"time":"<time>","values":{"<data point 1>":<value 1>,...},"units":{"<data point 1>":"<unit 1>",...}}
+/// Units are only included for data points that have one.
+fn structure_measurements(time: DateTime<Utc>, measurements: &[Measurement]) -> serde_json::Value {
+    let mut values = serde_json::Map::new();
+    let mut units = serde_json::Map::new();
+    for (datapoint, value, unit) in measurements {
+        values.insert(datapoint.to_string(), value.to_json());
+        if let Some(unit) = unit {
+            units.insert(datapoint.to_string(), unit.unit.clone().into());
+        }
+    }
+    let mut message = serde_json::Map::new();
+    message.insert("time".to_string(), time.to_rfc3339().into());
+    message.insert("values".to_string(), values.into());
+    if !units.is_empty() {
+        message.insert("units".to_string(), units.into());
+    }
+    serde_json::Value::Object(message)
+}
+
+/// Append the JSON value to the buffer.
+fn write_json(buffer: &mut Vec<u8>, value: &serde_json::Value) {
+    // Writing a JSON value to a vector cannot fail.
+    serde_json::to_writer(buffer, value).unwrap();
+}
+
+/// The event in the form {"time":"<time>","type":"<type>","text":"<text>"}.
+fn structure_event(time: DateTime<Utc>, event_type: &str, text: &str) -> serde_json::Value {
+    serde_json::json!({
+        "time": time.to_rfc3339(),
+        "type": event_type,
+        "text": text,
+    })
+}
+
+/// The JSON structure of the measurements encoded as CBOR.
+pub struct CborFormatter;
+
+impl PayloadFormatter for CborFormatter {
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        write_cbor(buffer, &structure_measurements(time, measurements));
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        write_cbor(&mut data, &structure_event(time, event_type, text));
+        Ok(data)
+    }
+}
+
+fn write_cbor(buffer: &mut Vec<u8>, value: &serde_json::Value) {
+    // Writing to a vector cannot fail.
+    ciborium::into_writer(value, buffer).unwrap();
+}
+
+/// The JSON structure of the measurements encoded as MessagePack.
+pub struct MessagePackFormatter;
+
+impl PayloadFormatter for MessagePackFormatter {
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        rmp_serde::encode::write(buffer, &structure_measurements(time, measurements)).unwrap();
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(rmp_serde::to_vec(&structure_event(time, event_type, text)).unwrap())
+    }
+}
+
+/// Cumulocity IoT measurements in JSON via MQTT format.
+pub struct CumulocityFormatter;
+
+impl PayloadFormatter for CumulocityFormatter {
+    /// Format measurements in the form
+    /// {"type":"S","time":"<time>","SF":{"<data point 1>":{"value":<value 1>,"unit":"<unit 1>"},...}}
+    /// like the SmartREST measurements. Booleans are sent as 0 and 1. Texts are left out, since
+    /// Cumulocity only accepts numerical measurements.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        let mut series = serde_json::Map::new();
+        for (datapoint, value, unit) in measurements {
+            let value: serde_json::Value = match value {
+                Value::Bool(value) => (*value as i64).into(),
+                Value::Text(_) => continue,
+                value => value.to_json(),
+            };
+            let mut entry = serde_json::Map::new();
+            entry.insert("value".to_string(), value);
+            if let Some(unit) = unit {
+                entry.insert("unit".to_string(), unit.unit.clone().into());
+            }
+            series.insert(datapoint.to_string(), entry.into());
+        }
+        let message = serde_json::json!({
+            "type": "S",
+            "time": time.to_rfc3339(),
+            "SF": series,
+        });
+        write_json(buffer, &message);
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        JsonFormatter.event(time, event_type, text)
+    }
+}
+
+/// The JSON structure of the measurements mapped to a user-supplied protobuf message type using the
+/// protobuf JSON mapping. Fields of the structure that the message type doesn't have are left out,
+/// so a message type can pick the fields it needs, for example:
+///
+/// message Telemetry {
+///   google.protobuf.Timestamp time = 1;
+///   map<string, double> values = 2;
+///   map<string, string> units = 3;
+///   string type = 4;
+///   string text = 5;
+/// }
+pub struct ProtobufFormatter {
+    message: MessageDescriptor,
+}
+
+impl ProtobufFormatter {
+    /// Fails if the message type doesn't fit the values. The settings check the message type with a sample
+    /// measurement and event, so that this only happens with values that the sample does not have.
+    fn encode(&self, buffer: &mut Vec<u8>, value: serde_json::Value) -> Result<(), String> {
+        let options = DeserializeOptions::new().deny_unknown_fields(false);
+        DynamicMessage::deserialize_with_options(self.message.clone(), value, &options)
+            .map_err(|e| format!("Cannot map message to {}: {}", self.message.full_name(), e))?
+            .encode(buffer)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl PayloadFormatter for ProtobufFormatter {
+    /// Booleans are sent as 0 and 1 and texts are left out, so that the values fit into a map of doubles.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        let measurements: Vec<Measurement> = measurements
+            .iter()
+            .filter_map(|(datapoint, value, unit)| match value {
+                Value::Bool(value) => Some((*datapoint, Value::Int(*value as i64), *unit)),
+                Value::Text(_) => None,
+                value => Some((*datapoint, value.clone(), *unit)),
+            })
+            .collect();
+        self.encode(buffer, structure_measurements(time, &measurements))
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        self.encode(&mut data, structure_event(time, event_type, text))?;
+        Ok(data)
+    }
+}
+
+/// Find a message type in a file descriptor set as written by protoc --descriptor_set_out.
+pub fn load_message_type(descriptor_set: &[u8], name: &str) -> Result<MessageDescriptor, String> {
+    DescriptorPool::decode(descriptor_set)
+        .map_err(|e| e.to_string())?
+        .get_message_by_name(name)
+        .ok_or_else(|| format!("Unknown message type {}.", name))
+}
+
+/// thin-edge.io JSON measurements and events.
+pub struct ThinEdgeFormatter;
+
+impl PayloadFormatter for ThinEdgeFormatter {
+    /// Format measurements in the form
+    /// {"time":"<time>","<data point 1>":<value 1>,"<data point 2>":<value 2>,...}
+    /// Booleans are sent as 0 and 1. Texts are left out, since thin-edge.io only accepts numerical
+    /// measurements. Units are left out as well, since thin-edge.io expects them as separate metadata.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        let mut message = serde_json::Map::new();
+        message.insert("time".to_string(), time.to_rfc3339().into());
+        for (datapoint, value, _) in measurements {
+            let value: serde_json::Value = match value {
+                Value::Bool(value) => (*value as i64).into(),
+                Value::Text(_) => continue,
+                value => value.to_json(),
+            };
+            message.insert(datapoint.to_string(), value);
+        }
+        write_json(buffer, &serde_json::Value::Object(message));
+        Ok(())
+    }
+
+    /// The event type is part of the topic.
+    fn event(&self, time: DateTime<Utc>, _event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(serde_json::json!({
+            "time": time.to_rfc3339(),
+            "text": text,
+        })
+        .to_string()
+        .into_bytes())
+    }
+}
+
+/// Report the measurements as state of an AWS IoT device shadow in the form
+/// {"state":{"reported":{"<data point 1>":<value 1>,...}}}
+pub fn format_aws_shadow<'a>(measurements: impl Iterator<Item = &'a Measurement<'a>>) -> Vec<u8> {
+    let reported: serde_json::Map<String, serde_json::Value> = measurements
+        .map(|(datapoint, value, _)| (datapoint.to_string(), value.to_json()))
+        .collect();
+    serde_json::json!({"state": {"reported": reported}})
+        .to_string()
+        .into_bytes()
+}
+
+/// Comma-separated values with one line per data point.
+pub struct CsvFormatter;
+
+impl PayloadFormatter for CsvFormatter {
+    /// Format measurements in the form
+    /// <time>,<data point 1>,<value 1>,<unit 1>
+    /// <time>,<data point 2>,<value 2>,<unit 2>
+    /// Texts are quoted.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        let mut time_stamp = String::with_capacity(32);
+        // Writing to a string or a vector cannot fail.
+        let _ = write!(time_stamp, "{}", time.format("%+"));
+        for (index, (datapoint, value, unit)) in measurements.iter().enumerate() {
+            if index > 0 {
+                buffer.push(b'\n');
+            }
+            let _ = write!(buffer, "{},{},", time_stamp, datapoint);
+            match value {
+                Value::Text(text) => quote(buffer, text),
+                value => {
+                    let _ = write!(buffer, "{}", value);
+                }
+            }
+            buffer.push(b',');
+            buffer.extend_from_slice(unit.map_or("", |unit| unit.unit.as_str()).as_bytes());
+        }
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        let mut message = format!("{},{},", time.format("%+"), event_type).into_bytes();
+        quote(&mut message, text);
+        Ok(message)
+    }
+}
+
+/// Append a quoted CSV field, doubling any quotes in it.
+fn quote(buffer: &mut Vec<u8>, text: &str) {
+    buffer.push(b'"');
+    for (index, part) in text.split('"').enumerate() {
+        if index > 0 {
+            buffer.extend_from_slice(b"\"\"");
+        }
+        buffer.extend_from_slice(part.as_bytes());
+    }
+    buffer.push(b'"');
+}
+
+/// InfluxDB line protocol with the device as tag and the data points as fields.
+pub struct LineProtocolFormatter {
+    device: String,
+}
+
+impl PayloadFormatter for LineProtocolFormatter {
+    /// Format measurements in the form
+    /// rumsim,device=<device> <data point 1>=<value 1>,<data point 2>=<value 2>,... <time in ns>
+    /// Units are left out, since line protocol has no place for them.
+    fn write_measurements(
+        &self,
+        buffer: &mut Vec<u8>,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> Result<(), String> {
+        buffer.extend_from_slice(b"rumsim,device=");
+        buffer.extend_from_slice(self.device.as_bytes());
+        for (index, (datapoint, value, _)) in measurements.iter().enumerate() {
+            buffer.push(if index == 0 { b' ' } else { b',' });
+            buffer.extend_from_slice(escape_tag(datapoint).as_bytes());
+            buffer.push(b'=');
+            // Writing to a vector cannot fail.
+            let _ = match value {
+                Value::Float(value) => write!(buffer, "{}", value),
+                Value::Int(value) => write!(buffer, "{}i", value),
+                Value::Bool(value) => write!(buffer, "{}", value),
+                Value::Text(text) => write!(buffer, "{}", escape_field(text)),
+            };
+        }
+        let _ = write!(
+            buffer,
+            " {}",
+            time.timestamp_nanos_opt().unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    fn event(&self, time: DateTime<Utc>, event_type: &str, text: &str) -> Result<Vec<u8>, String> {
+        Ok(format!(
+            "event,device={},type={} text={} {}",
+            self.device,
+            escape_tag(event_type),
+            escape_field(text),
+            time.timestamp_nanos_opt().unwrap_or_default()
+        )
+        .into_bytes())
+    }
+}
+
+/// Escape commas, equal signs and spaces in tags and field keys of line protocol. Most names have none of them, so
+/// they are not copied.
+fn escape_tag(tag: &str) -> Cow<'_, str> {
+    if !tag.contains([',', '=', ' ']) {
+        return Cow::Borrowed(tag);
+    }
+    let mut escaped = String::with_capacity(tag.len() + 4);
+    for c in tag.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Cow::Owned(escaped)
+}
+
+/// Quote a string field value of line protocol.
+fn escape_field(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(data: Vec<u8>) -> String {
+        String::from_utf8(data).unwrap()
+    }
+
+    fn measurements(unit: &UnitConfig) -> Vec<Measurement<'_>> {
+        vec![
+            ("sensor_0", Value::Float(1.5), Some(unit)),
+            ("alarm_0", Value::Bool(true), None),
+            ("status_0", Value::Int(3), None),
+        ]
+    }
+
+    #[test]
+    fn test_format_smartrest() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        assert_eq!(
+            text(
+                SmartRestFormatter
+                    .measurements(time, &measurements(&unit))
+                    .unwrap()
+            ),
+            "201,S,2024-01-01T00:00:00+00:00,SF,sensor_0,1.5,bar,SF,alarm_0,1,,SF,status_0,3,,"
+        );
+    }
+
+    #[test]
+    fn test_format_json() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let message: serde_json::Value = serde_json::from_slice(
+            &JsonFormatter
+                .measurements(time, &measurements(&unit))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "time": "2024-01-01T00:00:00+00:00",
+                "values": {"sensor_0": 1.5, "alarm_0": true, "status_0": 3},
+                "units": {"sensor_0": "bar"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_add_traceparent() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let payload = PayloadConfig {
+            format: PayloadFormat::Json,
+            ..PayloadConfig::default()
+        };
+        let message: serde_json::Value =
+            serde_json::from_slice(&payload.add_traceparent(br#"{"a":1}"#.to_vec(), traceparent))
+                .unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({"traceparent": traceparent, "a": 1})
+        );
+        assert_eq!(
+            payload.add_traceparent(b"{}".to_vec(), traceparent),
+            format!(r#"{{"traceparent":"{}"}}"#, traceparent).into_bytes()
+        );
+        assert_eq!(
+            payload.add_traceparent(b"[1]".to_vec(), traceparent),
+            b"[1]"
+        );
+        let csv = PayloadConfig {
+            format: PayloadFormat::Csv,
+            ..PayloadConfig::default()
+        };
+        assert_eq!(csv.add_traceparent(b"{a}".to_vec(), traceparent), b"{a}");
+    }
+
+    #[test]
+    fn test_format_binary() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let expected = structure_measurements(time, &measurements(&unit));
+        let json = JsonFormatter
+            .measurements(time, &measurements(&unit))
+            .unwrap();
+
+        let cbor = CborFormatter
+            .measurements(time, &measurements(&unit))
+            .unwrap();
+        let decoded: serde_json::Value = ciborium::from_reader(cbor.as_slice()).unwrap();
+        assert_eq!(decoded, expected);
+        assert!(cbor.len() < json.len());
+
+        let msgpack = MessagePackFormatter
+            .measurements(time, &measurements(&unit))
+            .unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&msgpack).unwrap();
+        assert_eq!(decoded, expected);
+        assert!(msgpack.len() < json.len());
+    }
+
+    #[test]
+    fn test_format_protobuf() {
+        use prost_reflect::prost_types::{
+            field_descriptor_proto::{Label, Type},
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+            MessageOptions,
+        };
+        use prost_reflect::MapKey;
+
+        let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            ..FieldDescriptorProto::default()
+        };
+        // map<string, double> values = 3;
+        let values = FieldDescriptorProto {
+            label: Some(Label::Repeated as i32),
+            type_name: Some(".factory.Telemetry.ValuesEntry".to_string()),
+            ..field("values", 3, Type::Message)
+        };
+        let descriptor_set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("telemetry.proto".to_string()),
+                package: Some("factory".to_string()),
+                message_type: vec![
+                    DescriptorProto {
+                        name: Some("Telemetry".to_string()),
+                        field: vec![
+                            field("time", 1, Type::String),
+                            field("type", 2, Type::String),
+                            values,
+                        ],
+                        nested_type: vec![DescriptorProto {
+                            name: Some("ValuesEntry".to_string()),
+                            field: vec![
+                                field("key", 1, Type::String),
+                                field("value", 2, Type::Double),
+                            ],
+                            options: Some(MessageOptions {
+                                map_entry: Some(true),
+                                ..MessageOptions::default()
+                            }),
+                            ..DescriptorProto::default()
+                        }],
+                        ..DescriptorProto::default()
+                    },
+                    // The time stamp does not fit into a number.
+                    DescriptorProto {
+                        name: Some("Counter".to_string()),
+                        field: vec![field("time", 1, Type::Int64)],
+                        ..DescriptorProto::default()
+                    },
+                ],
+                syntax: Some("proto3".to_string()),
+                ..FileDescriptorProto::default()
+            }],
+        };
+        let descriptor_set = descriptor_set.encode_to_vec();
+        assert!(load_message_type(&descriptor_set, "factory.Unknown").is_err());
+        let message = load_message_type(&descriptor_set, "factory.Telemetry").unwrap();
+
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let formatter = ProtobufFormatter {
+            message: message.clone(),
+        };
+        let data = formatter.event(time, "door", "Door opened").unwrap();
+        let decoded = DynamicMessage::decode(message.clone(), data.as_slice()).unwrap();
+        assert_eq!(
+            decoded.get_field_by_name("type").unwrap().as_str(),
+            Some("door")
+        );
+        assert_eq!(
+            decoded.get_field_by_name("time").unwrap().as_str(),
+            Some("2024-01-01T00:00:00+00:00")
+        );
+
+        let data = formatter
+            .measurements(
+                time,
+                &[
+                    ("sensor_0", Value::Float(1.5), None),
+                    ("alarm_0", Value::Bool(true), None),
+                    ("status_0", Value::Text("ok".to_string()), None),
+                ],
+            )
+            .unwrap();
+        let decoded = DynamicMessage::decode(message.clone(), data.as_slice()).unwrap();
+        let values = decoded.get_field_by_name("values").unwrap();
+        let values = values.as_map().unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(
+            values[&MapKey::String("sensor_0".to_string())].as_f64(),
+            Some(1.5)
+        );
+        assert_eq!(
+            values[&MapKey::String("alarm_0".to_string())].as_f64(),
+            Some(1.0)
+        );
+
+        // A message type that does not fit fails when checked and when formatting, without panicking.
+        let payload = |message| PayloadConfig {
+            format: PayloadFormat::Protobuf,
+            protobuf: Some(message),
+            ..PayloadConfig::default()
+        };
+        assert!(payload(message).check().is_ok());
+        let counter = load_message_type(&descriptor_set, "factory.Counter").unwrap();
+        assert!(payload(counter.clone()).check().is_err());
+        let formatter = ProtobufFormatter { message: counter };
+        assert!(formatter.measurements(time, &[]).is_err());
+        assert!(formatter.event(time, "door", "Door opened").is_err());
+    }
+
+    #[test]
+    fn test_format_cumulocity() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let mut measurements = measurements(&unit);
+        measurements.push(("state_0", Value::Text("idle".to_string()), None));
+        let message: serde_json::Value = serde_json::from_slice(
+            &CumulocityFormatter
+                .measurements(time, &measurements)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "type": "S",
+                "time": "2024-01-01T00:00:00+00:00",
+                "SF": {
+                    "sensor_0": {"value": 1.5, "unit": "bar"},
+                    "alarm_0": {"value": 1},
+                    "status_0": {"value": 3}
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_format_event() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let formatter = PayloadConfig::default().formatter("rumsim-0_1");
+        assert_eq!(
+            text(formatter.event(time, "door", "Door opened").unwrap()),
+            "400,door,\"Door opened\",2024-01-01T00:00:00+00:00"
+        );
+        let message: serde_json::Value =
+            serde_json::from_slice(&JsonFormatter.event(time, "door", "Door opened").unwrap())
+                .unwrap();
+        assert_eq!(message["type"], "door");
+        assert_eq!(message["text"], "Door opened");
+        assert_eq!(
+            text(
+                CsvFormatter
+                    .event(time, "door", "Door \"A\" opened")
+                    .unwrap()
+            ),
+            "2024-01-01T00:00:00+00:00,door,\"Door \"\"A\"\" opened\""
+        );
+        let formatter = LineProtocolFormatter {
+            device: escape_tag("rumsim-0_1").into_owned(),
+        };
+        assert_eq!(
+            text(formatter.event(time, "door", "Door opened").unwrap()),
+            "event,device=rumsim-0_1,type=door text=\"Door opened\" 1704067200000000000"
+        );
+    }
+
+    #[test]
+    fn test_format_csv() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        assert_eq!(
+            text(
+                CsvFormatter
+                    .measurements(time, &measurements(&unit))
+                    .unwrap()
+            ),
+            "2024-01-01T00:00:00+00:00,sensor_0,1.5,bar\n\
+             2024-01-01T00:00:00+00:00,alarm_0,1,\n\
+             2024-01-01T00:00:00+00:00,status_0,3,"
+        );
+    }
+
+    #[test]
+    fn test_format_line_protocol() {
+        let time = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let unit = UnitConfig {
+            unit: "bar".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let mut measurements = measurements(&unit);
+        measurements.push(("state_0", Value::Text("idle".to_string()), None));
+        let formatter = PayloadConfig {
+            format: PayloadFormat::LineProtocol,
+            ..PayloadConfig::default()
+        }
+        .formatter("rumsim-0_1");
+        assert_eq!(
+            text(formatter.measurements(time, &measurements).unwrap()),
+            "rumsim,device=rumsim-0_1 sensor_0=1.5,alarm_0=true,status_0=3i,state_0=\"idle\" \
+             1704067200000000000"
+        );
+        assert_eq!(escape_tag("sensor_0"), Cow::Borrowed("sensor_0"));
+        assert_eq!(escape_tag("a,b=c d"), "a\\,b\\=c\\ d");
+    }
+
+    #[test]
+    fn test_compression() {
+        let data = b"201,S,2024-01-01T00:00:00+00:00,SF,sensor_0,1.5,bar,".repeat(10);
+        assert_eq!(Compression::None.compress(data.clone()), data);
+
+        let compressed = Compression::Gzip.compress(data.clone());
+        assert!(compressed.len() < data.len());
+        let mut decompressed = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(compressed.as_slice()),
+            &mut decompressed,
+        )
+        .unwrap();
+        assert_eq!(decompressed, data);
+
+        let compressed = Compression::Zstd.compress(data.clone());
+        assert!(compressed.len() < data.len());
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data);
+
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_payload_config() {
+        assert_eq!("json".parse(), Ok(PayloadFormat::Json));
+        assert_eq!("sparkplug".parse(), Ok(PayloadFormat::SparkplugB));
+        assert_eq!("lineprotocol".parse(), Ok(PayloadFormat::LineProtocol));
+        assert!("xml".parse::<PayloadFormat>().is_err());
+        assert_eq!(
+            PayloadConfig::default().topic("rumsim-0", 1, "rumsim-0_1"),
+            "s/us/rumsim-0_1"
+        );
+        let config = PayloadConfig {
+            topic: "factory/{cluster}/{device_id}/{type}".to_string(),
+            ..PayloadConfig::default()
+        };
+        let topic = config.topic("rumsim-0", 1, "rumsim-0_1");
+        assert_eq!(topic, "factory/rumsim-0/1/{type}");
+        assert!(PayloadConfig::is_per_data_point(&topic));
+        assert_eq!(
+            PayloadConfig::default().per_data_point().topic,
+            "s/us/{device}/{datapoint}"
+        );
+        assert_eq!(
+            PayloadConfig::default()
+                .per_data_point()
+                .per_data_point()
+                .topic,
+            "s/us/{device}/{datapoint}"
+        );
+        assert!(PayloadConfig::default()
+            .sparkplug_node("rumsim-0")
+            .is_none());
+    }
+
+    #[test]
+    fn test_gateway() {
+        let config = PayloadConfig {
+            topic: "factory/{gateway}/{device}".to_string(),
+            ..PayloadConfig::default()
+        };
+        assert_eq!(
+            config.topic("rumsim-0", 1, "rumsim-0_1"),
+            "factory/rumsim-0_1/rumsim-0_1"
+        );
+        let child = config.for_gateway("rumsim-0", "rumsim-0_1");
+        assert_eq!(
+            child.topic("rumsim-0_1", 2, "rumsim-0_1_2"),
+            "factory/rumsim-0_1/rumsim-0_1_2"
+        );
+
+        let (topic, data) = config
+            .child_registration("rumsim-0_1", "rumsim-0_1_2")
+            .unwrap();
+        assert_eq!(topic, "s/us/rumsim-0_1");
+        assert_eq!(
+            text(data),
+            "101,rumsim-0_1_2,rumsim-0_1_2,c8y_MQTTChildDevice"
+        );
+
+        let config = PayloadConfig {
+            format: PayloadFormat::ThinEdge,
+            ..PayloadConfig::default()
+        };
+        let (topic, data) = config
+            .child_registration("rumsim-0_1", "rumsim-0_1_2")
+            .unwrap();
+        assert_eq!(topic, "te/device/rumsim-0_1_2//");
+        let data: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(data["@parent"], "device/rumsim-0_1//");
+
+        let config = PayloadConfig {
+            format: PayloadFormat::Json,
+            ..PayloadConfig::default()
+        };
+        assert!(config
+            .child_registration("rumsim-0_1", "rumsim-0_1_2")
+            .is_none());
+    }
+
+    #[test]
+    fn test_retain() {
+        assert!(topic_matches(
+            "rumsim/+/metadata",
+            "rumsim/rumsim-0_1/metadata"
+        ));
+        assert!(!topic_matches("rumsim/+/metadata", "rumsim/rumsim-0_1"));
+        assert!(!topic_matches("rumsim/+", "rumsim/rumsim-0_1/metadata"));
+        assert!(topic_matches("rumsim/#", "rumsim/rumsim-0_1/metadata"));
+        assert!(topic_matches("te/device/+//", "te/device/rumsim-0_1//"));
+        assert!(!topic_matches("te/device/+//", "te/device/rumsim-0_1///m/"));
+
+        let config = PayloadConfig {
+            format: PayloadFormat::Json,
+            metadata_topic: "factory/{cluster}/{device}/info".to_string(),
+            ..PayloadConfig::default()
+        };
+        assert_eq!(config.default_retain(), vec!["factory/+/+/info"]);
+        let config = PayloadConfig {
+            retain: config.default_retain(),
+            ..config
+        };
+        assert!(config.is_retained("factory/rumsim-0/rumsim-0_1/info"));
+        assert!(!config.is_retained("rumsim/rumsim-0_1"));
+        assert!(PayloadConfig::default().default_retain().is_empty());
+    }
+}
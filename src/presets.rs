@@ -0,0 +1,91 @@
+//! Named configuration profiles with SIM_PROFILE, so that new users get a meaningful load without knowing all the
+//! settings. The settings of a profile are defaults, which explicit settings override.
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// A few devices for a few runs, to check that the broker receives the messages.
+    Smoke,
+    /// A moderate load for a day, to find leaks and reconnect problems.
+    Soak,
+    /// As many messages as possible, to find the limits of the broker.
+    Stress,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smoke" => Ok(Preset::Smoke),
+            "soak" => Ok(Preset::Soak),
+            "stress" => Ok(Preset::Stress),
+            _ => Err(format!("Unknown profile {}", s)),
+        }
+    }
+}
+
+impl Preset {
+    /// The environment variables of the profile and their values.
+    pub fn settings(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Preset::Smoke => &[
+                ("SIM_DEVICES", "5"),
+                ("SIM_DATA_POINTS", "5"),
+                ("SIM_FREQUENCY_SECS", "1"),
+                ("SIM_RUNS", "10"),
+                ("BROKER_QOS", "1"),
+            ],
+            Preset::Soak => &[
+                ("SIM_DEVICES", "1000"),
+                ("SIM_DATA_POINTS", "20"),
+                ("SIM_FREQUENCY_SECS", "10"),
+                ("SIM_DURATION_SECS", "86400"),
+                ("BROKER_QOS", "1"),
+                ("BROKER_RECONNECT", "true"),
+            ],
+            Preset::Stress => &[
+                ("SIM_DEVICES", "10000"),
+                ("SIM_DATA_POINTS", "50"),
+                ("SIM_FREQUENCY_SECS", "1"),
+                ("SIM_MISSED_RUNS", "skip"),
+                ("BROKER_QOS", "0"),
+                ("BROKER_CONNECTIONS", "8"),
+                ("CAPACITY", "10000"),
+            ],
+        }
+    }
+
+    /// Set the environment variables of the profile in SIM_PROFILE that are not set yet. An unknown profile is left to
+    /// the validation of the settings.
+    pub fn apply() {
+        let Some(preset) = std::env::var("SIM_PROFILE")
+            .ok()
+            .and_then(|profile| profile.parse::<Preset>().ok())
+        else {
+            return;
+        };
+        for (env, value) in preset.settings() {
+            if std::env::var_os(env).is_none() {
+                std::env::set_var(env, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset() {
+        assert_eq!("stress".parse(), Ok(Preset::Stress));
+        assert!("heavy".parse::<Preset>().is_err());
+        for preset in [Preset::Smoke, Preset::Soak, Preset::Stress] {
+            assert!(preset
+                .settings()
+                .iter()
+                .any(|(env, _)| *env == "SIM_DEVICES"));
+        }
+    }
+}
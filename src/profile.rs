@@ -0,0 +1,170 @@
+//! Load profiles that vary the message rate over time, like daily patterns or surges.
+use serde::Deserialize;
+use std::f64::consts::PI;
+use tokio::time::Duration;
+
+/// A phase of the load profile. The rate is a factor for the number of runs per time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum Phase {
+    /// The same rate for the whole phase, for example a step or a spike.
+    Constant { rate: f64, duration_secs: u64 },
+    /// A linear change of the rate from one value to another.
+    Ramp {
+        from: f64,
+        to: f64,
+        duration_secs: u64,
+    },
+    /// A rate that oscillates between a minimum and a maximum, starting at the minimum.
+    Sine {
+        min: f64,
+        max: f64,
+        period_secs: u64,
+        duration_secs: u64,
+    },
+}
+
+impl Phase {
+    fn duration_secs(&self) -> u64 {
+        match self {
+            Phase::Constant { duration_secs, .. }
+            | Phase::Ramp { duration_secs, .. }
+            | Phase::Sine { duration_secs, .. } => *duration_secs,
+        }
+    }
+
+    fn rate(&self, secs: f64) -> f64 {
+        match self {
+            Phase::Constant { rate, .. } => *rate,
+            Phase::Ramp {
+                from,
+                to,
+                duration_secs,
+            } => from + (to - from) * secs / (*duration_secs).max(1) as f64,
+            Phase::Sine {
+                min,
+                max,
+                period_secs,
+                ..
+            } => {
+                let angle = 2.0 * PI * secs / (*period_secs).max(1) as f64;
+                min + (max - min) * (1.0 - angle.cos()) / 2.0
+            }
+        }
+    }
+
+    fn rates(&self) -> [f64; 2] {
+        match self {
+            Phase::Constant { rate, .. } => [*rate, *rate],
+            Phase::Ramp { from, to, .. } => [*from, *to],
+            Phase::Sine { min, max, .. } => [*min, *max],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LoadProfile {
+    pub phases: Vec<Phase>,
+    /// Start over after the last phase. Otherwise, the last rate stays.
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl LoadProfile {
+    /// YAML is a superset of JSON, so the YAML parser reads both.
+    pub fn parse(content: &[u8]) -> Result<Self, String> {
+        let profile: LoadProfile = serde_yaml::from_slice(content).map_err(|e| e.to_string())?;
+        if profile.phases.is_empty() {
+            return Err("No phases".to_string());
+        }
+        if profile
+            .phases
+            .iter()
+            .flat_map(Phase::rates)
+            .any(|rate| rate <= 0.0 || !rate.is_finite())
+        {
+            return Err("The rates must be positive".to_string());
+        }
+        Ok(profile)
+    }
+
+    /// The rate at the given time since the start of the simulation.
+    pub fn rate(&self, at: Duration) -> f64 {
+        let total: u64 = self.phases.iter().map(Phase::duration_secs).sum();
+        let mut secs = at.as_secs_f64();
+        if self.repeat && total > 0 {
+            secs %= total as f64;
+        }
+        for phase in &self.phases {
+            let duration = phase.duration_secs() as f64;
+            if secs < duration {
+                return phase.rate(secs);
+            }
+            secs -= duration;
+        }
+        // It's OK to unwrap, since a profile has at least one phase.
+        let last = self.phases.last().unwrap();
+        last.rate(last.duration_secs() as f64)
+    }
+
+    /// The time between the runs at the given time with the rate applied to the frequency.
+    pub fn frequency(&self, frequency: Duration, at: Duration) -> Duration {
+        frequency.div_f64(self.rate(at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_rate() {
+        let yaml = b"
+phases:
+  - shape: constant
+    rate: 1
+    duration_secs: 600
+  - shape: ramp
+    from: 1
+    to: 5
+    duration_secs: 100
+  - shape: sine
+    min: 1
+    max: 3
+    period_secs: 60
+    duration_secs: 120
+";
+        let profile = LoadProfile::parse(yaml).unwrap();
+        assert_eq!(profile.rate(secs(0)), 1.0);
+        assert_eq!(profile.rate(secs(650)), 3.0);
+        assert_eq!(profile.rate(secs(700)), 1.0);
+        assert!((profile.rate(secs(730)) - 3.0).abs() < 1e-9);
+        // The last rate stays.
+        assert!((profile.rate(secs(10000)) - 1.0).abs() < 1e-9);
+        assert_eq!(
+            profile.frequency(secs(10), secs(650)),
+            secs(10).div_f64(3.0)
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let yaml = br#"{"repeat": true, "phases": [
+            {"shape": "constant", "rate": 1, "duration_secs": 60},
+            {"shape": "constant", "rate": 5, "duration_secs": 10}]}"#;
+        let profile = LoadProfile::parse(yaml).unwrap();
+        assert_eq!(profile.rate(secs(65)), 5.0);
+        assert_eq!(profile.rate(secs(75)), 1.0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(LoadProfile::parse(b"phases: []").is_err());
+        let yaml = b"phases: [{shape: constant, rate: 0, duration_secs: 1}]";
+        assert!(LoadProfile::parse(yaml).is_err());
+    }
+}
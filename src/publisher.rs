@@ -0,0 +1,413 @@
+//! Where the simulated messages go. The simulation loop only sees the Publisher trait, so that it works with an MQTT
+//! broker as well as with other sinks.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Stdout, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+pub trait Publisher: Send {
+    /// Publish a message of a device in a run. The device is None for the batches that mix devices.
+    fn publish(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        device: Option<&str>,
+        run: usize,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Called after the last run, for example to say goodbye to the broker.
+    fn close(&mut self) -> impl Future<Output = ()> + Send;
+
+    /// Another publisher that publishes concurrently with this one over the same connections, if the publisher
+    /// supports it. Only the original publisher is closed.
+    fn fork(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// The queue of the messages that were handed to the publisher but are not delivered yet, if the publisher
+    /// buffers messages.
+    fn queue(&self) -> Option<Queue> {
+        None
+    }
+
+    /// Whether the publisher passes the trace context along with the messages, so that it is not added to the
+    /// payload.
+    fn has_trace_context(&self) -> bool {
+        false
+    }
+}
+
+/// What happens when the queue of the publisher is full, because the broker does not keep up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    /// Publishing waits until there is room in the queue, which delays the rest of the run.
+    Block,
+    /// New messages are dropped until there is room in the queue. Messages in the queue cannot be taken back.
+    Drop,
+    /// New messages wait in a backlog until there is room in the queue, and the oldest messages of the backlog are
+    /// dropped when it is full.
+    DropOldest,
+    /// Runs that start with a full queue are skipped, which leaves a gap in the data.
+    SkipRun,
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Backpressure::Block),
+            "drop" => Ok(Backpressure::Drop),
+            "drop-oldest" => Ok(Backpressure::DropOldest),
+            "skip-run" => Ok(Backpressure::SkipRun),
+            _ => Err(format!("Unknown backpressure {}", s)),
+        }
+    }
+}
+
+/// The number of messages of each connection that were handed to the publisher but not delivered yet. Publishers
+/// that share the connections share the queue.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    depths: Vec<Arc<AtomicUsize>>,
+    /// The number of messages of a connection that fit into the queue.
+    capacity: usize,
+}
+
+impl Queue {
+    pub fn new(connections: usize, capacity: usize) -> Self {
+        Queue {
+            depths: (0..connections)
+                .map(|_| Arc::new(AtomicUsize::new(0)))
+                .collect(),
+            capacity,
+        }
+    }
+
+    /// A message was handed to the connection.
+    pub fn push(&self, index: usize) {
+        self.depths[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message of the connection was delivered. The depth does not go below zero, since the messages of the
+    /// simulator itself, like the status, are delivered as well.
+    pub fn pop(&self, index: usize) {
+        let _ = self.depths[index].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+            depth.checked_sub(1)
+        });
+    }
+
+    /// The number of queued messages of all connections.
+    pub fn depth(&self) -> usize {
+        self.depths
+            .iter()
+            .map(|depth| depth.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Whether all connections together have as many messages queued as fit into their queues.
+    pub fn is_full(&self) -> bool {
+        self.depth() >= self.capacity * self.depths.len()
+    }
+}
+
+/// A message with topic, payload, device and run that is not handed to the publisher yet.
+pub type Message = (String, Vec<u8>, Option<String>, usize);
+
+/// The messages that wait for room in the queue of the publisher. The newest messages are kept, so the backlog drops
+/// its oldest messages when it is full.
+pub struct Backlog {
+    messages: VecDeque<Message>,
+    queue: Queue,
+    /// The number of messages that fit into the backlog.
+    capacity: usize,
+}
+
+impl Backlog {
+    pub fn new(queue: Queue, capacity: usize) -> Self {
+        Backlog {
+            messages: VecDeque::new(),
+            queue,
+            capacity,
+        }
+    }
+
+    /// Add a message, and return the number of old messages that were dropped to make room for it.
+    pub fn push(&mut self, message: Message) -> usize {
+        self.messages.push_back(message);
+        let dropped = self.messages.len().saturating_sub(self.capacity);
+        self.messages.drain(..dropped);
+        dropped
+    }
+
+    /// The oldest message, if the queue of the publisher has room for it.
+    pub fn pop(&mut self) -> Option<Message> {
+        if self.queue.is_full() {
+            return None;
+        }
+        self.messages.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// The result of a publish, how long it took and the size of the payload.
+pub type Outcome = (Result<(), String>, Duration, usize);
+
+/// Publishes messages concurrently with several publishers, each of them publishing one message at a time, so that
+/// a slow message does not hold up the others.
+pub struct Concurrent<'a, P> {
+    idle: Vec<&'a mut P>,
+    publishing: FuturesUnordered<BoxFuture<'a, (&'a mut P, Outcome)>>,
+    /// The outcomes of the publishes that completed since they were last taken.
+    completed: Vec<Outcome>,
+}
+
+impl<'a, P: Publisher> Concurrent<'a, P> {
+    pub fn new(publishers: Vec<&'a mut P>) -> Self {
+        Concurrent {
+            idle: publishers,
+            publishing: FuturesUnordered::new(),
+            completed: Vec::new(),
+        }
+    }
+
+    /// Wait until one of the publishers is idle.
+    pub async fn ready(&mut self) {
+        while self.idle.is_empty() {
+            match self.publishing.next().await {
+                Some(completed) => self.complete(completed),
+                None => break,
+            }
+        }
+    }
+
+    /// Start publishing a message with an idle publisher. Call `ready` before.
+    pub fn publish(&mut self, topic: String, payload: Vec<u8>, device: Option<String>, run: usize) {
+        // It's OK to panic, since ready() waits for an idle publisher.
+        let publisher = self.idle.pop().expect("No idle publisher");
+        self.publishing.push(Box::pin(async move {
+            let published = Instant::now();
+            let size = payload.len();
+            let result = publisher
+                .publish(topic, payload, device.as_deref(), run)
+                .await;
+            (publisher, (result, published.elapsed(), size))
+        }));
+    }
+
+    /// Wait for the future while the messages in flight continue to be published.
+    pub async fn wait<T>(&mut self, future: impl Future<Output = T>) -> T {
+        tokio::pin!(future);
+        loop {
+            tokio::select! {
+                biased;
+                output = &mut future => return output,
+                Some(completed) = self.publishing.next() => self.complete(completed),
+            }
+        }
+    }
+
+    /// Wait until all messages in flight are published.
+    pub async fn finish(&mut self) {
+        while let Some(completed) = self.publishing.next().await {
+            self.complete(completed);
+        }
+    }
+
+    /// The outcomes of the publishes that completed since the last call.
+    pub fn take_completed(&mut self) -> Vec<Outcome> {
+        std::mem::take(&mut self.completed)
+    }
+
+    fn complete(&mut self, (publisher, outcome): (&'a mut P, Outcome)) {
+        self.idle.push(publisher);
+        self.completed.push(outcome);
+    }
+}
+
+/// Writes each message as a JSON line with topic, device, run and payload, to the standard output or to a file.
+/// Payloads that are not UTF-8, like Protobuf or compressed payloads, are written in Base64 as payload_base64.
+pub struct WritePublisher<W: Write + Send> {
+    out: W,
+}
+
+impl WritePublisher<Stdout> {
+    pub fn stdout() -> Self {
+        WritePublisher {
+            out: std::io::stdout(),
+        }
+    }
+}
+
+impl WritePublisher<BufWriter<File>> {
+    pub fn file(path: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Cannot create {}: {}", path, e))?;
+        Ok(WritePublisher {
+            out: BufWriter::new(file),
+        })
+    }
+}
+
+impl<W: Write + Send> Publisher for WritePublisher<W> {
+    async fn publish(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        device: Option<&str>,
+        run: usize,
+    ) -> Result<(), String> {
+        let mut line = serde_json::json!({
+            "topic": topic,
+            "device": device,
+            "run": run,
+        });
+        match String::from_utf8(payload) {
+            Ok(payload) => line["payload"] = payload.into(),
+            Err(e) => line["payload_base64"] = STANDARD.encode(e.into_bytes()).into(),
+        }
+        writeln!(self.out, "{}", line).map_err(|e| e.to_string())
+    }
+
+    async fn close(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes a while to publish and fails on the topic "fail".
+    struct SlowPublisher;
+
+    impl Publisher for SlowPublisher {
+        async fn publish(
+            &mut self,
+            topic: String,
+            _payload: Vec<u8>,
+            _device: Option<&str>,
+            _run: usize,
+        ) -> Result<(), String> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            match topic.as_str() {
+                "fail" => Err("Failed".to_string()),
+                _ => Ok(()),
+            }
+        }
+
+        async fn close(&mut self) {}
+
+        fn fork(&self) -> Option<Self> {
+            Some(SlowPublisher)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent() {
+        let mut publisher = SlowPublisher;
+        let mut forks: Vec<_> = (1..4).map_while(|_| publisher.fork()).collect();
+        let mut concurrent =
+            Concurrent::new(std::iter::once(&mut publisher).chain(&mut forks).collect());
+        let start = Instant::now();
+        for topic in ["a", "b", "fail", "c", "d", "e", "f", "g"] {
+            concurrent.ready().await;
+            concurrent.publish(topic.to_string(), Vec::new(), None, 0);
+        }
+        // The messages in flight are published while waiting.
+        concurrent
+            .wait(tokio::time::sleep(Duration::from_millis(75)))
+            .await;
+        let completed = concurrent.take_completed();
+        assert_eq!(completed.len(), 8);
+        assert_eq!(
+            completed
+                .iter()
+                .filter(|(result, _, _)| result.is_err())
+                .count(),
+            1
+        );
+        assert!(start.elapsed() < Duration::from_millis(150));
+        concurrent.ready().await;
+        concurrent.publish("h".to_string(), vec![0; 3], None, 1);
+        concurrent.finish().await;
+        let completed = concurrent.take_completed();
+        assert_eq!(completed.len(), 1);
+        assert!(completed[0].1 >= Duration::from_millis(50));
+        assert_eq!(completed[0].2, 3);
+    }
+
+    #[tokio::test]
+    async fn test_write_publisher() {
+        let mut publisher = WritePublisher { out: Vec::new() };
+        publisher
+            .publish("a/b".to_string(), b"{\"x\":1}".to_vec(), None, 0)
+            .await
+            .unwrap();
+        publisher
+            .publish("c".to_string(), vec![0xff, 0x00], Some("dev"), 1)
+            .await
+            .unwrap();
+        publisher.close().await;
+        assert_eq!(
+            String::from_utf8(publisher.out).unwrap(),
+            "{\"device\":null,\"payload\":\"{\\\"x\\\":1}\",\"run\":0,\"topic\":\"a/b\"}\n\
+             {\"device\":\"dev\",\"payload_base64\":\"/wA=\",\"run\":1,\"topic\":\"c\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_queue() {
+        let queue = Queue::new(2, 2);
+        queue.push(0);
+        queue.push(1);
+        queue.push(1);
+        assert_eq!(queue.depth(), 3);
+        assert!(!queue.is_full());
+        queue.clone().push(0);
+        assert!(queue.is_full());
+        queue.pop(1);
+        assert!(!queue.is_full());
+        queue.pop(1);
+        queue.pop(1);
+        assert_eq!(queue.depth(), 2);
+        assert_eq!("skip-run".parse(), Ok(Backpressure::SkipRun));
+        assert_eq!("drop-oldest".parse(), Ok(Backpressure::DropOldest));
+        assert!("drop-newest".parse::<Backpressure>().is_err());
+    }
+
+    #[test]
+    fn test_backlog() {
+        let queue = Queue::new(1, 1);
+        let mut backlog = Backlog::new(queue.clone(), 2);
+        let message = |run| ("t".to_string(), vec![], None, run);
+        queue.push(0);
+        assert_eq!(backlog.push(message(0)), 0);
+        assert_eq!(backlog.push(message(1)), 0);
+        assert_eq!(backlog.push(message(2)), 1);
+        assert_eq!(backlog.len(), 2);
+        assert_eq!(backlog.pop(), None);
+
+        // The newest messages survive and go out once the queue has room.
+        queue.pop(0);
+        assert_eq!(backlog.pop(), Some(message(1)));
+        assert_eq!(backlog.pop(), Some(message(2)));
+        assert!(backlog.is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! Scenarios with phases that are simulated one after the other, so that benchmarks can be reproduced from a file
+//! instead of changing the environment between runs.
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::payload::{PayloadConfig, PayloadFormat};
+use crate::simulation::SimulationParameters;
+
+/// A phase of a scenario. Parameters that are not given are taken from the environment.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    pub devices: Option<usize>,
+    pub data_points: Option<usize>,
+    pub frequency_secs: Option<f64>,
+    /// How long the phase takes, as an alternative to the number of runs.
+    pub duration_secs: Option<u64>,
+    pub runs: Option<usize>,
+    format: Option<String>,
+    /// The payload configuration for the format of the phase.
+    #[serde(skip)]
+    payload: Option<PayloadConfig>,
+}
+
+impl Phase {
+    /// The parameters of the phase based on the parameters from the environment. A phase with its own number of
+    /// devices simulates uniform devices instead of the devices of a manifest.
+    pub fn parameters(&self, base: &SimulationParameters) -> SimulationParameters {
+        SimulationParameters {
+            client_id: base.client_id.clone(),
+            devices: self.devices.unwrap_or(base.devices),
+            manifest: match self.devices {
+                Some(_) => None,
+                None => base.manifest.clone(),
+            },
+            children: base.children,
+            data_points: self.data_points.unwrap_or(base.data_points),
+            seed: base.seed,
+            frequency_secs: self.frequency_secs.unwrap_or(base.frequency_secs),
+            generators: base.generators.clone(),
+            payload: self.payload.clone().unwrap_or(base.payload.clone()),
+            time: base.time,
+            delivery: base.delivery,
+            threads: base.threads,
+            shard: base.shard.clone(),
+        }
+    }
+
+    /// The payload configuration of the phase, if it has its own format.
+    pub fn payload(&self) -> Option<&PayloadConfig> {
+        self.payload.as_ref()
+    }
+
+    /// The number of runs of the phase with the given frequency.
+    pub fn runs(&self, frequency_secs: f64) -> usize {
+        match (self.runs, self.duration_secs) {
+            (Some(runs), _) => runs,
+            // A zero frequency counts the duration in runs of a second.
+            (None, Some(duration_secs)) if frequency_secs == 0.0 => duration_secs as usize,
+            (None, Some(duration_secs)) => (duration_secs as f64 / frequency_secs).ceil() as usize,
+            (None, None) => usize::MAX,
+        }
+    }
+}
+
+/// Read the phases from YAML or JSON. The payload configuration of a phase with its own format is created with the
+/// given function, so that it has the same defaults as when the format is set in the environment.
+pub fn parse_scenario(
+    content: &[u8],
+    payload: impl Fn(PayloadFormat) -> PayloadConfig,
+) -> Result<Vec<Phase>, String> {
+    let mut phases: Vec<Phase> = serde_yaml::from_slice(content).map_err(|e| e.to_string())?;
+    for phase in &mut phases {
+        if phase.runs.is_none() && phase.duration_secs.is_none() {
+            return Err(format!("Phase {} needs runs or duration_secs", phase.name));
+        }
+        if let Some(Err(e)) = phase.frequency_secs.map(Duration::try_from_secs_f64) {
+            return Err(format!(
+                "Invalid frequency_secs in phase {}: {}",
+                phase.name, e
+            ));
+        }
+        if let Some(format) = &phase.format {
+            phase.payload = Some(payload(format.parse()?));
+        }
+    }
+    Ok(phases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::GeneratorConfig;
+    use crate::simulation::{DeliveryConfig, TimeConfig};
+
+    #[test]
+    fn test_scenario() {
+        let yaml = b"
+- name: warmup
+  devices: 10
+  frequency_secs: 10
+  duration_secs: 95
+- name: load
+  data_points: 50
+  format: json
+  runs: 3
+";
+        let phases = parse_scenario(yaml, |format| PayloadConfig {
+            format,
+            ..PayloadConfig::default()
+        })
+        .unwrap();
+        let base = SimulationParameters {
+            client_id: "test".to_string(),
+            devices: 100,
+            manifest: None,
+            children: 0,
+            data_points: 20,
+            seed: 1,
+            frequency_secs: 1.0,
+            generators: GeneratorConfig::default(),
+            payload: PayloadConfig::default(),
+            time: TimeConfig::default(),
+            delivery: DeliveryConfig::default(),
+            threads: 1,
+            shard: None,
+        };
+
+        let warmup = phases[0].parameters(&base);
+        assert_eq!(
+            (warmup.devices, warmup.data_points, warmup.frequency_secs),
+            (10, 20, 10.0)
+        );
+        assert_eq!(phases[0].runs(warmup.frequency_secs), 10);
+
+        let load = phases[1].parameters(&base);
+        assert_eq!((load.devices, load.data_points), (100, 50));
+        assert_eq!(load.payload.format, PayloadFormat::Json);
+        assert_eq!(phases[1].runs(load.frequency_secs), 3);
+    }
+
+    #[test]
+    fn test_invalid_scenario() {
+        let payload = |_| PayloadConfig::default();
+        assert!(parse_scenario(b"- name: endless", payload).is_err());
+        assert!(parse_scenario(b"- {name: x, runs: 1, format: xml}", payload).is_err());
+    }
+}
@@ -1,80 +1,1255 @@
 use chrono::{DateTime, Utc};
+use prost_reflect::MessageDescriptor;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env::VarError;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+use crate::amqp::AmqpSasl;
+use crate::cli::{setting, Value};
+use crate::cluster::Role;
+use crate::device::DeviceProfile;
+use crate::generator::{
+    AlarmConfig, AnomalyConfig, ClockConfig, CounterConfig, DiurnalConfig, EventConfig,
+    GeneratorConfig, GeneratorType, GroupConfig, GroupMember, HistogramConfig, LocationConfig,
+    MachineConfig, MetadataConfig, NoiseConfig, NoiseDistribution, OfflineConfig, RampConfig,
+    RandomWalkConfig, ReplayData, SensorConfig, SetpointConfig, UnitConfig,
+};
+use crate::http::HttpAuth;
+use crate::mqtt::MqttVersion;
+use crate::observability::LogFormat;
+use crate::pacing::Pacing;
+use crate::payload::{load_message_type, BatchConfig, PayloadConfig, PayloadFormat};
+use crate::presets::Preset;
+use crate::profile::LoadProfile;
+use crate::publisher::Backpressure;
+use crate::scenario::{parse_scenario, Phase};
+use crate::simulation::{DeliveryConfig, MissedRuns, Shard, TimeConfig, TimeMode};
+
+/// The settings from the environment. The command line and the configuration file are read into the environment
+/// before, so that all sources are read the same way. The settings can also be deserialized from the variables and
+/// their values, like in a configuration file, so that they can be given to the simulator without the environment.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "HashMap<String, Value>")]
 pub struct Settings {
-    // Simulation related settings
-    pub sim_devices: usize,
-    pub sim_data_points: usize,
-    pub sim_frequency_secs: u64,
-    pub sim_start_time: Option<DateTime<Utc>>,
-    pub sim_runs: usize,
-    pub sim_seed: u64,
-
-    // MQTT related settings
-    pub broker_url: String,
-    pub broker_user: String,
-    pub broker_pass: String,
-    pub broker_client_id: String,
-    pub broker_qos: u8,
-
-    // Observability related settings
-    pub otlp_collector: Option<String>,
-    pub otlp_auth: Option<String>,
-
-    // Other parameters
+    pub sim: SimSettings,
+    pub broker: BrokerSettings,
+    pub otlp: OtlpSettings,
+    pub log_format: LogFormat,
     pub capacity: usize,
+    pub control_port: Option<u16>,
+    pub grpc_port: Option<u16>,
+}
+
+/// The SIM_ variables.
+#[derive(Debug, Clone)]
+pub struct SimSettings {
+    pub devices: usize,
+    pub gateway_children: usize,
+    pub manifest: Option<Vec<DeviceProfile>>,
+    pub data_points: usize,
+    pub frequency_secs: f64,
+    pub target_msgs_per_sec: Option<f64>,
+    pub pacing: Pacing,
+    pub jitter: f64,
+    pub threads: usize,
+    pub adaptive: bool,
+    pub start_time: Option<DateTime<Utc>>,
+    pub duration_secs: Option<u64>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub time: TimeConfig,
+    pub delivery: DeliveryConfig,
+    pub runs: usize,
+    pub seed: u64,
+    pub generators: GeneratorConfig,
+    pub payload: PayloadConfig,
+    pub snapshot_file: Option<String>,
+    pub snapshot_runs: usize,
+    pub missed_runs: MissedRuns,
+    pub start_paused: bool,
+    pub load_profile: Option<LoadProfile>,
+    pub scenario: Option<Vec<Phase>>,
+    pub profile: Option<Preset>,
+    pub shard: Option<Shard>,
+    pub cluster_role: Option<Role>,
+    pub summary_file: Option<String>,
+}
+
+/// The BROKER_ variables.
+#[derive(Debug, Clone)]
+pub struct BrokerSettings {
+    pub url: String,
+    pub user: String,
+    pub pass: String,
+    pub pass_file: Option<String>,
+    pub client_id: String,
+    pub qos: u8,
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub alpn: Option<String>,
+    pub azure_key: Option<String>,
+    pub azure_token_secs: u64,
+    pub lwt_topic: Option<String>,
+    pub lwt_payload: String,
+    pub lwt_qos: u8,
+    pub lwt_retain: bool,
+    pub lwt_on_close: bool,
+    pub connections: usize,
+    pub client_id_suffix: String,
+    pub connection_per_device: bool,
+    pub device_client_id: String,
+    pub keep_alive_secs: u64,
+    pub clean_session: bool,
+    pub max_inflight: u16,
+    pub max_packet_size: usize,
+    pub publish_concurrency: usize,
+    pub backpressure: Backpressure,
+    pub loopback_topic: Option<String>,
+    pub ack_budget_ms: Option<f64>,
+    pub reconnect: bool,
+    pub control_topic: Option<String>,
+    pub config_topic: Option<String>,
+    pub status_topic: Option<String>,
+    pub status_secs: u64,
+    pub cluster_topic: String,
+    pub churn_secs: Option<f64>,
+    pub churn_global: bool,
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+    pub mqtt_version: MqttVersion,
+    pub user_properties: Vec<(String, String)>,
+    pub topic_aliases: u16,
+    pub message_expiry_secs: Option<u32>,
+    pub http_auth: HttpAuth,
+    pub http_retries: usize,
+    pub amqp_sasl: AmqpSasl,
+}
+
+/// The OTLP_ variables.
+#[derive(Debug, Clone)]
+pub struct OtlpSettings {
+    pub collector: Option<String>,
+    pub auth: Option<String>,
+    pub auth_file: Option<String>,
+    pub traceparent: bool,
+    /// Whether traces are exported, or only metrics.
+    pub traces: bool,
+    pub sample_ratio: f64,
+    pub sample_per_sec: Option<f64>,
+}
+
+thread_local! {
+    /// The problems that were found while reading the settings, so that they can be reported together.
+    static PROBLEMS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// The variables that are read instead of the environment, if any.
+    static VARIABLES: RefCell<Option<HashMap<String, String>>> = const { RefCell::new(None) };
+}
+
+fn var(env_variable: &str) -> Result<String, VarError> {
+    VARIABLES.with(|variables| match &*variables.borrow() {
+        Some(variables) => variables
+            .get(env_variable)
+            .cloned()
+            .ok_or(VarError::NotPresent),
+        None => std::env::var(env_variable),
+    })
+}
+
+/// Record a problem with a variable. The setting falls back to its default, so that the other settings can still be
+/// checked.
+fn problem(env_variable: &str, problem: impl Display) {
+    PROBLEMS.with(|problems| {
+        problems
+            .borrow_mut()
+            .push(format!("{}: {}", env_variable, problem))
+    });
+}
+
+fn take_problems() -> Vec<String> {
+    PROBLEMS.with(|problems| problems.take())
 }
 
 fn get(env_variable: &str, default: &str) -> String {
-    std::env::var(env_variable).unwrap_or(default.to_string())
+    var(env_variable).unwrap_or(default.to_string())
 }
 
-fn get_num(env_variable: &str, default: usize) -> usize {
-    std::env::var(env_variable)
-        .unwrap_or(default.to_string())
+/// Parse a variable, or record a problem and use the default if it cannot be parsed.
+fn get_parsed<T>(env_variable: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    get_optional(env_variable).unwrap_or(default)
+}
+
+/// Parse a variable that has no default, or record a problem if it cannot be parsed.
+fn get_optional<T>(env_variable: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = var(env_variable).ok()?;
+    value
         .parse()
-        .unwrap() // It's OK to panic if someone sets a broken number in the environment.
+        .map_err(|e| problem(env_variable, format_args!("Invalid value {}: {}", value, e)))
+        .ok()
 }
 
-fn get_time(env_variable: &str, default: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
-    std::env::var(env_variable)
+/// Parse a variable with a name like smartrest, with a default name.
+fn get_named<T>(env_variable: &str, default: &str) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    // It's OK to panic, since the default names are valid.
+    get_optional(env_variable).unwrap_or_else(|| default.parse().ok().unwrap())
+}
+
+fn get_num(env_variable: &str, default: usize) -> usize {
+    get_parsed(env_variable, default)
+}
+
+fn get_float(env_variable: &str, default: f64) -> f64 {
+    get_parsed(env_variable, default)
+}
+
+fn get_bool(env_variable: &str, default: bool) -> bool {
+    get_parsed(env_variable, default)
+}
+
+/// Read a file that is given in a variable, or record a problem if it cannot be read.
+fn get_file(env_variable: &str) -> Option<(String, Vec<u8>)> {
+    let path = var(env_variable).ok()?;
+    match std::fs::read(&path) {
+        Ok(content) => Some((path, content)),
+        Err(e) => {
+            problem(env_variable, format_args!("Cannot read {}: {}", path, e));
+            None
+        }
+    }
+}
+
+fn get_replay_data(env_variable: &str) -> Option<Arc<ReplayData>> {
+    let (path, content) = get_file(env_variable)?;
+    ReplayData::from_csv(&String::from_utf8_lossy(&content))
+        .map_err(|e| {
+            problem(
+                env_variable,
+                format_args!("Invalid replay data {}: {}", path, e),
+            )
+        })
+        .ok()
+        .map(Arc::new)
+}
+
+/// Load a protobuf message type from a file descriptor set, if one is configured.
+fn get_protobuf_message(file_variable: &str, message_variable: &str) -> Option<MessageDescriptor> {
+    let (_, content) = get_file(file_variable)?;
+    load_message_type(&content, &get(message_variable, "Telemetry"))
+        .map_err(|e| problem(message_variable, e))
+        .ok()
+}
+
+/// Parse weighted values such as a generator mix of the form <value>:<weight>,<value>:<weight>,...
+fn get_weighted<T>(env_variable: &str, default: Vec<(T, u32)>) -> Vec<(T, u32)>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match var(env_variable) {
+        Ok(entries) => parse_weighted(&entries).unwrap_or_else(|e| {
+            problem(env_variable, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn parse_weighted<T>(entries: &str) -> Result<Vec<(T, u32)>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    entries
+        .split(',')
+        .map(|entry| match entry.split_once(':') {
+            Some((value, weight)) => Ok((
+                value
+                    .parse()
+                    .map_err(|e| format!("Invalid entry {}: {}", entry, e))?,
+                weight
+                    .parse()
+                    .map_err(|e| format!("Invalid weight in {}: {}", entry, e))?,
+            )),
+            None => Err(format!("Invalid weighted entry {}", entry)),
+        })
+        .collect()
+}
+
+/// A device in the manifest file. Generator mix and publish intervals have the format of the environment variables.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    name: String,
+    serial: Option<String>,
+    data_points: Option<usize>,
+    mix: Option<String>,
+    intervals: Option<String>,
+}
+
+/// Read the devices from a YAML or JSON manifest file. Devices without data points get the default number of data points.
+fn get_manifest(env_variable: &str, data_points: usize) -> Option<Vec<DeviceProfile>> {
+    let (path, content) = get_file(env_variable)?;
+    parse_manifest(&content, data_points)
+        .map_err(|e| {
+            problem(
+                env_variable,
+                format_args!("Invalid manifest {}: {}", path, e),
+            )
+        })
+        .ok()
+}
+
+fn get_load_profile(env_variable: &str) -> Option<LoadProfile> {
+    let (path, content) = get_file(env_variable)?;
+    LoadProfile::parse(&content)
+        .map_err(|e| {
+            problem(
+                env_variable,
+                format_args!("Invalid load profile {}: {}", path, e),
+            )
+        })
+        .ok()
+}
+
+fn get_scenario(env_variable: &str) -> Option<Vec<Phase>> {
+    let (path, content) = get_file(env_variable)?;
+    // The connections and the snapshot belong to the devices of a single simulation.
+    if get_bool("BROKER_CONNECTION_PER_DEVICE", false) || var("SIM_SNAPSHOT_FILE").is_ok() {
+        problem(
+            env_variable,
+            "Cannot be combined with BROKER_CONNECTION_PER_DEVICE or SIM_SNAPSHOT_FILE",
+        );
+        return None;
+    }
+    parse_scenario(&content, get_payload_config)
+        .map_err(|e| {
+            problem(
+                env_variable,
+                format_args!("Invalid scenario {}: {}", path, e),
+            )
+        })
         .ok()
-        .map(|time| {
-            DateTime::parse_from_rfc3339(&time)
-                .unwrap()
-                .with_timezone(&Utc)
+}
+
+/// YAML is a superset of JSON, so the YAML parser reads both.
+fn parse_manifest(content: &[u8], data_points: usize) -> Result<Vec<DeviceProfile>, String> {
+    let entries: Vec<ManifestEntry> = serde_yaml::from_slice(content).map_err(|e| e.to_string())?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            Ok(DeviceProfile {
+                mix: entry
+                    .mix
+                    .map(|mix| parse_weighted(&mix))
+                    .transpose()
+                    .map_err(|e| format!("{} in the mix of {}", e, entry.name))?,
+                intervals: entry
+                    .intervals
+                    .map(|intervals| parse_weighted(&intervals))
+                    .transpose()
+                    .map_err(|e| format!("{} in the intervals of {}", e, entry.name))?,
+                name: entry.name,
+                serial: entry.serial,
+                data_points: entry.data_points.unwrap_or(data_points),
+            })
         })
-        .or(default)
+        .collect()
+}
+
+/// Parse the name of a noise distribution and read its parameters from further variables. Parameters that do not
+/// describe the distribution are recorded as problems.
+fn get_noise_distribution(env_variable: &str) -> NoiseDistribution {
+    let get_lambda = || {
+        let lambda = get_float("SIM_NOISE_LAMBDA", 1.0);
+        // Also rejects NaN.
+        if !(lambda > 0.0 && lambda.is_finite()) {
+            problem("SIM_NOISE_LAMBDA", "Must be greater than 0");
+        }
+        lambda
+    };
+    match var(env_variable).as_deref() {
+        Err(_) | Ok("uniform") => NoiseDistribution::Uniform,
+        Ok("normal") => {
+            let std_dev = get_float("SIM_NOISE_STDDEV", 1.0);
+            if !(std_dev >= 0.0 && std_dev.is_finite()) {
+                problem("SIM_NOISE_STDDEV", "Must not be negative");
+            }
+            NoiseDistribution::Normal {
+                mean: get_float("SIM_NOISE_MEAN", 0.0),
+                std_dev,
+            }
+        }
+        Ok("exponential") => NoiseDistribution::Exponential {
+            lambda: get_lambda(),
+        },
+        Ok("poisson") => NoiseDistribution::Poisson {
+            lambda: get_lambda(),
+        },
+        Ok(other) => {
+            problem(
+                env_variable,
+                format_args!("Unknown noise distribution {}", other),
+            );
+            NoiseDistribution::Uniform
+        }
+    }
+}
+
+/// Parse units of the form <type>:<unit>[:<scale>[:<offset>]],...
+fn get_units(
+    env_variable: &str,
+    default: Vec<(GeneratorType, UnitConfig)>,
+) -> Vec<(GeneratorType, UnitConfig)> {
+    match var(env_variable) {
+        Ok(units) => units
+            .split(',')
+            .filter_map(|entry| {
+                let parts: Vec<&str> = entry.split(':').collect();
+                let unit = match parts[..] {
+                    [generator, unit, ref scaling @ ..] if scaling.len() <= 2 => {
+                        let scale = scaling.first().map_or(Ok(1.0), |scale| scale.parse());
+                        let offset = scaling.get(1).map_or(Ok(0.0), |offset| offset.parse());
+                        match (generator.parse(), scale, offset) {
+                            (Ok(generator), Ok(scale), Ok(offset)) => Some((
+                                generator,
+                                UnitConfig {
+                                    unit: unit.to_string(),
+                                    scale,
+                                    offset,
+                                },
+                            )),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if unit.is_none() {
+                    problem(env_variable, format_args!("Invalid unit {}", entry));
+                }
+                unit
+            })
+            .collect(),
+        Err(_) => default,
+    }
+}
+
+/// Parse event types of the form <type>:<text>;<type>:<text>;...
+fn get_event_types(env_variable: &str, default: Vec<(String, String)>) -> Vec<(String, String)> {
+    match var(env_variable) {
+        Ok(types) => types
+            .split(';')
+            .filter_map(|entry| match entry.split_once(':') {
+                Some((event_type, text)) => Some((event_type.to_string(), text.to_string())),
+                None => {
+                    problem(env_variable, format_args!("Invalid event type {}", entry));
+                    None
+                }
+            })
+            .collect(),
+        Err(_) => default,
+    }
+}
+
+/// Parse a list of the form <value>,<value>,...
+fn get_list(env_variable: &str, default: Vec<String>) -> Vec<String> {
+    match var(env_variable) {
+        Ok(values) => values.split(',').map(|value| value.to_string()).collect(),
+        Err(_) => default,
+    }
+}
+
+/// Parse MQTT 5 user properties of the form <key>:<value>,<key>:<value>,...
+fn get_user_properties(env_variable: &str) -> Vec<(String, String)> {
+    get_list(env_variable, vec![])
+        .into_iter()
+        .filter(|property| !property.is_empty())
+        .filter_map(|property| match property.split_once(':') {
+            Some((key, value)) => Some((key.to_string(), value.to_string())),
+            None => {
+                problem(
+                    env_variable,
+                    format_args!("Invalid user property {}", property),
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Parse group members of the form <offset>:<gain>:<noise>;<offset>:<gain>:<noise>;...
+fn get_group_members(env_variable: &str, default: Vec<GroupMember>) -> Vec<GroupMember> {
+    match var(env_variable) {
+        Ok(members) => members
+            .split(';')
+            .filter_map(|member| {
+                let parts: Result<Vec<f64>, _> =
+                    member.split(':').map(|part| part.parse()).collect();
+                match parts.as_deref() {
+                    Ok(&[offset, gain, noise]) => Some(GroupMember {
+                        offset,
+                        gain,
+                        noise,
+                    }),
+                    _ => {
+                        problem(
+                            env_variable,
+                            format_args!("Invalid group member {}", member),
+                        );
+                        None
+                    }
+                }
+            })
+            .collect(),
+        Err(_) => default,
+    }
+}
+
+/// The file of a secret in the variable with the suffix _FILE, like a Kubernetes or Docker secret that is mounted as a
+/// file.
+fn get_secret_file(env_variable: &str) -> Option<String> {
+    let file_variable = format!("{}_FILE", env_variable);
+    let path = var(&file_variable).ok()?;
+    if var(env_variable).is_ok() {
+        problem(
+            &file_variable,
+            format_args!("Cannot be combined with {}", env_variable),
+        );
+    }
+    Some(path)
+}
+
+/// A secret from its file, if any, otherwise from the variable.
+fn get_secret(env_variable: &str, file: Option<&str>) -> Option<String> {
+    match file {
+        Some(path) => read_secret(path)
+            .map_err(|e| problem(&format!("{}_FILE", env_variable), e))
+            .ok(),
+        None => var(env_variable).ok(),
+    }
+}
+
+/// Read a secret from a file. The line break at the end, which editors and `echo` add, is not part of the secret.
+/// An empty file is rather a secret that is not mounted yet than an empty secret.
+pub fn read_secret(path: &str) -> Result<String, String> {
+    let secret =
+        std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path));
+    }
+    Ok(secret.to_string())
+}
+
+/// The client ID defaults to the pod name from the Kubernetes downward API. {hostname} and {random} are replaced, so
+/// that replicas with the same environment have different client IDs.
+fn get_client_id() -> String {
+    let template = var("BROKER_CLIENT_ID")
+        .or_else(|_| var("POD_NAME"))
+        .unwrap_or_else(|_| "rumsim-0".to_string());
+    expand_client_id(&template, &hostname(), rand::random())
+}
+
+fn expand_client_id(template: &str, hostname: &str, random: u32) -> String {
+    template
+        .replace("{hostname}", hostname)
+        .replace("{random}", &format!("{:08x}", random))
+}
+
+/// The host name, which is the pod name in Kubernetes and the container ID in Docker.
+fn hostname() -> String {
+    var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// The shard of this instance with SIM_SHARDS. The index is taken from SIM_SHARD_INDEX, the pod index from the
+/// downward API or the number at the end of the client ID, like the ordinal in the pod names of a StatefulSet.
+fn get_shard(client_id: &str) -> Option<Shard> {
+    let count = get_optional("SIM_SHARDS")?;
+    let (cluster_id, ordinal) = split_ordinal(client_id);
+    let Some(index) = get_optional("SIM_SHARD_INDEX")
+        .or_else(|| get_optional("POD_INDEX"))
+        .or(ordinal)
+    else {
+        problem(
+            "SIM_SHARDS",
+            "Requires SIM_SHARD_INDEX or a client ID ending with the index",
+        );
+        return None;
+    };
+    if index >= count {
+        problem(
+            "SIM_SHARDS",
+            format_args!("The shard index {} is not below {}", index, count),
+        );
+        return None;
+    }
+    Some(Shard {
+        index,
+        count,
+        cluster_id: get("SIM_SHARD_CLUSTER", cluster_id),
+    })
+}
+
+/// Split a client ID like rumsim-3 into the common part and the ordinal.
+fn split_ordinal(client_id: &str) -> (&str, Option<usize>) {
+    match client_id.rsplit_once('-') {
+        Some((cluster_id, ordinal)) => match ordinal.parse() {
+            Ok(ordinal) => (cluster_id, Some(ordinal)),
+            Err(_) => (client_id, None),
+        },
+        None => (client_id, None),
+    }
+}
+
+fn get_optional_num(env_variable: &str) -> Option<i64> {
+    get_optional(env_variable)
+}
+
+fn get_time(env_variable: &str, default: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match var(env_variable) {
+        Ok(time) => DateTime::parse_from_rfc3339(&time)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|e| problem(env_variable, format_args!("Invalid time {}: {}", time, e)))
+            .ok(),
+        Err(_) => default,
+    }
+}
+
+/// The time between the runs in seconds, from SIM_FREQUENCY, or otherwise from SIM_FREQUENCY_SECS.
+fn get_frequency() -> f64 {
+    let env_variable = match var("SIM_FREQUENCY") {
+        Ok(_) => "SIM_FREQUENCY",
+        Err(_) => "SIM_FREQUENCY_SECS",
+    };
+    match var(env_variable) {
+        Ok(value) => parse_frequency(env_variable, &value).unwrap_or_else(|e| {
+            problem(env_variable, e);
+            1.0
+        }),
+        Err(_) => 1.0,
+    }
+}
+
+/// Parse a duration like 250ms or 2.5s for SIM_FREQUENCY, or a number of seconds for SIM_FREQUENCY_SECS.
+pub fn parse_frequency(env_variable: &str, value: &str) -> Result<f64, String> {
+    let secs = match env_variable {
+        "SIM_FREQUENCY" => humantime::parse_duration(value)
+            .map(|frequency| frequency.as_secs_f64())
+            .map_err(|e| e.to_string()),
+        _ => value.parse::<f64>().map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Invalid value {}: {}", value, e))?;
+    // Negative values and NaN cannot be converted to a duration.
+    if secs == 0.0 {
+        return Err("Must be greater than 0".to_string());
+    }
+    std::time::Duration::try_from_secs_f64(secs)
+        .map(|_| secs)
+        .map_err(|e| format!("Invalid value {}: {}", value, e))
+}
+
+/// Backfilling always runs in simulated time from the start time.
+fn get_time_config() -> TimeConfig {
+    let start = get_time("SIM_START_TIME", None);
+    let backfill_end = get_time("SIM_BACKFILL_END", None);
+    let mode = match backfill_end {
+        Some(_) if start.is_none() => {
+            problem("SIM_BACKFILL_END", "Requires SIM_START_TIME");
+            TimeMode::Simulated
+        }
+        Some(_) => TimeMode::Simulated,
+        None => get_named("SIM_TIME_MODE", "real"),
+    };
+    TimeConfig {
+        mode,
+        start,
+        backdate_secs: get_num("SIM_BACKDATE_SECS", 0) as u64,
+        backfill_end,
+    }
+}
+
+fn get_delivery_config(golden: bool) -> DeliveryConfig {
+    let config = DeliveryConfig {
+        drop_rate: get_float("SIM_DROP_RATE", 0.0),
+        delay_rate: get_float("SIM_DELAY_RATE", 0.0),
+        delay_runs: get_num("SIM_DELAY_RUNS", 1),
+        duplicate_rate: get_float("SIM_DUPLICATE_RATE", 0.0),
+    };
+    if golden {
+        config.golden()
+    } else {
+        config
+    }
+}
+
+fn get_generator_config(golden: bool, simulated_time: bool) -> GeneratorConfig {
+    let default = GeneratorConfig::default();
+    let config = GeneratorConfig {
+        mix: get_weighted("SIM_GENERATOR_MIX", default.mix),
+        intervals: get_weighted("SIM_PUBLISH_INTERVALS", default.intervals),
+        units: get_units("SIM_UNITS", default.units),
+        phase: get_num("SIM_PHASE", default.phase as usize) as u32,
+        events: EventConfig {
+            rate: get_float("SIM_EVENT_RATE", default.events.rate),
+            topic: get("SIM_EVENT_TOPIC", &default.events.topic),
+            types: get_event_types("SIM_EVENT_TYPES", default.events.types),
+        },
+        metadata: MetadataConfig {
+            runs: get_num("SIM_METADATA_RUNS", default.metadata.runs as usize) as u32,
+            models: get_list("SIM_METADATA_MODELS", default.metadata.models),
+            firmware: get_list("SIM_METADATA_FIRMWARE", default.metadata.firmware),
+            spread: get_float("SIM_METADATA_SPREAD", default.metadata.spread),
+        },
+        clock: ClockConfig {
+            skew_millis: get_num("SIM_CLOCK_SKEW_MS", 0) as u64,
+            jitter_millis: get_num("SIM_CLOCK_JITTER_MS", 0) as u64,
+        },
+        sensor: SensorConfig {
+            offset: get_float("SIM_SENSOR_OFFSET", default.sensor.offset),
+            amplitude: get_float("SIM_SENSOR_AMPLITUDE", default.sensor.amplitude),
+            jitter: get_float("SIM_SENSOR_JITTER", default.sensor.jitter),
+            period: get_num("SIM_SENSOR_PERIOD", default.sensor.period as usize) as u32,
+        },
+        noise: NoiseConfig {
+            distribution: get_noise_distribution("SIM_NOISE_DISTRIBUTION"),
+        },
+        ramp: RampConfig {
+            max: get_float("SIM_RAMP_MAX", default.ramp.max),
+            step: get_float("SIM_RAMP_STEP", default.ramp.step),
+        },
+        counter: CounterConfig {
+            min_increment: get_num(
+                "SIM_COUNTER_MIN_INCREMENT",
+                default.counter.min_increment as usize,
+            ) as u32,
+            max_increment: get_num(
+                "SIM_COUNTER_MAX_INCREMENT",
+                default.counter.max_increment as usize,
+            ) as u32,
+            bits: get_num("SIM_COUNTER_BITS", default.counter.bits as usize) as u32,
+            reset_probability: get_float(
+                "SIM_COUNTER_RESET_PROBABILITY",
+                default.counter.reset_probability,
+            ),
+        },
+        setpoint: SetpointConfig {
+            min: get_float("SIM_SETPOINT_MIN", default.setpoint.min),
+            max: get_float("SIM_SETPOINT_MAX", default.setpoint.max),
+            step: get_float("SIM_SETPOINT_STEP", default.setpoint.step),
+            hold: get_num("SIM_SETPOINT_HOLD", default.setpoint.hold as usize) as u32,
+        },
+        location: LocationConfig {
+            latitude: get_float("SIM_LOCATION_LATITUDE", default.location.latitude),
+            longitude: get_float("SIM_LOCATION_LONGITUDE", default.location.longitude),
+            speed: get_float("SIM_LOCATION_SPEED", default.location.speed),
+        },
+        alarm: AlarmConfig {
+            probability: get_float("SIM_ALARM_PROBABILITY", default.alarm.probability),
+            hold: get_num("SIM_ALARM_HOLD", default.alarm.hold as usize) as u32,
+        },
+        random_walk: RandomWalkConfig {
+            min: get_float("SIM_WALK_MIN", default.random_walk.min),
+            max: get_float("SIM_WALK_MAX", default.random_walk.max),
+            step: get_float("SIM_WALK_STEP", default.random_walk.step),
+        },
+        anomaly: AnomalyConfig {
+            rate: get_float("SIM_ANOMALY_RATE", default.anomaly.rate),
+            spike: get_float("SIM_ANOMALY_SPIKE", default.anomaly.spike),
+            stuck: get_num("SIM_ANOMALY_STUCK", default.anomaly.stuck as usize) as u32,
+        },
+        offline: OfflineConfig {
+            rate: get_float("SIM_OFFLINE_RATE", default.offline.rate),
+            duration: get_num("SIM_OFFLINE_DURATION", default.offline.duration as usize) as u32,
+            recovery_code: get_optional_num("SIM_OFFLINE_RECOVERY_CODE"),
+        },
+        group: GroupConfig {
+            step: get_float("SIM_GROUP_STEP", default.group.step),
+            members: get_group_members("SIM_GROUP_MEMBERS", default.group.members),
+        },
+        machine: MachineConfig {
+            start_probability: get_float(
+                "SIM_MACHINE_START_PROBABILITY",
+                default.machine.start_probability,
+            ),
+            stop_probability: get_float(
+                "SIM_MACHINE_STOP_PROBABILITY",
+                default.machine.stop_probability,
+            ),
+            fault_probability: get_float(
+                "SIM_MACHINE_FAULT_PROBABILITY",
+                default.machine.fault_probability,
+            ),
+            min_dwell: get_num("SIM_MACHINE_MIN_DWELL", default.machine.min_dwell as usize) as u32,
+            repair_time: get_num(
+                "SIM_MACHINE_REPAIR_TIME",
+                default.machine.repair_time as usize,
+            ) as u32,
+            idle_temperature: get_float(
+                "SIM_MACHINE_IDLE_TEMPERATURE",
+                default.machine.idle_temperature,
+            ),
+            running_temperature: get_float(
+                "SIM_MACHINE_RUNNING_TEMPERATURE",
+                default.machine.running_temperature,
+            ),
+            heating_rate: get_float("SIM_MACHINE_HEATING_RATE", default.machine.heating_rate),
+        },
+        diurnal: DiurnalConfig {
+            base: get_float("SIM_DIURNAL_BASE", default.diurnal.base),
+            peak: get_float("SIM_DIURNAL_PEAK", default.diurnal.peak),
+            peak_hour: get_float("SIM_DIURNAL_PEAK_HOUR", default.diurnal.peak_hour),
+            seasonal: get_float("SIM_DIURNAL_SEASONAL", default.diurnal.seasonal),
+            jitter: get_float("SIM_DIURNAL_JITTER", default.diurnal.jitter),
+        },
+        histogram: HistogramConfig {
+            states: get_weighted("SIM_HISTOGRAM_STATES", default.histogram.states),
+        },
+        expression: get_parsed("SIM_EXPRESSION", default.expression),
+        replay: get_replay_data("SIM_REPLAY_FILE"),
+    };
+    if golden {
+        let config = config.golden(simulated_time);
+        if config.mix.is_empty() {
+            problem(
+                "SIM_GOLDEN",
+                "None of the generators in SIM_GENERATOR_MIX is deterministic",
+            );
+        }
+        config
+    } else {
+        config
+    }
+}
+
+/// The payload configuration for the given format, with the defaults that depend on the format.
+fn get_payload_config(format: PayloadFormat) -> PayloadConfig {
+    let config = PayloadConfig {
+        format,
+        topic: get("SIM_TOPIC_TEMPLATE", format.default_topic()),
+        sparkplug_group: get("SIM_SPARKPLUG_GROUP", "rumsim"),
+        batch: BatchConfig {
+            records: get_num("SIM_BATCH_RECORDS", 1).max(1),
+            bytes: get_num("SIM_BATCH_BYTES", 0),
+        },
+        protobuf: get_protobuf_message("SIM_PROTOBUF_DESCRIPTOR", "SIM_PROTOBUF_MESSAGE"),
+        aws_shadow_runs: get_num("SIM_AWS_SHADOW_RUNS", 0) as u32,
+        compression: get_named("SIM_COMPRESSION", "none"),
+        metadata_topic: get("SIM_METADATA_TOPIC", format.default_metadata_topic()),
+        retain: Vec::new(),
+    };
+    let config = PayloadConfig {
+        retain: get_list("SIM_RETAIN_TOPICS", config.default_retain())
+            .into_iter()
+            .filter(|filter| !filter.is_empty())
+            .collect(),
+        ..config
+    };
+    if format == PayloadFormat::Protobuf && config.protobuf.is_none() {
+        problem(
+            "SIM_PAYLOAD_FORMAT",
+            "protobuf requires SIM_PROTOBUF_DESCRIPTOR",
+        );
+    }
+    if get_bool("SIM_MESSAGE_PER_DATAPOINT", false) {
+        config.per_data_point()
+    } else {
+        config
+    }
 }
 
 impl Settings {
-    pub fn new() -> Settings {
-        Settings {
-            // Simulation related settings
-            sim_devices: get_num("SIM_DEVICES", 100),
-            sim_data_points: get_num("SIM_DATA_POINTS", 100),
-            sim_seed: get_num("SIM_SEED", 0) as u64,
-            sim_frequency_secs: get_num("SIM_FREQUENCY_SECS", 1) as u64,
-            sim_start_time: get_time("SIM_START_TIME", None),
-            sim_runs: get_num("SIM_RUNS", usize::MAX),
-
-            // MQTT related settings
-            broker_url: get("BROKER_URL", "mqtt://localhost:1883"),
-            broker_user: get("BROKER_USER", "mqtt"),
-            broker_pass: get("BROKER_PASS", "pass"),
-            broker_client_id: get("BROKER_CLIENT_ID", "rumsim-0"),
-            broker_qos: get_num("BROKER_QOS", 1) as u8,
-
-            // Observability related settings
-            otlp_collector: std::env::var("OTLP_ENDPOINT").ok(),
-            otlp_auth: std::env::var("OLTP_AUTH").ok(),
-
-            // Other parameters
+    /// Read the settings from the given variables instead of the environment, so that the settings don't depend on
+    /// the environment and don't change it, for example in tests.
+    pub fn from_variables(variables: &[(&str, &str)]) -> Result<Settings, Vec<String>> {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Settings::with_variables(variables)
+    }
+
+    fn with_variables(variables: HashMap<String, String>) -> Result<Settings, Vec<String>> {
+        VARIABLES.with(|current| current.replace(Some(variables)));
+        let settings = Settings::new();
+        VARIABLES.with(|current| current.take());
+        settings
+    }
+
+    /// Read the settings from the environment, or all problems with the variables.
+    pub fn new() -> Result<Settings, Vec<String>> {
+        take_problems();
+        // The shard is taken from the client ID, which is only expanded once.
+        let broker_client_id = get_client_id();
+        let broker_pass_file = get_secret_file("BROKER_PASS");
+        let golden = get_bool("SIM_GOLDEN", false);
+        let time = get_time_config();
+        let simulated_time = time.mode == TimeMode::Simulated;
+        // The misspelled OLTP_AUTH of earlier versions still works.
+        let otlp_auth_file = get_secret_file("OTLP_AUTH");
+        // rumqttc always sends the host of the broker URL as the server name, so that it cannot be overridden.
+        if var("BROKER_TLS_SERVER_NAME").is_ok() {
+            problem(
+                "BROKER_TLS_SERVER_NAME",
+                "Is not supported, the server name is the host in BROKER_URL",
+            );
+        }
+        let otlp_auth =
+            get_secret("OTLP_AUTH", otlp_auth_file.as_deref()).or_else(|| var("OLTP_AUTH").ok());
+        let settings = Settings {
+            sim: SimSettings {
+                devices: get_num("SIM_DEVICES", 100),
+                gateway_children: get_num("SIM_GATEWAY_CHILDREN", 0),
+                manifest: get_manifest("SIM_DEVICE_MANIFEST", get_num("SIM_DATA_POINTS", 100)).map(
+                    |profiles| {
+                        profiles
+                            .into_iter()
+                            .map(|profile| {
+                                if golden {
+                                    profile.golden(simulated_time)
+                                } else {
+                                    profile
+                                }
+                            })
+                            .collect()
+                    },
+                ),
+                data_points: get_num("SIM_DATA_POINTS", 100),
+                seed: get_num("SIM_SEED", 0) as u64,
+                frequency_secs: get_frequency(),
+                start_time: get_time("SIM_START_TIME", None),
+                duration_secs: get_optional("SIM_DURATION_SECS"),
+                end_time: get_time("SIM_END_TIME", None),
+                time,
+                delivery: get_delivery_config(golden),
+                runs: get_num("SIM_RUNS", usize::MAX),
+                generators: get_generator_config(golden, simulated_time),
+                payload: get_payload_config(get_named("SIM_PAYLOAD_FORMAT", "smartrest")),
+                snapshot_file: var("SIM_SNAPSHOT_FILE").ok(),
+                snapshot_runs: get_num("SIM_SNAPSHOT_RUNS", 60),
+                missed_runs: get_named("SIM_MISSED_RUNS", "delay"),
+                target_msgs_per_sec: get_optional("SIM_TARGET_MSGS_PER_SEC"),
+                pacing: get_named("SIM_PACING", "burst"),
+                jitter: get_float("SIM_JITTER", 0.0),
+                threads: get_num("SIM_THREADS", 1),
+                adaptive: get_bool("SIM_ADAPTIVE", false),
+                start_paused: get_bool("SIM_START_PAUSED", false),
+                load_profile: get_load_profile("SIM_LOAD_PROFILE"),
+                scenario: get_scenario("SIM_SCENARIO"),
+                profile: get_optional("SIM_PROFILE"),
+                shard: get_shard(&broker_client_id),
+                summary_file: var("SIM_SUMMARY_FILE").ok(),
+                cluster_role: get_optional("SIM_CLUSTER_ROLE"),
+            },
+            broker: BrokerSettings {
+                url: get("BROKER_URL", "mqtt://localhost:1883"),
+                user: get("BROKER_USER", "mqtt"),
+                pass: get_secret("BROKER_PASS", broker_pass_file.as_deref())
+                    .unwrap_or("pass".to_string()),
+                pass_file: broker_pass_file,
+                client_id: broker_client_id,
+                qos: get_parsed("BROKER_QOS", 1),
+                ca_file: var("BROKER_CA_FILE").ok(),
+                cert_file: var("BROKER_CERT_FILE").ok(),
+                key_file: var("BROKER_KEY_FILE").ok(),
+                alpn: var("BROKER_ALPN").ok(),
+                azure_key: var("BROKER_AZURE_KEY").ok(),
+                azure_token_secs: get_num("BROKER_AZURE_TOKEN_SECS", 86400) as u64,
+                lwt_topic: var("BROKER_LWT_TOPIC").ok(),
+                lwt_payload: get("BROKER_LWT_PAYLOAD", "offline"),
+                lwt_qos: get_parsed("BROKER_LWT_QOS", 1),
+                lwt_retain: get_bool("BROKER_LWT_RETAIN", false),
+                lwt_on_close: get_bool("BROKER_LWT_ON_CLOSE", true),
+                connections: get_num("BROKER_CONNECTIONS", 1),
+                client_id_suffix: get("BROKER_CLIENT_ID_SUFFIX", "-{n}"),
+                connection_per_device: get_bool("BROKER_CONNECTION_PER_DEVICE", false),
+                device_client_id: get("BROKER_DEVICE_CLIENT_ID", "{device}"),
+                keep_alive_secs: get_num("BROKER_KEEP_ALIVE_SECS", 5) as u64,
+                clean_session: get_bool("BROKER_CLEAN_SESSION", true),
+                max_inflight: get_parsed("BROKER_MAX_INFLIGHT", 100),
+                max_packet_size: get_num("BROKER_MAX_PACKET_SIZE", 10 * 1024),
+                publish_concurrency: get_num("BROKER_PUBLISH_CONCURRENCY", 1),
+                backpressure: get_named("BROKER_BACKPRESSURE", "block"),
+                loopback_topic: var("BROKER_LOOPBACK_TOPIC").ok(),
+                ack_budget_ms: get_optional("BROKER_ACK_BUDGET_MS"),
+                reconnect: get_bool("BROKER_RECONNECT", true),
+                control_topic: var("BROKER_CONTROL_TOPIC").ok(),
+                config_topic: var("BROKER_CONFIG_TOPIC").ok(),
+                status_topic: var("BROKER_STATUS_TOPIC").ok(),
+                status_secs: get_num("BROKER_STATUS_SECS", 10) as u64,
+                cluster_topic: get("BROKER_CLUSTER_TOPIC", "rumsim/cluster"),
+                churn_secs: get_optional("BROKER_CHURN_SECS"),
+                churn_global: get_bool("BROKER_CHURN_GLOBAL", false),
+                reconnect_min_ms: get_num("BROKER_RECONNECT_MIN_MS", 100) as u64,
+                reconnect_max_ms: get_num("BROKER_RECONNECT_MAX_MS", 30000) as u64,
+                mqtt_version: get_named("BROKER_MQTT_VERSION", "3"),
+                user_properties: get_user_properties("BROKER_USER_PROPERTIES"),
+                topic_aliases: get_parsed("BROKER_TOPIC_ALIASES", 0),
+                message_expiry_secs: get_optional("BROKER_MESSAGE_EXPIRY_SECS"),
+                http_auth: get_named("BROKER_HTTP_AUTH", "none"),
+                http_retries: get_num("BROKER_HTTP_RETRIES", 3),
+                amqp_sasl: get_named("BROKER_AMQP_SASL", "plain"),
+            },
+            otlp: OtlpSettings {
+                collector: var("OTLP_ENDPOINT").ok(),
+                auth: otlp_auth,
+                auth_file: otlp_auth_file,
+                traceparent: get_bool("OTLP_TRACEPARENT", false),
+                traces: get_bool("OTLP_TRACES", true),
+                sample_ratio: get_float("OTLP_SAMPLE_RATIO", 1.0),
+                sample_per_sec: get_optional("OTLP_SAMPLE_PER_SEC"),
+            },
+            log_format: get_named("LOG_FORMAT", "text"),
             capacity: get_num("CAPACITY", 1000),
+            control_port: get_optional("CONTROL_PORT"),
+            grpc_port: get_optional("GRPC_PORT"),
+        };
+        settings.check();
+        let problems = take_problems();
+        if problems.is_empty() {
+            Ok(settings)
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Check the settings that can be read, but cannot be simulated.
+    fn check(&self) {
+        const SCHEMES: [&str; 10] = [
+            "mqtt", "mqtts", "ws", "wss", "http", "https", "amqp", "amqps", "stdout", "file",
+        ];
+        match self.broker.url.split_once("://") {
+            Some((scheme, _)) if SCHEMES.contains(&scheme) => (),
+            _ => problem(
+                "BROKER_URL",
+                format_args!(
+                    "The scheme of {} is not one of {}",
+                    self.broker.url,
+                    SCHEMES.join(", ")
+                ),
+            ),
+        }
+        for (env_variable, qos) in [
+            ("BROKER_QOS", self.broker.qos),
+            ("BROKER_LWT_QOS", self.broker.lwt_qos),
+        ] {
+            if qos > 2 {
+                problem(env_variable, format_args!("QoS {} is not 0, 1 or 2", qos));
+            }
+        }
+        for (env_variable, value) in [
+            ("SIM_DEVICES", self.sim.devices),
+            ("SIM_DATA_POINTS", self.sim.data_points),
+            ("SIM_THREADS", self.sim.threads),
+            ("BROKER_CONNECTIONS", self.broker.connections),
+            ("BROKER_MAX_INFLIGHT", self.broker.max_inflight as usize),
+            ("BROKER_MAX_PACKET_SIZE", self.broker.max_packet_size),
+            (
+                "BROKER_PUBLISH_CONCURRENCY",
+                self.broker.publish_concurrency,
+            ),
+            ("CAPACITY", self.capacity),
+        ] {
+            // The devices of a manifest replace SIM_DEVICES.
+            if value == 0 && !(env_variable == "SIM_DEVICES" && self.sim.manifest.is_some()) {
+                problem(env_variable, "Must be greater than 0");
+            }
+        }
+        for (env_variable, probability) in [
+            ("SIM_DROP_RATE", self.sim.delivery.drop_rate),
+            ("SIM_DELAY_RATE", self.sim.delivery.delay_rate),
+            ("SIM_DUPLICATE_RATE", self.sim.delivery.duplicate_rate),
+            ("SIM_EVENT_RATE", self.sim.generators.events.rate),
+            (
+                "SIM_ALARM_PROBABILITY",
+                self.sim.generators.alarm.probability,
+            ),
+            ("SIM_ANOMALY_RATE", self.sim.generators.anomaly.rate),
+            ("SIM_OFFLINE_RATE", self.sim.generators.offline.rate),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                problem(
+                    env_variable,
+                    format_args!("Probability {} is not between 0 and 1", probability),
+                );
+            }
+        }
+        if !(0.0..=1.0).contains(&self.sim.jitter) {
+            problem(
+                "SIM_JITTER",
+                format_args!("Fraction {} is not between 0 and 1", self.sim.jitter),
+            );
+        }
+        // The generators draw their steps from -step..=step and clamp to their ranges.
+        let generators = &self.sim.generators;
+        for (env_variable, step) in [
+            ("SIM_SETPOINT_STEP", generators.setpoint.step),
+            ("SIM_WALK_STEP", generators.random_walk.step),
+            ("SIM_GROUP_STEP", generators.group.step),
+        ] {
+            // Also rejects NaN.
+            if !(step >= 0.0 && step.is_finite()) {
+                problem(env_variable, "Must not be negative");
+            }
+        }
+        for (min_variable, min, max_variable, max) in [
+            (
+                "SIM_SETPOINT_MIN",
+                generators.setpoint.min,
+                "SIM_SETPOINT_MAX",
+                generators.setpoint.max,
+            ),
+            (
+                "SIM_WALK_MIN",
+                generators.random_walk.min,
+                "SIM_WALK_MAX",
+                generators.random_walk.max,
+            ),
+        ] {
+            if min > max || min.is_nan() || max.is_nan() {
+                problem(
+                    min_variable,
+                    format_args!("Is greater than {}", max_variable),
+                );
+            }
+        }
+        if let Some(rate) = self.sim.target_msgs_per_sec {
+            // Also rejects NaN.
+            if !(rate > 0.0 && rate.is_finite()) {
+                problem("SIM_TARGET_MSGS_PER_SEC", "Must be greater than 0");
+            }
+        }
+        // Without a collector, the spans have no trace context to pass on.
+        if self.otlp.traceparent && (self.otlp.collector.is_none() || !self.otlp.traces) {
+            problem("OTLP_TRACEPARENT", "Requires OTLP_ENDPOINT and OTLP_TRACES");
+        }
+        if !(0.0..=1.0).contains(&self.otlp.sample_ratio) {
+            problem(
+                "OTLP_SAMPLE_RATIO",
+                format_args!("Fraction {} is not between 0 and 1", self.otlp.sample_ratio),
+            );
+        }
+        if let Some(rate) = self.otlp.sample_per_sec {
+            // Also rejects NaN.
+            if !(rate > 0.0 && rate.is_finite()) {
+                problem("OTLP_SAMPLE_PER_SEC", "Must be greater than 0");
+            }
+        }
+        if let Some(budget) = self.broker.ack_budget_ms {
+            if !(budget > 0.0 && budget.is_finite()) {
+                problem("BROKER_ACK_BUDGET_MS", "Must be greater than 0");
+            }
+        }
+        // A restarted simulator resumes from its snapshot after the start time has passed.
+        if let Some(start) = self.sim.start_time {
+            if self.sim.time.mode == TimeMode::Real
+                && self.sim.snapshot_file.is_none()
+                && start <= Utc::now()
+            {
+                problem(
+                    "SIM_START_TIME",
+                    format_args!("{} is not in the future", start.to_rfc3339()),
+                );
+            }
+        }
+        if let (Some(start), Some(end)) = (self.sim.start_time, self.sim.end_time) {
+            if end <= start {
+                problem("SIM_END_TIME", "Is not after SIM_START_TIME");
+            }
+        }
+        let phases = self.sim.scenario.iter().flatten();
+        for payload in std::iter::once(&self.sim.payload).chain(phases.filter_map(Phase::payload)) {
+            if let Err(e) = payload.check() {
+                problem("SIM_PROTOBUF_MESSAGE", e);
+            }
+        }
+        match (&self.broker.cert_file, &self.broker.key_file) {
+            (Some(_), None) => problem("BROKER_CERT_FILE", "Requires BROKER_KEY_FILE"),
+            (None, Some(_)) => problem("BROKER_KEY_FILE", "Requires BROKER_CERT_FILE"),
+            _ => (),
+        }
+        if self.broker.reconnect_min_ms > self.broker.reconnect_max_ms {
+            problem(
+                "BROKER_RECONNECT_MIN_MS",
+                "Is greater than BROKER_RECONNECT_MAX_MS",
+            );
+        }
+        if let Some(secs) = self.broker.churn_secs {
+            // Also rejects NaN.
+            if !(secs > 0.0 && secs.is_finite()) {
+                problem("BROKER_CHURN_SECS", "Must be greater than 0");
+            }
+        }
+        let pooled = if self.broker.connection_per_device {
+            Some("BROKER_CONNECTION_PER_DEVICE")
+        } else {
+            (self.broker.connections > 1).then_some("BROKER_CONNECTIONS")
+        };
+        if let Some(env_variable) = pooled {
+            if self
+                .sim
+                .payload
+                .sparkplug_node(&self.broker.client_id)
+                .is_some()
+            {
+                problem(
+                    env_variable,
+                    "Sparkplug B requires a single connection, since the edge node has a single session",
+                );
+            }
+            if self.broker.azure_key.is_some() {
+                problem(
+                    env_variable,
+                    "Azure IoT Hub requires a single connection, since the device key belongs to a single device",
+                );
+            }
+        }
+        if self.broker.lwt_topic.is_some()
+            && self
+                .sim
+                .payload
+                .sparkplug_node(&self.broker.client_id)
+                .is_some()
+        {
+            problem(
+                "BROKER_LWT_TOPIC",
+                "Cannot be used with Sparkplug B, which has its own last will",
+            );
         }
     }
 }
 
+/// The variables in upper or lower case, like in a configuration file.
+impl TryFrom<HashMap<String, Value>> for Settings {
+    type Error = String;
+
+    fn try_from(values: HashMap<String, Value>) -> Result<Self, Self::Error> {
+        let variables = values
+            .into_iter()
+            .map(|(key, value)| Ok((setting(&key)?.to_string(), value.into_string())))
+            .collect::<Result<_, String>>()?;
+        Settings::with_variables(variables).map_err(|problems| problems.join(", "))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +1270,429 @@ mod tests {
         assert_eq!(get_num("TEST_NUM_VAR", 0), 0);
     }
 
+    #[test]
+    fn test_get_float_existing_variable() {
+        std::env::set_var("TEST_FLOAT_VAR", "2.5");
+        assert_eq!(get_float("TEST_FLOAT_VAR", 0.0), 2.5);
+        std::env::remove_var("TEST_FLOAT_VAR");
+        assert_eq!(get_float("TEST_FLOAT_VAR", 1.0), 1.0);
+    }
+
+    #[test]
+    fn test_get_bool() {
+        std::env::set_var("TEST_BOOL_VAR", "true");
+        assert!(get_bool("TEST_BOOL_VAR", false));
+        std::env::remove_var("TEST_BOOL_VAR");
+        assert!(!get_bool("TEST_BOOL_VAR", false));
+    }
+
+    #[test]
+    fn test_get_weighted_generator_mix() {
+        std::env::set_var("TEST_MIX_VAR", "status:20,noise:30,sensor:50");
+        let mix = get_weighted("TEST_MIX_VAR", vec![]);
+        assert_eq!(
+            mix,
+            vec![
+                (GeneratorType::Status, 20),
+                (GeneratorType::Noise, 30),
+                (GeneratorType::Sensor, 50)
+            ]
+        );
+
+        std::env::set_var("TEST_MIX_VAR", "hans:20");
+        get_weighted::<GeneratorType>("TEST_MIX_VAR", vec![]);
+        assert_eq!(take_problems().len(), 1);
+
+        std::env::remove_var("TEST_MIX_VAR");
+        assert_eq!(
+            get_weighted("TEST_MIX_VAR", vec![(GeneratorType::Ramp, 1)]),
+            vec![(GeneratorType::Ramp, 1)]
+        );
+    }
+
+    #[test]
+    fn test_get_weighted_states() {
+        std::env::set_var("TEST_HISTOGRAM_VAR", "0:70,1:20,-1:5");
+        assert_eq!(
+            get_weighted("TEST_HISTOGRAM_VAR", vec![]),
+            vec![(0, 70), (1, 20), (-1, 5)]
+        );
+
+        std::env::set_var("TEST_HISTOGRAM_VAR", "0:70,1");
+        get_weighted::<i64>("TEST_HISTOGRAM_VAR", vec![]);
+        assert_eq!(take_problems().len(), 1);
+
+        std::env::remove_var("TEST_HISTOGRAM_VAR");
+        assert_eq!(
+            get_weighted("TEST_HISTOGRAM_VAR", vec![(2, 1)]),
+            vec![(2, 1)]
+        );
+    }
+
+    #[test]
+    fn test_get_noise_distribution() {
+        assert_eq!(
+            get_noise_distribution("TEST_NOISE_VAR"),
+            NoiseDistribution::Uniform
+        );
+
+        std::env::set_var("TEST_NOISE_VAR", "normal");
+        assert_eq!(
+            get_noise_distribution("TEST_NOISE_VAR"),
+            NoiseDistribution::Normal {
+                mean: 0.0,
+                std_dev: 1.0
+            }
+        );
+
+        std::env::set_var("TEST_NOISE_VAR", "poisson");
+        assert_eq!(
+            get_noise_distribution("TEST_NOISE_VAR"),
+            NoiseDistribution::Poisson { lambda: 1.0 }
+        );
+
+        std::env::set_var("TEST_NOISE_VAR", "hans");
+        get_noise_distribution("TEST_NOISE_VAR");
+        assert_eq!(take_problems().len(), 1);
+        std::env::remove_var("TEST_NOISE_VAR");
+
+        for (distribution, parameter, value) in [
+            ("poisson", "SIM_NOISE_LAMBDA", "0"),
+            ("exponential", "SIM_NOISE_LAMBDA", "-1"),
+            ("normal", "SIM_NOISE_STDDEV", "NaN"),
+        ] {
+            let problems = Settings::from_variables(&[
+                ("SIM_NOISE_DISTRIBUTION", distribution),
+                (parameter, value),
+            ])
+            .unwrap_err();
+            assert!(problems[0].starts_with(parameter));
+        }
+    }
+
+    #[test]
+    fn test_get_units() {
+        std::env::set_var("TEST_UNITS_VAR", "sensor:°C,noise:rpm:0.1,ramp:bar:2:-1");
+        assert_eq!(
+            get_units("TEST_UNITS_VAR", vec![]),
+            vec![
+                (
+                    GeneratorType::Sensor,
+                    UnitConfig {
+                        unit: "°C".to_string(),
+                        scale: 1.0,
+                        offset: 0.0
+                    }
+                ),
+                (
+                    GeneratorType::Noise,
+                    UnitConfig {
+                        unit: "rpm".to_string(),
+                        scale: 0.1,
+                        offset: 0.0
+                    }
+                ),
+                (
+                    GeneratorType::Ramp,
+                    UnitConfig {
+                        unit: "bar".to_string(),
+                        scale: 2.0,
+                        offset: -1.0
+                    }
+                )
+            ]
+        );
+
+        std::env::set_var("TEST_UNITS_VAR", "sensor");
+        get_units("TEST_UNITS_VAR", vec![]);
+        assert_eq!(take_problems().len(), 1);
+
+        std::env::remove_var("TEST_UNITS_VAR");
+        assert!(get_units("TEST_UNITS_VAR", vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_get_event_types() {
+        std::env::set_var(
+            "TEST_EVENT_VAR",
+            "door:Door opened;error:Error: 42, retrying",
+        );
+        assert_eq!(
+            get_event_types("TEST_EVENT_VAR", vec![]),
+            vec![
+                ("door".to_string(), "Door opened".to_string()),
+                ("error".to_string(), "Error: 42, retrying".to_string())
+            ]
+        );
+
+        std::env::set_var("TEST_EVENT_VAR", "door");
+        get_event_types("TEST_EVENT_VAR", vec![]);
+        assert_eq!(take_problems().len(), 1);
+        std::env::remove_var("TEST_EVENT_VAR");
+    }
+
+    #[test]
+    fn test_get_group_members() {
+        std::env::set_var("TEST_GROUP_VAR", "1:2:0.5;-1:2.5:0");
+        let members = get_group_members("TEST_GROUP_VAR", vec![]);
+        assert_eq!(
+            members,
+            vec![
+                GroupMember {
+                    offset: 1.0,
+                    gain: 2.0,
+                    noise: 0.5
+                },
+                GroupMember {
+                    offset: -1.0,
+                    gain: 2.5,
+                    noise: 0.0
+                }
+            ]
+        );
+
+        std::env::set_var("TEST_GROUP_VAR", "1:2");
+        get_group_members("TEST_GROUP_VAR", vec![]);
+        assert_eq!(take_problems().len(), 1);
+
+        std::env::remove_var("TEST_GROUP_VAR");
+        assert!(get_group_members("TEST_GROUP_VAR", vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_from_variables() {
+        let settings = Settings::from_variables(&[
+            ("SIM_DEVICES", "5"),
+            ("BROKER_URL", "mqtts://broker:8883"),
+            ("OTLP_AUTH", "token"),
+            ("LOG_FORMAT", "json"),
+        ])
+        .unwrap();
+        assert_eq!(settings.sim.devices, 5);
+        assert_eq!(settings.sim.data_points, 100);
+        assert_eq!(settings.broker.url, "mqtts://broker:8883");
+        assert_eq!(settings.otlp.auth.as_deref(), Some("token"));
+        assert_eq!(settings.log_format, LogFormat::Json);
+        assert_eq!(settings.capacity, 1000);
+        assert_eq!(
+            settings.sim.generators.mix,
+            vec![
+                (GeneratorType::Status, 1),
+                (GeneratorType::Noise, 1),
+                (GeneratorType::Sensor, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tls() {
+        let problems = Settings::from_variables(&[
+            ("BROKER_CERT_FILE", "cert.pem"),
+            ("BROKER_TLS_SERVER_NAME", "broker.example.com"),
+        ])
+        .unwrap_err();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("BROKER_TLS_SERVER_NAME: "));
+        assert_eq!(problems[1], "BROKER_CERT_FILE: Requires BROKER_KEY_FILE");
+    }
+
+    #[test]
+    fn test_sparkplug_last_will() {
+        let problems = Settings::from_variables(&[
+            ("SIM_PAYLOAD_FORMAT", "sparkplug"),
+            ("BROKER_LWT_TOPIC", "status"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            problems,
+            vec!["BROKER_LWT_TOPIC: Cannot be used with Sparkplug B, which has its own last will"]
+        );
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let toml = r#"
+            SIM_DEVICES = 5
+            sim_start_paused = true
+            BROKER_URL = "mqtts://broker:8883"
+            otlp_sample_ratio = 0.5
+        "#;
+        let settings: Settings = toml::from_str(toml).unwrap();
+        assert_eq!(settings.sim.devices, 5);
+        assert!(settings.sim.start_paused);
+        assert_eq!(settings.broker.url, "mqtts://broker:8883");
+        assert_eq!(settings.otlp.sample_ratio, 0.5);
+        assert_eq!(settings.sim.data_points, 100);
+        let settings: Settings = serde_json::from_str(r#"{"sim_runs": 3}"#).unwrap();
+        assert_eq!(settings.sim.runs, 3);
+        let error = serde_json::from_str::<Settings>(r#"{"SIM_DEVICESS": 5}"#).unwrap_err();
+        assert!(error.to_string().contains("Unknown setting SIM_DEVICESS"));
+        let error = serde_json::from_str::<Settings>(r#"{"SIM_DEVICES": "many"}"#).unwrap_err();
+        assert!(error.to_string().contains("SIM_DEVICES"));
+    }
+
+    #[test]
+    fn test_frequency() {
+        let frequency = |variables: &[(&str, &str)]| {
+            Settings::from_variables(variables).map(|settings| settings.sim.frequency_secs)
+        };
+        assert_eq!(frequency(&[]), Ok(1.0));
+        assert_eq!(frequency(&[("SIM_FREQUENCY_SECS", "0.5")]), Ok(0.5));
+        assert_eq!(frequency(&[("SIM_FREQUENCY", "250ms")]), Ok(0.25));
+        assert_eq!(
+            frequency(&[("SIM_FREQUENCY", "2.5s"), ("SIM_FREQUENCY_SECS", "10")]),
+            Ok(2.5)
+        );
+        assert!(frequency(&[("SIM_FREQUENCY", "0s")]).is_err());
+        assert!(frequency(&[("SIM_FREQUENCY_SECS", "-1")]).is_err());
+        assert!(frequency(&[("SIM_FREQUENCY", "often")]).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let problems: Vec<String> = Settings::from_variables(&[
+            ("BROKER_URL", "tcp://localhost:1883"),
+            ("BROKER_QOS", "3"),
+            ("SIM_DEVICES", "0"),
+            ("SIM_DROP_RATE", "1.5"),
+            ("SIM_SETPOINT_MIN", "100"),
+            ("SIM_SETPOINT_MAX", "0"),
+            ("SIM_WALK_STEP", "-1"),
+            ("SIM_TARGET_MSGS_PER_SEC", "0"),
+            ("OTLP_SAMPLE_RATIO", "2"),
+            ("SIM_START_TIME", "2020-01-01T00:00:00Z"),
+            ("SIM_FREQUENCY_SECS", "often"),
+            ("BROKER_CHURN_SECS", "0"),
+            ("SIM_PAYLOAD_FORMAT", "sparkplug"),
+            ("BROKER_CONNECTIONS", "2"),
+        ])
+        .unwrap_err()
+        .into_iter()
+        .map(|problem| problem.split(':').next().unwrap().to_string())
+        .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "SIM_FREQUENCY_SECS",
+                "BROKER_URL",
+                "BROKER_QOS",
+                "SIM_DEVICES",
+                "SIM_DROP_RATE",
+                "SIM_WALK_STEP",
+                "SIM_SETPOINT_MIN",
+                "SIM_TARGET_MSGS_PER_SEC",
+                "OTLP_SAMPLE_RATIO",
+                "SIM_START_TIME",
+                "BROKER_CHURN_SECS",
+                "BROKER_CONNECTIONS"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_golden() {
+        let settings = Settings::from_variables(&[
+            ("SIM_GOLDEN", "true"),
+            ("SIM_GENERATOR_MIX", "noise:1,sensor:1"),
+        ])
+        .unwrap();
+        assert_eq!(
+            settings.sim.generators.mix,
+            vec![(GeneratorType::Sensor, 1)]
+        );
+
+        let problems =
+            Settings::from_variables(&[("SIM_GOLDEN", "true"), ("SIM_GENERATOR_MIX", "noise:1")])
+                .unwrap_err();
+        assert!(problems[0].starts_with("SIM_GOLDEN"));
+
+        // Diurnal data points follow the wall clock in real time.
+        let problems =
+            Settings::from_variables(&[("SIM_GOLDEN", "true"), ("SIM_GENERATOR_MIX", "diurnal:1")])
+                .unwrap_err();
+        assert!(problems[0].starts_with("SIM_GOLDEN"));
+        let settings = Settings::from_variables(&[
+            ("SIM_GOLDEN", "true"),
+            ("SIM_GENERATOR_MIX", "diurnal:1"),
+            ("SIM_TIME_MODE", "simulated"),
+            ("SIM_DROP_RATE", "0.1"),
+            ("SIM_DELAY_RATE", "0.1"),
+            ("SIM_DUPLICATE_RATE", "0.1"),
+            ("SIM_CLOCK_JITTER_MS", "100"),
+        ])
+        .unwrap();
+        assert_eq!(
+            settings.sim.generators.mix,
+            vec![(GeneratorType::Diurnal, 1)]
+        );
+        assert_eq!(settings.sim.generators.clock.jitter_millis, 0);
+        let delivery = settings.sim.delivery;
+        assert_eq!(
+            (
+                delivery.drop_rate,
+                delivery.delay_rate,
+                delivery.duplicate_rate
+            ),
+            (0.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_get_secret() {
+        let path = std::env::temp_dir().join("rumsim_test_secret");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "s3cret\n").unwrap();
+        std::env::set_var("TEST_SECRET_VAR_FILE", path);
+        let file = get_secret_file("TEST_SECRET_VAR");
+        assert_eq!(file.as_deref(), Some(path));
+        assert_eq!(
+            get_secret("TEST_SECRET_VAR", file.as_deref()),
+            Some("s3cret".to_string())
+        );
+        std::fs::write(path, "\n").unwrap();
+        assert_eq!(get_secret("TEST_SECRET_VAR", file.as_deref()), None);
+        assert_eq!(take_problems().len(), 1);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(get_secret("TEST_SECRET_VAR", file.as_deref()), None);
+        assert_eq!(take_problems().len(), 1);
+
+        std::env::set_var("TEST_SECRET_VAR", "secret");
+        get_secret_file("TEST_SECRET_VAR");
+        assert_eq!(take_problems().len(), 1);
+        std::env::remove_var("TEST_SECRET_VAR_FILE");
+        assert_eq!(get_secret_file("TEST_SECRET_VAR"), None);
+        assert_eq!(
+            get_secret("TEST_SECRET_VAR", None),
+            Some("secret".to_string())
+        );
+        std::env::remove_var("TEST_SECRET_VAR");
+    }
+
+    #[test]
+    fn test_expand_client_id() {
+        assert_eq!(
+            expand_client_id("rumsim-{hostname}-{random}", "node-1", 0xbeef),
+            "rumsim-node-1-0000beef"
+        );
+        assert_eq!(expand_client_id("rumsim-3", "node-1", 1), "rumsim-3");
+    }
+
+    #[test]
+    fn test_split_ordinal() {
+        assert_eq!(split_ordinal("rumsim-3"), ("rumsim", Some(3)));
+        assert_eq!(split_ordinal("my-sim-12"), ("my-sim", Some(12)));
+        assert_eq!(split_ordinal("my-sim"), ("my-sim", None));
+        assert_eq!(split_ordinal("rumsim"), ("rumsim", None));
+    }
+
+    #[test]
+    fn test_get_optional_num() {
+        std::env::set_var("TEST_OPT_NUM_VAR", "-3");
+        assert_eq!(get_optional_num("TEST_OPT_NUM_VAR"), Some(-3));
+        std::env::remove_var("TEST_OPT_NUM_VAR");
+        assert_eq!(get_optional_num("TEST_OPT_NUM_VAR"), None);
+    }
+
     #[test]
     fn test_get_time_existing_variable() {
         std::env::set_var("TEST_TIME_VAR", "2022-01-01T00:00:00Z");
@@ -103,13 +1701,39 @@ mod tests {
             .with_timezone(&Utc);
         assert_eq!(get_time("TEST_TIME_VAR", None), Some(expected_time));
 
-        let result = std::panic::catch_unwind(|| {
-            std::env::set_var("TEST_TIME_VAR", "Hans");
-            get_time("TEST_TIME_VAR", None);
-        });
-        assert!(result.is_err());
+        std::env::set_var("TEST_TIME_VAR", "Hans");
+        get_time("TEST_TIME_VAR", None);
+        assert_eq!(take_problems().len(), 1);
 
         std::env::remove_var("TEST_TIME_VAR");
         assert_eq!(get_time("TEST_TIME_VAR", None), None);
     }
+
+    #[test]
+    fn test_parse_manifest() {
+        let yaml = b"
+- name: press-1
+  serial: SN-0001
+  data_points: 4
+  mix: sensor:3,status:1
+- name: press-2
+  intervals: 1:1,10:1
+";
+        let devices = parse_manifest(yaml, 10).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "press-1");
+        assert_eq!(devices[0].serial.as_deref(), Some("SN-0001"));
+        assert_eq!(devices[0].data_points, 4);
+        assert_eq!(
+            devices[0].mix,
+            Some(vec![(GeneratorType::Sensor, 3), (GeneratorType::Status, 1)])
+        );
+        assert_eq!(devices[1].data_points, 10);
+        assert_eq!(devices[1].mix, None);
+        assert_eq!(devices[1].intervals, Some(vec![(1, 1), (10, 1)]));
+
+        let json = br#"[{"name": "press-1"}]"#;
+        assert_eq!(parse_manifest(json, 10).unwrap()[0].name, "press-1");
+        assert!(parse_manifest(b"[{name: press-1, color: red}]", 10).is_err());
+    }
 }
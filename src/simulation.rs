@@ -1,61 +1,574 @@
+use chrono::{DateTime, Duration, Utc};
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
 
-use crate::device::Device;
-use rand::rngs::StdRng;
+use crate::device::{Device, DeviceProfile, DeviceSnapshot};
+use crate::generator::GeneratorConfig;
+use crate::payload::{BatchConfig, Message, PayloadConfig};
+use crate::sparkplug::SparkplugNode;
 use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 pub struct SimulationParameters {
     pub client_id: String,
     pub devices: usize,
+    /// The devices listed in a manifest instead of the given number of uniform devices.
+    pub manifest: Option<Vec<DeviceProfile>>,
+    /// The number of child devices of each device, 0 for no gateways.
+    pub children: usize,
     pub data_points: usize,
     pub seed: u64,
-    pub frequency_secs: u64,
-    pub qos: u8,
+    pub frequency_secs: f64,
+    pub generators: GeneratorConfig,
+    pub payload: PayloadConfig,
+    pub time: TimeConfig,
+    pub delivery: DeliveryConfig,
+    /// The number of threads that generate the messages of the devices in parallel.
+    pub threads: usize,
+    pub shard: Option<Shard>,
+}
+
+/// A slice of the devices of a simulation that is spread over several instances, for example the replicas of a
+/// Kubernetes StatefulSet. The devices of all shards together are the same as in a single instance with the client
+/// ID of the cluster, including their names and seeds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+    /// The common part of the client IDs of the instances, which is used for the device names.
+    pub cluster_id: String,
+}
+
+impl Shard {
+    /// The devices of this shard out of the given number of devices.
+    pub fn devices(&self, devices: usize) -> std::ops::Range<usize> {
+        devices * self.index / self.count..devices * (self.index + 1) / self.count
+    }
+}
+
+/// How the time stamps of the runs are determined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeMode {
+    /// The system time when the run starts.
+    Real,
+    /// Logical time that starts at the start time and advances by the frequency in each run,
+    /// regardless of how long the runs actually take.
+    Simulated,
+}
+
+impl FromStr for TimeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "real" => Ok(TimeMode::Real),
+            "simulated" => Ok(TimeMode::Simulated),
+            _ => Err(format!("Unknown time mode {}", s)),
+        }
+    }
+}
+
+/// What happens with the runs that are due while a run takes too long, for example while the broker is unreachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissedRuns {
+    /// Publish the missed runs late, one after the other.
+    Delay,
+    /// Skip the missed runs and continue with the run that is due now.
+    Skip,
+}
+
+impl FromStr for MissedRuns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(MissedRuns::Delay),
+            "skip" => Ok(MissedRuns::Skip),
+            _ => Err(format!("Unknown handling of missed runs {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeConfig {
+    pub mode: TimeMode,
+    /// The time stamp of the first run in simulated time, the current time if not set.
+    pub start: Option<DateTime<Utc>>,
+    /// The number of seconds by which all time stamps are moved into the past.
+    pub backdate_secs: u64,
+    /// The end of the history, if backfilling the history from the start time as fast as possible.
+    pub backfill_end: Option<DateTime<Utc>>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        TimeConfig {
+            mode: TimeMode::Real,
+            start: None,
+            backdate_secs: 0,
+            backfill_end: None,
+        }
+    }
+}
+
+/// Faults in the delivery of the messages, so that consumers can be tested with gaps and late data.
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryConfig {
+    /// Probability that a message is dropped.
+    pub drop_rate: f64,
+    /// Probability that a message is published later with its original time stamp.
+    pub delay_rate: f64,
+    /// Number of runs by which delayed messages are published later.
+    pub delay_runs: usize,
+    /// Probability that a message is published twice, for testing deduplication.
+    pub duplicate_rate: f64,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            drop_rate: 0.0,
+            delay_rate: 0.0,
+            delay_runs: 1,
+            duplicate_rate: 0.0,
+        }
+    }
+}
+
+impl DeliveryConfig {
+    /// The same configuration without faults, so that each run of a golden simulation publishes the same messages.
+    pub fn golden(self) -> Self {
+        DeliveryConfig {
+            drop_rate: 0.0,
+            delay_rate: 0.0,
+            duplicate_rate: 0.0,
+            ..self
+        }
+    }
+
+    fn has_faults(&self) -> bool {
+        self.drop_rate > 0.0 || self.delay_rate > 0.0 || self.duplicate_rate > 0.0
+    }
 }
 
 pub struct Simulation {
     devices: Vec<Device>,
+    time: TimeConfig,
+    /// The time stamp of the first run in simulated time.
+    start: DateTime<Utc>,
+    frequency: Duration,
+    sparkplug: Option<SparkplugNode>,
+    batch: BatchConfig,
+    delivery: DeliveryConfig,
+    delivery_seed: u64,
+    /// The names of the devices in the same order.
+    names: Vec<String>,
+    /// The delayed messages with the run in which they are published.
+    delayed: Vec<(usize, DeviceMessage)>,
+    /// The generator configuration for changing the data points, if the devices are not from a manifest.
+    generators: Option<GeneratorConfig>,
+    threads: usize,
+}
+
+/// The state of a simulation after a number of completed runs.
+#[derive(Serialize, Deserialize)]
+struct SimulationSnapshot {
+    runs: usize,
+    devices: Vec<DeviceSnapshot>,
 }
 
 impl Simulation {
     pub fn new(parms: &SimulationParameters) -> Self {
         // Ensure that each instance of the simulator has a unique seed derived from the input seed and the instance ID.
+        // The shards of a cluster share the seed, so that each device has the same seed in any of the shards.
+        let cluster_id = match &parms.shard {
+            Some(shard) => &shard.cluster_id,
+            None => &parms.client_id,
+        };
         let mut hasher = DefaultHasher::new();
-        parms.client_id.hash(&mut hasher);
+        cluster_id.hash(&mut hasher);
         parms.seed.hash(&mut hasher);
-        let mut rng = StdRng::seed_from_u64(hasher.finish());
+        let mut rng = ChaCha12Rng::seed_from_u64(hasher.finish());
+
+        let sparkplug = parms.payload.sparkplug_node(&parms.client_id);
+        let profiles = match &parms.manifest {
+            Some(manifest) => manifest.clone(),
+            None => (0..parms.devices)
+                .map(|i| DeviceProfile::new(cluster_id, i, parms.data_points))
+                .collect(),
+        };
+        let shard = match &parms.shard {
+            Some(shard) => shard.devices(profiles.len()),
+            None => 0..profiles.len(),
+        };
+        let mut devices = Vec::with_capacity(shard.len() * (parms.children + 1));
+        for (i, profile) in profiles.iter().enumerate() {
+            if !shard.contains(&i) {
+                // Draw the seeds of the devices of the other shards, so that the seeds of the own devices stay.
+                for _ in 0..=parms.children {
+                    rng.gen::<u64>();
+                }
+                continue;
+            }
+            let mut gateway = Device::from_profile(
+                &parms.client_id,
+                i,
+                profile,
+                rng.gen(),
+                &parms.generators,
+                &parms.payload,
+                sparkplug.as_ref(),
+            );
+            // The children follow their gateway, so that they are registered before they send anything.
+            let gateway_name = gateway.name().to_string();
+            let child_payload = parms.payload.for_gateway(&parms.client_id, &gateway_name);
+            let children: Vec<Device> = (0..parms.children)
+                .map(|j| {
+                    Device::new(
+                        &gateway_name,
+                        j,
+                        parms.data_points,
+                        rng.gen(),
+                        &parms.generators,
+                        &child_payload,
+                        sparkplug.as_ref(),
+                    )
+                })
+                .collect();
+            for child in &children {
+                gateway.add_child(&parms.payload, child.name());
+            }
+            devices.push(gateway);
+            devices.extend(children);
+        }
 
-        let mut devices = Vec::with_capacity(parms.devices);
-        for i in 0..parms.devices {
-            let device = Device::new(&parms.client_id, i, parms.data_points, rng.gen());
-            devices.push(device);
+        let names = devices
+            .iter()
+            .map(|device| device.name().to_string())
+            .collect();
+        Simulation {
+            devices,
+            names,
+            time: parms.time,
+            start: parms.time.start.unwrap_or_else(Utc::now),
+            frequency: Duration::nanoseconds((parms.frequency_secs * 1e9) as i64),
+            sparkplug,
+            batch: parms.payload.batch(),
+            delivery: parms.delivery,
+            delivery_seed: rng.gen(),
+            delayed: Vec::new(),
+            generators: parms.manifest.is_none().then(|| parms.generators.clone()),
+            threads: parms.threads.max(1),
         }
+    }
+
+    /// The time stamp of the given run before the clock errors of the devices. Simulated time stops at the latest time
+    /// that can be represented, which is only reached after hundreds of thousands of years.
+    pub fn time(&self, run: usize) -> DateTime<Utc> {
+        let time = match self.time.mode {
+            TimeMode::Real => Utc::now(),
+            TimeMode::Simulated => self.start_after(run),
+        };
+        time - Duration::seconds(self.time.backdate_secs as i64)
+    }
+
+    /// The start time of a simulation that continues after the given number of runs in simulated time.
+    pub fn start_after(&self, runs: usize) -> DateTime<Utc> {
+        self.simulated_time(runs)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// The start time advanced by the frequency for each run, if it can be represented.
+    fn simulated_time(&self, run: usize) -> Option<DateTime<Utc>> {
+        // The frequency is created from nanoseconds, so it always has them.
+        let nanos = i128::from(self.frequency.num_nanoseconds()?) * i128::try_from(run).ok()?;
+        let offset = Duration::try_seconds(i64::try_from(nanos / 1_000_000_000).ok()?)?
+            + Duration::nanoseconds((nanos % 1_000_000_000) as i64);
+        self.start.checked_add_signed(offset)
+    }
+
+    /// The number of runs with a time stamp before the end of the backfill, if backfilling.
+    pub fn backfill_runs(&self) -> Option<usize> {
+        let end = self.time.backfill_end?;
+        let millis = (end - self.time(0)).num_milliseconds().max(0);
+        let frequency = self.frequency.num_milliseconds().max(1);
+        Some(((millis + frequency - 1) / frequency) as usize)
+    }
+
+    /// The total number of data points of all devices.
+    pub fn data_points(&self) -> usize {
+        self.devices.iter().map(|device| device.data_points()).sum()
+    }
+
+    /// Change the number of data points of each device at the next run, keeping the state of the data points that
+    /// stay. Devices from a manifest have their own data points, and the data points of Sparkplug B devices are
+    /// announced in their birth certificates, so they cannot be changed.
+    pub fn set_data_points(&mut self, data_points: usize) -> Result<(), String> {
+        if self.sparkplug.is_some() {
+            return Err("The data points of Sparkplug B devices cannot be changed".to_string());
+        }
+        let Some(generators) = &self.generators else {
+            return Err("The data points of devices from a manifest cannot be changed".to_string());
+        };
+        for device in &mut self.devices {
+            device.set_data_points(data_points, generators);
+        }
+        Ok(())
+    }
+
+    /// The names of the devices.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The Sparkplug B edge node of the simulation, if publishing Sparkplug B.
+    pub fn sparkplug(&self) -> Option<&SparkplugNode> {
+        self.sparkplug.as_ref()
+    }
 
-        Simulation { devices }
+    /// Write the state of the simulation after the given number of completed runs to a file.
+    /// The file is replaced atomically, so that a crash while writing does not lose the previous snapshot.
+    pub fn save(&self, path: &Path, runs: usize) -> Result<(), String> {
+        let snapshot = SimulationSnapshot {
+            runs,
+            devices: self
+                .devices
+                .iter()
+                .map(|device| device.snapshot())
+                .collect(),
+        };
+        let content = serde_json::to_vec(&snapshot).map_err(|e| e.to_string())?;
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, content).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp_path, path).map_err(|e| e.to_string())
     }
 
-    pub fn iter(&mut self) -> SimulationIterator {
+    /// Restore the state of a simulation with the same parameters from a file.
+    /// Returns the number of runs that were completed when the snapshot was taken.
+    pub fn restore(&mut self, path: &Path) -> Result<usize, String> {
+        let content = std::fs::read(path).map_err(|e| e.to_string())?;
+        let snapshot: SimulationSnapshot =
+            serde_json::from_slice(&content).map_err(|e| e.to_string())?;
+        if snapshot.devices.len() != self.devices.len() {
+            return Err(format!(
+                "Snapshot has {} devices instead of {}.",
+                snapshot.devices.len(),
+                self.devices.len()
+            ));
+        }
+        for (device, device_snapshot) in self.devices.iter_mut().zip(snapshot.devices) {
+            device.restore(device_snapshot)?;
+        }
+        Ok(snapshot.runs)
+    }
+
+    /// Iterate over the messages of the given run.
+    pub fn iter(&mut self, run: usize) -> SimulationIterator<'_> {
+        // The faults are drawn from a separate generator per run, so that they don't change the measurements
+        // and stay the same when continuing from a snapshot.
+        let fault_rng = self
+            .delivery
+            .has_faults()
+            .then(|| ChaCha12Rng::seed_from_u64(self.delivery_seed.wrapping_add(run as u64)));
         SimulationIterator {
-            devices_iter: self.devices.iter_mut(),
+            run,
+            time: self.time(run),
+            devices: &mut self.devices,
+            threads: self.threads,
+            names: &self.names,
+            generated: Vec::new().into_iter(),
+            pending: Vec::new().into_iter(),
+            current: 0,
+            next_device: 0,
+            peeked: None,
+            batch: self.batch,
+            delivery: self.delivery,
+            fault_rng,
+            delayed: &mut self.delayed,
+            duplicate: None,
+            device: None,
         }
     }
 }
 
+/// How many devices each thread generates at a time. More devices make up for starting the threads, but take more
+/// memory for the messages that are generated ahead.
+const DEVICES_PER_THREAD: usize = 256;
+
+/// A message with the index of the device that sent it, or None if it is a batch of several devices.
+type DeviceMessage = (Message, Option<usize>);
+
 pub struct SimulationIterator<'a> {
-    devices_iter: std::slice::IterMut<'a, Device>,
+    run: usize,
+    time: DateTime<Utc>,
+    devices: &'a mut [Device],
+    threads: usize,
+    names: &'a [String],
+    /// The messages of the devices that were generated ahead, one entry per device.
+    generated: std::vec::IntoIter<Vec<Message>>,
+    /// The remaining messages of the current device.
+    pending: std::vec::IntoIter<Message>,
+    /// The index of the current device.
+    current: usize,
+    /// The index of the first device that was not generated yet.
+    next_device: usize,
+    /// A message that was taken to check whether it fits into the previous batch, but didn't.
+    peeked: Option<(Message, usize)>,
+    batch: BatchConfig,
+    delivery: DeliveryConfig,
+    fault_rng: Option<ChaCha12Rng>,
+    delayed: &'a mut Vec<(usize, DeviceMessage)>,
+    /// A copy of the previous message that is published again.
+    duplicate: Option<DeviceMessage>,
+    /// The index of the device of the previous message.
+    device: Option<usize>,
+}
+
+impl<'a> SimulationIterator<'a> {
+    /// The name of the device that sent the previous message, or None if it is a batch of several devices.
+    pub fn device(&self) -> Option<&str> {
+        self.device.map(|index| self.names[index].as_str())
+    }
+
+    /// The next message of the devices. Devices without any message in this run are skipped.
+    fn next_message(&mut self) -> Option<(Message, usize)> {
+        if let Some(message) = self.peeked.take() {
+            return Some(message);
+        }
+        loop {
+            if let Some(message) = self.pending.next() {
+                return Some((message, self.current));
+            }
+            if self.generated.len() == 0 {
+                self.generate_ahead()?;
+            }
+            self.current = self.next_device - self.generated.len();
+            self.pending = self.generated.next()?.into_iter();
+        }
+    }
+
+    /// Generate the messages of the next devices. With several threads, the devices are split between the threads
+    /// and generated in parallel. Each device has its own random generator, so the messages are the same as with a
+    /// single thread. Returns None if all devices are generated.
+    fn generate_ahead(&mut self) -> Option<()> {
+        let remaining = &mut self.devices[self.next_device..];
+        if remaining.is_empty() {
+            return None;
+        }
+        let time = self.time;
+        let generated: Vec<Vec<Message>> = if self.threads == 1 {
+            vec![remaining[0].generate(time)]
+        } else {
+            let devices = remaining.len().min(self.threads * DEVICES_PER_THREAD);
+            let chunk = devices.div_ceil(self.threads);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = remaining[..devices]
+                    .chunks_mut(chunk)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter_mut()
+                                .map(|device| device.generate(time))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        };
+        self.next_device += generated.len();
+        self.generated = generated.into_iter();
+        Some(())
+    }
+
+    /// Consecutive messages with the same topic are joined by newlines as long as they fit into the batch.
+    fn next_batch(&mut self) -> Option<DeviceMessage> {
+        let ((topic, mut data), device) = self.next_message()?;
+        let mut single_device = true;
+        for _ in 1..self.batch.records {
+            match self.next_message() {
+                Some(((next_topic, next_data), next_device))
+                    if next_topic == topic && self.batch.fits(data.len() + 1 + next_data.len()) =>
+                {
+                    data.push(b'\n');
+                    data.extend(next_data);
+                    single_device &= next_device == device;
+                }
+                message => {
+                    self.peeked = message;
+                    break;
+                }
+            }
+        }
+        Some(((topic, data), single_device.then_some(device)))
+    }
+
+    /// Whether a message is dropped or delayed.
+    fn draw_fault(&mut self) -> Option<Fault> {
+        let draw: f64 = self.fault_rng.as_mut()?.gen();
+        if draw < self.delivery.drop_rate {
+            Some(Fault::Drop)
+        } else if draw < self.delivery.drop_rate + self.delivery.delay_rate {
+            Some(Fault::Delay)
+        } else if draw
+            < self.delivery.drop_rate + self.delivery.delay_rate + self.delivery.duplicate_rate
+        {
+            Some(Fault::Duplicate)
+        } else {
+            None
+        }
+    }
+
+    /// The messages of the run without the dropped and delayed ones and with the duplicates right after
+    /// the original messages, followed by the delayed messages of earlier runs that are due, so that they arrive out of order.
+    fn next_delivered(&mut self) -> Option<DeviceMessage> {
+        if let Some(message) = self.duplicate.take() {
+            return Some(message);
+        }
+        while let Some(message) = self.next_batch() {
+            match self.draw_fault() {
+                None => return Some(message),
+                Some(Fault::Duplicate) => {
+                    self.duplicate = Some(message.clone());
+                    return Some(message);
+                }
+                Some(Fault::Drop) => (),
+                Some(Fault::Delay) => self
+                    .delayed
+                    .push((self.run + self.delivery.delay_runs, message)),
+            }
+        }
+        let index = self.delayed.iter().position(|(due, _)| *due <= self.run)?;
+        Some(self.delayed.remove(index).1)
+    }
+}
+
+/// A fault in the delivery of a message.
+enum Fault {
+    Drop,
+    Delay,
+    Duplicate,
 }
 
 impl<'a> Iterator for SimulationIterator<'a> {
-    type Item = (String, String);
+    type Item = Message;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.devices_iter.next().map(|device| device.generate())
+        let (message, device) = self.next_delivered()?;
+        self.device = device;
+        Some(message)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::generator::{AnomalyConfig, GeneratorType, OfflineConfig};
 
     #[test]
     fn test_simulation_new() {
@@ -64,17 +577,25 @@ mod tests {
         let parms = SimulationParameters {
             client_id: client_id.clone(),
             devices,
+            manifest: None,
+            children: 0,
             data_points: 1,
             seed: 12345,
-            frequency_secs: 60,
-            qos: 2,
+            frequency_secs: 60.0,
+            generators: GeneratorConfig::default(),
+            payload: PayloadConfig::default(),
+            time: TimeConfig::default(),
+            delivery: DeliveryConfig::default(),
+            threads: 1,
+            shard: None,
         };
 
         let mut simulation = Simulation::new(&parms);
         assert_eq!(simulation.devices.len(), devices);
 
-        let mut iter = simulation.iter();
+        let mut iter = simulation.iter(0);
         let (name, value) = iter.next().unwrap();
+        let value = String::from_utf8(value).unwrap();
         assert!(name.contains(&client_id));
         assert!(name.contains("0")); // The device number of the first device.
         assert!(value.contains("sensor_0")); // The name of the first data point.
@@ -82,4 +603,371 @@ mod tests {
 
         assert!(iter.next().is_none());
     }
+
+    /// A single device with one data point, which the tests change as they need.
+    fn parameters() -> SimulationParameters {
+        SimulationParameters {
+            client_id: "test".to_string(),
+            devices: 1,
+            manifest: None,
+            children: 0,
+            data_points: 1,
+            seed: 12345,
+            frequency_secs: 60.0,
+            generators: GeneratorConfig::default(),
+            payload: PayloadConfig::default(),
+            time: TimeConfig::default(),
+            delivery: DeliveryConfig::default(),
+            threads: 1,
+            shard: None,
+        }
+    }
+
+    #[test]
+    fn test_simulation_batch() {
+        let parms = SimulationParameters {
+            devices: 3,
+            payload: PayloadConfig {
+                topic: "s/us".to_string(),
+                batch: BatchConfig {
+                    records: 2,
+                    bytes: 0,
+                },
+                ..PayloadConfig::default()
+            },
+            ..parameters()
+        };
+        let messages: Vec<Message> = Simulation::new(&parms).iter(0).collect();
+        assert_eq!(messages.len(), 2);
+        let data = String::from_utf8(messages[0].1.clone()).unwrap();
+        assert_eq!(data.lines().count(), 2);
+        assert!(data.lines().all(|line| line.starts_with("201,S,")));
+
+        let mut simulation = Simulation::new(&parms);
+        let mut iter = simulation.iter(0);
+        iter.next();
+        assert_eq!(iter.device(), None);
+        iter.next();
+        assert_eq!(iter.device(), Some("test_2"));
+
+        let parms = SimulationParameters {
+            payload: PayloadConfig {
+                batch: BatchConfig {
+                    records: 2,
+                    bytes: 50,
+                },
+                ..parms.payload
+            },
+            ..parms
+        };
+        assert_eq!(Simulation::new(&parms).iter(0).count(), 3);
+
+        let parms = SimulationParameters {
+            payload: PayloadConfig {
+                topic: "s/us/{device}".to_string(),
+                batch: BatchConfig {
+                    records: 2,
+                    bytes: 0,
+                },
+                ..parms.payload
+            },
+            ..parms
+        };
+        assert_eq!(Simulation::new(&parms).iter(0).count(), 3);
+    }
+
+    #[test]
+    fn test_simulation_gateway() {
+        let parms = SimulationParameters {
+            devices: 2,
+            children: 2,
+            ..parameters()
+        };
+        let mut simulation = Simulation::new(&parms);
+        assert_eq!(simulation.devices.len(), 6);
+
+        let topics: Vec<String> = simulation.iter(0).map(|(topic, _data)| topic).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "s/us/test_0", // Registration of test_0_0.
+                "s/us/test_0", // Registration of test_0_1.
+                "s/us/test_0",
+                "s/us/test_0_0",
+                "s/us/test_0_1",
+                "s/us/test_1",
+                "s/us/test_1",
+                "s/us/test_1",
+                "s/us/test_1_0",
+                "s/us/test_1_1",
+            ]
+        );
+        // The children are only registered in the first run.
+        assert_eq!(simulation.iter(0).count(), 6);
+    }
+
+    #[test]
+    fn test_simulation_manifest() {
+        let parms = SimulationParameters {
+            devices: 100,
+            manifest: Some(vec![
+                DeviceProfile {
+                    name: "press-1".to_string(),
+                    data_points: 3,
+                    mix: Some(vec![(GeneratorType::Counter, 1)]),
+                    ..DeviceProfile::new("", 0, 0)
+                },
+                DeviceProfile {
+                    name: "press-2".to_string(),
+                    ..DeviceProfile::new("", 0, 2)
+                },
+            ]),
+            ..parameters()
+        };
+        let mut simulation = Simulation::new(&parms);
+        assert_eq!(simulation.data_points(), 5);
+
+        let messages: Vec<(String, String)> = simulation
+            .iter(0)
+            .map(|(topic, data)| (topic, String::from_utf8(data).unwrap()))
+            .collect();
+        assert_eq!(messages[0].0, "s/us/press-1");
+        assert!(messages[0].1.contains("counter_2"));
+        assert_eq!(messages[1].0, "s/us/press-2");
+    }
+
+    #[test]
+    fn test_simulation_time() {
+        let start = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let simulated = TimeConfig {
+            mode: TimeMode::Simulated,
+            start: Some(start),
+            ..TimeConfig::default()
+        };
+        let parms = |time| SimulationParameters {
+            time,
+            ..parameters()
+        };
+        let mut simulation = Simulation::new(&parms(simulated));
+        assert_eq!(simulation.time(0), start);
+        assert_eq!(simulation.time(2), start + Duration::minutes(2));
+        let (_topic, data) = simulation.iter(2).next().unwrap();
+        assert!(String::from_utf8(data)
+            .unwrap()
+            .contains("2024-03-01T12:02:00"));
+
+        let simulation = Simulation::new(&parms(TimeConfig {
+            backdate_secs: 86400,
+            ..simulated
+        }));
+        assert_eq!(simulation.time(0), start - Duration::days(1));
+
+        let simulation = Simulation::new(&parms(TimeConfig {
+            backfill_end: Some(start + Duration::seconds(150)),
+            ..simulated
+        }));
+        assert_eq!(simulation.backfill_runs(), Some(3));
+        let simulation = Simulation::new(&parms(TimeConfig {
+            backfill_end: Some(start - Duration::seconds(150)),
+            ..simulated
+        }));
+        assert_eq!(simulation.backfill_runs(), Some(0));
+        assert_eq!(Simulation::new(&parms(simulated)).backfill_runs(), None);
+
+        // Runs beyond the range of 32 bits advance the time as well, until it cannot be represented any more.
+        let simulation = Simulation::new(&parms(simulated));
+        let run = 1 << 32;
+        assert_eq!(
+            simulation.time(run),
+            start + Duration::minutes(1) * (1 << 16) * (1 << 16)
+        );
+        assert_eq!(simulation.time(usize::MAX), DateTime::<Utc>::MAX_UTC);
+
+        let simulation = Simulation::new(&parms(TimeConfig::default()));
+        assert!((simulation.time(5) - Utc::now()).num_seconds().abs() < 5);
+    }
+
+    #[test]
+    fn test_simulation_delivery() {
+        let start = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let parms = |delivery| SimulationParameters {
+            devices: 10,
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                ..TimeConfig::default()
+            },
+            delivery,
+            ..parameters()
+        };
+
+        let mut simulation = Simulation::new(&parms(DeliveryConfig {
+            drop_rate: 0.5,
+            ..DeliveryConfig::default()
+        }));
+        let count = simulation.iter(0).count();
+        assert!(count > 0 && count < 10);
+
+        let mut simulation = Simulation::new(&parms(DeliveryConfig {
+            delay_rate: 1.0,
+            delay_runs: 2,
+            ..DeliveryConfig::default()
+        }));
+        assert_eq!(simulation.iter(0).count(), 0);
+        assert_eq!(simulation.iter(1).count(), 0);
+        let delayed: Vec<Message> = simulation.iter(2).collect();
+        assert_eq!(delayed.len(), 10);
+        assert!(delayed
+            .iter()
+            .all(|(_topic, data)| String::from_utf8(data.clone())
+                .unwrap()
+                .contains("2024-03-01T12:00:00")));
+
+        let mut simulation = Simulation::new(&parms(DeliveryConfig {
+            duplicate_rate: 1.0,
+            ..DeliveryConfig::default()
+        }));
+        let messages: Vec<Message> = simulation.iter(0).collect();
+        assert_eq!(messages.len(), 20);
+        assert_eq!(messages[0], messages[1]);
+        assert_ne!(messages[1], messages[2]);
+    }
+
+    /// The data points of a message without the time stamp.
+    fn data_points(simulation: &mut Simulation) -> Vec<String> {
+        simulation
+            .iter(0)
+            .map(|(_topic, data)| {
+                String::from_utf8(data)
+                    .unwrap()
+                    .split(',')
+                    .skip(3)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_simulation_shard() {
+        let start = Utc::now();
+        let parms = |client_id: &str, shard| SimulationParameters {
+            client_id: client_id.to_string(),
+            devices: 5,
+            children: 1,
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                ..TimeConfig::default()
+            },
+            shard,
+            ..parameters()
+        };
+        let shard = |index| Shard {
+            index,
+            count: 2,
+            cluster_id: "test".to_string(),
+        };
+        assert_eq!(shard(0).devices(5), 0..2);
+        assert_eq!(shard(1).devices(5), 2..5);
+
+        // The shards together publish the same as a single instance.
+        let expected: Vec<Message> = Simulation::new(&parms("test", None)).iter(0).collect();
+        let mut sharded: Vec<Message> = Simulation::new(&parms("test-0", Some(shard(0))))
+            .iter(0)
+            .collect();
+        let second: Vec<Message> = Simulation::new(&parms("test-1", Some(shard(1))))
+            .iter(0)
+            .collect();
+        assert!(sharded.len() < expected.len());
+        sharded.extend(second);
+        assert_eq!(sharded, expected);
+    }
+
+    #[test]
+    fn test_simulation_snapshot() {
+        let parms = SimulationParameters {
+            devices: 3,
+            data_points: 20,
+            generators: GeneratorConfig {
+                mix: [
+                    GeneratorType::Status,
+                    GeneratorType::Counter,
+                    GeneratorType::RandomWalk,
+                    GeneratorType::Setpoint,
+                    GeneratorType::Location,
+                    GeneratorType::Sensor,
+                ]
+                .into_iter()
+                .map(|generator_type| (generator_type, 1))
+                .collect(),
+                anomaly: AnomalyConfig {
+                    rate: 0.1,
+                    ..AnomalyConfig::default()
+                },
+                offline: OfflineConfig {
+                    rate: 0.1,
+                    duration: 3,
+                    recovery_code: Some(1),
+                },
+                ..GeneratorConfig::default()
+            },
+            ..parameters()
+        };
+        let path = std::env::temp_dir().join(format!("rumsim-{}.json", std::process::id()));
+
+        let mut simulation = Simulation::new(&parms);
+        for _ in 0..10 {
+            data_points(&mut simulation);
+        }
+        simulation.save(&path, 10).unwrap();
+        let expected: Vec<Vec<String>> = (0..10).map(|_| data_points(&mut simulation)).collect();
+
+        let mut restored = Simulation::new(&parms);
+        assert_eq!(restored.restore(&path), Ok(10));
+        let actual: Vec<Vec<String>> = (0..10).map(|_| data_points(&mut restored)).collect();
+        assert_eq!(actual, expected);
+
+        let mut smaller = Simulation::new(&SimulationParameters {
+            devices: 2,
+            ..parms
+        });
+        assert!(smaller.restore(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_threads() {
+        let start = Utc::now();
+        let parms = |threads| SimulationParameters {
+            devices: 1000,
+            data_points: 2,
+            time: TimeConfig {
+                mode: TimeMode::Simulated,
+                start: Some(start),
+                ..TimeConfig::default()
+            },
+            threads,
+            ..parameters()
+        };
+        let messages = |threads| {
+            let mut simulation = Simulation::new(&parms(threads));
+            let mut messages = Vec::new();
+            for run in 0..2 {
+                let mut iter = simulation.iter(run);
+                while let Some(message) = iter.next() {
+                    messages.push((iter.device().unwrap().to_string(), message));
+                }
+            }
+            messages
+        };
+        // More devices than the threads generate at a time, in the same order and with the same values.
+        let single = messages(1);
+        assert_eq!(single.len(), 2000);
+        assert_eq!(single[999].0, "test_999");
+        assert_eq!(messages(3), single);
+    }
 }
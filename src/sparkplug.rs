@@ -0,0 +1,257 @@
+//! Sparkplug B topic namespace and payloads.
+//!
+//! The simulator is a single edge node that publishes on behalf of all of its devices.
+//! The edge node announces itself with NBIRTH on startup and registers NDEATH as MQTT last will.
+//! Each device publishes DBIRTH with all of its metrics and their aliases the first time it sends
+//! data and DDATA with aliases only afterwards. If a device sends a metric that was not part of
+//! its birth message, for example because the data point was offline during the birth, it publishes
+//! a new DBIRTH instead.
+use chrono::{DateTime, Utc};
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::generator::Value;
+use crate::payload::Measurement;
+
+const NAMESPACE: &str = "spBv1.0";
+
+/// Sparkplug B data types of metrics.
+const DATATYPE_INT64: u32 = 4;
+const DATATYPE_DOUBLE: u32 = 10;
+const DATATYPE_BOOLEAN: u32 = 11;
+const DATATYPE_STRING: u32 = 12;
+
+/// The subset of the Sparkplug B payload definition used by the simulator.
+#[derive(Clone, PartialEq, Message)]
+pub struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(oneof = "MetricValue", tags = "11, 13, 14, 15")]
+    pub value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum MetricValue {
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(double, tag = "13")]
+    Double(f64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    Text(String),
+}
+
+impl Metric {
+    fn new(name: Option<String>, alias: u64, time: u64, value: &Value) -> Metric {
+        let (datatype, value) = match value {
+            // Sparkplug transports signed integers in their two's complement representation.
+            Value::Int(value) => (DATATYPE_INT64, MetricValue::Long(*value as u64)),
+            Value::Float(value) => (DATATYPE_DOUBLE, MetricValue::Double(*value)),
+            Value::Bool(value) => (DATATYPE_BOOLEAN, MetricValue::Boolean(*value)),
+            Value::Text(value) => (DATATYPE_STRING, MetricValue::Text(value.clone())),
+        };
+        Metric {
+            name,
+            alias: Some(alias),
+            timestamp: Some(time),
+            datatype: Some(datatype),
+            value: Some(value),
+        }
+    }
+}
+
+/// The edge node that publishes the messages of all devices of a simulation.
+#[derive(Debug, Clone)]
+pub struct SparkplugNode {
+    pub group: String,
+    pub node: String,
+    /// The sequence number of the last message, shared by all devices of the edge node.
+    seq: Arc<AtomicU64>,
+}
+
+impl SparkplugNode {
+    pub fn new(group: &str, node: &str) -> Self {
+        SparkplugNode {
+            group: group.to_string(),
+            node: node.to_string(),
+            seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        match device {
+            Some(device) => format!(
+                "{}/{}/{}/{}/{}",
+                NAMESPACE, self.group, message_type, self.node, device
+            ),
+            None => format!(
+                "{}/{}/{}/{}",
+                NAMESPACE, self.group, message_type, self.node
+            ),
+        }
+    }
+
+    /// Sequence numbers of messages run from 0 to 255. NBIRTH always has the sequence number 0.
+    fn next_seq(&self) -> u64 {
+        (self.seq.fetch_add(1, Ordering::Relaxed) + 1) % 256
+    }
+
+    /// The NBIRTH message that announces the edge node. The birth/death sequence number
+    /// is always 0, since the simulator does not keep it across restarts.
+    pub fn birth(&self, time: DateTime<Utc>) -> (String, Vec<u8>) {
+        self.seq.store(0, Ordering::Relaxed);
+        let time = time.timestamp_millis() as u64;
+        let payload = Payload {
+            timestamp: Some(time),
+            metrics: vec![
+                Metric::new(Some("bdSeq".to_string()), 0, time, &Value::Int(0)),
+                Metric::new(
+                    Some("Node Control/Rebirth".to_string()),
+                    1,
+                    time,
+                    &Value::Bool(false),
+                ),
+            ],
+            seq: Some(0),
+        };
+        (self.topic("NBIRTH", None), payload.encode_to_vec())
+    }
+
+    /// The NDEATH message that is registered as last will of the MQTT connection.
+    pub fn death(&self) -> (String, Vec<u8>) {
+        let payload = Payload {
+            timestamp: None,
+            metrics: vec![Metric {
+                name: Some("bdSeq".to_string()),
+                alias: None,
+                timestamp: None,
+                datatype: Some(DATATYPE_INT64),
+                value: Some(MetricValue::Long(0)),
+            }],
+            seq: None,
+        };
+        (self.topic("NDEATH", None), payload.encode_to_vec())
+    }
+}
+
+/// The aliases of the metrics of a device.
+#[derive(Debug, Default)]
+pub struct SparkplugDevice {
+    aliases: HashMap<String, u64>,
+}
+
+impl SparkplugDevice {
+    /// Create a DDATA message with the measurements, or a DBIRTH message if any of the
+    /// measurements has not been announced yet.
+    pub fn data(
+        &mut self,
+        node: &SparkplugNode,
+        device: &str,
+        time: DateTime<Utc>,
+        measurements: &[Measurement],
+    ) -> (String, Vec<u8>) {
+        let birth = measurements
+            .iter()
+            .any(|(name, _, _)| !self.aliases.contains_key(*name));
+        if birth {
+            // Aliases of earlier births are kept, so that they stay stable across births.
+            for (name, _, _) in measurements {
+                let next_alias = self.aliases.len() as u64;
+                self.aliases.entry(name.to_string()).or_insert(next_alias);
+            }
+        }
+
+        let time_millis = time.timestamp_millis() as u64;
+        let metrics = measurements
+            .iter()
+            .map(|(name, value, _)| {
+                let alias = self.aliases[*name];
+                Metric::new(birth.then(|| name.to_string()), alias, time_millis, value)
+            })
+            .collect();
+        let payload = Payload {
+            timestamp: Some(time_millis),
+            metrics,
+            seq: Some(node.next_seq()),
+        };
+        let message_type = if birth { "DBIRTH" } else { "DDATA" };
+        (
+            node.topic(message_type, Some(device)),
+            payload.encode_to_vec(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8]) -> Payload {
+        Payload::decode(data).unwrap()
+    }
+
+    #[test]
+    fn test_node_birth_and_death() {
+        let node = SparkplugNode::new("factory", "rumsim-0");
+        let (topic, data) = node.birth(Utc::now());
+        assert_eq!(topic, "spBv1.0/factory/NBIRTH/rumsim-0");
+        let payload = decode(&data);
+        assert_eq!(payload.seq, Some(0));
+        assert_eq!(payload.metrics[0].name.as_deref(), Some("bdSeq"));
+
+        let (topic, data) = node.death();
+        assert_eq!(topic, "spBv1.0/factory/NDEATH/rumsim-0");
+        assert_eq!(decode(&data).metrics[0].value, Some(MetricValue::Long(0)));
+    }
+
+    #[test]
+    fn test_device_birth_and_data() {
+        let node = SparkplugNode::new("factory", "rumsim-0");
+        node.birth(Utc::now());
+        let mut device = SparkplugDevice::default();
+        let measurements = vec![
+            ("sensor_0", Value::Float(1.5), None),
+            ("status_0", Value::Int(-1), None),
+        ];
+
+        let (topic, data) = device.data(&node, "rumsim-0_1", Utc::now(), &measurements);
+        assert_eq!(topic, "spBv1.0/factory/DBIRTH/rumsim-0/rumsim-0_1");
+        let payload = decode(&data);
+        assert_eq!(payload.seq, Some(1));
+        assert_eq!(payload.metrics[0].name.as_deref(), Some("sensor_0"));
+        assert_eq!(payload.metrics[1].alias, Some(1));
+        assert_eq!(payload.metrics[1].datatype, Some(DATATYPE_INT64));
+        assert_eq!(payload.metrics[1].value, Some(MetricValue::Long(u64::MAX)));
+
+        let (topic, data) = device.data(&node, "rumsim-0_1", Utc::now(), &measurements[1..]);
+        assert_eq!(topic, "spBv1.0/factory/DDATA/rumsim-0/rumsim-0_1");
+        let payload = decode(&data);
+        assert_eq!(payload.seq, Some(2));
+        assert_eq!(payload.metrics[0].name, None);
+        assert_eq!(payload.metrics[0].alias, Some(1));
+
+        let measurements = vec![("alarm_0", Value::Bool(true), None)];
+        let (topic, data) = device.data(&node, "rumsim-0_1", Utc::now(), &measurements);
+        assert_eq!(topic, "spBv1.0/factory/DBIRTH/rumsim-0/rumsim-0_1");
+        assert_eq!(decode(&data).metrics[0].alias, Some(2));
+    }
+}
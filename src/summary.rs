@@ -0,0 +1,300 @@
+//! The summary of a simulation at the end, so that benchmarks can be compared, for example in CI.
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The reconnects are counted by the listeners of the connections, which run separately from the simulation.
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn reconnects() -> u64 {
+    RECONNECTS.load(Ordering::Relaxed)
+}
+
+/// The latencies are kept in buckets that are 1% apart, so that the memory does not grow with the messages.
+const BUCKET_FACTOR: f64 = 1.01;
+const BUCKETS: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub messages: u64,
+    pub data_points: u64,
+    pub runs: usize,
+    pub duration_secs: f64,
+    pub avg_latency_ms: f64,
+    pub p95_latency_ms: f64,
+    pub overloads: u64,
+    pub reconnects: u64,
+    pub publish_errors: u64,
+    pub skipped_runs: u64,
+    pub dropped_messages: u64,
+    /// The highest rate of messages of a run that was not overloaded.
+    pub sustained_msgs_per_sec: f64,
+    /// The messages that were received back with BROKER_LOOPBACK_TOPIC and their time from publishing to receiving.
+    pub loopback_messages: u64,
+    pub avg_loopback_ms: f64,
+    pub p95_loopback_ms: f64,
+    pub p99_loopback_ms: f64,
+    /// The messages with QoS 1 or 2 that the broker acknowledged and their time from sending to the acknowledgement.
+    pub acknowledged_messages: u64,
+    pub avg_ack_ms: f64,
+    pub p99_ack_ms: f64,
+    /// The runs in which the 99th percentile of the acknowledgements exceeded BROKER_ACK_BUDGET_MS.
+    pub slow_ack_runs: u64,
+}
+
+impl Summary {
+    /// Log the summary and write it as JSON to the given file.
+    pub fn report(&self, file: Option<&str>) {
+        info!(
+            messages = self.messages,
+            data_points = self.data_points,
+            runs = self.runs,
+            duration_secs = self.duration_secs,
+            avg_latency_ms = self.avg_latency_ms,
+            p95_latency_ms = self.p95_latency_ms,
+            overloads = self.overloads,
+            reconnects = self.reconnects,
+            publish_errors = self.publish_errors,
+            skipped_runs = self.skipped_runs,
+            dropped_messages = self.dropped_messages,
+            sustained_msgs_per_sec = self.sustained_msgs_per_sec,
+            loopback_messages = self.loopback_messages,
+            avg_loopback_ms = self.avg_loopback_ms,
+            p95_loopback_ms = self.p95_loopback_ms,
+            p99_loopback_ms = self.p99_loopback_ms,
+            acknowledged_messages = self.acknowledged_messages,
+            avg_ack_ms = self.avg_ack_ms,
+            p99_ack_ms = self.p99_ack_ms,
+            slow_ack_runs = self.slow_ack_runs,
+            "Summary."
+        );
+        if let Some(file) = file {
+            // It's OK to unwrap, since the summary is always valid JSON.
+            let json = serde_json::to_vec_pretty(self).unwrap();
+            if let Err(e) = std::fs::write(file, json) {
+                warn!(error = ?e, file, "Failed to write the summary");
+            }
+        }
+    }
+}
+
+/// Collects the summary while the simulation runs.
+pub struct Recorder {
+    start: Instant,
+    messages: u64,
+    data_points: u64,
+    runs: usize,
+    overloads: u64,
+    publish_errors: u64,
+    skipped_runs: u64,
+    dropped_messages: u64,
+    sustained_rate: f64,
+    latencies: Latencies,
+    loopback: Latencies,
+    acks: Latencies,
+    slow_ack_runs: u64,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            start: Instant::now(),
+            messages: 0,
+            data_points: 0,
+            runs: 0,
+            overloads: 0,
+            publish_errors: 0,
+            skipped_runs: 0,
+            dropped_messages: 0,
+            sustained_rate: 0.0,
+            latencies: Latencies::new(),
+            loopback: Latencies::new(),
+            acks: Latencies::new(),
+            slow_ack_runs: 0,
+        }
+    }
+
+    pub fn record_publish(&mut self, latency: Duration) {
+        self.messages += 1;
+        self.latencies.record(latency);
+    }
+
+    pub fn record_run(&mut self, data_points: usize) {
+        self.runs += 1;
+        self.data_points += data_points as u64;
+    }
+
+    pub fn record_overload(&mut self) {
+        self.overloads += 1;
+    }
+
+    pub fn record_publish_errors(&mut self, errors: u64) {
+        self.publish_errors += errors;
+    }
+
+    pub fn record_skipped_runs(&mut self, runs: usize) {
+        self.skipped_runs += runs as u64;
+    }
+
+    pub fn record_dropped_messages(&mut self, messages: u64) {
+        self.dropped_messages += messages;
+    }
+
+    pub fn record_loopback(&mut self, latencies: &Latencies) {
+        self.loopback.merge(latencies);
+    }
+
+    /// Record the acknowledgements of a run, and whether they were slower than the budget.
+    pub fn record_acks(&mut self, latencies: &Latencies, slow: bool) {
+        self.acks.merge(latencies);
+        if slow {
+            self.slow_ack_runs += 1;
+        }
+    }
+
+    pub fn record_sustained_rate(&mut self, msgs_per_sec: f64) {
+        self.sustained_rate = self.sustained_rate.max(msgs_per_sec);
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            messages: self.messages,
+            data_points: self.data_points,
+            runs: self.runs,
+            duration_secs: self.start.elapsed().as_secs_f64(),
+            avg_latency_ms: millis(self.latencies.average()),
+            p95_latency_ms: millis(self.latencies.percentile(0.95)),
+            overloads: self.overloads,
+            reconnects: reconnects(),
+            publish_errors: self.publish_errors,
+            skipped_runs: self.skipped_runs,
+            dropped_messages: self.dropped_messages,
+            sustained_msgs_per_sec: self.sustained_rate,
+            loopback_messages: self.loopback.count(),
+            avg_loopback_ms: millis(self.loopback.average()),
+            p95_loopback_ms: millis(self.loopback.percentile(0.95)),
+            p99_loopback_ms: millis(self.loopback.percentile(0.99)),
+            acknowledged_messages: self.acks.count(),
+            avg_ack_ms: millis(self.acks.average()),
+            p99_ack_ms: millis(self.acks.percentile(0.99)),
+            slow_ack_runs: self.slow_ack_runs,
+        }
+    }
+}
+
+/// A distribution of latencies in buckets.
+#[derive(Debug, Clone)]
+pub struct Latencies {
+    count: u64,
+    sum: Duration,
+    buckets: Vec<u64>,
+}
+
+impl Latencies {
+    pub fn new() -> Self {
+        Latencies {
+            count: 0,
+            sum: Duration::ZERO,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.sum += latency;
+        self.buckets[bucket(latency)] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn average(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.sum.div_f64(count as f64),
+        }
+    }
+
+    /// The upper bound of the bucket with the given percentile of the latencies.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let rank = (self.count as f64 * percentile).ceil() as u64;
+        let mut count = 0;
+        for (bucket, latencies) in self.buckets.iter().enumerate() {
+            count += latencies;
+            if count >= rank.max(1) {
+                return Duration::from_micros(BUCKET_FACTOR.powi(bucket as i32 + 1) as u64);
+            }
+        }
+        Duration::ZERO
+    }
+
+    /// Add the latencies of another distribution.
+    pub fn merge(&mut self, other: &Latencies) {
+        self.count += other.count;
+        self.sum += other.sum;
+        for (bucket, latencies) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += latencies;
+        }
+    }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The bucket of a latency in microseconds. Latencies below one microsecond are in the first bucket, latencies above
+/// about 12 minutes in the last.
+fn bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros().max(1) as f64;
+    (micros.ln() / BUCKET_FACTOR.ln())
+        .floor()
+        .min((BUCKETS - 1) as f64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut recorder = Recorder::new();
+        assert_eq!(recorder.summary().p95_latency_ms, 0.0);
+        for millis in 1..=100 {
+            recorder.record_publish(Duration::from_millis(millis));
+        }
+        recorder.record_run(200);
+        recorder.record_overload();
+        recorder.record_skipped_runs(2);
+        recorder.record_dropped_messages(3);
+        recorder.record_sustained_rate(20.0);
+        recorder.record_sustained_rate(10.0);
+        let mut loopback = Latencies::new();
+        loopback.record(Duration::from_millis(4));
+        recorder.record_loopback(&loopback);
+        recorder.record_loopback(&loopback);
+        recorder.record_acks(&loopback, false);
+        recorder.record_acks(&Latencies::new(), true);
+
+        let summary = recorder.summary();
+        assert_eq!((summary.messages, summary.data_points), (100, 200));
+        assert_eq!((summary.overloads, summary.skipped_runs), (1, 2));
+        assert_eq!(summary.dropped_messages, 3);
+        assert_eq!(summary.sustained_msgs_per_sec, 20.0);
+        assert_eq!(summary.loopback_messages, 2);
+        assert!((summary.avg_loopback_ms - 4.0).abs() < 1e-6);
+        assert!((summary.p99_loopback_ms - 4.0).abs() < 0.1);
+        assert_eq!(
+            (summary.acknowledged_messages, summary.slow_ack_runs),
+            (1, 1)
+        );
+        assert!((summary.avg_latency_ms - 50.5).abs() < 1e-6);
+        // Within the precision of the buckets.
+        assert!((summary.p95_latency_ms - 95.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,59 @@
+//! The TLS configuration for brokers with a private CA, client certificates or ALPN.
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+/// Create the rustls configuration from PEM files, with the root certificates of the system if there is no CA file.
+/// The client certificate and key are optional, but only together.
+pub fn client_config(
+    ca: Option<&[u8]>,
+    client_auth: Option<(&[u8], &[u8])>,
+    alpn: Option<Vec<Vec<u8>>>,
+) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in rustls_pemfile::certs(&mut &*ca) {
+                roots
+                    .add(cert.map_err(|e| e.to_string())?)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs().map_err(|e| e.to_string())?;
+            roots.add_parsable_certificates(certs);
+        }
+    }
+    if roots.is_empty() {
+        return Err("No CA certificate found".to_string());
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let mut config = match client_auth {
+        Some((cert, key)) => {
+            let certs = rustls_pemfile::certs(&mut &*cert)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?;
+            let key = rustls_pemfile::private_key(&mut &*key)
+                .map_err(|e| e.to_string())?
+                .ok_or("No private key found")?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| e.to_string())?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    if let Some(alpn) = alpn {
+        config.alpn_protocols = alpn;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_config_without_ca() {
+        let result = client_config(Some(b""), None, None);
+        assert_eq!(result.err(), Some("No CA certificate found".to_string()));
+    }
+}